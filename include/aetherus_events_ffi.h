@@ -0,0 +1,48 @@
+/* C API of aetherus-events for engines populating a ledger, built with the `ffi` feature:
+ *     cargo rustc --release --features ffi --crate-type staticlib
+ * Raw events are composed with the constants of the gen-header output, or event_encode_mcrt. */
+#ifndef AETHERUS_EVENTS_FFI_H
+#define AETHERUS_EVENTS_FFI_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define AEV_OK          0
+#define AEV_ERR_NULL    (-1)
+#define AEV_ERR_INVALID (-2)
+#define AEV_ERR_LEDGER  (-3)
+#define AEV_ERR_IO      (-4)
+#define AEV_ERR_PANIC   (-5)
+
+typedef struct AevLedger AevLedger;
+
+typedef struct AevUid {
+    uint32_t seq_id;
+    uint32_t event;
+} AevUid;
+
+AevLedger *ledger_new(void);
+void ledger_free(AevLedger *ledger);
+
+/* Id of the registered source, or a negative error code. `group` may be NULL. */
+int32_t ledger_with_mat(AevLedger *ledger, const char *name);
+int32_t ledger_with_surf(AevLedger *ledger, const char *name, const char *group);
+int32_t ledger_with_light(AevLedger *ledger, const char *name);
+int32_t ledger_with_detector(AevLedger *ledger, const char *name);
+
+/* AEV_OK or a negative error code, the UID of the inserted event is written to `uid` */
+int32_t ledger_insert_start(AevLedger *ledger, uint32_t event, AevUid *uid);
+int32_t ledger_insert(AevLedger *ledger, AevUid prev, uint32_t event, AevUid *uid);
+int32_t ledger_write_json(AevLedger *ledger, const char *path);
+
+/* MCRT event from the unshifted codes of the raw enums, 0 for codes without a variant */
+uint32_t event_encode_mcrt(uint8_t supertype, uint8_t subtype, uint8_t scatter, uint8_t dir, uint16_t src_id);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* AETHERUS_EVENTS_FFI_H */
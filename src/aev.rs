@@ -0,0 +1,442 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::TryDecode;
+use crate::EventId;
+use crate::ledger::{Ledger, SrcTable, Uid};
+
+// ----------------------------------------------------
+// Binary event stream format (.aev)
+// ----------------------------------------------------
+// Framed little-endian stream, written while the simulation runs and replayed offline into a Ledger:
+// | Magic "AEV\0" (4) | Layout version (u16) | Frame encoding (u8) | SrcTable length (u32) | SrcTable JSON |
+// followed by the frames, either raw:
+// | Frame: prev_seq (u32) | raw event (u32) | ...
+// or packed in blocks:
+// | Block: payload length (u32) | frame count (u32) | payload | ...
+// where the payload holds the zigzag varint delta of prev_seq to the previous frame and the varint
+// raw event of each frame, optionally compressed with zstd.
+// `prev_seq` is the seq_id of the entry, i.e. the seq_id allocated to its cause, with 0 for
+// start events. Layout version 1 has no frame encoding byte and only raw frames.
+
+pub const MAGIC: [u8; 4] = *b"AEV\0";
+pub const LAYOUT_VERSION: u16 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameEncoding {
+    Raw,
+    // Blocks of `frames` delta/varint encoded frames
+    Varint { frames: u32 },
+    // Same as Varint, with each block payload compressed with zstd at `level`
+    #[cfg(feature = "zstd")]
+    Zstd { frames: u32, level: i32 },
+}
+
+impl FrameEncoding {
+    pub const DEFAULT_BLOCK_FRAMES: u32 = 4096;
+
+    fn code(&self) -> u8 {
+        match self {
+            FrameEncoding::Raw => 0,
+            FrameEncoding::Varint { .. } => 1,
+            #[cfg(feature = "zstd")]
+            FrameEncoding::Zstd { .. } => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> io::Result<Self> {
+        match code {
+            0 => Ok(FrameEncoding::Raw),
+            1 => Ok(FrameEncoding::Varint { frames: Self::DEFAULT_BLOCK_FRAMES }),
+            #[cfg(feature = "zstd")]
+            2 => Ok(FrameEncoding::Zstd { frames: Self::DEFAULT_BLOCK_FRAMES, level: 0 }),
+            #[cfg(not(feature = "zstd"))]
+            2 => Err(invalid_data("Stream is zstd compressed, enable the zstd feature to read it".to_string())),
+            _ => Err(invalid_data(format!("Unknown aev frame encoding {}", code))),
+        }
+    }
+
+    fn block_frames(&self) -> Option<u32> {
+        match self {
+            FrameEncoding::Raw => None,
+            FrameEncoding::Varint { frames } => Some(*frames),
+            #[cfg(feature = "zstd")]
+            FrameEncoding::Zstd { frames, .. } => Some(*frames),
+        }
+    }
+}
+
+pub(crate) fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Insert the entry of a (prev_seq, raw event) frame, frames must be replayed in insertion order
+pub(crate) fn replay_frame(ledger: &mut Ledger, prev_seq: u32, raw: u32) -> io::Result<Uid> {
+    let event = EventId::try_decode(raw).map_err(|err| invalid_data(err.to_string()))?;
+    if prev_seq == 0 {
+        return ledger.insert_start(event).map_err(|err| invalid_data(err.to_string()));
+    }
+    let prev_uid = ledger
+        .get_prev(prev_seq)
+        .ok_or_else(|| invalid_data(format!("Frame 0x{:08X} refers to unknown seq_id {}", raw, prev_seq)))?;
+    ledger.insert(prev_uid, event).map_err(|err| invalid_data(err.to_string()))
+}
+
+// ----------------------------------------------------
+// Varint helpers
+// ----------------------------------------------------
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn read_varint(payload: &[u8], pos: &mut usize) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *payload.get(*pos).ok_or_else(|| invalid_data("Truncated varint in aev block".to_string()))?;
+        *pos += 1;
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_data("Varint overflow in aev block".to_string()))
+}
+
+fn zigzag(delta: i64) -> u64 {
+    ((delta << 1) ^ (delta >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+// ----------------------------------------------------
+// Writer
+// ----------------------------------------------------
+pub struct AevWriter<W: Write> {
+    writer: W,
+    encoding: FrameEncoding,
+    // Pending block of the Varint/Zstd encodings
+    block: Vec<u8>,
+    block_frames: u32,
+    last_prev_seq: u32,
+}
+
+impl AevWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, ledger: &Ledger) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), ledger)
+    }
+}
+
+impl<W: Write> AevWriter<W> {
+    // Write the header with the sources currently registered in `ledger`
+    pub fn new(writer: W, ledger: &Ledger) -> io::Result<Self> {
+        Self::with_encoding(writer, ledger, FrameEncoding::Raw)
+    }
+
+    pub fn with_encoding(mut writer: W, ledger: &Ledger, encoding: FrameEncoding) -> io::Result<Self> {
+        if let Some(frames) = encoding.block_frames() {
+            assert!(frames > 0, "Block size must be at least one frame");
+        }
+        let src_table = serde_json::to_vec(&ledger.src_table())?;
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&LAYOUT_VERSION.to_le_bytes())?;
+        writer.write_all(&[encoding.code()])?;
+        writer.write_all(&(src_table.len() as u32).to_le_bytes())?;
+        writer.write_all(&src_table)?;
+        Ok(Self {
+            writer,
+            encoding,
+            block: Vec::new(),
+            block_frames: 0,
+            last_prev_seq: 0,
+        })
+    }
+
+    // Log an entry with the Uid returned by `Ledger::insert` or `Ledger::insert_start`
+    pub fn write(&mut self, uid: &Uid) -> io::Result<()> {
+        self.write_frame(uid.seq_id, uid.event)
+    }
+
+    pub fn write_frame(&mut self, prev_seq: u32, raw: u32) -> io::Result<()> {
+        let Some(max_frames) = self.encoding.block_frames() else {
+            let mut frame = [0u8; 8];
+            frame[..4].copy_from_slice(&prev_seq.to_le_bytes());
+            frame[4..].copy_from_slice(&raw.to_le_bytes());
+            return self.writer.write_all(&frame);
+        };
+        write_varint(&mut self.block, zigzag(prev_seq as i64 - self.last_prev_seq as i64));
+        write_varint(&mut self.block, raw as u64);
+        self.last_prev_seq = prev_seq;
+        self.block_frames += 1;
+        if self.block_frames >= max_frames {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    // NOTE: Flushing closes the pending block, flushing too often degrades the compression
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.writer.flush()
+    }
+
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.block_frames == 0 {
+            return Ok(());
+        }
+        let payload = match self.encoding {
+            #[cfg(feature = "zstd")]
+            FrameEncoding::Zstd { level, .. } => zstd::bulk::compress(&self.block, level)?,
+            _ => std::mem::take(&mut self.block),
+        };
+        self.writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&self.block_frames.to_le_bytes())?;
+        self.writer.write_all(&payload)?;
+        self.block.clear();
+        self.block_frames = 0;
+        // Each block is self-contained, such that a truncated stream can be read up to the last block
+        self.last_prev_seq = 0;
+        Ok(())
+    }
+}
+
+// ----------------------------------------------------
+// Reader
+// ----------------------------------------------------
+pub struct AevReader<R: Read> {
+    reader: R,
+    version: u16,
+    encoding: FrameEncoding,
+    src_table: SrcTable,
+    // Decoded frames of the current block
+    pending: VecDeque<(u32, u32)>,
+}
+
+impl AevReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> AevReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid_data("Not an aev event stream".to_string()));
+        }
+        let mut version = [0u8; 2];
+        reader.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        if version > LAYOUT_VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported aev layout version {}, expected at most {}",
+                version, LAYOUT_VERSION
+            )));
+        }
+        let encoding = if version >= 2 {
+            let mut code = [0u8; 1];
+            reader.read_exact(&mut code)?;
+            FrameEncoding::from_code(code[0])?
+        } else {
+            FrameEncoding::Raw
+        };
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let mut src_table = vec![0u8; u32::from_le_bytes(len) as usize];
+        reader.read_exact(&mut src_table)?;
+        let src_table = serde_json::from_slice(&src_table)?;
+        Ok(Self {
+            reader,
+            version,
+            encoding,
+            src_table,
+            pending: VecDeque::new(),
+        })
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn src_table(&self) -> &SrcTable {
+        &self.src_table
+    }
+
+    // Next (prev_seq, raw) frame, or None at the end of the stream
+    pub fn read_frame(&mut self) -> io::Result<Option<(u32, u32)>> {
+        if self.encoding.block_frames().is_none() {
+            let mut frame = [0u8; 8];
+            if !self.read_chunk(&mut frame)? {
+                return Ok(None);
+            }
+            let prev_seq = u32::from_le_bytes(frame[..4].try_into().unwrap());
+            let raw = u32::from_le_bytes(frame[4..].try_into().unwrap());
+            return Ok(Some((prev_seq, raw)));
+        }
+        while self.pending.is_empty() {
+            if !self.read_block()? {
+                return Ok(None);
+            }
+        }
+        Ok(self.pending.pop_front())
+    }
+
+    // Replay all frames into a Ledger with the sources of the header
+    pub fn into_ledger(mut self) -> io::Result<Ledger> {
+        let mut ledger = Ledger::from_src_table(self.src_table.clone());
+        while let Some((prev_seq, raw)) = self.read_frame()? {
+            replay_frame(&mut ledger, prev_seq, raw)?;
+        }
+        Ok(ledger)
+    }
+
+    // Fill `buffer`, returning false on a clean end of stream
+    fn read_chunk(&mut self, buffer: &mut [u8]) -> io::Result<bool> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match self.reader.read(&mut buffer[filled..])? {
+                0 if filled == 0 => return Ok(false),
+                0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated aev frame")),
+                n => filled += n,
+            }
+        }
+        Ok(true)
+    }
+
+    fn read_block(&mut self) -> io::Result<bool> {
+        let mut block_header = [0u8; 8];
+        if !self.read_chunk(&mut block_header)? {
+            return Ok(false);
+        }
+        let len = u32::from_le_bytes(block_header[..4].try_into().unwrap()) as usize;
+        let frames = u32::from_le_bytes(block_header[4..].try_into().unwrap());
+        let mut payload = vec![0u8; len];
+        self.reader.read_exact(&mut payload)?;
+        #[cfg(feature = "zstd")]
+        if let FrameEncoding::Zstd { .. } = self.encoding {
+            // Each frame takes at most 5 + 5 bytes
+            payload = zstd::bulk::decompress(&payload, frames as usize * 10)?;
+        }
+
+        let mut pos = 0;
+        let mut prev_seq = 0u32;
+        for _ in 0..frames {
+            let delta = unzigzag(read_varint(&payload, &mut pos)?);
+            prev_seq = u32::try_from(prev_seq as i64 + delta)
+                .map_err(|_| invalid_data("Invalid prev_seq delta in aev block".to_string()))?;
+            let raw = u32::try_from(read_varint(&payload, &mut pos)?)
+                .map_err(|_| invalid_data("Invalid raw event in aev block".to_string()))?;
+            self.pending.push_back((prev_seq, raw));
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SrcId, mcrt_event};
+    use crate::emission::Emission;
+    use crate::detection::Detection;
+    use crate::ledger::RunMetadata;
+
+    // Ledger with a diffuse chain and the entries in insertion order
+    fn diffuse_ledger() -> (Ledger, Vec<Uid>) {
+        let mut ledger = Ledger::new();
+        ledger.set_metadata(RunMetadata::new("run-42".to_string()).with_seed(7));
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let uid = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+        let mut uids = vec![uid];
+        for _ in 0..50 {
+            let uid = ledger.insert(*uids.last().unwrap(), EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+            uids.push(uid);
+        }
+        let uid = ledger.insert(*uids.last().unwrap(), EventId::new_detection(Detection::Direct, SrcId::Detector(0))).unwrap();
+        uids.push(uid);
+        (ledger, uids)
+    }
+
+    fn round_trip(encoding: FrameEncoding) -> usize {
+        let (ledger, uids) = diffuse_ledger();
+        let mut writer = AevWriter::with_encoding(Vec::new(), &ledger, encoding).expect("Unable to write header");
+        for uid in &uids {
+            writer.write(uid).expect("Unable to write frame");
+        }
+        let bytes = writer.into_inner().unwrap();
+
+        let reader = AevReader::new(bytes.as_slice()).expect("Unable to read header");
+        assert_eq!(reader.version(), LAYOUT_VERSION);
+        assert_eq!(reader.src_table(), &ledger.src_table());
+        let replayed = reader.into_ledger().expect("Unable to replay events");
+        assert_eq!(replayed.get_chain(*uids.last().unwrap()), uids);
+        assert_eq!(replayed.metadata(), ledger.metadata());
+        bytes.len()
+    }
+
+    #[test]
+    fn aev_round_trip() {
+        let raw_size = round_trip(FrameEncoding::Raw);
+        let varint_size = round_trip(FrameEncoding::Varint { frames: 16 });
+        assert!(varint_size < raw_size, "{} >= {}", varint_size, raw_size);
+        #[cfg(feature = "zstd")]
+        {
+            let zstd_size = round_trip(FrameEncoding::Zstd { frames: 64, level: 3 });
+            assert!(zstd_size < varint_size, "{} >= {}", zstd_size, varint_size);
+        }
+    }
+
+    #[test]
+    fn aev_reads_layout_v1() {
+        let ledger = Ledger::new();
+        let src_table = serde_json::to_vec(&ledger.src_table()).unwrap();
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&(src_table.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&src_table);
+        bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        let mut reader = AevReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.version(), 1);
+        assert_eq!(reader.read_frame().unwrap(), Some((0, 0x01000000)));
+        assert_eq!(reader.read_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn aev_rejects_invalid_stream() {
+        assert!(AevReader::new(&b"JSON"[..]).is_err());
+
+        let ledger = Ledger::new();
+        let mut bytes = AevWriter::new(Vec::new(), &ledger).unwrap().into_inner().unwrap();
+        bytes.extend_from_slice(&[1, 0, 0]);
+        let mut reader = AevReader::new(bytes.as_slice()).unwrap();
+        assert!(reader.read_frame().is_err());
+    }
+
+    #[test]
+    fn varint_zigzag() {
+        let mut buffer = Vec::new();
+        for value in [0, 1, 127, 128, u32::MAX as u64] {
+            write_varint(&mut buffer, value);
+        }
+        let mut pos = 0;
+        for value in [0, 1, 127, 128, u32::MAX as u64] {
+            assert_eq!(read_varint(&buffer, &mut pos).unwrap(), value);
+        }
+        for delta in [0, -1, 1, i32::MIN as i64, i32::MAX as i64] {
+            assert_eq!(unzigzag(zigzag(delta)), delta);
+        }
+    }
+}
@@ -1,100 +1,335 @@
-use std::fs::File;
-use std::io::Read;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::error::Error;
 
-use serde::{Deserialize, Serialize};
-
-use aetherus_events::{filter_seq, ledger::Ledger};
-use aetherus_events::SrcId;
-use aetherus_events::filter::find_forward_uid_seq;
-
-#[derive(Deserialize, Serialize)]
-struct CsvRecord {
-    pos_x: f64,
-    pos_y: f64,
-    pos_z: f64,
-    dir_x: f64,
-    dir_y: f64,
-    dir_z: f64,
-    wavelength: f64,
-    power: f64,
-    weight: f64,
-    tof: f64,
-    #[serde(serialize_with = "array_bytes::ser_hexify", deserialize_with = "array_bytes::de_dehexify")]
-    uid: u64,
+use aetherus_events::{filter_seq, ledger::{Ledger, Uid, read_ledger_from_json, sample_uids}};
+use aetherus_events::{RawEvent, SrcId};
+use aetherus_events::filter::{FilterStep, find_forward_uid_expr, find_forward_uid_seq, parse_with_ledger};
+use aetherus_events::photons::{PhotonGate, PhotonRecord, PhotonSchema, UidColumn, read_photons_csv_with_schema, time_unit};
+
+// Outputs written next to the photon inputs, see the end of `main`
+const FILTERED_OUTPUT: &str = "filtered_photons.csv";
+const COMPLEMENT_OUTPUT: &str = "complement_photons.csv";
+const FILTERED_SUFFIX: &str = "_filtered";
+const COMPLEMENT_SUFFIX: &str = "_complement";
+
+// Output of a previous run, such that globs over the inputs don't pick it up again
+fn is_generated_output(path: &std::path::Path) -> bool {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    file_name == FILTERED_OUTPUT
+        || file_name == COMPLEMENT_OUTPUT
+        || (path.extension().is_some_and(|ext| ext == "csv")
+            && (stem.ends_with(FILTERED_SUFFIX) || stem.ends_with(COMPLEMENT_SUFFIX)))
+}
+
+// Photon inputs, expanding the arguments containing glob patterns, i.e. "photons_*.csv", without the
+// outputs of previous runs. Only CSV photon tables are read, Parquet ones are out of scope and
+// have to be converted first.
+fn expand_inputs(patterns: &[String]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        if pattern.contains(['*', '?', '[']) {
+            let mut matched = glob::glob(pattern)?
+                .filter(|path| path.as_ref().is_ok_and(|path| !is_generated_output(path)))
+                .collect::<Result<Vec<PathBuf>, _>>()?;
+            if matched.is_empty() {
+                return Err(format!("No photon file matches {}", pattern).into());
+            }
+            matched.sort();
+            paths.extend(matched);
+        } else {
+            paths.push(PathBuf::from(pattern));
+        }
+    }
+    if let Some(path) = paths.iter().find(|path| path.extension().is_some_and(|ext| ext == "parquet")) {
+        return Err(format!("Parquet photon files are out of scope, convert {} to CSV first", path.display()).into());
+    }
+    Ok(paths)
+}
+
+// Filter expression of a file holding one stage per line, `#` starting a comment
+fn read_filter_file(path: &str) -> Result<String, std::io::Error> {
+    let contents = std::fs::read_to_string(path)?;
+    let stages = contents.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<&str>>();
+    Ok(stages.join(" -> "))
+}
+
+// Compact description of the chain leading to `uid`, i.e. "Emission(PencilBeam) -> MCRT(..) -> Detection"
+fn chain_summary(ledger: &Ledger, uid: Uid) -> String {
+    ledger.get_chain(uid)
+        .iter()
+        .map(|chain_uid| format!("{:?}", chain_uid.event.decode().event_type))
+        .collect::<Vec<String>>()
+        .join(" -> ")
 }
 
-fn read_csv(file_path: &str) -> Result<Vec<CsvRecord>, Box<dyn Error>> {
-    let file = File::open(file_path)?;
-    let mut rdr = csv::Reader::from_reader(file);
-    let mut records = Vec::new();
+// Write the matched photon records and their chain summaries of each filter in its own group
+#[cfg(feature = "hdf5")]
+fn write_hdf5(
+    path: &std::path::Path,
+    filter_groups: &[(String, Vec<&PhotonRecord>)],
+    summaries: &HashMap<u64, String>,
+) -> hdf5::Result<()> {
+    use hdf5::types::VarLenUnicode;
 
-    for result in rdr.deserialize() {
-        let record: CsvRecord = result?;
-        records.push(record);
+    let file = hdf5::File::create(path)?;
+    for (idx, (filter_desc, records)) in filter_groups.iter().enumerate() {
+        let group = file.create_group(&format!("filter_{}", idx))?;
+        let filter_attr: VarLenUnicode = filter_desc.parse().unwrap();
+        group.new_attr::<VarLenUnicode>().create("filter")?.write_scalar(&filter_attr)?;
+
+        let columns: [(&str, fn(&PhotonRecord) -> f64); 10] = [
+            ("pos_x", |r| r.pos_x), ("pos_y", |r| r.pos_y), ("pos_z", |r| r.pos_z),
+            ("dir_x", |r| r.dir_x), ("dir_y", |r| r.dir_y), ("dir_z", |r| r.dir_z),
+            ("wavelength", |r| r.wavelength), ("power", |r| r.power),
+            ("weight", |r| r.weight), ("tof", |r| r.tof),
+        ];
+        for (name, column) in columns {
+            let data = records.iter().map(|record| column(record)).collect::<Vec<f64>>();
+            group.new_dataset_builder().with_data(&data).create(name)?;
+        }
+        let uids = records.iter().map(|record| record.uid).collect::<Vec<u64>>();
+        group.new_dataset_builder().with_data(&uids).create("uid")?;
+
+        let chains = records.iter()
+            .map(|record| summaries.get(&record.uid).map(String::as_str).unwrap_or("").parse().unwrap())
+            .collect::<Vec<VarLenUnicode>>();
+        group.new_dataset_builder().with_data(&chains).create("chain")?;
     }
+    Ok(())
+}
 
-    Ok(records)
+#[cfg(not(feature = "hdf5"))]
+fn write_hdf5(
+    _path: &std::path::Path,
+    _filter_groups: &[(String, Vec<&PhotonRecord>)],
+    _summaries: &HashMap<u64, String>,
+) -> Result<(), String> {
+    Err("filter_target was built without the `hdf5` feature".to_string())
 }
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let ledger_path = args[1].parse::<PathBuf>().unwrap();
+// Quick-look time-of-flight histogram of the filtered photons
+#[cfg(feature = "plots")]
+fn plot_tof(path: &std::path::Path, records: &[&PhotonRecord]) -> Result<(), String> {
+    use aetherus_events::plots::Figure;
 
-    let file = File::open(ledger_path).expect("Unable to create file");
-    let json_data = {
-        let mut buf_reader = std::io::BufReader::new(file);
-        let mut contents = String::new();
-        buf_reader
-            .read_to_string(&mut contents)
-            .expect("Unable to read ledger file");
-        contents
-    };
-    let ledger: Ledger = serde_json::from_str(&json_data).expect("Unable to parse ledger file");
+    let tof = records.iter().map(|record| record.tof).collect::<Vec<f64>>();
+    Figure::TimeOfFlight { tof, bins: 64 }.save(path)
+}
+
+#[cfg(not(feature = "plots"))]
+fn plot_tof(_path: &std::path::Path, _records: &[&PhotonRecord]) -> Result<(), String> {
+    Err("filter_target was built without the `plots` feature".to_string())
+}
+
+fn write_csv(path: &std::path::Path, records: &[&PhotonRecord]) {
+    let mut csv_writer = csv::Writer::from_path(path)
+        .expect("Unable to create output CSV file");
+    for record in records {
+        csv_writer.serialize(record)
+        .expect("Unable to write filtered CSV file");
+    }
+}
 
-    let filter_seq = vec![
-        filter_seq!(MCRT, Interface, Refraction, SrcId::Surf(0xFFFF)),
-        filter_seq!(MCRT, Material, Elastic, HenyeyGreenstein, Any, SrcId::Mat(0xFFFF)),
-        filter_seq!(Detection, SrcId::None),
-    ];
+fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    let hdf5_path = args.iter()
+        .position(|arg| arg == "--hdf5")
+        .map(|idx| {
+            let path = args.get(idx + 1).expect("--hdf5 expects an output path").parse::<PathBuf>().unwrap();
+            args.drain(idx..idx + 2);
+            path
+        });
+    let tof_plot_path = args.iter()
+        .position(|arg| arg == "--plot-tof")
+        .map(|idx| {
+            let path = args.get(idx + 1).expect("--plot-tof expects an output path").parse::<PathBuf>().unwrap();
+            args.drain(idx..idx + 2);
+            path
+        });
+    // `--invert` keeps the photons NOT matching the filter, `--both` also writes the complement set
+    let invert = args.iter().position(|arg| arg == "--invert").map(|idx| args.remove(idx)).is_some();
+    let both = args.iter().position(|arg| arg == "--both").map(|idx| args.remove(idx)).is_some();
+    // Name and format (auto, hex, dec, display) of the photon uid column, detected by default
+    let mut uid_column = UidColumn::default();
+    if let Some(idx) = args.iter().position(|arg| arg == "--uid-column") {
+        uid_column.name = Some(args.get(idx + 1).expect("--uid-column expects a column name").clone());
+        args.drain(idx..idx + 2);
+    }
+    if let Some(idx) = args.iter().position(|arg| arg == "--uid-format") {
+        uid_column.format = args.get(idx + 1).expect("--uid-format expects a format").parse().expect("Invalid --uid-format");
+        args.drain(idx..idx + 2);
+    }
+    // Mapping of the photon columns to the record fields, for tables written by other engines
+    let schema = args.iter()
+        .position(|arg| arg == "--schema")
+        .map(|idx| {
+            let path = args.get(idx + 1).expect("--schema expects a TOML file").clone();
+            args.drain(idx..idx + 2);
+            PhotonSchema::from_file(path).expect("Invalid --schema")
+        })
+        .unwrap_or_default();
+    // Photon and chain conditions applied on top of the filter sequence, i.e. `--gate "tof < 200ps"`,
+    // with `--tof-unit` the unit of the tof column (s, ms, us, ns, ps)
+    let gate_expr = args.iter()
+        .position(|arg| arg == "--gate")
+        .map(|idx| {
+            let expr = args.get(idx + 1).expect("--gate expects an expression").clone();
+            args.drain(idx..idx + 2);
+            expr
+        });
+    let tof_unit = args.iter()
+        .position(|arg| arg == "--tof-unit")
+        .map(|idx| {
+            let unit = args.get(idx + 1).and_then(|unit| time_unit(unit)).expect("--tof-unit expects s, ms, us, ns or ps");
+            args.drain(idx..idx + 2);
+            unit
+        })
+        .unwrap_or(1.0);
+    let gate = gate_expr.as_ref()
+        .map(|expr| expr.parse::<PhotonGate>().expect("Invalid --gate").with_tof_unit(tof_unit));
+    // Write the output next to each photon input instead of concatenating them
+    let per_input = args.iter().position(|arg| arg == "--per-input").map(|idx| args.remove(idx)).is_some();
+    // Reproducible random subset of the matched UIDs: `--sample N [--seed S]`
+    let sample = args.iter()
+        .position(|arg| arg == "--sample")
+        .map(|idx| {
+            let n = args.get(idx + 1).and_then(|n| n.parse::<usize>().ok()).expect("--sample expects a number");
+            args.drain(idx..idx + 2);
+            n
+        });
+    let seed = args.iter()
+        .position(|arg| arg == "--seed")
+        .map(|idx| {
+            let seed = args.get(idx + 1).and_then(|n| n.parse::<u64>().ok()).expect("--seed expects a number");
+            args.drain(idx..idx + 2);
+            seed
+        })
+        .unwrap_or(0);
+    // Filter expression replacing the default one, i.e. `--filter "MCRT|Material|{Elastic,Inelastic}|*|*|Mat(3) -> Detection"`,
+    // or `--filter-file` with one stage per line
+    let filter_expr = args.iter()
+        .position(|arg| arg == "--filter")
+        .map(|idx| {
+            let expr = args.get(idx + 1).expect("--filter expects an expression").clone();
+            args.drain(idx..idx + 2);
+            expr
+        })
+        .or_else(|| args.iter()
+            .position(|arg| arg == "--filter-file")
+            .map(|idx| {
+                let path = args.get(idx + 1).expect("--filter-file expects a path").clone();
+                args.drain(idx..idx + 2);
+                read_filter_file(&path).expect("Unable to read --filter-file")
+            }));
+    let ledger_path = args[1].parse::<PathBuf>().unwrap();
 
-    println!("Filter seq: {:?}", filter_seq);
+    let ledger = read_ledger_from_json(ledger_path).expect("Unable to parse ledger file");
 
-    let uids = find_forward_uid_seq(&ledger, filter_seq);
+    let (filter_desc, mut uids) = match filter_expr {
+        Some(expr) => {
+            let filter_expr = parse_with_ledger(&expr, &ledger).expect("Invalid filter expression");
+            println!("Filter: {}", expr);
+            (expr, find_forward_uid_expr(&ledger, &filter_expr))
+        }
+        None => {
+            let filter_seq: Vec<FilterStep> = vec![
+                filter_seq!(MCRT, Interface, Refraction, SrcId::Surf(0xFFFF)).into(),
+                filter_seq!(MCRT, Material, Elastic, HenyeyGreenstein, Any, SrcId::Mat(0xFFFF)).into(),
+                filter_seq!(Detection, SrcId::None),
+            ];
+            println!("Filter seq: {:?}", filter_seq);
+            (format!("{:?}", filter_seq), find_forward_uid_seq(&ledger, filter_seq))
+        }
+    };
+    if let Some(n) = sample {
+        uids = sample_uids(&uids, n, seed);
+    }
     for uid in uids.clone() {
         println!("Found UID: {}", uid);
     }
 
-    let csv_path = args.get(2).map(|s| s.parse::<PathBuf>().unwrap());
-    let phot_records = if let Some(csv_path) = csv_path.clone() {
-        read_csv(csv_path.to_str().unwrap()).expect("Unable to read CSV file")
-    } else {
-        Vec::new()
-    };
+    // Photon dumps are sharded per thread: every remaining argument is a photon CSV or a glob of them
+    let photon_paths = expand_inputs(&args[2..]).expect("Invalid photon inputs");
+    let photon_inputs = photon_paths.iter()
+        .map(|path| (path.clone(), read_photons_csv_with_schema(path, &uid_column, &schema).expect("Unable to read CSV file")))
+        .collect::<Vec<(PathBuf, Vec<PhotonRecord>)>>();
 
     let hex_uids = uids.iter()
         .map(|uid| uid.encode())
-        .collect::<Vec<u64>>();
+        .collect::<HashSet<u64>>();
+    let mut gate_matcher = gate.as_ref()
+        .map(|gate| gate.matcher(&ledger))
+        .transpose()
+        .expect("Unable to resolve the --gate chain conditions");
+    let summaries = uids.iter()
+        .map(|uid| (uid.encode(), chain_summary(&ledger, *uid)))
+        .collect::<HashMap<u64, String>>();
 
-    let phot_filtered = phot_records.iter()
-    .filter(|record| {
-        hex_uids.contains(&record.uid)
-    }).collect::<Vec<&CsvRecord>>();
+    // Single pass over the photons: the matching ones and their complement, e.g. for background estimates
+    let split_inputs = photon_inputs.iter()
+        .map(|(path, records)| {
+            let (phot_matched, phot_unmatched): (Vec<&PhotonRecord>, Vec<&PhotonRecord>) = records.iter()
+                .partition(|record| {
+                    hex_uids.contains(&record.uid)
+                        && gate_matcher.as_mut().is_none_or(|matcher| matcher.matches(record))
+                });
+            println!(
+                "Filtered photon records of {}: len={} matched, {} unmatched from {}",
+                path.display(), phot_matched.len(), phot_unmatched.len(), records.len()
+            );
+            if invert { (path, phot_unmatched, phot_matched) } else { (path, phot_matched, phot_unmatched) }
+        })
+        .collect::<Vec<(&PathBuf, Vec<&PhotonRecord>, Vec<&PhotonRecord>)>>();
+    let filter_desc = match &gate_expr {
+        Some(expr) => format!("{} AND {}", filter_desc, expr),
+        None => filter_desc,
+    };
+    let filter_desc = if invert { format!("NOT {}", filter_desc) } else { filter_desc };
+    let phot_filtered = split_inputs.iter()
+        .flat_map(|(_, filtered, _)| filtered.iter().copied())
+        .collect::<Vec<&PhotonRecord>>();
+    let phot_complement = split_inputs.iter()
+        .flat_map(|(_, _, complement)| complement.iter().copied())
+        .collect::<Vec<&PhotonRecord>>();
 
-    println!("Filtered photon records: len={} from {}", phot_filtered.len(), phot_records.len());
+    if let Some(hdf5_path) = hdf5_path {
+        let filter_groups = if per_input {
+            split_inputs.iter()
+                .map(|(path, filtered, _)| (format!("{} in {}", filter_desc, path.display()), filtered.clone()))
+                .collect()
+        } else {
+            vec![(filter_desc, phot_filtered.clone())]
+        };
+        write_hdf5(&hdf5_path, &filter_groups, &summaries).expect("Unable to write HDF5 file");
+        println!("Wrote filtered photons to {}", hdf5_path.display());
+    }
 
-    let csv_dirpath = csv_path.map(|p| p.parent().unwrap().to_path_buf());
-    let csv_outpath = if let Some(dirpath) = csv_dirpath {
-        dirpath.join("filtered_photons.csv")
-    } else {
-        PathBuf::from("filtered_photons.csv")
-    };
+    if let Some(tof_plot_path) = tof_plot_path {
+        plot_tof(&tof_plot_path, &phot_filtered).expect("Unable to plot time of flight");
+        println!("Wrote time-of-flight histogram to {}", tof_plot_path.display());
+    }
 
-    let mut csv_writer = csv::Writer::from_path(csv_outpath)
-        .expect("Unable to create output CSV file");
-    for filtered_record in phot_filtered {
-        csv_writer.serialize(&filtered_record)
-        .expect("Unable to write filtered CSV file");
+    if per_input {
+        // Next to each input: photons_3.csv -> photons_3_filtered.csv, photons_3_complement.csv
+        for (path, filtered, complement) in &split_inputs {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            write_csv(&path.with_file_name(format!("{}{}.csv", stem, FILTERED_SUFFIX)), filtered);
+            if both {
+                write_csv(&path.with_file_name(format!("{}{}.csv", stem, COMPLEMENT_SUFFIX)), complement);
+            }
+        }
+    } else {
+        let csv_dirpath = photon_paths.first()
+            .and_then(|p| p.parent().map(|dirpath| dirpath.to_path_buf()))
+            .unwrap_or_default();
+        write_csv(&csv_dirpath.join(FILTERED_OUTPUT), &phot_filtered);
+        if both {
+            write_csv(&csv_dirpath.join(COMPLEMENT_OUTPUT), &phot_complement);
+        }
     }
 }
@@ -0,0 +1,56 @@
+use std::path::Path;
+use std::process::exit;
+
+use aetherus_events::codegen::{c_header, header_is_current, schema_json, wgsl_consts};
+
+const USAGE: &str = "Usage: gen-header [--schema | --wgsl | --check] [out.h]
+
+Writes the C/CUDA header of the raw event layout (masks, shifts and values of every field) to
+out.h, or to stdout without a path.
+
+Options:
+    --schema    write the JSON schema of the layout instead, for decoders in other languages
+    --wgsl      write the WGSL constants and helpers instead, for wgpu kernels
+    --check     exit with an error if out.h doesn't match the current layout, i.e. in CI";
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mode = args.first().filter(|arg| ["--schema", "--wgsl", "--check"].contains(&arg.as_str())).cloned();
+    if mode.is_some() {
+        args.remove(0);
+    }
+    if args.len() > 1 || args.first().is_some_and(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        exit(if args.len() > 1 { 1 } else { 0 });
+    }
+    if mode.as_deref() == Some("--check") {
+        let Some(path) = args.first() else {
+            eprintln!("--check expects the path of the header\n\n{}", USAGE);
+            exit(1);
+        };
+        match header_is_current(Path::new(path)) {
+            Ok(true) => println!("{} is up to date", path),
+            Ok(false) => {
+                eprintln!("{} is stale, regenerate it with `gen-header {}`", path, path);
+                exit(1);
+            }
+            Err(err) => {
+                eprintln!("Unable to read {}: {}", path, err);
+                exit(1);
+            }
+        }
+        return;
+    }
+    let header = match mode.as_deref() {
+        Some("--schema") => schema_json(),
+        Some("--wgsl") => wgsl_consts(),
+        _ => c_header(),
+    };
+    match args.first() {
+        Some(path) => std::fs::write(path, header).unwrap_or_else(|err| {
+            eprintln!("Unable to write {}: {}", path, err);
+            exit(1);
+        }),
+        None => print!("{}", header),
+    }
+}
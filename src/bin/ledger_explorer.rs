@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use eframe::egui::{self, Align2, Color32, FontId, Rect, Sense, Stroke, Vec2};
+
+use aetherus_events::filter::{find_forward_uid_expr, parse_with_ledger};
+use aetherus_events::kind::{EventKind, Granularity};
+use aetherus_events::ledger::{Ledger, Uid, read_ledger_from_json};
+use aetherus_events::photons::{PhotonRecord, UidColumn, read_photons_csv};
+use aetherus_events::query::Query;
+use aetherus_events::taxonomy::legend;
+use aetherus_events::RawEvent;
+
+const USAGE: &str = "Usage: ledger-explorer [ledger.json] [photons.csv]
+
+Browse a ledger interactively: select a UID to show the events around it, apply filter
+expressions or SELECT queries to list matching UIDs, and show the photon records of the
+selected UID from the CSV written by the simulation (uid column detected, see photons.rs).";
+
+// Number of nodes drawn per column of the DAG view, the remaining ones are summarized
+const MAX_COLUMN_NODES: usize = 24;
+const NODE_SIZE: Vec2 = Vec2::new(170.0, 34.0);
+const NODE_SPACING: Vec2 = Vec2::new(60.0, 12.0);
+
+fn read_photons(path: &str) -> Result<HashMap<u64, Vec<PhotonRecord>>, String> {
+    let mut photons: HashMap<u64, Vec<PhotonRecord>> = HashMap::new();
+    for record in read_photons_csv(path, &UidColumn::default())? {
+        photons.entry(record.uid).or_default().push(record);
+    }
+    Ok(photons)
+}
+
+// Column of the DAG view, each node with the node of the previous column it is linked to
+struct Column {
+    nodes: Vec<(Uid, Option<Uid>)>,
+    hidden: usize,
+}
+
+#[derive(Default)]
+struct Explorer {
+    ledger_path: String,
+    photons_path: String,
+    ledger: Option<Ledger>,
+    photons: HashMap<u64, Vec<PhotonRecord>>,
+    colors: HashMap<String, Color32>,
+    filter_text: String,
+    matches: Vec<Uid>,
+    selected: Option<Uid>,
+    uid_text: String,
+    depth: usize,
+    status: String,
+}
+
+impl Explorer {
+    fn new(ledger_path: Option<String>, photons_path: Option<String>) -> Self {
+        let colors = legend()
+            .into_iter()
+            .filter_map(|entry| Color32::from_hex(&entry.color).ok().map(|color| (entry.kind, color)))
+            .collect();
+        let mut explorer = Explorer {
+            ledger_path: ledger_path.unwrap_or_default(),
+            photons_path: photons_path.unwrap_or_default(),
+            colors,
+            depth: 3,
+            ..Default::default()
+        };
+        if !explorer.ledger_path.is_empty() {
+            explorer.load_ledger();
+        }
+        if !explorer.photons_path.is_empty() {
+            explorer.load_photons();
+        }
+        explorer
+    }
+
+    fn load_ledger(&mut self) {
+        match read_ledger_from_json(PathBuf::from(&self.ledger_path)) {
+            Ok(ledger) => {
+                self.matches = ledger.get_start_events().clone();
+                self.status = format!("Loaded {} with {} roots", self.ledger_path, self.matches.len());
+                self.selected = self.matches.first().copied();
+                self.ledger = Some(ledger);
+            }
+            Err(err) => self.status = format!("Unable to load {}: {}", self.ledger_path, err),
+        }
+    }
+
+    fn load_photons(&mut self) {
+        match read_photons(&self.photons_path) {
+            Ok(photons) => {
+                self.status = format!("Loaded {} photon records", photons.values().map(Vec::len).sum::<usize>());
+                self.photons = photons;
+            }
+            Err(err) => self.status = format!("Unable to load {}: {}", self.photons_path, err),
+        }
+    }
+
+    fn apply_filter(&mut self) {
+        let Some(ledger) = &self.ledger else {
+            return;
+        };
+        let text = self.filter_text.trim();
+        let result = if text.is_empty() {
+            Ok(ledger.get_start_events().clone())
+        } else if text.get(..6).is_some_and(|keyword| keyword.eq_ignore_ascii_case("SELECT")) {
+            Query::parse(text)
+                .and_then(|query| query.execute(ledger))
+                .map(|result| result.groups.into_values().flatten().filter_map(|chain| chain.last().copied()).collect())
+        } else {
+            parse_with_ledger(text, ledger).map(|filter_expr| find_forward_uid_expr(ledger, &filter_expr))
+        };
+        match result {
+            Ok(matches) => {
+                self.status = format!("{} matching UIDs", matches.len());
+                self.matches = matches;
+            }
+            Err(err) => self.status = format!("Invalid filter: {}", err),
+        }
+    }
+
+    fn color(&self, uid: &Uid) -> Color32 {
+        let kind = EventKind::from_raw(uid.event, Granularity::Full);
+        self.colors.get(&format!("{:?}", kind)).copied().unwrap_or(Color32::GRAY)
+    }
+
+    // Ancestors up to `depth` before the selected UID, then `depth` levels of descendants, including
+    // the roots of the child chains
+    fn columns(&self, ledger: &Ledger, selected: Uid) -> Vec<Column> {
+        let chain = ledger.get_chain_across(selected);
+        let ancestors = &chain[chain.len().saturating_sub(self.depth + 1)..];
+        let mut columns: Vec<Column> = ancestors
+            .iter()
+            .enumerate()
+            .map(|(idx, uid)| Column {
+                nodes: vec![(*uid, idx.checked_sub(1).map(|prev| ancestors[prev]))],
+                hidden: 0,
+            })
+            .collect();
+        for _ in 0..self.depth {
+            let mut nodes = Vec::new();
+            for (uid, _) in &columns.last().unwrap().nodes {
+                nodes.extend(ledger.get_next_across(uid).into_iter().map(|next| (next, Some(*uid))));
+            }
+            if nodes.is_empty() {
+                break;
+            }
+            let hidden = nodes.len().saturating_sub(MAX_COLUMN_NODES);
+            nodes.truncate(MAX_COLUMN_NODES);
+            columns.push(Column { nodes, hidden });
+        }
+        columns
+    }
+
+    fn dag_view(&mut self, ui: &mut egui::Ui) {
+        let (Some(ledger), Some(selected)) = (&self.ledger, self.selected) else {
+            ui.label("Select a UID to show its neighbourhood");
+            return;
+        };
+        let columns = self.columns(ledger, selected);
+        let rows = columns.iter().map(|column| column.nodes.len() + 1).max().unwrap_or(1);
+        let size = Vec2::new(
+            columns.len() as f32 * (NODE_SIZE.x + NODE_SPACING.x),
+            rows as f32 * (NODE_SIZE.y + NODE_SPACING.y),
+        );
+        let (canvas, _) = ui.allocate_exact_size(size, Sense::hover());
+        let painter = ui.painter_at(canvas);
+
+        let mut positions: HashMap<Uid, Rect> = HashMap::new();
+        for (col, column) in columns.iter().enumerate() {
+            for (row, (uid, _)) in column.nodes.iter().enumerate() {
+                let min = canvas.min
+                    + Vec2::new(col as f32 * (NODE_SIZE.x + NODE_SPACING.x), row as f32 * (NODE_SIZE.y + NODE_SPACING.y));
+                positions.insert(*uid, Rect::from_min_size(min, NODE_SIZE));
+            }
+        }
+
+        let mut clicked = None;
+        for (col, column) in columns.iter().enumerate() {
+            for (uid, link) in &column.nodes {
+                let rect = positions[uid];
+                if let Some(link_rect) = link.and_then(|link| positions.get(&link)) {
+                    let child_root = ledger.get_parent(uid).is_some();
+                    let stroke = Stroke::new(1.5, if child_root { Color32::LIGHT_RED } else { Color32::GRAY });
+                    painter.line_segment([link_rect.right_center(), rect.left_center()], stroke);
+                }
+                let response = ui.interact(rect, ui.id().with(("node", uid.encode())), Sense::click());
+                let fill = self.color(uid);
+                painter.rect_filled(rect, 4.0, fill);
+                if *uid == selected {
+                    painter.rect_stroke(rect, 4.0, Stroke::new(2.5, Color32::WHITE), egui::StrokeKind::Outside);
+                }
+                let kind = EventKind::from_raw(uid.event, Granularity::Full).label();
+                let src = uid.event.decode().src_id;
+                painter.text(rect.center(), Align2::CENTER_CENTER, format!("{}\n{}", kind, src), FontId::proportional(11.0), Color32::BLACK);
+                if response.clicked() {
+                    clicked = Some(*uid);
+                }
+                response.on_hover_text(uid.to_string());
+            }
+            if column.hidden > 0 {
+                let pos = canvas.min
+                    + Vec2::new(
+                        col as f32 * (NODE_SIZE.x + NODE_SPACING.x) + NODE_SIZE.x / 2.0,
+                        column.nodes.len() as f32 * (NODE_SIZE.y + NODE_SPACING.y) + NODE_SIZE.y / 2.0,
+                    );
+                painter.text(pos, Align2::CENTER_CENTER, format!("+{} more", column.hidden), FontId::proportional(11.0), Color32::GRAY);
+            }
+        }
+        if let Some(uid) = clicked {
+            self.select(uid);
+        }
+    }
+
+    fn select(&mut self, uid: Uid) {
+        self.selected = Some(uid);
+        self.uid_text = uid.to_string();
+    }
+
+    fn details(&self, ui: &mut egui::Ui) {
+        let (Some(ledger), Some(selected)) = (&self.ledger, self.selected) else {
+            return;
+        };
+        let event_id = selected.event.decode();
+        let names: Vec<String> = ledger.event_names(&event_id).iter().map(|name| name.to_string()).collect();
+        ui.label(format!("UID {}: {:?} {} [{}]", selected, event_id.event_type, event_id.src_id, names.join(", ")));
+        if let Some(time) = ledger.get_timestamp(&selected) {
+            ui.label(format!("Timestamp: {}", time));
+        }
+        if let Some(parent) = ledger.get_parent(&selected) {
+            ui.label(format!("Child root of {}", parent));
+        }
+        let packet_ids = ledger.packet_ids(&selected);
+        if !packet_ids.is_empty() {
+            ui.label(format!("Packets: {:?}", packet_ids));
+        }
+
+        let records = self.photons.get(&selected.encode()).map(Vec::as_slice).unwrap_or_default();
+        ui.label(format!("{} photon records", records.len()));
+        egui::ScrollArea::vertical().id_salt("photons").max_height(160.0).show(ui, |ui| {
+            egui::Grid::new("photon_records").striped(true).show(ui, |ui| {
+                for header in ["pos", "dir", "wavelength", "power", "weight", "tof"] {
+                    ui.strong(header);
+                }
+                ui.end_row();
+                for record in records {
+                    ui.label(format!("({:.3}, {:.3}, {:.3})", record.pos_x, record.pos_y, record.pos_z));
+                    ui.label(format!("({:.3}, {:.3}, {:.3})", record.dir_x, record.dir_y, record.dir_z));
+                    ui.label(format!("{:.2}", record.wavelength));
+                    ui.label(format!("{:.4e}", record.power));
+                    ui.label(format!("{:.4}", record.weight));
+                    ui.label(format!("{:.4e}", record.tof));
+                    ui.end_row();
+                }
+            });
+        });
+    }
+}
+
+impl eframe::App for Explorer {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        egui::TopBottomPanel::top("inputs").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Ledger");
+                ui.text_edit_singleline(&mut self.ledger_path);
+                if ui.button("Load").clicked() {
+                    self.load_ledger();
+                }
+                ui.separator();
+                ui.label("Photons");
+                ui.text_edit_singleline(&mut self.photons_path);
+                if ui.button("Load").clicked() {
+                    self.load_photons();
+                }
+            });
+            ui.label(&self.status);
+        });
+
+        egui::SidePanel::left("matches").default_width(260.0).show(ctx, |ui| {
+            ui.label("Filter expression or SELECT query");
+            let response = ui.text_edit_multiline(&mut self.filter_text);
+            let submitted = response.lost_focus() && ui.input(|input| input.modifiers.ctrl && input.key_pressed(egui::Key::Enter));
+            if ui.button("Apply").clicked() || submitted {
+                self.apply_filter();
+            }
+            ui.separator();
+            let mut clicked = None;
+            let row_height = ui.text_style_height(&egui::TextStyle::Body);
+            egui::ScrollArea::vertical().id_salt("match_list").show_rows(ui, row_height, self.matches.len(), |ui, range| {
+                for uid in &self.matches[range] {
+                    if ui.selectable_label(self.selected == Some(*uid), uid.to_string()).clicked() {
+                        clicked = Some(*uid);
+                    }
+                }
+            });
+            if let Some(uid) = clicked {
+                self.select(uid);
+            }
+        });
+
+        egui::TopBottomPanel::bottom("details").resizable(true).show(ctx, |ui| self.details(ui));
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("UID");
+                let response = ui.text_edit_singleline(&mut self.uid_text);
+                if (response.lost_focus() && ui.input(|input| input.key_pressed(egui::Key::Enter))) || ui.button("Go").clicked() {
+                    match self.uid_text.trim().parse::<Uid>() {
+                        Ok(uid) if self.ledger.as_ref().is_some_and(|ledger| ledger.contains(&uid)) => self.select(uid),
+                        Ok(uid) => self.status = format!("UID {} is not in the ledger", uid),
+                        Err(err) => self.status = err,
+                    }
+                }
+                ui.add(egui::Slider::new(&mut self.depth, 1..=8).text("depth"));
+            });
+            ui.separator();
+            egui::ScrollArea::both().id_salt("dag").show(ui, |ui| self.dag_view(ui));
+        });
+    }
+}
+
+fn main() -> eframe::Result {
+    let mut positional = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    let explorer = Explorer::new(positional.next(), positional.next());
+
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default().with_inner_size([1280.0, 800.0]),
+        ..Default::default()
+    };
+    eframe::run_native("ledger-explorer", options, Box::new(|_cc| Ok(Box::new(explorer))))
+}
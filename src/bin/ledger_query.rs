@@ -0,0 +1,219 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use aetherus_events::RawEvent;
+use aetherus_events::export::write_chains_ndjson;
+use aetherus_events::filter::{find_forward_uid_expr, parse_with_ledger};
+use aetherus_events::ledger::{Ledger, Uid, read_ledger_from_json, sample_uids};
+use aetherus_events::query::{Query, Select};
+
+const USAGE: &str = "Usage: ledger-query <ledger.json> <filter expression | query> [--limit N] [--sample N [--seed S]] [--ndjson <out.ndjson>] [--plot (kinds|orders|flows) <out.png|out.svg>]
+
+--sample picks a reproducible random subset of the matched UIDs, or chains for queries.
+--ndjson writes every matched chain as a line of JSON.
+--plot renders the event kinds, scattering orders or step-wise flows of the matched chains,
+       requires the `plots` feature.
+
+Filter expression stages are separated by `->`, with fields in the pipe syntax:
+    \"MCRT|Material|Inelastic|*|*|Mat(water) -> Detection\"
+Queries select whole chains, optionally grouped:
+    \"SELECT (chains|count) [WHERE seq MATCHES '<filter expression>' AND length > N] [GROUP BY (src|root|kind|length)] [LIMIT N]\"";
+
+fn describe_uid(ledger: &Ledger, uid: &Uid) -> String {
+    let event_id = uid.event.decode();
+    let names: Vec<String> = ledger.event_names(&event_id).iter().map(|name| name.to_string()).collect();
+    format!(
+        "{:<24} {:?} {} [{}]",
+        uid.to_string(),
+        event_id.event_type,
+        event_id.src_id,
+        names.join(", ")
+    )
+}
+
+fn export_ndjson(ledger: &Ledger, chains: Vec<Vec<Uid>>, path: &Path) {
+    let file = File::create(path).unwrap_or_else(|err| {
+        eprintln!("Unable to create {}: {}", path.display(), err);
+        exit(1);
+    });
+    match write_chains_ndjson(ledger, chains, BufWriter::new(file)) {
+        Ok(count) => println!("Wrote {} chains to {}", count, path.display()),
+        Err(err) => {
+            eprintln!("Unable to write {}: {}", path.display(), err);
+            exit(1);
+        }
+    }
+}
+
+#[cfg(feature = "plots")]
+fn plot_chains(chains: &[Vec<Uid>], figure: &str, path: &Path) -> Result<(), String> {
+    use aetherus_events::kind::Granularity;
+    use aetherus_events::plots::{Figure, kind_histogram, scatter_orders, step_transitions};
+
+    let figure = match figure {
+        "kinds" => Figure::KindHistogram(kind_histogram(chains, Granularity::SubType)),
+        "orders" => Figure::ScatterOrders(scatter_orders(chains)),
+        "flows" => Figure::Sankey(step_transitions(chains, Granularity::SuperType, 8)),
+        _ => return Err(format!("Unknown figure `{}`, expected kinds, orders or flows", figure)),
+    };
+    figure.save(path)
+}
+
+#[cfg(not(feature = "plots"))]
+fn plot_chains(_chains: &[Vec<Uid>], _figure: &str, _path: &Path) -> Result<(), String> {
+    Err("ledger-query was built without the `plots` feature".to_string())
+}
+
+fn export_plot(chains: &[Vec<Uid>], plot: Option<&(String, PathBuf)>) {
+    let Some((figure, path)) = plot else {
+        return;
+    };
+    match plot_chains(chains, figure, path) {
+        Ok(()) => println!("Wrote {} figure to {}", figure, path.display()),
+        Err(err) => {
+            eprintln!("Unable to plot {}: {}", path.display(), err);
+            exit(1);
+        }
+    }
+}
+
+fn run_query(
+    ledger: &Ledger,
+    text: &str,
+    limit: Option<usize>,
+    sample: Option<(usize, u64)>,
+    ndjson: Option<&Path>,
+    plot: Option<&(String, PathBuf)>,
+) {
+    let query = Query::parse(text).unwrap_or_else(|err| {
+        eprintln!("Invalid query: {}", err);
+        exit(1);
+    });
+    let mut result = query.execute(ledger).unwrap_or_else(|err| {
+        eprintln!("Unable to run query: {}", err);
+        exit(1);
+    });
+    if let Some((n, seed)) = sample {
+        for chains in result.groups.values_mut() {
+            let leaves: Vec<Uid> = chains.iter().map(|chain| *chain.last().unwrap()).collect();
+            let picked = sample_uids(&leaves, n, seed);
+            chains.retain(|chain| picked.contains(chain.last().unwrap()));
+        }
+    }
+    println!("Matched {} chains", result.total());
+
+    for (group, chains) in result.groups.iter() {
+        match query.select {
+            Select::Count => println!("{:<32} {}", group, chains.len()),
+            Select::Chains => {
+                if !group.is_empty() {
+                    println!("Group {}: {} chains", group, chains.len());
+                }
+                for chain in chains.iter().take(limit.unwrap_or(usize::MAX)) {
+                    println!("Chain ending in UID: {}", chain.last().unwrap());
+                    for chain_uid in chain {
+                        println!("    {}", describe_uid(ledger, chain_uid));
+                    }
+                }
+            }
+        }
+    }
+
+    let chains: Vec<Vec<Uid>> = result.groups.into_values().flatten().collect();
+    export_plot(&chains, plot);
+    if let Some(path) = ndjson {
+        export_ndjson(ledger, chains, path);
+    }
+}
+
+fn main() {
+    let mut positional = Vec::new();
+    let mut limit = None;
+    let mut sample = None;
+    let mut seed = 0;
+    let mut ndjson = None;
+    let mut plot = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--limit" => {
+                let value = args.next().and_then(|n| n.parse::<usize>().ok());
+                if value.is_none() {
+                    eprintln!("--limit expects a number\n\n{}", USAGE);
+                    exit(1);
+                }
+                limit = value;
+            }
+            "--sample" | "--seed" => {
+                let value = args.next().and_then(|n| n.parse::<u64>().ok());
+                match (arg.as_str(), value) {
+                    ("--sample", Some(n)) => sample = Some(n as usize),
+                    ("--seed", Some(s)) => seed = s,
+                    _ => {
+                        eprintln!("{} expects a number\n\n{}", arg, USAGE);
+                        exit(1);
+                    }
+                }
+            }
+            "--ndjson" => {
+                let Some(path) = args.next() else {
+                    eprintln!("--ndjson expects an output path\n\n{}", USAGE);
+                    exit(1);
+                };
+                ndjson = Some(PathBuf::from(path));
+            }
+            "--plot" => {
+                let (Some(figure), Some(path)) = (args.next(), args.next()) else {
+                    eprintln!("--plot expects a figure and an output path\n\n{}", USAGE);
+                    exit(1);
+                };
+                plot = Some((figure, PathBuf::from(path)));
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != 2 {
+        eprintln!("{}", USAGE);
+        exit(1);
+    }
+
+    let ledger_path = positional[0].parse::<PathBuf>().unwrap();
+    let ledger = read_ledger_from_json(ledger_path).expect("Unable to parse ledger file");
+
+    if positional[1].trim_start().get(..6).is_some_and(|keyword| keyword.eq_ignore_ascii_case("SELECT")) {
+        run_query(&ledger, &positional[1], limit, sample.map(|n| (n, seed)), ndjson.as_deref(), plot.as_ref());
+        return;
+    }
+
+    let filter_expr = parse_with_ledger(&positional[1], &ledger).unwrap_or_else(|err| {
+        eprintln!("Invalid filter expression: {}", err);
+        exit(1);
+    });
+    println!("Filter expr: {:?}", filter_expr);
+
+    let mut uids = find_forward_uid_expr(&ledger, &filter_expr);
+    println!("Matched {} UIDs", uids.len());
+    if let Some(n) = sample {
+        uids = sample_uids(&uids, n, seed);
+        println!("Sampled {} UIDs with seed {}", uids.len(), seed);
+    }
+
+    for uid in uids.iter().take(limit.unwrap_or(usize::MAX)) {
+        println!("Found UID: {}", uid);
+        for chain_uid in ledger.get_chain(*uid) {
+            println!("    {}", describe_uid(&ledger, &chain_uid));
+        }
+    }
+
+    let chains: Vec<Vec<Uid>> = uids.iter().map(|uid| ledger.get_chain(*uid)).collect();
+    export_plot(&chains, plot.as_ref());
+    if let Some(path) = ndjson {
+        export_ndjson(&ledger, chains, &path);
+    }
+}
@@ -0,0 +1,184 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::process::exit;
+
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+
+use aetherus_events::export::write_chains_ndjson;
+use aetherus_events::filter::{find_forward_uid_expr, parse_with_ledger};
+use aetherus_events::ledger::{Ledger, Uid, read_ledger_from_json, sample_uids};
+use aetherus_events::query::Query;
+use aetherus_events::{RawEvent, SrcId};
+
+const USAGE: &str = "Usage: ledger-shell <ledger.json>";
+
+const HELP: &str = "Commands:
+    <filter expression>       select the UIDs matching the stages, i.e. \"MCRT|Material|*|*|*|Mat(water) -> Detection\"
+                              sources can be nested groups, i.e. \"MCRT|Interface|*|Grp(probe/fiber)\"
+    SELECT ...                select the chains of a query, see ledger-query --help
+    show [N]                  print the chains of the first N selected UIDs (default: limit)
+    chain <idx | uid>         print the chain of the idx-th selected UID, or of a UID \"seq_id, 0xEVENT\"
+    name <SrcId>              names of a source, i.e. Mat(1)
+    src <name>                SrcId registered under a name
+    sources                   every registered source
+    sample <N> [seed]         keep a reproducible random subset of the selection
+    export <out.ndjson>       write the chains of the selection as lines of JSON
+    limit <N>                 number of chains printed at most by show (default: 10)
+    help                      this message
+    quit | exit               leave the shell";
+
+struct Shell {
+    ledger: Ledger,
+    // Leaves of the chains selected by the last filter or query
+    selection: Vec<Uid>,
+    limit: usize,
+}
+
+impl Shell {
+    fn describe_uid(&self, uid: &Uid) -> String {
+        let event_id = uid.event.decode();
+        let names: Vec<String> = self.ledger.event_names(&event_id).iter().map(|name| name.to_string()).collect();
+        format!("{:<24} {:?} {} [{}]", uid.to_string(), event_id.event_type, event_id.src_id, names.join(", "))
+    }
+
+    fn print_chain(&self, uid: Uid) {
+        println!("Chain ending in UID: {}", uid);
+        for chain_uid in self.ledger.get_chain(uid) {
+            println!("    {}", self.describe_uid(&chain_uid));
+        }
+    }
+
+    fn select(&mut self, uids: Vec<Uid>) {
+        self.selection = uids;
+        println!("Selected {} UIDs", self.selection.len());
+    }
+
+    // Run a command line, returns false once the shell should exit
+    fn run(&mut self, line: &str) -> Result<bool, String> {
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        match command {
+            "quit" | "exit" => return Ok(false),
+            "help" => println!("{}", HELP),
+            "show" => {
+                let n = if rest.is_empty() { self.limit } else { parse_number(rest)? };
+                for uid in self.selection.iter().take(n) {
+                    self.print_chain(*uid);
+                }
+                if self.selection.len() > n {
+                    println!("... {} more", self.selection.len() - n);
+                }
+            }
+            "chain" => {
+                let uid = match rest.parse::<usize>() {
+                    Ok(idx) => *self
+                        .selection
+                        .get(idx)
+                        .ok_or_else(|| format!("Selection only has {} UIDs", self.selection.len()))?,
+                    Err(_) => rest.parse::<Uid>()?,
+                };
+                if !self.ledger.contains(&uid) {
+                    return Err(format!("UID {} is not in the ledger", uid));
+                }
+                self.print_chain(uid);
+            }
+            "name" => {
+                let src_id = rest.parse::<SrcId>()?;
+                let names: Vec<String> = self.ledger.names(&src_id).iter().map(|name| name.to_string()).collect();
+                if names.is_empty() {
+                    return Err(format!("No source registered as {}", src_id));
+                }
+                println!("{}: {}", src_id, names.join(", "));
+            }
+            "src" => match self.ledger.src_id_by_name(rest) {
+                Some(src_id) => println!("{}: {}", rest, src_id),
+                None => return Err(format!("No source named `{}`", rest)),
+            },
+            "sources" => {
+                for (src_id, names) in self.ledger.sources() {
+                    let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+                    println!("{:<16} {}", src_id.to_string(), names.join(", "));
+                }
+            }
+            "sample" => {
+                let mut args = rest.split_whitespace();
+                let n = parse_number(args.next().unwrap_or_default())?;
+                let seed = args.next().map(parse_number).transpose()?.unwrap_or(0) as u64;
+                let sampled = sample_uids(&self.selection, n, seed);
+                self.select(sampled);
+            }
+            "export" => {
+                if rest.is_empty() {
+                    return Err("export expects an output path".to_string());
+                }
+                let path = PathBuf::from(rest);
+                let file = File::create(&path).map_err(|err| format!("Unable to create {}: {}", path.display(), err))?;
+                let chains = self.selection.iter().map(|uid| self.ledger.get_chain(*uid));
+                let count = write_chains_ndjson(&self.ledger, chains, BufWriter::new(file))
+                    .map_err(|err| format!("Unable to write {}: {}", path.display(), err))?;
+                println!("Wrote {} chains to {}", count, path.display());
+            }
+            "limit" => self.limit = parse_number(rest)?,
+            _ if command.eq_ignore_ascii_case("SELECT") => {
+                let query = Query::parse(line).map_err(|err| format!("Invalid query: {}", err))?;
+                let result = query.execute(&self.ledger).map_err(|err| format!("Unable to run query: {}", err))?;
+                for (group, chains) in result.groups.iter().filter(|(group, _)| !group.is_empty()) {
+                    println!("{:<32} {}", group, chains.len());
+                }
+                let leaves = result.groups.into_values().flatten().map(|chain| *chain.last().unwrap()).collect();
+                self.select(leaves);
+            }
+            _ => {
+                let filter_expr = parse_with_ledger(line, &self.ledger)
+                    .map_err(|err| format!("Invalid filter expression: {}, see `help`", err))?;
+                let uids = find_forward_uid_expr(&self.ledger, &filter_expr);
+                self.select(uids);
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn parse_number(value: &str) -> Result<usize, String> {
+    value.parse::<usize>().map_err(|_| format!("Expected a number, got `{}`", value))
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() != 1 || args[0] == "-h" || args[0] == "--help" {
+        println!("{}\n\n{}", USAGE, HELP);
+        exit(if args.len() == 1 { 0 } else { 1 });
+    }
+    let ledger = read_ledger_from_json(&args[0]).unwrap_or_else(|err| {
+        eprintln!("Unable to read {}: {}", args[0], err);
+        exit(1);
+    });
+    println!("Loaded {} entries from {}, type `help` for the commands", ledger.entries().count(), args[0]);
+
+    let mut shell = Shell { ledger, selection: Vec::new(), limit: 10 };
+    let mut editor = DefaultEditor::new().expect("Unable to start the line editor");
+    loop {
+        match editor.readline("ledger> ") {
+            Ok(line) => {
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let _ = editor.add_history_entry(line);
+                match shell.run(line) {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(err) => eprintln!("{}", err),
+                }
+            }
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("Unable to read the command: {}", err);
+                exit(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,409 @@
+use std::fmt::{Debug, Write};
+
+use serde::Serialize;
+
+use crate::detection::{self, Detection};
+use crate::emission::{self, Emission, TemporalMode};
+use crate::processing::Processing;
+use crate::raw::{self, Elastic, Inelastic, Interface, MCRT, Material, Pipeline, RawField, Reflector, Roulette, ScatterDir};
+use crate::raw64;
+use crate::transport::{self, Transport};
+use crate::voxel::Voxel;
+use crate::wavelength::Channel;
+use crate::SrcId;
+
+// ----------------------------------------------------
+// C/CUDA header and WGSL constants of the raw event layout
+// ----------------------------------------------------
+// Generated from the RawField implementations, such that device-side code emitting raw events
+// can't drift from the crate's layout. Each field gets its mask, shift and size, and each variant
+// its value already shifted in place, i.e. an elastic Mie forward scattering event is composed as
+//     AEV_PIPELINE_MCRT | AEV_MCRT_MATERIAL | AEV_MATERIAL_ELASTIC | AEV_ELASTIC_MIE
+//         | AEV_SCATTER_DIR_FORWARD | (src_id & AEV_SRC_ID_MASK)
+// or with the `aev_*_event` helpers. The header can be included from C, C++ and CUDA sources, and
+// `wgsl_consts` gives the same constants and helpers for WGSL kernels.
+
+const GUARD: &str = "AETHERUS_EVENTS_H";
+
+// HenyeyGreenstein -> HENYEY_GREENSTEIN, MCRT -> MCRT
+pub(crate) fn upper_snake(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut snake = String::new();
+    for (idx, c) in chars.iter().enumerate() {
+        let follows_lower = idx > 0 && chars[idx - 1].is_ascii_lowercase();
+        let starts_word = idx > 0 && chars[idx - 1].is_ascii_uppercase() && chars.get(idx + 1).is_some_and(|next| next.is_ascii_lowercase());
+        if c.is_ascii_uppercase() && (follows_lower || starts_word) {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_uppercase());
+    }
+    snake
+}
+
+// Whether the header at `path` matches the current layout, i.e. for build systems regenerating it
+// only when the taxonomy changed
+pub fn header_is_current(path: &std::path::Path) -> std::io::Result<bool> {
+    match std::fs::read_to_string(path) {
+        Ok(header) => Ok(header == c_header()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+// Variants of a field enum in encoding order, skipping the alternative codes of a variant
+pub(crate) fn variants<T: TryFrom<u8> + Into<u8> + Copy>() -> Vec<T> {
+    (0..=u8::MAX)
+        .filter_map(|code| T::try_from(code).ok().filter(|variant| (*variant).into() == code))
+        .collect()
+}
+
+// Value of a generated constant, 64-bit ones only exist in the C header
+#[derive(Clone, Copy, Debug)]
+enum Constant {
+    Bits(u32),
+    Number(usize),
+    Bits64(u64),
+}
+
+struct Section {
+    comment: String,
+    constants: Vec<(String, Constant)>,
+}
+
+impl Section {
+    fn new(comment: &str) -> Self {
+        Section { comment: comment.to_string(), constants: Vec::new() }
+    }
+
+    fn define(&mut self, name: &str, value: Constant) {
+        self.constants.push((name.to_string(), value));
+    }
+
+    fn field_layout<T: RawField>(mut self, field: &str) -> Self {
+        self.define(&format!("{}_MASK", field), Constant::Bits(T::mask()));
+        self.define(&format!("{}_SHIFT", field), Constant::Number(T::shift()));
+        self.define(&format!("{}_BITS", field), Constant::Number(T::bitsize()));
+        self
+    }
+
+    fn field<T: RawField + TryFrom<u8> + Into<u8> + Copy + Debug>(self, field: &str) -> Self {
+        let mut section = self.field_layout::<T>(field);
+        for variant in variants::<T>() {
+            section.define(&format!("{}_{}", field, upper_snake(&format!("{:?}", variant))), Constant::Bits(variant.encode()));
+        }
+        section
+    }
+
+    fn with(mut self, name: &str, value: Constant) -> Self {
+        self.define(name, value);
+        self
+    }
+}
+
+fn layout_sections() -> Vec<Section> {
+    use Constant::{Bits, Bits64, Number};
+    vec![
+        Section::new("Pipeline of the event").field::<Pipeline>("PIPELINE"),
+        Section::new("Source of the event").field_layout::<SrcId>("SRC_ID"),
+        Section::new("Wavelength channel of emission and inelastic events, 0 without channel").field_layout::<Channel>("CHANNEL"),
+        Section::new("Emission: beam shape").field::<Emission>("EMISSION"),
+        Section::new("Emission: temporal modulation")
+            .field::<TemporalMode>("TEMPORAL_MODE")
+            .with("PULSE_INDEXED", Bits(emission::PULSE_INDEXED))
+            .with("PULSE_MASK", Bits(emission::PULSE_MASK))
+            .with("PULSE_SHIFT", Number(emission::PULSE_SHIFT)),
+        Section::new("MCRT: super type")
+            .field::<MCRT>("MCRT")
+            .with("MCRT_CUSTOM_MASK", Bits(raw::MCRT_CUSTOM_MASK))
+            .with("MCRT_CUSTOM_SHIFT", Number(raw::MCRT_CUSTOM_SHIFT)),
+        Section::new("MCRT: interface events").field::<Interface>("INTERFACE"),
+        Section::new("MCRT: reflector events").field::<Reflector>("REFLECTOR"),
+        Section::new("MCRT: material events").field::<Material>("MATERIAL"),
+        Section::new("MCRT: elastic scattering").field::<Elastic>("ELASTIC"),
+        Section::new("MCRT: inelastic scattering").field::<Inelastic>("INELASTIC"),
+        Section::new("MCRT: scattering direction").field::<ScatterDir>("SCATTER_DIR"),
+        Section::new("MCRT: russian roulette, survivors record their boost class")
+            .field::<Roulette>("ROULETTE")
+            .with("BOOST_CLASS_MASK", Bits(raw::BOOST_CLASS_MASK))
+            .with("BOOST_CLASS_SHIFT", Number(raw::BOOST_CLASS_SHIFT)),
+        Section::new("Detection")
+            .field::<Detection>("DETECTION")
+            .with("PIXELATED", Bits(detection::PIXELATED))
+            .with("MAX_PIXEL", Number(detection::MAX_PIXEL as usize))
+            .with("MAX_PIXELATED_DETECTOR", Number(detection::MAX_PIXELATED_DETECTOR as usize)),
+        Section::new("Processing").field::<Processing>("PROCESSING"),
+        Section::new("Transport")
+            .field::<Transport>("TRANSPORT")
+            .with("FACE_MASK", Bits(transport::FACE_MASK))
+            .with("NO_FACE", Number(transport::NO_FACE as usize)),
+        Section::new("Voxel: index of the voxel in the low bits")
+            .with("VOXEL_MASK", Bits(Voxel::MASK))
+            .with("VOXEL_BITS", Number(Voxel::BITSIZE)),
+        Section::new("64-bit extended events: the u32 layout in the low word, extension fields in the high word")
+            .with("EXT_VERSION_MASK", Bits64(raw64::VERSION_MASK))
+            .with("EXT_VERSION_SHIFT", Number(raw64::VERSION_SHIFT))
+            .with("EXT_VERSION", Number(raw64::EXTENDED_VERSION as usize))
+            .with("EXT_SRC_ID_HI_MASK", Bits64(raw64::SRC_ID_HI_MASK))
+            .with("EXT_SRC_ID_HI_SHIFT", Number(raw64::SRC_ID_HI_SHIFT))
+            .with("EXT_SUBTYPE_MASK", Bits64(raw64::SUBTYPE_EXT_MASK))
+            .with("EXT_SUBTYPE_SHIFT", Number(raw64::SUBTYPE_EXT_SHIFT))
+            .with("EXT_MAX_SRC_ID", Bits(raw64::MAX_SRC_ID)),
+    ]
+}
+
+// Helpers composing an event word from the shifted values of its fields, the ones of the fields
+// an event doesn't use being 0, i.e. aev_mcrt_event(AEV_MCRT_INTERFACE, AEV_INTERFACE_REFRACTION, 0, 0, surf_id)
+const C_HELPERS: &str = "
+#include <stdint.h>
+
+#ifdef __CUDACC__
+#define AEV_FN __host__ __device__ static inline
+#else
+#define AEV_FN static inline
+#endif
+
+AEV_FN uint32_t aev_event(uint32_t pipeline, uint32_t type_bits, uint32_t src_id) {
+    return pipeline | type_bits | (src_id & AEV_SRC_ID_MASK);
+}
+
+AEV_FN uint32_t aev_mcrt_event(uint32_t supertype, uint32_t subtype, uint32_t scatter, uint32_t dir, uint32_t src_id) {
+    return aev_event(AEV_PIPELINE_MCRT, supertype | subtype | scatter | dir, src_id);
+}
+
+AEV_FN uint32_t aev_emission_event(uint32_t emission, uint32_t temporal_mode, uint32_t light_id) {
+    return aev_event(AEV_PIPELINE_EMISSION, emission | temporal_mode, light_id);
+}
+
+AEV_FN uint32_t aev_detection_event(uint32_t detection, uint32_t detector_id) {
+    return aev_event(AEV_PIPELINE_DETECTION, detection, detector_id);
+}
+";
+
+const WGSL_HELPERS: &str = "
+fn aev_event(pipeline: u32, type_bits: u32, src_id: u32) -> u32 {
+    return pipeline | type_bits | (src_id & AEV_SRC_ID_MASK);
+}
+
+fn aev_mcrt_event(supertype: u32, subtype: u32, scatter: u32, dir: u32, src_id: u32) -> u32 {
+    return aev_event(AEV_PIPELINE_MCRT, supertype | subtype | scatter | dir, src_id);
+}
+
+fn aev_emission_event(emission: u32, temporal_mode: u32, light_id: u32) -> u32 {
+    return aev_event(AEV_PIPELINE_EMISSION, emission | temporal_mode, light_id);
+}
+
+fn aev_detection_event(detection: u32, detector_id: u32) -> u32 {
+    return aev_event(AEV_PIPELINE_DETECTION, detection, detector_id);
+}
+";
+
+pub fn c_header() -> String {
+    let mut out = String::new();
+    writeln!(out, "/* Raw event layout of aetherus-events {}, generated by gen-header: do not edit */", env!("CARGO_PKG_VERSION")).unwrap();
+    writeln!(out, "#ifndef {}\n#define {}", GUARD, GUARD).unwrap();
+    for section in layout_sections() {
+        writeln!(out, "\n/* {} */", section.comment).unwrap();
+        for (name, value) in section.constants {
+            let value = match value {
+                Constant::Bits(bits) => format!("0x{:08X}u", bits),
+                Constant::Number(number) => number.to_string(),
+                Constant::Bits64(bits) => format!("0x{:016X}ull", bits),
+            };
+            writeln!(out, "#define AEV_{:<40} {}", name, value).unwrap();
+        }
+    }
+    out.push_str(C_HELPERS);
+    writeln!(out, "\n#endif /* {} */", GUARD).unwrap();
+    out
+}
+
+// WGSL constants and helpers of the u32 layout, to be prepended to the kernels emitting events.
+// WGSL has no 64-bit integers, the extended format is left out.
+pub fn wgsl_consts() -> String {
+    let mut out = String::new();
+    writeln!(out, "// Raw event layout of aetherus-events {}, generated by gen-header --wgsl: do not edit", env!("CARGO_PKG_VERSION")).unwrap();
+    for section in layout_sections() {
+        // Sections with 64-bit constants are left out as a whole
+        let constants: Option<Vec<String>> = section
+            .constants
+            .into_iter()
+            .map(|(name, value)| match value {
+                Constant::Bits(bits) => Some(format!("const AEV_{}: u32 = 0x{:08X}u;", name, bits)),
+                Constant::Number(number) => Some(format!("const AEV_{}: u32 = {}u;", name, number)),
+                Constant::Bits64(_) => None,
+            })
+            .collect();
+        if let Some(constants) = constants {
+            writeln!(out, "\n// {}\n{}", section.comment, constants.join("\n")).unwrap();
+        }
+    }
+    out.push_str(WGSL_HELPERS);
+    out
+}
+
+// ----------------------------------------------------
+// JSON schema of the raw event layout
+// ----------------------------------------------------
+// Same layout as the C header, for analysis tools in other languages (Python, C++) decoding raw
+// events without linking this crate. Each field lists its shift, width and mask, the table of its
+// values (unshifted codes, alternative codes left out) and the values of the enclosing fields it
+// applies under, i.e. Elastic under Pipeline = MCRT, MCRT = Material and Material = Elastic.
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FieldValue {
+    pub name: String,
+    pub code: u8,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FieldSchema {
+    pub name: String,
+    pub shift: usize,
+    pub width: usize,
+    pub mask: u32,
+    pub values: Vec<FieldValue>,
+    // Values of the enclosing fields the field applies under, empty for the fields of every event
+    pub applies_under: Vec<(String, Vec<String>)>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LayoutSchema {
+    pub version: String,
+    pub word_bits: usize,
+    pub fields: Vec<FieldSchema>,
+}
+
+type AppliesUnder<'a> = &'a [(&'a str, &'a [&'a str])];
+
+fn field_schema<T: RawField>(name: &str, values: Vec<FieldValue>, applies_under: AppliesUnder) -> FieldSchema {
+    FieldSchema {
+        name: name.to_string(),
+        shift: T::shift(),
+        width: T::bitsize(),
+        mask: T::mask(),
+        values,
+        applies_under: applies_under
+            .iter()
+            .map(|(field, values)| (field.to_string(), values.iter().map(|value| value.to_string()).collect()))
+            .collect(),
+    }
+}
+
+fn enum_schema<T: RawField + TryFrom<u8> + Into<u8> + Copy + Debug>(applies_under: AppliesUnder) -> FieldSchema {
+    let name = std::any::type_name::<T>().rsplit("::").next().unwrap_or_default();
+    let values = variants::<T>()
+        .into_iter()
+        .map(|variant| FieldValue { name: format!("{:?}", variant), code: variant.into() })
+        .collect();
+    field_schema::<T>(name, values, applies_under)
+}
+
+pub fn layout_schema() -> LayoutSchema {
+    const MCRT_PIPE: (&str, &[&str]) = ("Pipeline", &["MCRT"]);
+    const MATERIAL: (&str, &[&str]) = ("MCRT", &["Material"]);
+    let fields = vec![
+        enum_schema::<Pipeline>(&[]),
+        field_schema::<SrcId>("SrcId", Vec::new(), &[]),
+        field_schema::<Channel>("Channel", Vec::new(), &[]),
+        enum_schema::<Emission>(&[("Pipeline", &["Emission"])]),
+        enum_schema::<TemporalMode>(&[("Pipeline", &["Emission"])]),
+        enum_schema::<MCRT>(&[MCRT_PIPE]),
+        enum_schema::<Interface>(&[MCRT_PIPE, ("MCRT", &["Interface"])]),
+        enum_schema::<Reflector>(&[MCRT_PIPE, ("MCRT", &["Reflector"])]),
+        enum_schema::<Material>(&[MCRT_PIPE, MATERIAL]),
+        enum_schema::<Elastic>(&[MCRT_PIPE, MATERIAL, ("Material", &["Elastic"])]),
+        enum_schema::<Inelastic>(&[MCRT_PIPE, MATERIAL, ("Material", &["Inelastic"])]),
+        enum_schema::<ScatterDir>(&[MCRT_PIPE, MATERIAL, ("Material", &["Elastic", "Inelastic"])]),
+        enum_schema::<Roulette>(&[MCRT_PIPE, MATERIAL, ("Material", &["Roulette"])]),
+        enum_schema::<Detection>(&[("Pipeline", &["Detection"])]),
+        enum_schema::<Processing>(&[("Pipeline", &["Processing"])]),
+        enum_schema::<Transport>(&[("Pipeline", &["Transport"])]),
+    ];
+    LayoutSchema { version: env!("CARGO_PKG_VERSION").to_string(), word_bits: 32, fields }
+}
+
+pub fn schema_json() -> String {
+    serde_json::to_string_pretty(&layout_schema()).expect("The layout schema serializes to JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(header: &str, name: &str) -> u32 {
+        let line = header.lines().find(|line| line.split_whitespace().nth(1) == Some(name)).unwrap();
+        let value = line.split_whitespace().nth(2).unwrap().trim_end_matches('u');
+        match value.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16).unwrap(),
+            None => value.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn header_matches_layout() {
+        assert_eq!(upper_snake("HenyeyGreenstein"), "HENYEY_GREENSTEIN");
+        assert_eq!(upper_snake("MCRT"), "MCRT");
+        assert_eq!(upper_snake("CompRetroRef"), "COMP_RETRO_REF");
+
+        let header = c_header();
+        let mie_forward = value(&header, "AEV_PIPELINE_MCRT")
+            | value(&header, "AEV_MCRT_MATERIAL")
+            | value(&header, "AEV_MATERIAL_ELASTIC")
+            | value(&header, "AEV_ELASTIC_MIE")
+            | value(&header, "AEV_SCATTER_DIR_FORWARD")
+            | 1;
+        assert_eq!(mie_forward, 0x03a50001);
+        assert_eq!(value(&header, "AEV_PIPELINE_SHIFT"), 24);
+        // Alternative codes of the reflector variants are not duplicated
+        assert_eq!(header.matches("AEV_REFLECTOR_DIFFUSE ").count(), 1);
+        assert!(header.contains("#define AEV_DETECTION_REJECTED"));
+        assert!(header.trim_end().ends_with("#endif /* AETHERUS_EVENTS_H */"));
+        assert_eq!(value(&header, "AEV_MCRT_CUSTOM"), 0x00C00000);
+        assert!(header.contains("#define AEV_EXT_SRC_ID_HI_MASK                       0x00FF000000000000ull"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("aetherus_events.h");
+        assert!(!header_is_current(&path).unwrap());
+        std::fs::write(&path, &header).unwrap();
+        assert!(header_is_current(&path).unwrap());
+        std::fs::write(&path, header.replace("0x03000000u", "0x05000000u")).unwrap();
+        assert!(!header_is_current(&path).unwrap());
+    }
+
+    #[test]
+    fn wgsl_matches_header() {
+        let header = c_header();
+        let wgsl = wgsl_consts();
+        for line in wgsl.lines().filter(|line| line.starts_with("const ")) {
+            let name = line.split([' ', ':']).nth(1).unwrap();
+            let value = line.rsplit(' ').next().unwrap().trim_end_matches(';').trim_end_matches('u');
+            let value = match value.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16).unwrap(),
+                None => value.parse().unwrap(),
+            };
+            assert_eq!(value, self::value(&header, name), "{}", name);
+        }
+        assert!(wgsl.contains("const AEV_ELASTIC_MIE: u32 = 0x00040000u;"));
+        assert!(!wgsl.contains("AEV_EXT_"));
+        assert!(wgsl.contains("fn aev_mcrt_event(supertype: u32, subtype: u32, scatter: u32, dir: u32, src_id: u32) -> u32"));
+        assert!(header.contains("AEV_FN uint32_t aev_mcrt_event("));
+    }
+
+    #[test]
+    fn schema_decodes_events() {
+        let schema: serde_json::Value = serde_json::from_str(&schema_json()).unwrap();
+        let fields = schema["fields"].as_array().unwrap();
+        let field = |name: &str| fields.iter().find(|field| field["name"] == name).unwrap();
+        let code = |name: &str, value: &str| {
+            let field = field(name);
+            let code = field["values"].as_array().unwrap().iter().find(|entry| entry["name"] == value).unwrap()["code"].as_u64().unwrap();
+            (code as u32) << field["shift"].as_u64().unwrap()
+        };
+        let mie_forward = code("Pipeline", "MCRT") | code("MCRT", "Material") | code("Material", "Elastic")
+            | code("Elastic", "Mie") | code("ScatterDir", "Forward") | 1;
+        assert_eq!(mie_forward, 0x03a50001);
+        assert_eq!((field("SrcId")["width"].as_u64(), field("SrcId")["mask"].as_u64()), (Some(16), Some(0xFFFF)));
+        assert_eq!(field("Elastic")["applies_under"][2], serde_json::json!(["Material", ["Elastic"]]));
+        assert_eq!(field("Reflector")["values"].as_array().unwrap().len(), 5);
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::kind::{EventKind, Granularity, scatter_order_histogram};
+use crate::ledger::{Ledger, Uid};
+use crate::photons::PhotonRecord;
+
+// ----------------------------------------------------
+// Statistical comparison of two runs
+// ----------------------------------------------------
+// Tests whether two ledgers (i.e. a reference run and a new one) sample the same distributions:
+// - Scattering orders of the chains: chi-square test of homogeneity
+// - Transitions between event kinds (super types) along the chains: chi-square test
+// - Time of flight of the detected photons, given the photon tables: two-sample Kolmogorov-Smirnov
+// A low p-value flags a regression, see `ComparisonReport::regressions`. The ledger merges identical
+// histories, such that the chain distributions weight each distinct chain by the photons which took
+// it (`Ledger::get_count` of its leaf), chains recorded without counts counting once.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TestMethod {
+    ChiSquare,
+    KolmogorovSmirnov,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TestResult {
+    pub name: String,
+    pub method: TestMethod,
+    pub statistic: f64,
+    // Degrees of freedom of the chi-square tests
+    pub dof: Option<usize>,
+    pub p_value: f64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ComparisonReport {
+    pub tests: Vec<TestResult>,
+}
+
+impl ComparisonReport {
+    pub fn new(reference: &Ledger, candidate: &Ledger) -> Self {
+        let reference_chains = weighted_chains(reference);
+        let candidate_chains = weighted_chains(candidate);
+        let tests = vec![
+            chi_square("scatter orders", &scatter_orders(&reference_chains), &scatter_orders(&candidate_chains)),
+            chi_square("transitions", &transitions(&reference_chains), &transitions(&candidate_chains)),
+        ];
+        ComparisonReport { tests }
+    }
+
+    pub fn with_tof(mut self, reference: &[PhotonRecord], candidate: &[PhotonRecord]) -> Self {
+        let tof = |records: &[PhotonRecord]| records.iter().map(|record| record.tof).collect::<Vec<f64>>();
+        self.tests.push(kolmogorov_smirnov("time of flight", &tof(reference), &tof(candidate)));
+        self
+    }
+
+    // Tests rejecting the hypothesis of identical distributions at the significance level `alpha`
+    pub fn regressions(&self, alpha: f64) -> Vec<&TestResult> {
+        self.tests.iter().filter(|test| test.p_value < alpha).collect()
+    }
+}
+
+impl fmt::Display for ComparisonReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<20} {:<20} {:>12} {:>6} {:>10}", "test", "method", "statistic", "dof", "p-value")?;
+        for test in &self.tests {
+            let dof = test.dof.map(|dof| dof.to_string()).unwrap_or_default();
+            writeln!(
+                f,
+                "{:<20} {:<20} {:>12.4} {:>6} {:>10.4e}",
+                test.name,
+                format!("{:?}", test.method),
+                test.statistic,
+                dof,
+                test.p_value
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn weighted_chains(ledger: &Ledger) -> Vec<(Vec<Uid>, usize)> {
+    ledger
+        .chains()
+        .map(|chain| {
+            let count = chain.last().map_or(0, |leaf| ledger.get_count(leaf));
+            (chain, count.max(1) as usize)
+        })
+        .collect()
+}
+
+fn scatter_orders(chains: &[(Vec<Uid>, usize)]) -> BTreeMap<usize, usize> {
+    scatter_order_histogram(chains.iter().map(|(chain, count)| (chain.as_slice(), *count)))
+}
+
+fn transitions(chains: &[(Vec<Uid>, usize)]) -> BTreeMap<(EventKind, EventKind), usize> {
+    let mut transitions = BTreeMap::new();
+    for (chain, count) in chains {
+        for pair in chain.windows(2) {
+            let from = EventKind::from_raw(pair[0].event, Granularity::SuperType);
+            let to = EventKind::from_raw(pair[1].event, Granularity::SuperType);
+            *transitions.entry((from, to)).or_default() += count;
+        }
+    }
+    transitions
+}
+
+// ----------------------------------------------------
+// Two-sample tests
+// ----------------------------------------------------
+// Chi-square test of homogeneity of two histograms with different totals, over the bins filled in
+// either of them
+pub fn chi_square<K: Ord + Clone>(name: &str, a: &BTreeMap<K, usize>, b: &BTreeMap<K, usize>) -> TestResult {
+    let (total_a, total_b) = (a.values().sum::<usize>() as f64, b.values().sum::<usize>() as f64);
+    let mut bins: Vec<&K> = a.keys().chain(b.keys()).collect();
+    bins.sort();
+    bins.dedup();
+    let (statistic, dof) = if total_a == 0.0 || total_b == 0.0 || bins.len() < 2 {
+        (0.0, 0)
+    } else {
+        let (scale_a, scale_b) = ((total_b / total_a).sqrt(), (total_a / total_b).sqrt());
+        let statistic = bins
+            .iter()
+            .map(|bin| {
+                let count_a = a.get(bin).cloned().unwrap_or(0) as f64;
+                let count_b = b.get(bin).cloned().unwrap_or(0) as f64;
+                (scale_a * count_a - scale_b * count_b).powi(2) / (count_a + count_b)
+            })
+            .sum();
+        (statistic, bins.len() - 1)
+    };
+    let p_value = if dof == 0 { 1.0 } else { gamma_q(dof as f64 / 2.0, statistic / 2.0) };
+    TestResult { name: name.to_string(), method: TestMethod::ChiSquare, statistic, dof: Some(dof), p_value }
+}
+
+pub fn kolmogorov_smirnov(name: &str, a: &[f64], b: &[f64]) -> TestResult {
+    let sorted = |values: &[f64]| {
+        let mut values: Vec<f64> = values.iter().copied().filter(|value| value.is_finite()).collect();
+        values.sort_by(f64::total_cmp);
+        values
+    };
+    let (a, b) = (sorted(a), sorted(b));
+    let (n_a, n_b) = (a.len() as f64, b.len() as f64);
+    let mut statistic: f64 = 0.0;
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let value = a[i].min(b[j]);
+        while i < a.len() && a[i] == value {
+            i += 1;
+        }
+        while j < b.len() && b[j] == value {
+            j += 1;
+        }
+        statistic = statistic.max((i as f64 / n_a - j as f64 / n_b).abs());
+    }
+    let p_value = if a.is_empty() || b.is_empty() {
+        1.0
+    } else {
+        let en = (n_a * n_b / (n_a + n_b)).sqrt();
+        kolmogorov_q((en + 0.12 + 0.11 / en) * statistic)
+    };
+    TestResult { name: name.to_string(), method: TestMethod::KolmogorovSmirnov, statistic, dof: None, p_value }
+}
+
+// ----------------------------------------------------
+// Distribution functions
+// ----------------------------------------------------
+// Survival function of the Kolmogorov distribution
+fn kolmogorov_q(lambda: f64) -> f64 {
+    if lambda < 1e-3 {
+        return 1.0;
+    }
+    let mut sum = 0.0;
+    for j in 1..=100 {
+        let term = 2.0 * (-2.0 * (j * j) as f64 * lambda * lambda).exp();
+        sum += if j % 2 == 1 { term } else { -term };
+        if term < 1e-12 {
+            break;
+        }
+    }
+    sum.clamp(0.0, 1.0)
+}
+
+fn ln_gamma(x: f64) -> f64 {
+    // Lanczos approximation, g = 7
+    const COEFFS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x < 0.5 {
+        return (std::f64::consts::PI / (std::f64::consts::PI * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+    let x = x - 1.0;
+    let t = x + 7.5;
+    let series = COEFFS[1..].iter().enumerate().fold(COEFFS[0], |sum, (i, coeff)| sum + coeff / (x + i as f64 + 1.0));
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + series.ln()
+}
+
+// Regularized upper incomplete gamma function Q(a, x), the chi-square survival function with
+// 2a degrees of freedom at 2x
+fn gamma_q(a: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 1.0;
+    }
+    let prefactor = (-x + a * x.ln() - ln_gamma(a)).exp();
+    if x < a + 1.0 {
+        // Series of the lower function P(a, x)
+        let (mut term, mut sum, mut n) = (1.0 / a, 1.0 / a, a);
+        while term.abs() > sum.abs() * 1e-15 {
+            n += 1.0;
+            term *= x / n;
+            sum += term;
+        }
+        (1.0 - sum * prefactor).clamp(0.0, 1.0)
+    } else {
+        // Continued fraction (modified Lentz)
+        let tiny = 1e-300;
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / tiny;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for i in 1..500 {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            d = if d.abs() < tiny { tiny } else { d };
+            c = b + an / c;
+            c = if c.abs() < tiny { tiny } else { c };
+            d = 1.0 / d;
+            let delta = d * c;
+            h *= delta;
+            if (delta - 1.0).abs() < 1e-15 {
+                break;
+            }
+        }
+        (prefactor * h).clamp(0.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detection::Detection;
+    use crate::emission::Emission;
+    use crate::{EventId, SrcId, mcrt_event};
+
+    // 40 photons scattering up to `max_order` times, each detector sees one photon such that the chains are distinct
+    fn scattering_ledger(max_order: usize) -> Ledger {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("tissue".to_string()).unwrap();
+        for detector in 0..40u16 {
+            let mut uid = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+            for _ in 0..(detector as usize % (max_order + 1)) {
+                uid = ledger.insert(uid, EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+            }
+            ledger.insert(uid, EventId::new_detection(Detection::Direct, SrcId::Detector(detector))).unwrap();
+        }
+        ledger
+    }
+
+    #[test]
+    fn distribution_functions() {
+        // Chi-square with 2 dof: exp(-x/2)
+        assert!((gamma_q(1.0, 1.5) - (-1.5f64).exp()).abs() < 1e-12);
+        assert!((gamma_q(1.0, 0.2) - (-0.2f64).exp()).abs() < 1e-12);
+        assert!((ln_gamma(5.0) - 24f64.ln()).abs() < 1e-12);
+        assert!((kolmogorov_q(1.36) - 0.0494).abs() < 1e-3);
+    }
+
+    #[test]
+    fn flags_regressions() {
+        let report = ComparisonReport::new(&scattering_ledger(3), &scattering_ledger(3));
+        assert!(report.tests.iter().all(|test| test.p_value > 0.99));
+        assert!(report.regressions(0.05).is_empty());
+
+        let report = ComparisonReport::new(&scattering_ledger(3), &scattering_ledger(0));
+        let regressions: Vec<&str> = report.regressions(0.01).iter().map(|test| test.name.as_str()).collect();
+        assert_eq!(regressions, vec!["scatter orders", "transitions"]);
+
+        // Same distinct chains, the ballistic photons being repeated
+        let mut repeated = scattering_ledger(3);
+        let light_id = repeated.src_id_by_name("laser").unwrap();
+        for _ in 0..200 {
+            let start = repeated.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+            repeated.insert(start, EventId::new_detection(Detection::Direct, SrcId::Detector(0))).unwrap();
+        }
+        assert_eq!(repeated.chains().count(), scattering_ledger(3).chains().count());
+        let report = ComparisonReport::new(&scattering_ledger(3), &repeated);
+        let regressions: Vec<&str> = report.regressions(0.01).iter().map(|test| test.name.as_str()).collect();
+        assert_eq!(regressions, vec!["scatter orders", "transitions"]);
+
+        let photon = |tof: f64| PhotonRecord { tof, ..PhotonRecord::default() };
+        let reference: Vec<PhotonRecord> = (0..200).map(|i| photon(i as f64)).collect();
+        let shifted: Vec<PhotonRecord> = (0..200).map(|i| photon(i as f64 + 60.0)).collect();
+        let report = ComparisonReport::default().with_tof(&reference, &reference);
+        assert_eq!(report.tests[0].statistic, 0.0);
+        let report = report.with_tof(&reference, &shifted);
+        assert!((report.tests[1].statistic - 0.3).abs() < 1e-12);
+        assert_eq!(report.regressions(0.01).len(), 1);
+        assert!(report.to_string().contains("KolmogorovSmirnov"));
+    }
+}
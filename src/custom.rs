@@ -0,0 +1,323 @@
+use crate::error::DecodeError;
+use crate::raw::{self, RawField};
+use crate::{Encode, Decode, TryDecode};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// NOTE: Custom pipelines reuse the MCRT layout below the pipeline nibble:
+// | Pipeline (4) | SuperType (2) | SubType (6) | SrcId (16) |
+// The crate only knows the codes, the meaning is given by the `define_pipeline!` declaration.
+
+// Pipeline codes 1, 3, 5, 7, 11 and 13 are claimed by the built-in `raw::Pipeline` stages, and code
+// 0 is reserved such that a zeroed word never decodes to an event
+pub const fn is_free_code(code: u8) -> bool {
+    code != 0 && code < 16 && code != 1 && code != 3 && code != 5 && code != 7 && code != 11 && code != 13
+}
+
+// Event of a pipeline that is not known by this crate, holding the pipeline code and the 8-bit
+// event type field (supertype | subtype) verbatim
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CustomEvent {
+    pub pipeline: u8,
+    pub code: u8,
+}
+
+impl CustomEvent {
+    pub fn new(pipeline: u8, code: u8) -> Self {
+        debug_assert!(is_free_code(pipeline), "Pipeline code {} is reserved or out of range", pipeline);
+        Self { pipeline, code }
+    }
+}
+
+impl Encode<u32> for CustomEvent {
+    fn encode(&self) -> u32 {
+        ((self.pipeline as u32) << raw::Pipeline::shift()) | ((self.code as u32) << 16)
+    }
+}
+
+impl Decode<u32> for CustomEvent {
+    fn decode(raw: u32) -> Self where Self: Sized {
+        CustomEvent {
+            pipeline: ((raw & raw::Pipeline::mask()) >> raw::Pipeline::shift()) as u8,
+            code:     ((raw & 0x00FF0000) >> 16) as u8,
+        }
+    }
+}
+
+// Implemented by the structured event enum generated with `define_pipeline!`
+pub trait CustomPipeline: Encode<u32> + TryDecode<u32> {
+    const CODE: u8;
+    const NAME: &'static str;
+
+    // (code, label) of every declared subtype, used to fill a `CodeRegistry`
+    fn labels() -> Vec<(u8, String)>;
+
+    fn to_custom(&self) -> CustomEvent {
+        CustomEvent::new(Self::CODE, (self.encode() >> 16) as u8)
+    }
+    // None for the events of other pipelines, failing on codes the declaration doesn't know
+    fn from_custom(event: &CustomEvent) -> Result<Option<Self>, DecodeError> {
+        if event.pipeline == Self::CODE {
+            Self::try_decode(event.encode()).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+// Declare a downstream pipeline stage with its supertypes and subtypes, mirroring the layout of
+// the `mcrt` module: each supertype is both a variant of the pipeline enum and the raw subtype enum
+// it wraps, i.e.
+// define_pipeline! {
+//     pub Voxel = 9 {
+//         Crossing = 0 { Enter = 0, Exit = 1 },
+//         Deposit  = 1 { Energy = 0 },
+//     }
+// }
+// -> Voxel::Crossing(Crossing::Enter), filtered with `filter_seq!(Custom(Voxel), Crossing, Enter, src_id)`
+#[macro_export]
+macro_rules! define_pipeline {
+    (
+        $(#[$meta:meta])*
+        $vis:vis $pipeline:ident = $code:literal {
+            $(
+                $supertype:ident = $super_code:literal {
+                    $( $subtype:ident = $sub_code:literal ),* $(,)?
+                }
+            ),* $(,)?
+        }
+    ) => {
+        const _: () = assert!($crate::custom::is_free_code($code), "Pipeline code is reserved by aetherus-events or out of range");
+
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        $vis enum $pipeline {
+            $( $supertype($supertype), )*
+        }
+
+        $(
+            #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+            #[repr(u8)]
+            $vis enum $supertype {
+                $( $subtype = $sub_code, )*
+            }
+
+            const _: () = assert!($super_code < 4, "SuperType code exceeds 2 bits");
+            $( const _: () = assert!($sub_code < 64, "SubType code exceeds 6 bits"); )*
+
+            impl $supertype {
+                pub const SUPERTYPE: u8 = $super_code;
+            }
+
+            impl ::core::convert::TryFrom<u8> for $supertype {
+                type Error = u8;
+                fn try_from(value: u8) -> ::core::result::Result<Self, u8> {
+                    match value {
+                        $( $sub_code => Ok($supertype::$subtype), )*
+                        _ => Err(value),
+                    }
+                }
+            }
+
+            impl ::core::convert::From<$supertype> for u8 {
+                fn from(value: $supertype) -> u8 {
+                    value as u8
+                }
+            }
+
+            impl $crate::raw::RawField for $supertype {
+                const MASK: u32 = 0x003F0000;
+                const SHIFT: usize = 16;
+                const BITSIZE: usize = 6;
+            }
+        )*
+
+        impl $crate::Encode<u32> for $pipeline {
+            fn encode(&self) -> u32 {
+                match self {
+                    $( $pipeline::$supertype(st) => (($super_code as u32) << 22) | <$supertype as $crate::raw::RawField>::encode(st), )*
+                }
+            }
+        }
+
+        impl $crate::TryDecode<u32> for $pipeline {
+            fn try_decode(raw: u32) -> ::core::result::Result<Self, $crate::error::DecodeError> {
+                let super_code = ((raw & 0x00C00000) >> 22) as u8;
+                match super_code {
+                    $( $super_code => Ok($pipeline::$supertype($crate::raw::decode_field::<$supertype>(raw)?)), )*
+                    _ => Err($crate::error::DecodeError::UnknownCode { field: stringify!($pipeline), code: super_code, raw }),
+                }
+            }
+        }
+
+        impl $crate::custom::CustomPipeline for $pipeline {
+            const CODE: u8 = $code;
+            const NAME: &'static str = stringify!($pipeline);
+
+            fn labels() -> Vec<(u8, String)> {
+                vec![
+                    $( $(
+                        ((($super_code as u8) << 6) | ($sub_code as u8), format!("{}::{}", stringify!($supertype), stringify!($subtype))),
+                    )* )*
+                ]
+            }
+        }
+
+        impl ::core::convert::From<$pipeline> for $crate::EventType {
+            fn from(event: $pipeline) -> Self {
+                $crate::EventType::Custom($crate::custom::CustomPipeline::to_custom(&event))
+            }
+        }
+    };
+}
+
+// ----------------------------------------------------
+// Registry of labels for custom event codes
+// ----------------------------------------------------
+// Stored alongside the ledger, such that custom events recorded by one engine can be decoded to
+// meaningful names by whoever analyses the ledger, without access to the `define_pipeline!` source.
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct PipelineLabels {
+    pub name: String,
+    pub codes: BTreeMap<u8, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct CodeRegistry {
+    pipelines: BTreeMap<u8, PipelineLabels>,
+}
+
+impl CodeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pipelines.is_empty()
+    }
+
+    // Register all subtypes declared for a pipeline with `define_pipeline!`
+    pub fn register_pipeline<P: CustomPipeline>(&mut self) {
+        self.register_name(P::CODE, P::NAME.to_string());
+        for (code, label) in P::labels() {
+            self.register(P::CODE, code, label);
+        }
+    }
+
+    pub fn register_name(&mut self, pipeline: u8, name: String) {
+        assert!(is_free_code(pipeline), "Pipeline code {} is reserved or out of range", pipeline);
+        self.pipelines.entry(pipeline).or_default().name = name;
+    }
+
+    pub fn register(&mut self, pipeline: u8, code: u8, label: String) {
+        assert!(is_free_code(pipeline), "Pipeline code {} is reserved or out of range", pipeline);
+        let labels = self.pipelines.entry(pipeline).or_default();
+        if let Some(old_label) = labels.codes.insert(code, label) {
+            log::warn!("Overwriting label {} of custom code 0x{:02X} in pipeline {}", old_label, code, pipeline);
+        }
+    }
+
+    // Labels of a merged ledger, keeping the ones already registered
+    pub fn merge(&mut self, other: &CodeRegistry) {
+        for (pipeline, other_labels) in &other.pipelines {
+            let labels = self.pipelines.entry(*pipeline).or_default();
+            if labels.name.is_empty() {
+                labels.name = other_labels.name.clone();
+            }
+            for (code, label) in &other_labels.codes {
+                let merged = labels.codes.entry(*code).or_insert_with(|| label.clone());
+                if merged != label {
+                    log::warn!("Keeping label {} of custom code 0x{:02X} in pipeline {} over {}", merged, code, pipeline, label);
+                }
+            }
+        }
+    }
+
+    pub fn pipeline_name(&self, pipeline: u8) -> Option<&str> {
+        self.pipelines.get(&pipeline)
+            .map(|labels| labels.name.as_str())
+            .filter(|name| !name.is_empty())
+    }
+
+    pub fn label(&self, event: &CustomEvent) -> Option<&str> {
+        self.pipelines.get(&event.pipeline)
+            .and_then(|labels| labels.codes.get(&event.code))
+            .map(|label| label.as_str())
+    }
+
+    // Full name of the event as `Pipeline::SuperType::SubType`, falling back to the codes
+    pub fn describe(&self, event: &CustomEvent) -> String {
+        let pipeline = match self.pipeline_name(event.pipeline) {
+            Some(name) => name.to_string(),
+            None => format!("Custom({})", event.pipeline),
+        };
+        match self.label(event) {
+            Some(label) => format!("{}::{}", pipeline, label),
+            None => format!("{}::0x{:02X}", pipeline, event.code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{EventId, EventType, SrcId, filter_seq};
+    use crate::custom::{CodeRegistry, CustomEvent, CustomPipeline};
+    use crate::{Encode, Decode, TryDecode};
+    use crate::error::DecodeError;
+
+    crate::define_pipeline! {
+        pub Voxel = 9 {
+            Crossing = 0 { Enter = 0, Exit = 1 },
+            Deposit  = 2 { Energy = 3 },
+        }
+    }
+
+    #[test]
+    fn custom_pipeline_encoding() {
+        let event = Voxel::Deposit(Deposit::Energy);
+        assert_eq!(event.encode(), 0x00830000);
+        assert_eq!(Voxel::try_decode(0x00830000), Ok(event));
+        assert_eq!(event.to_custom(), CustomEvent::new(9, 0x83));
+        assert_eq!(Voxel::from_custom(&CustomEvent::new(9, 0x01)), Ok(Some(Voxel::Crossing(Crossing::Exit))));
+        assert_eq!(Voxel::from_custom(&CustomEvent::new(8, 0x01)), Ok(None));
+        // Undeclared codes are errors rather than panics
+        assert_eq!(
+            Voxel::from_custom(&CustomEvent::new(9, 0x41)),
+            Err(DecodeError::UnknownCode { field: "Voxel", code: 1, raw: 0x09410000 })
+        );
+        assert!(Voxel::try_decode(0x00020000).is_err());
+        assert!(!crate::custom::is_free_code(0));
+    }
+
+    #[test]
+    fn custom_event_id_round_trip() {
+        let event_id = EventId::new(Voxel::Crossing(Crossing::Exit).into(), SrcId::MatSurf(7));
+        let raw_event = event_id.encode();
+        assert_eq!(raw_event, 0x09010007);
+        let decoded = EventId::decode(raw_event);
+        assert_eq!(decoded.event_type, EventType::Custom(CustomEvent::new(9, 0x01)));
+        assert_eq!(decoded.src_id, SrcId::MatSurf(7));
+    }
+
+    #[test]
+    fn custom_filter_seq() {
+        let bits_match = filter_seq!(Custom(Voxel), Crossing, Exit, SrcId::None);
+        assert_eq!(bits_match.mask, 0x0FFF0000);
+        assert_eq!(bits_match.value, 0x09010000);
+        let bits_match = filter_seq!(Custom(Voxel), Deposit, SrcId::MatSurf(3));
+        assert_eq!(bits_match.mask, 0x0FC0FFFF);
+        assert_eq!(bits_match.value, 0x09800003);
+    }
+
+    #[test]
+    fn code_registry_labels() {
+        let mut registry = CodeRegistry::new();
+        registry.register_pipeline::<Voxel>();
+        assert_eq!(registry.pipeline_name(9), Some("Voxel"));
+        assert_eq!(registry.label(&CustomEvent::new(9, 0x01)), Some("Crossing::Exit"));
+        assert_eq!(registry.describe(&CustomEvent::new(9, 0x83)), "Voxel::Deposit::Energy");
+        assert_eq!(registry.describe(&CustomEvent::new(9, 0x02)), "Voxel::0x02");
+        assert_eq!(registry.describe(&CustomEvent::new(10, 0x02)), "Custom(10)::0x02");
+    }
+}
@@ -0,0 +1,236 @@
+use std::ops::RangeInclusive;
+
+use serde::{Deserialize, Serialize};
+
+use crate::SrcId;
+use crate::filter::{BitsMatch, FilterStep};
+use crate::raw::{Pipeline, RawField};
+use num_enum::{TryFromPrimitive, IntoPrimitive};
+
+// NOTE: Detection events follow the emission layout, with the detector id in the SrcId bits:
+// | Pipeline (4) | Detection (8) | DetectorId (16) |
+// Array detectors record the element/pixel hit in the bits left unused by detection events, at the
+// cost of limiting their detector id to 8 bits:
+// | PixelHi (4) | Pipeline (4) | Pixelated (1) | PixelMid (3) | Detection (4) | PixelLo (8) | DetectorId (8) |
+// The subtype is hence limited to 4 bits (16 variants) for all detection events, the high bits of
+// its byte being left to the pixel index.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive, IntoPrimitive, Hash, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum Detection {
+    // Photon accepted and counted by the detector
+    Direct,
+    // Photon reached the detector surface but fell outside its acceptance (angle, aperture, ...)
+    Rejected,
+    // Photon accepted within the time gate of a gated detector (i.e. time-gated PMT or SPAD)
+    TimeGated,
+    // Photon counted in coincidence with a hit on another detector or channel
+    Coincidence,
+    // Photon reaching a saturated detector (pile-up, dead time), recorded but not counted
+    Saturated,
+}
+
+impl RawField for Detection {
+    const MASK: u32 = 0x000F0000;
+    const SHIFT: usize = 16;
+    const BITSIZE: usize = 4;
+}
+
+crate::raw::const_encode!(Detection);
+
+pub const PIXELATED: u32 = 0x00800000;
+pub const PIXEL_BITSIZE: usize = 15;
+pub const MAX_PIXEL: u16 = (1 << PIXEL_BITSIZE) - 1;
+pub const MAX_PIXELATED_DETECTOR: u16 = 0xFF;
+
+// (mask of the pixel bits, shift from the pixel index to the raw bits) for each part of the index
+const PIXEL_PARTS: [(u32, u32); 3] = [
+    (0x00FF, 8),  // PixelLo  -> bits 8..16
+    (0x0700, 12), // PixelMid -> bits 20..23
+    (0x7800, 17), // PixelHi  -> bits 28..32
+];
+
+// Raw bits of the pixel index bits `pixel_bits`, also used to scatter masks
+pub fn scatter_pixel(pixel_bits: u16) -> u32 {
+    PIXEL_PARTS
+        .iter()
+        .map(|(mask, shift)| ((pixel_bits as u32) & mask) << shift)
+        .fold(0, |raw, bits| raw | bits)
+}
+
+pub fn pixel_of(raw_event: u32) -> Option<u16> {
+    if (raw_event & Pipeline::mask()) != Pipeline::Detection.encode() || (raw_event & PIXELATED) == 0 {
+        return None;
+    }
+    let pixel = PIXEL_PARTS
+        .iter()
+        .map(|(mask, shift)| (raw_event >> shift) & mask)
+        .fold(0, |pixel, bits| pixel | bits);
+    Some(pixel as u16)
+}
+
+// ----------------------------------------------------
+// Filters by detector
+// ----------------------------------------------------
+// Plain events hold the detector id in the 16 SrcId bits, pixelated hits only in the low byte. A
+// detector fitting 8 bits is thus matched by two alternatives, each checking the pixelated flag.
+
+pub fn detector_bits_matches(detector_id: u16) -> Vec<BitsMatch> {
+    let mut bits_matches = vec![BitsMatch::new(PIXELATED | SrcId::mask(), detector_id as u32)];
+    if detector_id <= MAX_PIXELATED_DETECTOR {
+        bits_matches.push(BitsMatch::new(
+            PIXELATED | MAX_PIXELATED_DETECTOR as u32,
+            PIXELATED | detector_id as u32,
+        ));
+    }
+    bits_matches
+}
+
+// Detection events of the subtype `detection` (any if None) on the detector `src_id` (any if
+// SrcId::None), with or without pixel index. Backs the Detection arms of `filter_seq!`.
+pub fn detection_filter(detection: Option<Detection>, src_id: SrcId) -> FilterStep {
+    let (mask, value) = match detection {
+        Some(detection) => (Pipeline::mask() | Detection::mask(), Pipeline::Detection.encode() | detection.encode()),
+        None => (Pipeline::mask(), Pipeline::Detection.encode()),
+    };
+    match src_id {
+        SrcId::None => FilterStep::new(BitsMatch::new(mask, value)),
+        SrcId::Detector(id) => FilterStep::any_of(
+            detector_bits_matches(id)
+                .into_iter()
+                .map(|bits_match| BitsMatch::new(mask | bits_match.mask, value | bits_match.value))
+                .collect(),
+        ),
+        src_id => panic!("Detection events can only be filtered by DetectorId, not {}", src_id),
+    }
+}
+
+// Rows x cols of an array detector, pixels are numbered row-major
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DetectorGeometry {
+    pub rows: u16,
+    pub cols: u16,
+}
+
+impl DetectorGeometry {
+    pub fn new(rows: u16, cols: u16) -> Self {
+        assert!(rows > 0 && cols > 0, "Empty detector geometry {}x{}", rows, cols);
+        assert!(
+            rows as u32 * cols as u32 <= MAX_PIXEL as u32 + 1,
+            "Detector geometry {}x{} exceeds {} pixels", rows, cols, MAX_PIXEL as u32 + 1
+        );
+        DetectorGeometry { rows, cols }
+    }
+
+    pub fn pixel(&self, row: u16, col: u16) -> u16 {
+        assert!(row < self.rows && col < self.cols, "Pixel ({}, {}) outside of {}x{}", row, col, self.rows, self.cols);
+        row * self.cols + col
+    }
+
+    pub fn position(&self, pixel: u16) -> (u16, u16) {
+        (pixel / self.cols, pixel % self.cols)
+    }
+}
+
+// ----------------------------------------------------
+// Filters by pixel
+// ----------------------------------------------------
+// As for voxel ranges, a range of pixels is split into aligned power-of-two blocks, each matched by a
+// single BitsMatch with the pixel bits scattered in place.
+
+pub fn pixel_range_bits_matches(detector_id: u16, range: RangeInclusive<u16>) -> Vec<BitsMatch> {
+    assert!(detector_id <= MAX_PIXELATED_DETECTOR, "Pixelated detector id {} exceeds 8 bits", detector_id);
+    let (mut start, end) = (*range.start() as u32, (*range.end()).min(MAX_PIXEL) as u32);
+    let mut bits_matches = Vec::new();
+    while start <= end {
+        let mut size = if start == 0 { 1u32 << PIXEL_BITSIZE } else { 1u32 << start.trailing_zeros() };
+        while start + size - 1 > end {
+            size >>= 1;
+        }
+        let pixel_mask = MAX_PIXEL & !((size - 1) as u16);
+        bits_matches.push(BitsMatch::new(
+            Pipeline::mask() | PIXELATED | scatter_pixel(pixel_mask) | MAX_PIXELATED_DETECTOR as u32,
+            Pipeline::Detection.encode() | PIXELATED | scatter_pixel(start as u16) | detector_id as u32,
+        ));
+        start += size;
+    }
+    bits_matches
+}
+
+// Rectangular region of an array detector, one set of blocks per row
+pub fn pixel_region_bits_matches(
+    detector_id: u16,
+    geometry: &DetectorGeometry,
+    rows: RangeInclusive<u16>,
+    cols: RangeInclusive<u16>,
+) -> Vec<BitsMatch> {
+    let last_col = (*cols.end()).min(geometry.cols - 1);
+    rows.filter(|row| *row < geometry.rows)
+        .flat_map(|row| {
+            let first = geometry.pixel(row, *cols.start());
+            let last = geometry.pixel(row, last_col);
+            pixel_range_bits_matches(detector_id, first..=last)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Decode, Encode, EventId, EventType, SrcId};
+
+    #[test]
+    fn pixel_encoding() {
+        let raw_event = EventId::new_detection(Detection::Direct, SrcId::Detector(3)).encode();
+        assert_eq!(pixel_of(raw_event), None);
+
+        let geometry = DetectorGeometry::new(128, 256);
+        let pixel = geometry.pixel(100, 17);
+        let raw_event = EventId::new_detection(Detection::Rejected, SrcId::Detector(3)).with_pixel(pixel).encode();
+        assert_eq!(pixel_of(raw_event), Some(pixel));
+        let decoded = EventId::decode(raw_event);
+        assert_eq!(decoded.event_type, EventType::Detection(Detection::Rejected));
+        assert_eq!(decoded.src_id, SrcId::Detector(3));
+        assert_eq!(decoded.pixel, Some(pixel));
+        assert_eq!(decoded.channel, None);
+        assert_eq!(decoded.encode(), raw_event);
+        assert_eq!(geometry.position(pixel), (100, 17));
+        assert_eq!(scatter_pixel(MAX_PIXEL) & 0x0F0F00FF, 0);
+    }
+
+    #[test]
+    fn pixel_filters() {
+        let range = 5..=300;
+        let bits_matches = pixel_range_bits_matches(3, range.clone());
+        for pixel in (0..1024).chain([MAX_PIXEL]) {
+            let raw_event = EventId::new_detection(Detection::Direct, SrcId::Detector(3)).with_pixel(pixel).encode();
+            let matched = bits_matches.iter().any(|bm| (raw_event & bm.mask) == bm.value);
+            assert_eq!(matched, range.contains(&pixel), "Pixel {}", pixel);
+        }
+        let other_detector = EventId::new_detection(Detection::Direct, SrcId::Detector(4)).with_pixel(10).encode();
+        assert!(!bits_matches.iter().any(|bm| (other_detector & bm.mask) == bm.value));
+
+        let geometry = DetectorGeometry::new(4, 8);
+        let bits_matches = pixel_region_bits_matches(3, &geometry, 1..=2, 2..=5);
+        for pixel in 0..32 {
+            let (row, col) = geometry.position(pixel);
+            let raw_event = EventId::new_detection(Detection::Direct, SrcId::Detector(3)).with_pixel(pixel).encode();
+            let matched = bits_matches.iter().any(|bm| (raw_event & bm.mask) == bm.value);
+            assert_eq!(matched, (1..=2).contains(&row) && (2..=5).contains(&col), "Pixel ({}, {})", row, col);
+        }
+    }
+
+    #[test]
+    fn detector_filter_on_pixelated_hits() {
+        let hit = EventId::new_detection(Detection::TimeGated, SrcId::Detector(3)).with_pixel(0x0104).encode();
+        let plain = EventId::new_detection(Detection::TimeGated, SrcId::Detector(3)).encode();
+        let step = crate::filter_seq!(Detection, SrcId::Detector(3));
+        assert!(step.matches(hit) && step.matches(plain));
+        assert!(crate::filter_seq!(Detection, TimeGated, SrcId::Detector(3)).matches(hit));
+        assert!(!crate::filter_seq!(Detection, Direct, SrcId::Detector(3)).matches(hit));
+        assert!(!crate::filter_seq!(Detection, SrcId::Detector(4)).matches(hit));
+        // The pixel bits in the high byte don't alias a plain event of a wider detector id
+        assert!(!crate::filter_seq!(Detection, SrcId::Detector(0x0403)).matches(hit));
+        assert!(crate::filter_seq!(Detection, SrcId::None).matches(hit));
+    }
+}
@@ -1,7 +1,17 @@
-use crate::raw::RawField;
+use serde::{Deserialize, Serialize};
+
+use crate::filter::BitsMatch;
+use crate::raw::{Pipeline, RawField};
+use crate::TryDecode;
+use crate::error::DecodeError;
 use num_enum::{TryFromPrimitive, IntoPrimitive};
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+// NOTE: The emission subtype byte is split between the beam shape and the temporal modulation of
+// the source, such that time- and frequency-domain runs separate photons straight from the code:
+// | PulseIndexed (1) | TemporalMode (2) | Emission (5) |
+// Continuous sources without pulse index encode as before the modulation was introduced.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive, IntoPrimitive, Hash, Serialize, Deserialize)]
 #[repr(u8)]
 pub enum Emission {
     PencilBeam,
@@ -9,10 +19,170 @@ pub enum Emission {
     PointSource,
     PlaneSource,
     PlaneWave,
+    Laser,
+    // Light emitting diode, broad angular and spectral emission
+    Led,
+    Isotropic,
+    Collimated,
+    // Pulsed laser source, the pulse timing itself is recorded by the temporal mode
+    PulsedLaser,
+    // Photon re-emitted by the medium (i.e. after a fluorescence absorption), starting a child chain
+    SecondaryReEmission,
 }
 
 impl RawField for Emission {
-    fn mask() -> u32 { 0x00ff0000 }
-    fn shift() -> usize { 16 }
-    fn bitsize() -> usize { 8 }
+    const MASK: u32 = 0x001F0000;
+    const SHIFT: usize = 16;
+    const BITSIZE: usize = 5;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, TryFromPrimitive, IntoPrimitive, Hash, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum TemporalMode {
+    // Continuous wave
+    #[default]
+    Continuous,
+    Pulsed,
+    // Intensity modulated, i.e. frequency-domain measurements
+    Modulated,
+}
+
+impl RawField for TemporalMode {
+    const MASK: u32 = 0x00600000;
+    const SHIFT: usize = 21;
+    const BITSIZE: usize = 2;
+}
+
+crate::raw::const_encode!(Emission, TemporalMode);
+
+// Pulsed sources can optionally record the index of the pulse (modulo 16) in the top nibble,
+// which is then not available for the wavelength channel
+pub const PULSE_INDEXED: u32 = 0x00800000;
+pub const PULSE_MASK: u32 = 0xF0000000;
+pub const PULSE_SHIFT: usize = 28;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Hash, Serialize, Deserialize)]
+pub struct Modulation {
+    pub mode: TemporalMode,
+    pub pulse: Option<u8>,
+}
+
+impl Modulation {
+    pub fn pulsed(pulse: Option<u8>) -> Self {
+        Modulation { mode: TemporalMode::Pulsed, pulse: pulse.map(|pulse| pulse % 16) }
+    }
+
+    pub fn encode(&self) -> u32 {
+        let pulse_code = match self.pulse {
+            Some(pulse) => PULSE_INDEXED | ((pulse as u32 % 16) << PULSE_SHIFT),
+            None => 0,
+        };
+        self.mode.encode() | pulse_code
+    }
+
+    // Modulation of a raw emission event, None for continuous sources without pulse index
+    pub fn decode(raw: u32) -> Option<Self> {
+        Self::try_decode(raw).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_decode(raw: u32) -> Result<Option<Self>, DecodeError> {
+        let modulation = Modulation {
+            mode: TemporalMode::try_decode(raw)?,
+            pulse: (raw & PULSE_INDEXED != 0).then_some(((raw & PULSE_MASK) >> PULSE_SHIFT) as u8),
+        };
+        Ok((modulation != Modulation::default()).then_some(modulation))
+    }
+}
+
+// Emission events of sources with the given temporal mode
+pub fn mode_bits_match(mode: TemporalMode) -> BitsMatch {
+    BitsMatch::new(
+        Pipeline::mask() | TemporalMode::mask(),
+        Pipeline::Emission.encode() | mode.encode(),
+    )
+}
+
+// Emission events of the pulse `pulse` (modulo 16) of pulse-indexed sources
+pub fn pulse_bits_match(pulse: u8) -> BitsMatch {
+    let modulation = Modulation::pulsed(Some(pulse));
+    BitsMatch::new(
+        Pipeline::mask() | TemporalMode::mask() | PULSE_INDEXED | PULSE_MASK,
+        Pipeline::Emission.encode() | modulation.encode(),
+    )
+}
+
+// Counterpart of `mcrt_event!` for emissions, building the source type or the whole event
+// i.e.
+// 1. emission_event!(Laser) -> Emission::Laser
+// 2. emission_event!(Led, light_id) -> EventId::new_emission(Emission::Led, light_id)
+// 3. emission_event!(PulsedLaser, Pulsed, light_id) -> the same event with a pulsed temporal mode
+#[macro_export]
+macro_rules! emission_event {
+    ($emission:ident) => {
+        $crate::emission::Emission::$emission
+    };
+    ($emission:ident, $src_id:expr) => {
+        $crate::EventId::new_emission($crate::emission::Emission::$emission, $src_id)
+    };
+    ($emission:ident, $mode:ident, $src_id:expr) => {
+        $crate::emission_event!($emission, $src_id).with_modulation($crate::emission::Modulation {
+            mode: $crate::emission::TemporalMode::$mode,
+            pulse: None,
+        })
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Decode, Encode, EventId, EventType, SrcId};
+
+    #[test]
+    fn emission_event_macro() {
+        assert_eq!(emission_event!(Laser), Emission::Laser);
+        let light_id = SrcId::Light(2);
+        let codes = [
+            (Emission::Laser, 0x01050002),
+            (Emission::Led, 0x01060002),
+            (Emission::Isotropic, 0x01070002),
+            (Emission::Collimated, 0x01080002),
+            (Emission::PulsedLaser, 0x01090002),
+            (Emission::SecondaryReEmission, 0x010A0002),
+        ];
+        for (emission, raw_event) in codes {
+            assert_eq!(EventId::new_emission(emission, light_id).encode(), raw_event);
+            assert_eq!(EventId::decode(raw_event).event_type, EventType::Emission(emission));
+        }
+        assert_eq!(emission_event!(Led, light_id).encode(), 0x01060002);
+        let pulsed = emission_event!(PulsedLaser, Pulsed, light_id);
+        assert_eq!(pulsed.modulation, Some(Modulation::pulsed(None)));
+        assert_eq!(pulsed.encode(), 0x01290002);
+    }
+
+    #[test]
+    fn temporal_modes() {
+        let light_id = SrcId::Light(1);
+        let cw = EventId::new_emission(Emission::GaussianBeam, light_id);
+        assert_eq!(cw.encode(), 0x01010001);
+        assert_eq!(EventId::decode(cw.encode()).modulation, None);
+
+        let pulsed = EventId::new_emission(Emission::GaussianBeam, light_id).with_modulation(Modulation::pulsed(Some(18)));
+        let raw_event = pulsed.encode();
+        assert_eq!(raw_event, 0x21A10001);
+        let decoded = EventId::decode(raw_event);
+        assert_eq!(decoded.event_type, EventType::Emission(Emission::GaussianBeam));
+        assert_eq!(decoded.modulation, Some(Modulation { mode: TemporalMode::Pulsed, pulse: Some(2) }));
+        assert_eq!(decoded.channel, None);
+        assert_eq!(decoded.encode(), raw_event);
+
+        let modulated = EventId::new_emission(Emission::PlaneWave, light_id)
+            .with_modulation(Modulation { mode: TemporalMode::Modulated, pulse: None })
+            .encode();
+        let bits_match = mode_bits_match(TemporalMode::Modulated);
+        assert_eq!(modulated & bits_match.mask, bits_match.value);
+        assert_ne!(raw_event & bits_match.mask, bits_match.value);
+        let bits_match = pulse_bits_match(2);
+        assert_eq!(raw_event & bits_match.mask, bits_match.value);
+        assert_ne!(raw_event & pulse_bits_match(3).mask, pulse_bits_match(3).value);
+    }
 }
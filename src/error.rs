@@ -0,0 +1,77 @@
+use thiserror::Error;
+
+#[cfg(feature = "std")]
+use crate::{SrcId, SrcKind};
+
+// ----------------------------------------------------
+// Errors of the Ledger operations
+// ----------------------------------------------------
+// Returned instead of panicking on conflicting registrations and unknown UIDs, such that a long
+// simulation can log the faulty photon and carry on. UIDs are kept formatted, as the error is
+// shared by the ledgers of any event width.
+
+#[cfg(feature = "std")]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum LedgerError {
+    #[error("UID {0} is not in the ledger")]
+    UnknownUid(String),
+    #[error("Group path `{0}` has an empty segment")]
+    InvalidGroup(String),
+    #[error("Group name `{group}` is already used for {src_id}")]
+    GroupConflict { group: String, src_id: SrcId },
+    #[error("Group `{group}` is {src_id} in the ledger and {other_src_id} in the merged one")]
+    GroupKindMismatch { group: String, src_id: SrcId, other_src_id: SrcId },
+    #[error("{0} is not registered")]
+    UnknownSrc(SrcId),
+    #[error("Unknown source name: {0}")]
+    UnknownSrcName(String),
+    // See `Ledger::alias_src`
+    #[error("{alias} is already merged into {canonical}")]
+    SrcAlreadyMerged { alias: SrcId, canonical: SrcId },
+    #[error("Cannot merge {alias} into {canonical} of another kind")]
+    AliasKindMismatch { alias: SrcId, canonical: SrcId },
+    #[error("Packet {packet_id} is already tagged to UID {uid}")]
+    PacketTagged { packet_id: u64, uid: String },
+    // A delta refers to entries of an earlier delta which wasn't applied, see `Ledger::apply_delta`
+    #[error("Delta refers to seq_id {seq_id}, the ledger only reaches {reached}: an earlier delta is missing")]
+    DeltaOutOfOrder { seq_id: u32, reached: u32 },
+    // Mat and Surf ids grow upwards, MatSurf ids downwards in the same 16 bits, see `LedgerConfig`
+    #[error("No {0} id left, the id ranges would overlap")]
+    SrcIdsExhausted(SrcKind),
+    #[error("No seq_id left to allocate, compact the ledger or split the run")]
+    SeqIdsExhausted,
+    #[error("Empty {0} id range in the ledger config")]
+    EmptySrcRange(SrcKind),
+    #[error("The merged ledger records other wavelength channels")]
+    ChannelMismatch,
+    #[error("{0} does not fit the 8 bits of the detector id of pixelated detection events")]
+    PixelatedDetectorId(SrcId),
+    // Event of a merged ledger with codes without a variant, see `DecodeError`
+    #[error("Undecodable event: {0}")]
+    Decode(#[from] DecodeError),
+    // Spilling or streaming entries to disk failed, the io::Error is kept formatted to stay comparable
+    #[error("Ledger file error: {0}")]
+    Io(String),
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for LedgerError {
+    fn from(err: std::io::Error) -> Self {
+        LedgerError::Io(err.to_string())
+    }
+}
+
+// ----------------------------------------------------
+// Errors of the fallible decoding
+// ----------------------------------------------------
+// Raw events read from corrupted files, or written by a newer version of the layout, can hold codes
+// without a variant. `TryDecode` reports the field instead of panicking like `Decode`.
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("Unknown {field} code {code} in raw event 0x{raw:08X}")]
+    UnknownCode { field: &'static str, code: u8, raw: u32 },
+    // The 16 bits of the SrcId of an EventId can't hold the source id of a wide event
+    #[error("SrcId {src_id} of raw event 0x{raw:016X} exceeds the 16 bits of an EventId")]
+    WideSrcId { src_id: u32, raw: u64 },
+}
@@ -0,0 +1,439 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::{EventId, EventType, TryDecode};
+use crate::kind::{EventKind, Granularity};
+use crate::ledger::{Ledger, Uid};
+
+// ----------------------------------------------------
+// Newline-delimited JSON export of chains
+// ----------------------------------------------------
+// One JSON object per chain and line, such that the export streams into jq/Spark line by line
+// instead of loading a single giant array:
+// {"root":"0, 0x01000000","leaf":"2, 0x03800001","length":2,"events":[{"uid":"0, 0x01000000",
+//  "raw":"0x01000000","pipeline":"Emission","kind":"PencilBeam","src":"Light(0)",
+//  "src_names":["laser"],"count":12,"is_leaf":false}, ...]}
+// Events of ledgers with timestamps also hold their "time".
+
+#[derive(Serialize, Debug)]
+pub struct ChainRecord {
+    pub root: String,
+    pub leaf: String,
+    pub length: usize,
+    pub events: Vec<EventRecord>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct EventRecord {
+    pub uid: String,
+    pub raw: String,
+    pub pipeline: String,
+    pub kind: String,
+    pub src: String,
+    pub src_names: Vec<String>,
+    // Photons which took the transition to the entry, see `Ledger::get_count`
+    pub count: u64,
+    // Whether the entry has no subsequent event in the ledger, a chain might stop before its leaf
+    pub is_leaf: bool,
+    // Simulation time of the entry, only for ledgers with timestamps, see `Ledger::get_timestamp`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<f32>,
+}
+
+impl ChainRecord {
+    pub fn new(ledger: &Ledger, chain: &[Uid]) -> Self {
+        assert!(!chain.is_empty(), "Cannot export an empty chain");
+        ChainRecord {
+            root: chain.first().unwrap().to_string(),
+            leaf: chain.last().unwrap().to_string(),
+            length: chain.len(),
+            events: chain.iter().map(|uid| EventRecord::new(ledger, uid)).collect(),
+        }
+    }
+}
+
+// Value of the decoded attributes of events which don't decode, i.e. read from a corrupted ledger
+const UNKNOWN: &str = "unknown";
+
+impl EventRecord {
+    pub fn new(ledger: &Ledger, uid: &Uid) -> Self {
+        let event_id = EventId::try_decode(uid.event).ok();
+        let label = |granularity| {
+            event_id.as_ref().map_or(UNKNOWN.to_string(), |event_id| EventKind::from_event(event_id, granularity).label())
+        };
+        EventRecord {
+            uid: uid.to_string(),
+            raw: format!("0x{:08X}", uid.event),
+            pipeline: label(Granularity::Pipeline),
+            kind: label(Granularity::Full),
+            src: event_id.as_ref().map_or(UNKNOWN.to_string(), |event_id| event_id.src_id.to_string()),
+            src_names: event_id.as_ref()
+                .map(|event_id| ledger.event_names(event_id).iter().map(|name| name.to_string()).collect())
+                .unwrap_or_default(),
+            count: ledger.get_count(uid),
+            is_leaf: ledger.get_next(uid).is_empty(),
+            time: ledger.get_timestamp(uid),
+        }
+    }
+}
+
+// Write each of `chains` as a line of JSON, returns the number of chains written
+pub fn write_chains_ndjson<W, I>(ledger: &Ledger, chains: I, mut writer: W) -> io::Result<usize>
+where
+    W: Write,
+    I: IntoIterator<Item = Vec<Uid>>,
+{
+    let mut count = 0;
+    for chain in chains {
+        serde_json::to_writer(&mut writer, &ChainRecord::new(ledger, &chain))?;
+        writer.write_all(b"\n")?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+// Export every chain of the ledger, see `Ledger::chains`
+pub fn write_ledger_ndjson<P: AsRef<Path>>(ledger: &Ledger, path: P) -> io::Result<usize> {
+    let writer = BufWriter::new(File::create(path)?);
+    write_chains_ndjson(ledger, ledger.chains(), writer)
+}
+
+// ----------------------------------------------------
+// Sparse adjacency export of the ledger graph
+// ----------------------------------------------------
+// The entries are the nodes of a DAG, numbered in the order of `Ledger::entries`, with an edge
+// from each entry to its subsequent events and to the child roots it started. Written as:
+// - Edge list (.csv): `source,target,kind` with kind `next` or `child`, 0-based node ids
+// - Matrix Market (.mtx): coordinate pattern matrix, 1-based as per the format
+// along with a node table (.csv) `id,uid,raw,pipeline,kind,src,src_names,count,is_leaf`, src_names
+// separated by `;`, for networkx/igraph/Gephi.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    Next,
+    Child,
+}
+
+impl EdgeKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            EdgeKind::Next => "next",
+            EdgeKind::Child => "child",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdjacencyFormat {
+    EdgeList,
+    MatrixMarket,
+}
+
+impl AdjacencyFormat {
+    // Matrix Market for `.mtx` files, edge list otherwise
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension() {
+            Some(ext) if ext == "mtx" => AdjacencyFormat::MatrixMarket,
+            _ => AdjacencyFormat::EdgeList,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Adjacency {
+    pub nodes: Vec<Uid>,
+    pub edges: Vec<(usize, usize, EdgeKind)>,
+}
+
+impl Adjacency {
+    pub fn new(ledger: &Ledger) -> Self {
+        let nodes: Vec<Uid> = ledger.entries().collect();
+        let ids: HashMap<Uid, usize> = nodes.iter().enumerate().map(|(id, uid)| (*uid, id)).collect();
+        let mut edges = Vec::new();
+        for (source, uid) in nodes.iter().enumerate() {
+            for next in ledger.get_next(uid) {
+                edges.push((source, ids[&next], EdgeKind::Next));
+            }
+            for child in ledger.get_child_roots(uid) {
+                edges.push((source, ids[child], EdgeKind::Child));
+            }
+        }
+        Adjacency { nodes, edges }
+    }
+
+    pub fn write_edges<W: Write>(&self, format: AdjacencyFormat, mut writer: W) -> io::Result<()> {
+        match format {
+            AdjacencyFormat::EdgeList => {
+                writeln!(writer, "source,target,kind")?;
+                for (source, target, kind) in &self.edges {
+                    writeln!(writer, "{},{},{}", source, target, kind.label())?;
+                }
+            }
+            AdjacencyFormat::MatrixMarket => {
+                writeln!(writer, "%%MatrixMarket matrix coordinate pattern general")?;
+                writeln!(writer, "{} {} {}", self.nodes.len(), self.nodes.len(), self.edges.len())?;
+                for (source, target, _) in &self.edges {
+                    writeln!(writer, "{} {}", source + 1, target + 1)?;
+                }
+            }
+        }
+        writer.flush()
+    }
+
+    pub fn write_nodes<W: Write>(&self, ledger: &Ledger, writer: W) -> io::Result<()> {
+        let mut csv_writer = csv::Writer::from_writer(writer);
+        csv_writer.write_record(["id", "uid", "raw", "pipeline", "kind", "src", "src_names", "count", "is_leaf"])?;
+        for (id, uid) in self.nodes.iter().enumerate() {
+            let record = EventRecord::new(ledger, uid);
+            csv_writer.write_record([
+                id.to_string(),
+                record.uid,
+                record.raw,
+                record.pipeline,
+                record.kind,
+                record.src,
+                record.src_names.join(";"),
+                record.count.to_string(),
+                record.is_leaf.to_string(),
+            ])?;
+        }
+        csv_writer.flush()
+    }
+}
+
+// Write the edges in the format of `edges_path`, and the node table, returns the number of nodes and edges
+pub fn write_ledger_adjacency<P: AsRef<Path>, Q: AsRef<Path>>(
+    ledger: &Ledger,
+    edges_path: P,
+    nodes_path: Q,
+) -> io::Result<(usize, usize)> {
+    let adjacency = Adjacency::new(ledger);
+    let format = AdjacencyFormat::from_path(&edges_path);
+    adjacency.write_edges(format, BufWriter::new(File::create(edges_path)?))?;
+    adjacency.write_nodes(ledger, BufWriter::new(File::create(nodes_path)?))?;
+    Ok((adjacency.nodes.len(), adjacency.edges.len()))
+}
+
+// ----------------------------------------------------
+// GraphML export of the ledger graph
+// ----------------------------------------------------
+// Same nodes and edges as the adjacency export, as a single GraphML file for Gephi/Cytoscape/yEd.
+// The nodes carry the decoded event as attributes: pipeline, supertype, subtype, scatter direction
+// (empty for non scattering events), kind (leaf label, also the display label), src, src_names
+// (separated by `;`), count and is_leaf. Events which don't decode get `unknown` decoded attributes. The edges carry their kind and the count of their target,
+// i.e. the photons which took the transition.
+
+const GRAPHML_NODE_KEYS: [(&str, &str); 11] = [
+    ("label", "string"),
+    ("uid", "string"),
+    ("raw", "string"),
+    ("pipeline", "string"),
+    ("supertype", "string"),
+    ("subtype", "string"),
+    ("direction", "string"),
+    ("src", "string"),
+    ("src_names", "string"),
+    ("count", "long"),
+    ("is_leaf", "boolean"),
+];
+
+impl Adjacency {
+    pub fn write_graphml<W: Write>(&self, ledger: &Ledger, mut writer: W) -> io::Result<()> {
+        writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(writer, r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#)?;
+        for (name, attr_type) in GRAPHML_NODE_KEYS {
+            writeln!(writer, r#"  <key id="{0}" for="node" attr.name="{0}" attr.type="{1}"/>"#, name, attr_type)?;
+        }
+        writeln!(writer, r#"  <key id="edge_kind" for="edge" attr.name="kind" attr.type="string"/>"#)?;
+        writeln!(writer, r#"  <key id="edge_count" for="edge" attr.name="count" attr.type="long"/>"#)?;
+        writeln!(writer, r#"  <graph id="ledger" edgedefault="directed">"#)?;
+        for (id, uid) in self.nodes.iter().enumerate() {
+            let record = EventRecord::new(ledger, uid);
+            let event_id = EventId::try_decode(uid.event).ok();
+            let label = |granularity| {
+                event_id.as_ref().map_or(UNKNOWN.to_string(), |event_id| EventKind::from_event(event_id, granularity).label())
+            };
+            let direction = match event_id.as_ref().map(|event_id| &event_id.event_type) {
+                Some(EventType::MCRT(mcrt)) => mcrt.scatter_dir().map(|dir| format!("{:?}", dir)).unwrap_or_default(),
+                Some(_) => String::new(),
+                None => UNKNOWN.to_string(),
+            };
+            let values = [
+                record.kind.clone(),
+                record.uid,
+                record.raw,
+                record.pipeline,
+                label(Granularity::SuperType),
+                label(Granularity::SubType),
+                direction,
+                record.src,
+                record.src_names.join(";"),
+                record.count.to_string(),
+                record.is_leaf.to_string(),
+            ];
+            writeln!(writer, r#"    <node id="n{}">"#, id)?;
+            for ((name, _), value) in GRAPHML_NODE_KEYS.iter().zip(values) {
+                writeln!(writer, r#"      <data key="{}">{}</data>"#, name, xml_escape(&value))?;
+            }
+            writeln!(writer, "    </node>")?;
+        }
+        for (source, target, kind) in &self.edges {
+            writeln!(writer, r#"    <edge source="n{}" target="n{}">"#, source, target)?;
+            writeln!(writer, r#"      <data key="edge_kind">{}</data>"#, kind.label())?;
+            writeln!(writer, r#"      <data key="edge_count">{}</data>"#, ledger.get_count(&self.nodes[*target]))?;
+            writeln!(writer, "    </edge>")?;
+        }
+        writeln!(writer, "  </graph>")?;
+        writeln!(writer, "</graphml>")?;
+        writer.flush()
+    }
+}
+
+// Write the ledger graph as GraphML, returns the number of nodes and edges
+pub fn write_ledger_graphml<P: AsRef<Path>>(ledger: &Ledger, path: P) -> io::Result<(usize, usize)> {
+    let adjacency = Adjacency::new(ledger);
+    adjacency.write_graphml(ledger, BufWriter::new(File::create(path)?))?;
+    Ok((adjacency.nodes.len(), adjacency.edges.len()))
+}
+
+// Source names are user given, hence may hold markup characters
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emission::Emission;
+    use crate::mcrt_event;
+
+    #[test]
+    fn ndjson_one_line_per_chain() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let uid1 = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+        let uid2 = ledger.insert(uid1, EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+        ledger.insert(uid2, EventId::new_mcrt(mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        ledger.insert(uid1, EventId::new_mcrt(mcrt_event!(Material, Absorption), mat_id)).unwrap();
+
+        let mut buffer = Vec::new();
+        let count = write_chains_ndjson(&ledger, ledger.chains(), &mut buffer).expect("Unable to export chains");
+        assert_eq!(count, 2);
+
+        let text = String::from_utf8(buffer).unwrap();
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("Invalid JSON line"))
+            .collect();
+        assert_eq!(lines.len(), 2);
+        let longest = lines.iter().find(|line| line["length"] == 3).expect("Missing chain of length 3");
+        assert_eq!(longest["root"], uid1.to_string());
+        assert_eq!(longest["events"][0]["kind"], "PencilBeam");
+        assert_eq!(longest["events"][0]["src_names"][0], "laser");
+        assert_eq!(longest["events"][1]["kind"], "Mie/Forward");
+        assert_eq!(longest["events"][1]["pipeline"], "MCRT");
+        assert_eq!(longest["events"][1]["src_names"][0], "water");
+        assert_eq!(longest["events"][1]["is_leaf"], false);
+        assert_eq!(longest["events"][1]["count"], 1);
+        assert_eq!(longest["events"][2]["is_leaf"], true);
+        assert!(longest["events"][0].get("time").is_none());
+
+        let mut timed = Ledger::new();
+        timed.enable_timestamps(crate::ledger::TimeBase::Simulation);
+        let light_id = timed.with_light("laser".to_string()).unwrap();
+        let mat_id = timed.with_mat("water".to_string()).unwrap();
+        let start = timed.insert_start_at(EventId::new_emission(Emission::PencilBeam, light_id), 0.5).unwrap();
+        let leaf = timed.insert_at(start, EventId::new_mcrt(mcrt_event!(Material, Absorption), mat_id), 2.5).unwrap();
+        let mut buffer = Vec::new();
+        write_chains_ndjson(&timed, vec![vec![start, leaf]], &mut buffer).unwrap();
+        let line: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(line["events"][0]["time"], 0.5);
+        assert_eq!(line["events"][1]["time"], 2.5);
+    }
+
+    #[test]
+    fn adjacency_edges_and_nodes() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let uid1 = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+        let uid2 = ledger.insert(uid1, EventId::new_mcrt(mcrt_event!(Material, Inelastic, Fluorescence, Any), mat_id)).unwrap();
+        ledger.insert(uid1, EventId::new_mcrt(mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        ledger.insert_child_root(uid2, EventId::new_emission(Emission::PointSource, light_id)).unwrap();
+
+        let adjacency = Adjacency::new(&ledger);
+        assert_eq!(adjacency.nodes.len(), 4);
+        assert_eq!(adjacency.edges.len(), 3);
+        assert_eq!(adjacency.edges.iter().filter(|edge| edge.2 == EdgeKind::Child).count(), 1);
+
+        let mut edges = Vec::new();
+        adjacency.write_edges(AdjacencyFormat::MatrixMarket, &mut edges).unwrap();
+        let edges = String::from_utf8(edges).unwrap();
+        assert_eq!(edges.lines().nth(1), Some("4 4 3"));
+        assert!(edges.lines().skip(2).all(|line| line.split(' ').all(|id| (1..=4).contains(&id.parse::<usize>().unwrap()))));
+
+        let dir = tempfile::tempdir().unwrap();
+        let (nodes_path, edges_path) = (dir.path().join("nodes.csv"), dir.path().join("edges.csv"));
+        assert_eq!(write_ledger_adjacency(&ledger, &edges_path, &nodes_path).unwrap(), (4, 3));
+        let edges = std::fs::read_to_string(&edges_path).unwrap();
+        assert_eq!(edges.lines().next(), Some("source,target,kind"));
+        assert!(edges.lines().any(|line| line.ends_with(",child")));
+        let nodes = std::fs::read_to_string(&nodes_path).unwrap();
+        assert_eq!(nodes.lines().count(), 5);
+        assert!(nodes.lines().any(|line| line.contains("PencilBeam") && line.contains("laser")));
+    }
+
+    #[test]
+    fn graphml_attributes() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser <red>".to_string()).unwrap();
+        let mat_id = ledger.with_mat("salt & water".to_string()).unwrap();
+        let uid1 = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+        for _ in 0..3 {
+            ledger.insert(uid1, EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Backward), mat_id)).unwrap();
+        }
+        // Unknown detection code
+        ledger.insert_raw(uid1, 0x050F0002).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger.graphml");
+        assert_eq!(write_ledger_graphml(&ledger, &path).unwrap(), (3, 2));
+        let graphml = std::fs::read_to_string(&path).unwrap();
+        assert!(graphml.starts_with("<?xml"));
+        assert!(graphml.trim_end().ends_with("</graphml>"));
+        for data in [
+            r#"<data key="label">Mie/Backward</data>"#,
+            r#"<data key="supertype">Material</data>"#,
+            r#"<data key="subtype">Mie</data>"#,
+            r#"<data key="direction">Backward</data>"#,
+            r#"<data key="direction"></data>"#,
+            r#"<data key="src_names">salt &amp; water</data>"#,
+            r#"<data key="src_names">laser &lt;red&gt;</data>"#,
+            r#"<edge source="n0" target="n1">"#,
+            r#"<data key="edge_count">3</data>"#,
+            r#"<data key="raw">0x050F0002</data>"#,
+            r#"<data key="label">unknown</data>"#,
+            r#"<data key="direction">unknown</data>"#,
+        ] {
+            assert!(graphml.contains(data), "Missing {} in\n{}", data, graphml);
+        }
+        assert_eq!(graphml.matches("<node ").count(), 3);
+    }
+}
@@ -0,0 +1,268 @@
+use std::ffi::{CStr, c_char};
+use std::fs::File;
+use std::io::BufWriter;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+
+use crate::ledger::{Ledger, Uid};
+use crate::mcrt;
+use crate::raw::{self, RawField};
+use crate::{SrcId, TryDecode};
+
+// ----------------------------------------------------
+// C API for populating a ledger from the engine, behind the `ffi` feature
+// ----------------------------------------------------
+// A C/C++ engine links the static or dynamic library (`cargo rustc --release --features ffi
+// --crate-type staticlib`) with the declarations of include/aetherus_events_ffi.h, and inserts the
+// events as it simulates instead of shipping raw logs to a post-processor:
+//     AevLedger *ledger = ledger_new();
+//     int32_t water = ledger_with_mat(ledger, "water");
+//     AevUid start, uid;
+//     ledger_insert_start(ledger, AEV_PIPELINE_EMISSION | AEV_EMISSION_PENCIL_BEAM | light, &start);
+//     ledger_insert(ledger, start, event_encode_mcrt(2, 2, 1, 1, water), &uid);
+//     ledger_write_json(ledger, "ledger.json");
+//     ledger_free(ledger);
+// Functions return AEV_OK or a negative error code, the results are written to the out pointers.
+// Pointers must be valid (or null, which is reported as AEV_ERR_NULL) and strings nul-terminated.
+// A ledger is not thread-safe, engines share it behind their own lock. Panics are caught and
+// reported as AEV_ERR_PANIC rather than unwinding into C.
+
+pub const AEV_OK: i32 = 0;
+pub const AEV_ERR_NULL: i32 = -1;
+// Invalid UTF-8 string, or raw event failing to decode
+pub const AEV_ERR_INVALID: i32 = -2;
+// Error of the ledger operation, i.e. unknown previous UID, conflicting group or exhausted ids
+pub const AEV_ERR_LEDGER: i32 = -3;
+pub const AEV_ERR_IO: i32 = -4;
+pub const AEV_ERR_PANIC: i32 = -5;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AevUid {
+    pub seq_id: u32,
+    pub event: u32,
+}
+
+impl From<Uid> for AevUid {
+    fn from(uid: Uid) -> Self {
+        AevUid { seq_id: uid.seq_id, event: uid.event }
+    }
+}
+
+impl From<AevUid> for Uid {
+    fn from(uid: AevUid) -> Self {
+        Uid { seq_id: uid.seq_id, event: uid.event }
+    }
+}
+
+fn guard(body: impl FnOnce() -> Result<(), i32>) -> i32 {
+    match catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(())) => AEV_OK,
+        Ok(Err(code)) => code,
+        Err(_) => AEV_ERR_PANIC,
+    }
+}
+
+unsafe fn ledger_mut<'a>(ledger: *mut Ledger) -> Result<&'a mut Ledger, i32> {
+    unsafe { ledger.as_mut() }.ok_or(AEV_ERR_NULL)
+}
+
+unsafe fn string(text: *const c_char) -> Result<String, i32> {
+    if text.is_null() {
+        return Err(AEV_ERR_NULL);
+    }
+    unsafe { CStr::from_ptr(text) }.to_str().map(str::to_string).map_err(|_| AEV_ERR_INVALID)
+}
+
+// Id of a registered source, or the error code
+fn src_result(body: impl FnOnce() -> Result<SrcId, i32>) -> i32 {
+    let mut id = 0;
+    match guard(|| body().map(|src_id| id = src_id.id().unwrap_or(0) as i32)) {
+        AEV_OK => id,
+        code => code,
+    }
+}
+
+fn check_event(event: u32) -> Result<u32, i32> {
+    crate::EventId::try_decode(event).map(|_| event).map_err(|_| AEV_ERR_INVALID)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn ledger_new() -> *mut Ledger {
+    Box::into_raw(Box::new(Ledger::new()))
+}
+
+/// # Safety
+/// `ledger` must be null or a pointer returned by `ledger_new`, not used after this call nor freed
+/// twice.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ledger_free(ledger: *mut Ledger) {
+    if !ledger.is_null() {
+        drop(unsafe { Box::from_raw(ledger) });
+    }
+}
+
+/// Registration of the sources return the id of the source (SrcId bits of its events), or the
+/// negative error code
+///
+/// # Safety
+/// `ledger` must be null or a pointer returned by `ledger_new` and not yet freed, not used by
+/// another thread during the call.
+/// `name` must be null or a nul-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ledger_with_mat(ledger: *mut Ledger, name: *const c_char) -> i32 {
+    src_result(|| unsafe { ledger_mut(ledger) }?.with_mat(unsafe { string(name) }?).map_err(|_| AEV_ERR_LEDGER))
+}
+
+/// `group` may be null for ungrouped surfaces
+///
+/// # Safety
+/// `ledger` must be null or a pointer returned by `ledger_new` and not yet freed, not used by
+/// another thread during the call.
+/// `name` and `group` must be null or nul-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ledger_with_surf(ledger: *mut Ledger, name: *const c_char, group: *const c_char) -> i32 {
+    src_result(|| {
+        let ledger = unsafe { ledger_mut(ledger) }?;
+        let group = if group.is_null() { None } else { Some(unsafe { string(group) }?) };
+        ledger.with_surf(unsafe { string(name) }?, group).map_err(|_| AEV_ERR_LEDGER)
+    })
+}
+
+/// # Safety
+/// `ledger` must be null or a pointer returned by `ledger_new` and not yet freed, not used by
+/// another thread during the call.
+/// `name` must be null or a nul-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ledger_with_light(ledger: *mut Ledger, name: *const c_char) -> i32 {
+    src_result(|| unsafe { ledger_mut(ledger) }?.with_light(unsafe { string(name) }?).map_err(|_| AEV_ERR_LEDGER))
+}
+
+/// # Safety
+/// `ledger` must be null or a pointer returned by `ledger_new` and not yet freed, not used by
+/// another thread during the call.
+/// `name` must be null or a nul-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ledger_with_detector(ledger: *mut Ledger, name: *const c_char) -> i32 {
+    src_result(|| unsafe { ledger_mut(ledger) }?.with_detector(unsafe { string(name) }?).map_err(|_| AEV_ERR_LEDGER))
+}
+
+/// # Safety
+/// `ledger` must be null or a pointer returned by `ledger_new` and not yet freed, not used by
+/// another thread during the call.
+/// `uid` must be null or valid for writes of an `AevUid`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ledger_insert_start(ledger: *mut Ledger, event: u32, uid: *mut AevUid) -> i32 {
+    guard(|| {
+        let ledger = unsafe { ledger_mut(ledger) }?;
+        let uid = unsafe { uid.as_mut() }.ok_or(AEV_ERR_NULL)?;
+        *uid = ledger.insert_start_raw(check_event(event)?).map_err(|_| AEV_ERR_LEDGER)?.into();
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `ledger` must be null or a pointer returned by `ledger_new` and not yet freed, not used by
+/// another thread during the call.
+/// `uid` must be null or valid for writes of an `AevUid`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ledger_insert(ledger: *mut Ledger, prev: AevUid, event: u32, uid: *mut AevUid) -> i32 {
+    guard(|| {
+        let ledger = unsafe { ledger_mut(ledger) }?;
+        let uid = unsafe { uid.as_mut() }.ok_or(AEV_ERR_NULL)?;
+        *uid = ledger.insert_raw(prev.into(), check_event(event)?).map_err(|_| AEV_ERR_LEDGER)?.into();
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `ledger` must be null or a pointer returned by `ledger_new` and not yet freed, not used by
+/// another thread during the call.
+/// `path` must be null or a nul-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ledger_write_json(ledger: *mut Ledger, path: *const c_char) -> i32 {
+    guard(|| {
+        let ledger = unsafe { ledger_mut(ledger) }?;
+        let file = File::create(unsafe { string(path) }?).map_err(|_| AEV_ERR_IO)?;
+        serde_json::to_writer(BufWriter::new(file), ledger).map_err(|_| AEV_ERR_IO)
+    })
+}
+
+// MCRT event from the codes of the `raw` enums, unshifted:
+// - Interface, Reflector and Custom: `subtype` is the Interface/Reflector variant or custom code,
+//   `scatter` and `dir` are 0
+// - Material: `subtype` is the Material variant, `scatter` the Elastic/Inelastic variant and `dir`
+//   the ScatterDir, or for roulettes the Roulette outcome and the boost class of survivors
+// Returns 0, which is never a valid event, for codes without a variant.
+#[unsafe(no_mangle)]
+pub extern "C" fn event_encode_mcrt(supertype: u8, subtype: u8, scatter: u8, dir: u8, src_id: u16) -> u32 {
+    let code = |value: u8, shift: usize| (value as u32) << shift;
+    if subtype > raw::MAX_MCRT_CUSTOM as u8 || scatter > 3 || dir > raw::MAX_BOOST_CLASS {
+        return 0;
+    }
+    let type_bits = code(supertype, raw::MCRT::SHIFT)
+        | match (raw::MCRT::try_from(supertype), raw::Material::try_from(subtype)) {
+            (Ok(raw::MCRT::Material), Ok(raw::Material::Roulette)) => {
+                code(subtype, raw::Material::SHIFT) | code(scatter, raw::Roulette::SHIFT) | code(dir, raw::BOOST_CLASS_SHIFT)
+            }
+            (Ok(raw::MCRT::Material), _) => {
+                code(subtype, raw::Material::SHIFT) | code(scatter, raw::Elastic::SHIFT) | code(dir, raw::ScatterDir::SHIFT)
+            }
+            (Ok(_), _) if scatter == 0 && dir == 0 => code(subtype, raw::Interface::SHIFT),
+            _ => return 0,
+        };
+    match mcrt::MCRT::try_decode(type_bits) {
+        // Codes ignored by the variant, i.e. a direction given to an absorption, don't round trip
+        Ok(event) if event.encode() == type_bits => raw::Pipeline::MCRT.encode() | type_bits | src_id as u32,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detection::Detection;
+    use crate::emission::Emission;
+    use crate::ledger::read_ledger_from_json;
+    use crate::{Encode, EventId};
+
+    #[test]
+    fn c_api() {
+        assert_eq!(event_encode_mcrt(2, 2, 1, 1, 1), 0x03a50001);
+        let survived = mcrt::MCRT::Material(mcrt::Material::Roulette(mcrt::Roulette::Survived { boost_class: 3 }));
+        assert_eq!(event_encode_mcrt(2, 3, 1, 3, 7), EventId::new_mcrt(survived, SrcId::Mat(7)).encode());
+        assert_eq!(event_encode_mcrt(0, 1, 0, 0, 2), 0x03010002);
+        assert_eq!(event_encode_mcrt(2, 0, 0, 1, 1), 0);
+        assert_eq!(event_encode_mcrt(1, 0, 0, 0, 1), 0);
+        assert_eq!(event_encode_mcrt(0, 1, 1, 0, 1), 0);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = std::ffi::CString::new(dir.path().join("ledger.json").to_str().unwrap()).unwrap();
+        unsafe {
+            let ledger = ledger_new();
+            let light = ledger_with_light(ledger, c"laser".as_ptr());
+            let water = ledger_with_mat(ledger, c"water".as_ptr());
+            assert!(ledger_with_surf(ledger, c"lens".as_ptr(), std::ptr::null()) >= 0);
+            assert_eq!(ledger_with_surf(ledger, c"lens".as_ptr(), c"optics//lenses".as_ptr()), AEV_ERR_LEDGER);
+            assert_eq!(ledger_with_mat(std::ptr::null_mut(), c"air".as_ptr()), AEV_ERR_NULL);
+
+            let mut start = AevUid { seq_id: 0, event: 0 };
+            let emission = EventId::new_emission(Emission::PencilBeam, SrcId::Light(light as u16)).encode();
+            assert_eq!(ledger_insert_start(ledger, emission, &mut start), AEV_OK);
+            let mut uid = start;
+            assert_eq!(ledger_insert(ledger, start, event_encode_mcrt(2, 2, 1, 1, water as u16), &mut uid), AEV_OK);
+            let detection = EventId::new_detection(Detection::Direct, SrcId::Detector(0)).encode();
+            assert_eq!(ledger_insert(ledger, uid, detection, &mut uid), AEV_OK);
+            assert_eq!(ledger_insert(ledger, AevUid { seq_id: 99, event: emission }, detection, &mut uid), AEV_ERR_LEDGER);
+            assert_eq!(ledger_insert(ledger, start, 0x03400000, &mut uid), AEV_ERR_INVALID);
+            assert_eq!(ledger_insert(ledger, start, detection, std::ptr::null_mut()), AEV_ERR_NULL);
+            assert_eq!(ledger_write_json(ledger, path.as_ptr()), AEV_OK);
+            assert_eq!(ledger_write_json(ledger, c"/nonexistent/ledger.json".as_ptr()), AEV_ERR_IO);
+            ledger_free(ledger);
+        }
+
+        let ledger = read_ledger_from_json(dir.path().join("ledger.json")).unwrap();
+        let leaf = ledger.leaves()[0];
+        assert_eq!(ledger.get_chain(leaf).len(), 3);
+        assert_eq!(ledger.get_chain(leaf)[1].event, 0x03a50000 | ledger.src_id_by_name("water").unwrap().id().unwrap() as u32);
+    }
+}
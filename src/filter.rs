@@ -6,25 +6,25 @@ use std::fmt;
 /// # Examples
 ///
 /// 1. We could filter for all Scattering Events coming from a specific material with MatId as such
-/// ` filter_seq!(MCRT|Material|{Inelastic, Elastic}|*|*|MatId)`
+///    ` filter_seq!(MCRT|Material|{Inelastic, Elastic}|*|*|MatId)`
 ///
 /// 2. Filter for all interactions with objects that have SurfId(x) or MatId(x) described by
 ///    MatSurfId(x)
-/// `filter_seq!(MCRT|*|*|MatSurfId)`
+///    `filter_seq!(MCRT|*|*|MatSurfId)`
 ///
 /// 3. Filter for events that have N number of interactions described by
-/// `
-/// use aetherus_events::filter_seq;
-/// filter_seq!([MCRT|Interface|Refraction|SurfId, MCRT|Material|{Inelastic, Elastic}|*|*|MatId, ... ]);
-/// `
+///    `
+///    use aetherus_events::filter_seq;
+///    filter_seq!([MCRT|Interface|Refraction|SurfId, MCRT|Material|{Inelastic, Elastic}|*|*|MatId, ... ]);
+///    `
 ///
 /// 4. Filter for permutations of events
-/// `
-/// filter_seq!(perm![ MCRT|Interface|*|SurfId,
-///                    MCRT|Material|{Elastic, Inelastic}|*|*|MatId,
-///                    ... ])
-/// `
-
+///    `
+///    filter_perm![ MCRT|Interface|*|SurfId,
+///                  MCRT|Material|{Elastic, Inelastic}|*|*|MatId,
+///                  ... ]
+///    `
+///
 /// Macro to create a filter specification using pipe-delimited syntax
 /// Single event filter:
 /// ```ignore
@@ -41,101 +41,1019 @@ use std::fmt;
 /// filter_perm![MCRT|Interface|*|SurfId, MCRT|Material|{Inelastic, Elastic}|*|*|MatId]
 /// ```
 use crate::ledger::{Ledger, Uid};
+use crate::raw::{self, RawField};
+use crate::{RawEvent, SrcId, SrcKind, detection, emission, processing, transport, voxel};
 
-#[derive(Clone, Copy)]
+pub mod presets;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct BitsMatch {
     pub mask: u32,
     pub value: u32,
+    // Bits of the extension word of wide events, see `RawEvent::ext_word`. u32 events have none,
+    // such that they only match an unset `ext_mask`.
+    pub ext_mask: u32,
+    pub ext_value: u32,
 }
 impl BitsMatch {
     pub fn new(mask: u32, value: u32) -> Self {
-        BitsMatch { mask, value }
+        BitsMatch { mask, value, ext_mask: 0, ext_value: 0 }
     }
 }
 impl fmt::Debug for BitsMatch {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "BitsMatch {{ mask: 0x{:08X}, value: 0x{:08X} }}", self.mask, self.value)
+        write!(f, "BitsMatch {{ mask: 0x{:08X}, value: 0x{:08X}", self.mask, self.value)?;
+        if self.ext_mask != 0 {
+            write!(f, ", ext_mask: 0x{:08X}, ext_value: 0x{:08X}", self.ext_mask, self.ext_value)?;
+        }
+        write!(f, " }}")
     }
 }
 
-struct SeqQueueEntry {
-    pub uid: Uid,
-    pub bits_match_seq: VecDeque<BitsMatch>,
+// ----------------------------------------------------
+// Quantified filter steps
+// ----------------------------------------------------
+// Stages of a sequence match in order along the chain, not necessarily adjacent. Once a step
+// reached its `min`, the next event matching one of the following steps ends it (an event
+// matching both can go either way), such that the events matching the pattern of a step between
+// the previous and the next step are all counted against `min..=max`, i.e. exactly 3 Mie
+// scatterings between the refraction and the detection. The last step runs until the end of the
+// chain. A plain BitsMatch is a step matching at least once, which is how sequences always
+// matched. A step can hold alternatives which don't fit a single mask, i.e. elastic or inelastic
+// scattering, an event matching any of them.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilterStep {
+    pub alternatives: Vec<BitsMatch>,
+    pub min: u32,
+    // Unbounded when None
+    pub max: Option<u32>,
 }
 
-pub fn find_forward_uid_seq(ledger: &Ledger, bits_match_seq: Vec<BitsMatch>) -> Vec<Uid> {
-    let mut seq_queue: VecDeque<SeqQueueEntry> = VecDeque::new();
+impl FilterStep {
+    pub fn new(pattern: BitsMatch) -> Self {
+        FilterStep::any_of(vec![pattern])
+    }
+
+    pub fn any_of(alternatives: Vec<BitsMatch>) -> Self {
+        FilterStep { alternatives, min: 1, max: None }
+    }
+
+    pub fn repeat<S: Into<FilterStep>>(step: S, min: u32, max: Option<u32>) -> Self {
+        FilterStep { min, max, ..step.into() }
+    }
+
+    pub fn matches(&self, event: u32) -> bool {
+        self.matches_event(&event)
+    }
+
+    // Match of an event of any width, see `RawEvent::matches`
+    pub fn matches_event<E: RawEvent>(&self, event: &E) -> bool {
+        self.alternatives.iter().any(|pattern| event.matches(pattern))
+    }
+}
+
+impl From<BitsMatch> for FilterStep {
+    fn from(pattern: BitsMatch) -> Self {
+        FilterStep::new(pattern)
+    }
+}
+
+// States of a chain being matched: the active step and the count of its pattern so far
+type StepState = (usize, u32);
+
+fn seq_step(states: &[StepState], event: u32, steps: &[FilterStep]) -> Vec<StepState> {
+    let mut next_states: Vec<StepState> =
+        states.iter().flat_map(|state| seq_transitions(*state, event, steps)).map(|(state, _)| state).collect();
+    next_states.sort_unstable();
+    next_states.dedup();
+    next_states
+}
+
+// States following `state` on `event`, with the step counting the event if any
+fn seq_transitions((idx, matched): StepState, event: u32, steps: &[FilterStep]) -> Vec<(StepState, Option<usize>)> {
+    // Count of the step once `event` matched it, None if it exceeds the step's max
+    let count = |idx: usize, count: u32| {
+        let step = &steps[idx];
+        match step.max {
+            Some(max) => (count < max).then_some(count + 1),
+            // Only reaching min matters, saturate such that the states stay few
+            None => Some((count + 1).min(step.min.max(1))),
+        }
+    };
+    if idx == steps.len() {
+        return vec![((idx, matched), None)];
+    }
+    let mut transitions = Vec::new();
+    let mut ends_step = false;
+    if matched >= steps[idx].min {
+        // The following steps the event can match, skipping the optional ones in between
+        let optional = steps[idx + 1..].iter().take_while(|step| step.min == 0).count();
+        for (next_idx, step) in steps.iter().enumerate().skip(idx + 1).take(optional + 1) {
+            if step.matches(event) {
+                ends_step = true;
+                if let Some(matched) = count(next_idx, 0) {
+                    transitions.push(((next_idx, matched), Some(next_idx)));
+                }
+            }
+        }
+    }
+    if steps[idx].matches(event) {
+        if let Some(matched) = count(idx, matched) {
+            transitions.push(((idx, matched), Some(idx)));
+        }
+    } else if !ends_step {
+        transitions.push(((idx, matched), None));
+    }
+    transitions
+}
+
+fn seq_complete(states: &[StepState], steps: &[FilterStep]) -> bool {
+    states.iter().any(|&(idx, matched)| {
+        idx == steps.len() || (matched >= steps[idx].min && steps[idx + 1..].iter().all(|step| step.min == 0))
+    })
+}
+
+// Leaves of the chains matching the steps in order, the start event excluded
+pub fn find_forward_uid_seq<S: Into<FilterStep>>(ledger: &Ledger, bits_match_seq: Vec<S>) -> Vec<Uid> {
+    let steps = with_aliases(ledger, bits_match_seq.into_iter().map(Into::into).collect());
+    let mut found_uids: Vec<Uid> = Vec::new();
+    let mut queue: VecDeque<(Uid, Vec<StepState>)> =
+        ledger.get_start_events().iter().map(|uid| (*uid, vec![(0, 0)])).collect();
+    while let Some(entry) = queue.pop_front() {
+        seq_visit(ledger, entry, &steps, &mut queue, &mut found_uids);
+    }
+    found_uids
+}
+
+// Queue the subsequent events of an entry, or keep it if it's a leaf completing the sequence
+fn seq_visit(
+    ledger: &Ledger,
+    (uid, states): (Uid, Vec<StepState>),
+    steps: &[FilterStep],
+    queue: &mut VecDeque<(Uid, Vec<StepState>)>,
+    found_uids: &mut Vec<Uid>,
+) {
+    let next_uids = ledger.get_next(&uid);
+    if next_uids.is_empty() {
+        // If last UID in sequence of events, output as valid UID
+        if seq_complete(&states, steps) {
+            found_uids.push(uid);
+        }
+        return;
+    }
+    for next_uid in next_uids {
+        queue.push_back((next_uid, seq_step(&states, next_uid.event, steps)));
+    }
+}
+
+// Same result as `find_forward_uid_seq` in another order: the tree is walked breadth first until
+// the frontier holds a few entries per worker, then each worker walks the subtrees of its share of
+// the frontier. As identical histories are merged, a handful of start events usually hold the whole
+// ledger, hence the frontier rather than the start events is partitioned.
+#[cfg(feature = "parallel")]
+pub fn par_find_forward_uid_seq<S: Into<FilterStep>>(ledger: &Ledger, bits_match_seq: Vec<S>) -> Vec<Uid> {
+    use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+    let steps = with_aliases(ledger, bits_match_seq.into_iter().map(Into::into).collect());
     let mut found_uids: Vec<Uid> = Vec::new();
-    // Initialize the queue with all events that have seq_no=0
-    for uid in ledger.get_start_events() {
-        seq_queue.push_back(SeqQueueEntry {
-            uid: *uid,
-            bits_match_seq: bits_match_seq.clone().into(),
-        });
-    }
-    while !seq_queue.is_empty() {
-        let uid_seq = seq_queue.pop_front().unwrap();
-        if ledger.get_next(&uid_seq.uid).is_empty() {
-            // If last UID in sequence of events, output as valid UID
-            if uid_seq.bits_match_seq.is_empty() {
-                found_uids.push(uid_seq.uid);
+    let mut frontier: VecDeque<(Uid, Vec<StepState>)> =
+        ledger.get_start_events().iter().map(|uid| (*uid, vec![(0, 0)])).collect();
+    let min_frontier = 4 * rayon::current_num_threads();
+    while frontier.len() < min_frontier
+        && let Some(entry) = frontier.pop_front()
+    {
+        seq_visit(ledger, entry, &steps, &mut frontier, &mut found_uids);
+    }
+    let subtree_uids: Vec<Uid> = frontier
+        .into_par_iter()
+        .flat_map_iter(|entry| {
+            let (mut queue, mut found_uids) = (VecDeque::from([entry]), Vec::new());
+            while let Some(entry) = queue.pop_front() {
+                seq_visit(ledger, entry, &steps, &mut queue, &mut found_uids);
             }
-        } else {
-            let next_uids = ledger.get_next(&uid_seq.uid);
-            assert!(next_uids.len() > 0, "No more subsequent events for UID: {}", uid_seq.uid);
-            for next_uid in next_uids {
-                if uid_seq.bits_match_seq.is_empty() {
-                    seq_queue.push_back(SeqQueueEntry {
-                        uid: next_uid,
-                        bits_match_seq: uid_seq.bits_match_seq.clone()
-                    });
+            found_uids
+        })
+        .collect();
+    found_uids.extend(subtree_uids);
+    found_uids
+}
 
+// A matched chain and the events counted by each step, as (step index, UID) in chain order
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilterMatch {
+    pub terminal: Uid,
+    pub chain: Vec<Uid>,
+    pub captures: Vec<(usize, Uid)>,
+}
+
+impl FilterMatch {
+    // Events counted by the step `idx`
+    pub fn step(&self, idx: usize) -> Vec<Uid> {
+        self.captures.iter().filter(|(step, _)| *step == idx).map(|(_, uid)| *uid).collect()
+    }
+}
+
+// Same search as `find_forward_uid_seq`, also returning the events matched by each step
+pub fn find_forward_uid_seq_matches<S: Into<FilterStep>>(ledger: &Ledger, bits_match_seq: Vec<S>) -> Vec<FilterMatch> {
+    let steps = with_aliases(ledger, bits_match_seq.into_iter().map(Into::into).collect());
+    find_forward_uid_seq(ledger, steps.clone())
+        .into_iter()
+        .map(|terminal| {
+            let chain = ledger.get_chain(terminal);
+            let captures = chain_captures(&chain, &steps).expect("Leaves found by the filter match their chain");
+            FilterMatch { terminal, chain, captures }
+        })
+        .collect()
+}
+
+// Events of `chain` counted by each step, None if the chain doesn't match. When the events can be
+// split over the steps in several ways, each step ends as early as possible.
+pub fn chain_captures(chain: &[Uid], steps: &[FilterStep]) -> Option<Vec<(usize, Uid)>> {
+    let mut states: Vec<(StepState, Vec<(usize, Uid)>)> = vec![((0, 0), Vec::new())];
+    for uid in chain.iter().skip(1) {
+        let mut next_states: Vec<(StepState, Vec<(usize, Uid)>)> = Vec::new();
+        for (state, captures) in &states {
+            for (next_state, step) in seq_transitions(*state, uid.event, steps) {
+                // Keep the first way of reaching each state
+                if next_states.iter().any(|(other, _)| *other == next_state) {
+                    continue;
+                }
+                let mut captures = captures.clone();
+                captures.extend(step.map(|step| (step, *uid)));
+                next_states.push((next_state, captures));
+            }
+        }
+        states = next_states;
+    }
+    states.into_iter().find(|(state, _)| seq_complete(&[*state], steps)).map(|(_, captures)| captures)
+}
+
+// Whether `chain` matches the quantified steps, see `find_forward_uid_seq`
+pub fn chain_matches_steps(chain: &[Uid], steps: &[FilterStep]) -> bool {
+    let states = chain.iter().skip(1).fold(vec![(0, 0)], |states, uid| seq_step(&states, uid.event, steps));
+    seq_complete(&states, steps)
+}
+
+// ----------------------------------------------------
+// Merged sources
+// ----------------------------------------------------
+// `filter_seq!` builds its patterns without the ledger, hence a pattern on a source only matches
+// the id it was given. The searches over a ledger extend such patterns to the sources merged with
+// it, see `Ledger::alias_src`, as `parse_with_ledger` does when resolving the sources.
+
+fn with_aliases(ledger: &Ledger, steps: Vec<FilterStep>) -> Vec<FilterStep> {
+    steps
+        .into_iter()
+        .map(|step| {
+            let alternatives = step.alternatives.iter().flat_map(|pattern| pattern_aliases(ledger, pattern)).collect();
+            FilterStep { alternatives: merge_alternatives(alternatives), ..step }
+        })
+        .collect()
+}
+
+// `pattern` followed by its copies on the sources merged with its source, if any. The kind of
+// the source isn't stored in the events, hence any kind the pipeline of the pattern allows.
+fn pattern_aliases(ledger: &Ledger, pattern: &BitsMatch) -> Vec<BitsMatch> {
+    let src_mask = pattern.mask & SrcId::mask();
+    if src_mask == 0 {
+        return vec![*pattern];
+    }
+    let pipeline = match pattern.mask & raw::Pipeline::mask() {
+        0 => None,
+        _ => raw::Pipeline::try_from(((pattern.value & raw::Pipeline::mask()) >> raw::Pipeline::shift()) as u8).ok(),
+    };
+    let id = (pattern.value & src_mask) as u16;
+    let mut patterns = vec![*pattern];
+    for src_id in [SrcId::Mat(id), SrcId::Surf(id), SrcId::MatSurf(id), SrcId::Light(id), SrcId::Detector(id)] {
+        if pipeline.is_some_and(|pipeline| !src_kind_allowed(pipeline, src_id.kind())) {
+            continue;
+        }
+        // Pixelated detection patterns only hold the low byte of the id
+        for merged_id in ledger.merged_srcs(&src_id).iter().filter_map(SrcId::id).filter(|id| *id as u32 & !src_mask == 0) {
+            patterns.push(BitsMatch::new(pattern.mask, (pattern.value & !src_mask) | (merged_id as u32 & src_mask)));
+        }
+    }
+    patterns
+}
+
+// ----------------------------------------------------
+// Boolean filter expressions
+// ----------------------------------------------------
+// Combine sequences with Not/And/Or, i.e. the chains refracting into a material but never Raman
+// scattering:
+//     FilterExpr::seq(vec![refraction]).and(!FilterExpr::seq(vec![raman]))
+// Each `Match` follows the semantics of `find_forward_uid_seq`. The ledger is walked once, carrying
+// the states of every sequence of the expression, and the expression is evaluated at the leaves.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FilterExpr {
+    Match(Vec<FilterStep>),
+    Not(Box<FilterExpr>),
+    And(Vec<FilterExpr>),
+    Or(Vec<FilterExpr>),
+}
+
+impl FilterExpr {
+    pub fn seq<S: Into<FilterStep>>(seq: Vec<S>) -> Self {
+        FilterExpr::Match(seq.into_iter().map(Into::into).collect())
+    }
+
+    pub fn and(self, other: FilterExpr) -> Self {
+        match self {
+            FilterExpr::And(mut exprs) => {
+                exprs.push(other);
+                FilterExpr::And(exprs)
+            }
+            expr => FilterExpr::And(vec![expr, other]),
+        }
+    }
+
+    pub fn or(self, other: FilterExpr) -> Self {
+        match self {
+            FilterExpr::Or(mut exprs) => {
+                exprs.push(other);
+                FilterExpr::Or(exprs)
+            }
+            expr => FilterExpr::Or(vec![expr, other]),
+        }
+    }
+
+    pub fn matches(&self, chain: &[Uid]) -> bool {
+        let mut results = self.sequences().into_iter().map(|steps| chain_matches_steps(chain, steps));
+        self.evaluate(&mut results)
+    }
+
+    // Sequences of the expression, depth first
+    fn sequences(&self) -> Vec<&[FilterStep]> {
+        match self {
+            FilterExpr::Match(steps) => vec![steps.as_slice()],
+            FilterExpr::Not(expr) => expr.sequences(),
+            FilterExpr::And(exprs) | FilterExpr::Or(exprs) => exprs.iter().flat_map(|expr| expr.sequences()).collect(),
+        }
+    }
+
+    // Same expression with the patterns extended to the merged sources, see `with_aliases`
+    fn with_aliases(&self, ledger: &Ledger) -> FilterExpr {
+        match self {
+            FilterExpr::Match(steps) => FilterExpr::Match(with_aliases(ledger, steps.clone())),
+            FilterExpr::Not(expr) => FilterExpr::Not(Box::new(expr.with_aliases(ledger))),
+            FilterExpr::And(exprs) => FilterExpr::And(exprs.iter().map(|expr| expr.with_aliases(ledger)).collect()),
+            FilterExpr::Or(exprs) => FilterExpr::Or(exprs.iter().map(|expr| expr.with_aliases(ledger)).collect()),
+        }
+    }
+
+    // Evaluate given the results of the sequences in the order of `sequences`
+    fn evaluate(&self, results: &mut impl Iterator<Item = bool>) -> bool {
+        match self {
+            FilterExpr::Match(_) => results.next().unwrap(),
+            FilterExpr::Not(expr) => !expr.evaluate(results),
+            // Every sub-expression consumes its results, no short-circuit
+            FilterExpr::And(exprs) => exprs.iter().map(|expr| expr.evaluate(results)).collect::<Vec<_>>().into_iter().all(|matched| matched),
+            FilterExpr::Or(exprs) => exprs.iter().map(|expr| expr.evaluate(results)).collect::<Vec<_>>().into_iter().any(|matched| matched),
+        }
+    }
+}
+
+impl std::ops::Not for FilterExpr {
+    type Output = FilterExpr;
+
+    fn not(self) -> Self::Output {
+        match self {
+            FilterExpr::Not(expr) => *expr,
+            expr => FilterExpr::Not(Box::new(expr)),
+        }
+    }
+}
+
+impl From<Vec<BitsMatch>> for FilterExpr {
+    fn from(seq: Vec<BitsMatch>) -> Self {
+        FilterExpr::seq(seq)
+    }
+}
+
+// Leaves of the chains matching the expression
+pub fn find_forward_uid_expr(ledger: &Ledger, expr: &FilterExpr) -> Vec<Uid> {
+    let expr = expr.with_aliases(ledger);
+    let sequences = expr.sequences();
+    let mut found_uids: Vec<Uid> = Vec::new();
+    let mut queue: VecDeque<(Uid, Vec<Vec<StepState>>)> =
+        ledger.get_start_events().iter().map(|uid| (*uid, vec![vec![(0, 0)]; sequences.len()])).collect();
+    while let Some((uid, states)) = queue.pop_front() {
+        let next_uids = ledger.get_next(&uid);
+        if next_uids.is_empty() {
+            let mut results = states.iter().zip(&sequences).map(|(states, steps)| seq_complete(states, steps));
+            if expr.evaluate(&mut results) {
+                found_uids.push(uid);
+            }
+            continue;
+        }
+        for next_uid in next_uids {
+            let next_states =
+                states.iter().zip(&sequences).map(|(states, steps)| seq_step(states, next_uid.event, steps)).collect();
+            queue.push_back((next_uid, next_states));
+        }
+    }
+    found_uids
+}
+
+// Whether the stages of `bits_match_seq` appear in order within `chain`, not necessarily adjacent
+pub fn chain_matches(chain: &[Uid], bits_match_seq: &[BitsMatch]) -> bool {
+    let mut stages = bits_match_seq.iter().peekable();
+    for uid in chain {
+        if let Some(bits_match) = stages.peek()
+            && (uid.event & bits_match.mask) == bits_match.value
+        {
+            stages.next();
+        }
+    }
+    stages.peek().is_none()
+}
+
+// ----------------------------------------------------
+// Permutation filters
+// ----------------------------------------------------
+// Chains containing every pattern of the set in any order, each pattern matched by a distinct
+// event, i.e. a refraction and a Mie scattering whichever comes first. An event matching several
+// patterns can stand for any of them, hence each chain tracks every combination of the patterns
+// matched so far as a bitset. A pattern is a `FilterStep` such that it can hold alternatives, its
+// repetition bounds being ignored.
+
+const MAX_PERM_PATTERNS: usize = 64;
+
+fn perm_step(states: &[u64], event: u32, patterns: &[FilterStep]) -> Vec<u64> {
+    let mut next_states = states.to_vec();
+    for state in states {
+        for (idx, pattern) in patterns.iter().enumerate() {
+            if state & (1 << idx) == 0 && pattern.matches(event) {
+                next_states.push(state | (1 << idx));
+            }
+        }
+    }
+    next_states.sort_unstable();
+    next_states.dedup();
+    next_states
+}
+
+fn perm_complete(states: &[u64], patterns: &[FilterStep]) -> bool {
+    let all = if patterns.len() == MAX_PERM_PATTERNS { u64::MAX } else { (1 << patterns.len()) - 1 };
+    states.contains(&all)
+}
+
+// Leaves of the chains matching every pattern in any order
+pub fn find_forward_uid_perm<S: Into<FilterStep>>(ledger: &Ledger, patterns: Vec<S>) -> Vec<Uid> {
+    let patterns = with_aliases(ledger, patterns.into_iter().map(Into::into).collect());
+    assert!(patterns.len() <= MAX_PERM_PATTERNS, "Permutation filters hold at most {} patterns", MAX_PERM_PATTERNS);
+    let mut found_uids: Vec<Uid> = Vec::new();
+    let mut queue: VecDeque<(Uid, Vec<u64>)> = ledger
+        .get_start_events()
+        .iter()
+        .map(|uid| (*uid, perm_step(&[0], uid.event, &patterns)))
+        .collect();
+    while let Some((uid, states)) = queue.pop_front() {
+        let next_uids = ledger.get_next(&uid);
+        if next_uids.is_empty() {
+            if perm_complete(&states, &patterns) {
+                found_uids.push(uid);
+            }
+            continue;
+        }
+        for next_uid in next_uids {
+            // Once complete, the remaining events can't undo the match
+            let next_states = if perm_complete(&states, &patterns) {
+                states.clone()
+            } else {
+                perm_step(&states, next_uid.event, &patterns)
+            };
+            queue.push_back((next_uid, next_states));
+        }
+    }
+    found_uids
+}
+
+// Whether `chain` holds every pattern in any order
+pub fn chain_matches_perm<S: Clone + Into<FilterStep>>(chain: &[Uid], patterns: &[S]) -> bool {
+    assert!(patterns.len() <= MAX_PERM_PATTERNS, "Permutation filters hold at most {} patterns", MAX_PERM_PATTERNS);
+    let patterns: Vec<FilterStep> = patterns.iter().cloned().map(Into::into).collect();
+    let states = chain.iter().fold(vec![0], |states, uid| perm_step(&states, uid.event, &patterns));
+    perm_complete(&states, &patterns)
+}
+
+// Patterns of `filter_perm!` in the pipe syntax of `parse`, separated by commas, i.e.
+// "MCRT|Interface|*|Surf(1), MCRT|Material|{Elastic, Inelastic}|*|*|Mat(2)"
+pub fn parse_perm(expr: &str) -> Result<Vec<FilterStep>, String> {
+    let mut patterns = Vec::new();
+    let (mut start, mut depth) = (0, 0);
+    for (idx, c) in expr.char_indices().chain([(expr.len(), ',')]) {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 || idx == expr.len() => {
+                let pattern = expr[start..idx].trim();
+                if !pattern.is_empty() {
+                    patterns.push(FilterStep::any_of(parse_stage_alternatives(pattern, &unresolved)?));
+                }
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    Ok(patterns)
+}
+
+// ----------------------------------------------------
+// Source kind validation
+// ----------------------------------------------------
+// Each pipeline only references one family of sources. `filter_seq!` rejects a literal
+// `SrcId::Kind(id)` of the wrong kind at compile time, sources held in variables are checked when
+// the filter is built: `try_filter_seq!` returns a `SrcKindMismatch`, `filter_seq!` panics.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SrcKindMismatch {
+    pub pipeline: raw::Pipeline,
+    pub src_id: SrcId,
+}
+
+impl fmt::Display for SrcKindMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} events cannot be filtered by the {} source {}", self.pipeline, self.src_id.kind(), self.src_id)
+    }
+}
+
+impl std::error::Error for SrcKindMismatch {}
+
+/// Whether events of `pipeline` can reference a source of `kind`, usable in const context.
+///
+/// ```compile_fail
+/// use aetherus_events::{SrcId, filter_seq};
+/// filter_seq!(MCRT, Interface, Refraction, SrcId::Light(0));
+/// ```
+pub const fn src_kind_allowed(pipeline: raw::Pipeline, kind: SrcKind) -> bool {
+    use raw::Pipeline;
+    matches!(
+        (pipeline, kind),
+        (_, SrcKind::None)
+            | (Pipeline::Emission, SrcKind::Light)
+            | (Pipeline::MCRT, SrcKind::Mat | SrcKind::Surf | SrcKind::MatSurf)
+            | (Pipeline::Detection, SrcKind::Detector)
+    )
+}
+
+pub fn check_src_kind(pipeline: raw::Pipeline, src_id: SrcId) -> Result<SrcId, SrcKindMismatch> {
+    if src_kind_allowed(pipeline, src_id.kind()) {
+        Ok(src_id)
+    } else {
+        Err(SrcKindMismatch { pipeline, src_id })
+    }
+}
+
+// Same arguments as `filter_seq!`, returning a `Result<BitsMatch, SrcKindMismatch>`
+#[macro_export]
+macro_rules! try_filter_seq {
+    ($pipeline:ident, $src_id:expr) => {
+        $crate::filter::check_src_kind($crate::raw::Pipeline::$pipeline, $src_id)
+            .map(|src_id| $crate::filter_seq!($pipeline, src_id))
+    };
+    ($pipeline:ident, $type:ident, $src_id:expr) => {
+        $crate::filter::check_src_kind($crate::raw::Pipeline::$pipeline, $src_id)
+            .map(|src_id| $crate::filter_seq!($pipeline, $type, src_id))
+    };
+    ($pipeline:ident, $supertype:ident, $subtype:ident, $src_id:expr) => {
+        $crate::filter::check_src_kind($crate::raw::Pipeline::$pipeline, $src_id)
+            .map(|src_id| $crate::filter_seq!($pipeline, $supertype, $subtype, src_id))
+    };
+    ($pipeline:ident, $supertype:ident, $subtype:ident, $scatter:ident, $dir:ident, $src_id:expr) => {
+        $crate::filter::check_src_kind($crate::raw::Pipeline::$pipeline, $src_id)
+            .map(|src_id| $crate::filter_seq!($pipeline, $supertype, $subtype, $scatter, $dir, src_id))
+    };
+}
+
+// ----------------------------------------------------
+// Runtime parser for the pipe-delimited filter syntax
+// ----------------------------------------------------
+// `Pipeline|SuperType|SubType|Scatter|Dir|SrcId` with `*` as a wildcard for any field, and the
+// stages of a sequence separated by `->`, i.e.
+// "MCRT|Interface|Refraction|Surf(0) -> MCRT|Material|Elastic|*|Forward|Mat(2) -> Detection"
+// Trailing type fields and the SrcId can be omitted. The SrcId takes either the numeric id,
+// `Mat(2)`, or when parsed against a ledger the registered name, `Mat(water)`.
+// A field can list alternatives in braces, i.e. "MCRT|Material|{Elastic, Inelastic}|*|*|Mat(3)",
+// parsed into the alternatives of the step. The ones fitting a single mask are merged, such as
+// "MCRT|Material|Elastic|*|{Side, Backward}" matching on the high bit of the direction.
+// The sequence is parsed into a `FilterExpr::Match`, searched with `find_forward_uid_expr` and
+// combined with the other expressions and presets.
+
+pub fn parse(expr: &str) -> Result<FilterExpr, String> {
+    parse_stages(expr, &unresolved).map(FilterExpr::Match)
+}
+
+pub fn parse_with_ledger(expr: &str, ledger: &Ledger) -> Result<FilterExpr, String> {
+    parse_stages(expr, &|field| resolve_in_ledger(field, ledger)).map(FilterExpr::Match)
+}
+
+// Sources a src field stands for, i.e. `Mat(2)`, or when parsed against a ledger `Mat(water)`
+type ResolveSrcs<'a> = dyn Fn(&str) -> Result<Vec<SrcId>, String> + 'a;
+
+fn unresolved(field: &str) -> Result<Vec<SrcId>, String> {
+    if let Ok(src_id) = field.parse::<SrcId>() {
+        return Ok(vec![src_id]);
+    }
+    let (_, name) = split_src_field(field)?;
+    Err(format!("Cannot resolve source name '{}' without a ledger", name))
+}
+
+// A source matches the sources merged with it, see `Ledger::alias_src`, and a nested group of
+// sources, `Grp(probe/fiber)`, matches any source of the group and of its subgroups, see
+// `Ledger::group_src_ids`. The sources become alternatives of the step, such that the chains are
+// still walked once whatever the number of sources.
+fn resolve_in_ledger(field: &str, ledger: &Ledger) -> Result<Vec<SrcId>, String> {
+    let src_ids = match field.parse::<SrcId>() {
+        Ok(src_id) => vec![src_id],
+        Err(_) => match split_src_field(field)? {
+            ("Grp", path) => {
+                let src_ids = ledger.group_src_ids(path);
+                if src_ids.is_empty() {
+                    return Err(format!("Unknown group: {}", path));
+                }
+                src_ids
+            }
+            (kind, name) => {
+                let src_id = ledger.src_id_by_name(name).ok_or_else(|| format!("Unknown source name: {}", name))?;
+                if src_id.kind().to_string() != kind {
+                    log::warn!("Source '{}' is registered as {}, not as {}", name, src_id, kind);
+                }
+                vec![src_id]
+            }
+        },
+    };
+    Ok(src_ids.iter().flat_map(|src_id| ledger.merged_srcs(src_id)).collect())
+}
+
+// Kind and name of a `Kind(name)` src field
+fn split_src_field(field: &str) -> Result<(&str, &str), String> {
+    field
+        .strip_suffix(')')
+        .and_then(|field| field.split_once('('))
+        .map(|(kind, name)| (kind.trim(), name.trim()))
+        .ok_or_else(|| format!("Invalid SrcId format: {}", field))
+}
+
+fn parse_stages(expr: &str, resolve: &ResolveSrcs) -> Result<Vec<FilterStep>, String> {
+    expr.split("->").map(|stage| Ok(FilterStep::any_of(parse_stage_alternatives(stage, resolve)?))).collect()
+}
+
+fn parse_stage_alternatives(stage: &str, resolve: &ResolveSrcs) -> Result<Vec<BitsMatch>, String> {
+    let mut parsed = Vec::new();
+    for stage in expand_braces(stage)? {
+        parsed.extend(parse_stage(&stage, resolve)?);
+    }
+    Ok(merge_alternatives(parsed))
+}
+
+// Expand the `{A, B}` alternatives of a stage into one stage per combination
+fn expand_braces(stage: &str) -> Result<Vec<String>, String> {
+    let Some(open) = stage.find('{') else {
+        if stage.contains('}') {
+            return Err(format!("Unbalanced '}}' in filter stage: '{}'", stage.trim()));
+        }
+        return Ok(vec![stage.to_string()]);
+    };
+    let close = stage[open..]
+        .find('}')
+        .map(|idx| open + idx)
+        .ok_or_else(|| format!("Unclosed '{{' in filter stage: '{}'", stage.trim()))?;
+    let options = &stage[open + 1..close];
+    if options.contains('{') {
+        return Err(format!("Nested alternatives in filter stage: '{}'", stage.trim()));
+    }
+    let tails = expand_braces(&stage[close + 1..])?;
+    let mut expanded = Vec::new();
+    for option in options.split(',').map(str::trim) {
+        if option.is_empty() {
+            return Err(format!("Empty alternative in filter stage: '{}'", stage.trim()));
+        }
+        for tail in &tails {
+            expanded.push(format!("{}{}{}", &stage[..open], option, tail));
+        }
+    }
+    Ok(expanded)
+}
+
+// Drop the duplicates and merge the pairs of alternatives differing by a single masked bit, i.e.
+// Side (0b10) and Backward (0b11) directions merge into a match on the high bit only
+fn merge_alternatives(mut alternatives: Vec<BitsMatch>) -> Vec<BitsMatch> {
+    let same = |lhs: &BitsMatch, rhs: &BitsMatch| lhs.mask == rhs.mask && lhs.value == rhs.value;
+    'merge: loop {
+        for i in 0..alternatives.len() {
+            for j in i + 1..alternatives.len() {
+                let (lhs, rhs) = (alternatives[i], alternatives[j]);
+                let diff = lhs.value ^ rhs.value;
+                if same(&lhs, &rhs) {
+                    alternatives.remove(j);
+                } else if lhs.mask == rhs.mask && diff.count_ones() == 1 {
+                    alternatives[i] = BitsMatch::new(lhs.mask & !diff, lhs.value & !diff);
+                    alternatives.remove(j);
                 } else {
-                    let bits_match = uid_seq.bits_match_seq.front().unwrap();
-                    let mut new_bits_match_seq = uid_seq.bits_match_seq.clone();
-                    if (next_uid.event & bits_match.mask) == bits_match.value {
-                        // Match found, proceed to next event in sequence
-                        new_bits_match_seq.pop_front();
+                    continue;
+                }
+                continue 'merge;
+            }
+        }
+        return alternatives;
+    }
+}
+
+// One BitsMatch per source the stage stands for
+fn parse_stage(stage: &str, resolve: &ResolveSrcs) -> Result<Vec<BitsMatch>, String> {
+    let mut fields: Vec<&str> = stage.split('|').map(str::trim).collect();
+    if fields.iter().any(|field| field.is_empty()) {
+        return Err(format!("Empty field in filter stage: '{}'", stage.trim()));
+    }
+    let pipeline_field = fields.remove(0);
+    let (mut mask, mut value) = field_by_name::<raw::Pipeline>(pipeline_field)?.unwrap_or_default();
+    let pipeline = raw::Pipeline::try_from(((value & mask) >> raw::Pipeline::shift()) as u8).ok();
+    let max_depth = match pipeline {
+        Some(raw::Pipeline::MCRT) => 4,
+        Some(raw::Pipeline::Emission) | Some(raw::Pipeline::Detection) | Some(raw::Pipeline::Processing) => 1,
+        Some(raw::Pipeline::Transport) | Some(raw::Pipeline::Voxel) => 1,
+        _ => 0,
+    };
+
+    // The SrcId is the last field, either in the `Kind(id)` form or as a wildcard past the type fields
+    let is_src_field = |field: &&str| field.contains('(') || *field == "None";
+    let src_field = match fields.last() {
+        Some(field) if is_src_field(field) => fields.pop(),
+        Some(&"*") if fields.len() == max_depth + 1 => fields.pop(),
+        _ => None,
+    };
+    if fields.iter().skip(max_depth).any(|field| *field != "*") {
+        return Err(format!("Too many fields for {} filter stage: '{}'", pipeline_field, stage.trim()));
+    }
+
+    let (type_mask, type_value) = match pipeline {
+        Some(raw::Pipeline::MCRT) => parse_mcrt_fields(&fields)?,
+        Some(raw::Pipeline::Emission) => {
+            field_by_name::<emission::Emission>(fields.first().unwrap_or(&"*"))?.unwrap_or((0, 0))
+        }
+        Some(raw::Pipeline::Detection) => {
+            field_by_name::<detection::Detection>(fields.first().unwrap_or(&"*"))?.unwrap_or((0, 0))
+        }
+        Some(raw::Pipeline::Processing) => {
+            field_by_name::<processing::Processing>(fields.first().unwrap_or(&"*"))?.unwrap_or((0, 0))
+        }
+        Some(raw::Pipeline::Transport) => {
+            field_by_name::<transport::Transport>(fields.first().unwrap_or(&"*"))?.unwrap_or((0, 0))
+        }
+        // Exact voxel index, see `voxel::range_bits_matches` for ranges
+        Some(raw::Pipeline::Voxel) => match fields.first() {
+            None | Some(&"*") => (0, 0),
+            Some(index) => {
+                let index = index.parse::<u32>().ok().filter(|index| *index <= voxel::Voxel::MAX_INDEX)
+                    .ok_or_else(|| format!("Invalid voxel index: {}", index))?;
+                (voxel::Voxel::MASK, index)
+            }
+        },
+        _ => (0, 0),
+    };
+    mask |= type_mask;
+    value |= type_value;
+
+    let src_ids = match src_field {
+        None | Some("*") => vec![SrcId::None],
+        Some(field) => resolve(field)?,
+    };
+    Ok(src_ids
+        .iter()
+        .flat_map(|src_id| match (pipeline, src_id.id()) {
+            // Pixelated hits of the detector as well, see `detection::detector_bits_matches`
+            (Some(raw::Pipeline::Detection), Some(id)) => detection::detector_bits_matches(id)
+                .into_iter()
+                .map(|bits_match| BitsMatch::new(mask | bits_match.mask, value | bits_match.value))
+                .collect(),
+            (_, Some(id)) => vec![BitsMatch::new(mask | SrcId::mask(), value | id as u32)],
+            (_, None) => vec![BitsMatch::new(mask, value)],
+        })
+        .collect())
+}
+
+fn parse_mcrt_fields(fields: &[&str]) -> Result<(u32, u32), String> {
+    let field = |idx: usize| fields.get(idx).cloned().unwrap_or("*");
+    let mut bits = (0, 0);
+    let mut add = |field_bits: Option<(u32, u32)>| {
+        if let Some((mask, value)) = field_bits {
+            bits.0 |= mask;
+            bits.1 |= value;
+        }
+    };
+    let supertype = field(0);
+    add(field_by_name::<raw::MCRT>(supertype)?);
+    let max_depth = match supertype {
+        "Interface" => {
+            add(field_by_name::<raw::Interface>(field(1))?);
+            2
+        }
+        "Reflector" => {
+            add(field_by_name::<raw::Reflector>(field(1))?);
+            2
+        }
+        "Material" => {
+            let material = field(1);
+            add(field_by_name::<raw::Material>(material)?);
+            match material {
+                "Elastic" => add(field_by_name::<raw::Elastic>(field(2))?),
+                "Inelastic" => add(field_by_name::<raw::Inelastic>(field(2))?),
+                // Roulette outcome followed by the boost class of survivors instead of a direction
+                "Roulette" => {
+                    add(field_by_name::<raw::Roulette>(field(2))?);
+                    if field(3) != "*" {
+                        let boost_class = field(3).parse::<u8>().ok().filter(|class| *class <= raw::MAX_BOOST_CLASS)
+                            .ok_or_else(|| format!("Invalid roulette boost class: {}", field(3)))?;
+                        add(Some((raw::Roulette::mask() | raw::BOOST_CLASS_MASK, raw::Roulette::Survived.encode() | ((boost_class as u32) << raw::BOOST_CLASS_SHIFT))));
                     }
-                    seq_queue.push_back(SeqQueueEntry {
-                        uid: next_uid,
-                        bits_match_seq: new_bits_match_seq
-                    });
+                    return match fields.iter().skip(4).find(|field| **field != "*") {
+                        Some(field) => Err(format!("Cannot match {} under MCRT {} events", field, supertype)),
+                        None => Ok(bits),
+                    };
                 }
+                _ if field(2) != "*" => {
+                    return Err(format!("Cannot match scatter type {} of {} material event", field(2), material));
+                }
+                _ => {}
+            }
+            // The scatter direction bits are shared by all scattering events
+            add(field_by_name::<raw::ScatterDir>(field(3))?);
+            4
+        }
+        // Code of the custom interaction
+        "Custom" => {
+            if field(1) != "*" {
+                let code = field(1).parse::<u16>().ok().filter(|code| *code <= raw::MAX_MCRT_CUSTOM)
+                    .ok_or_else(|| format!("Invalid custom MCRT code: {}", field(1)))?;
+                add(Some((raw::MCRT_CUSTOM_MASK, (code as u32) << raw::MCRT_CUSTOM_SHIFT)));
             }
+            2
         }
+        _ => 1,
+    };
+    match fields.iter().skip(max_depth).find(|field| **field != "*") {
+        Some(field) => Err(format!("Cannot match {} under MCRT {} events", field, supertype)),
+        None => Ok(bits),
     }
+}
 
-    found_uids
+// Find the enum variant with the Debug name `name`, returning its (mask, value) bits or None
+// for the `*` wildcard
+fn field_by_name<T>(name: &str) -> Result<Option<(u32, u32)>, String>
+where
+    T: RawField + TryFrom<u8> + Into<u8> + fmt::Debug,
+{
+    if name == "*" {
+        return Ok(None);
+    }
+    for code in 0..(1u16 << T::bitsize()) {
+        if let Ok(field) = T::try_from(code as u8)
+            && format!("{:?}", field) == name
+        {
+            return Ok(Some((T::mask(), field.encode())));
+        }
+    }
+    let type_name = std::any::type_name::<T>().rsplit("::").next().unwrap_or_default();
+    Err(format!("Unknown {} field: {}", type_name, name))
 }
 
 #[macro_export]
 macro_rules! filter_seq {
+    // Repetitions of any other filter as a `FilterStep`: exactly `{3}`, at least `{3,}`, or `{3, 10}`
+    // i.e. `filter_seq!({3, 10}, MCRT, Material, Elastic, Mie, Any, SrcId::None)`
+    ({ $min:expr }, $($rest:tt)+) => {
+        $crate::filter::FilterStep::repeat($crate::filter_seq!($($rest)+), $min, Some($min))
+    };
+    ({ $min:expr, }, $($rest:tt)+) => {
+        $crate::filter::FilterStep::repeat($crate::filter_seq!($($rest)+), $min, None)
+    };
+    ({ $min:expr, $max:expr }, $($rest:tt)+) => {
+        $crate::filter::FilterStep::repeat($crate::filter_seq!($($rest)+), $min, Some($max))
+    };
+
+    // Wavelength channel of emission and inelastic events, prepended to any other filter
+    // i.e. `filter_seq!(Channel(red), Emission, SrcId::Light(0))`
+    (Channel($channel:expr), $($rest:tt)+) => {
+        $crate::wavelength::channel_bits_match($channel, $crate::filter_seq!($($rest)+))
+    };
+
+    // 0. Custom pipelines declared with `define_pipeline!`
+    // i.e. `filter_seq!(Custom(Voxel), SrcId::None)` or
+    //      `filter_seq!(Custom(Voxel), Crossing, Enter, SrcId::MatSurf(3))`
+    (Custom($pipeline:ty), $src_id:expr) => {
+        $crate::filter_custom_seq!($pipeline, $src_id)
+    };
+    (Custom($pipeline:ty), $supertype:ident, $src_id:expr) => {
+        $crate::filter_custom_seq!($pipeline, $supertype, $src_id)
+    };
+    (Custom($pipeline:ty), $supertype:ident, $subtype:ident, $src_id:expr) => {
+        $crate::filter_custom_seq!($pipeline, $supertype, $subtype, $src_id)
+    };
+
+    // Roulette outcome, any boost class: `filter_seq!(MCRT, Material, Roulette, Survived, SrcId::Mat(1))`,
+    // see `mcrt::boost_class_bits_match` for a single class
+    (MCRT, Material, Roulette, $outcome:ident, $src_id:expr) => {{
+        use $crate::raw::{Pipeline, RawField};
+        let (mut mask, mut value) = $crate::filter_mcrt_seq!(Material, Roulette, $src_id);
+        mask  |= Pipeline::mask() | $crate::raw::Roulette::mask();
+        value |= Pipeline::MCRT.encode() | $crate::raw::Roulette::$outcome.encode();
+        $crate::filter::BitsMatch::new(mask, value)
+    }};
+
+    // Custom MCRT interactions by code, `_` matching any of them: `filter_seq!(MCRT, Custom, 5, SrcId::Mat(1))`
+    (MCRT, Custom, $code:tt, $src_id:expr) => {{
+        use $crate::raw::{Pipeline, RawField};
+        let (mask, value) = $crate::filter_mcrt_seq!(Custom, $code, $src_id);
+        $crate::filter::BitsMatch::new(mask | Pipeline::mask(), value | Pipeline::MCRT.encode())
+    }};
+
+    // Literal sources are checked against the pipeline at compile time, then forwarded to the arms
+    // below in parentheses such that they don't match here again
+    ($pipeline:ident, SrcId::$kind:ident($id:expr)) => {{
+        $crate::check_src_literal!($pipeline, $kind);
+        $crate::filter_seq!($pipeline, (SrcId::$kind($id)))
+    }};
+    ($pipeline:ident, $type:ident, SrcId::$kind:ident($id:expr)) => {{
+        $crate::check_src_literal!($pipeline, $kind);
+        $crate::filter_seq!($pipeline, $type, (SrcId::$kind($id)))
+    }};
+    ($pipeline:ident, $supertype:ident, $subtype:ident, SrcId::$kind:ident($id:expr)) => {{
+        $crate::check_src_literal!($pipeline, $kind);
+        $crate::filter_seq!($pipeline, $supertype, $subtype, (SrcId::$kind($id)))
+    }};
+    ($pipeline:ident, $supertype:ident, $subtype:ident, $scatter:ident, $dir:ident, SrcId::$kind:ident($id:expr)) => {{
+        $crate::check_src_literal!($pipeline, $kind);
+        $crate::filter_seq!($pipeline, $supertype, $subtype, $scatter, $dir, (SrcId::$kind($id)))
+    }};
+
+    // 0. Detection events: `filter_seq!(Detection, SrcId::Detector(1))` or
+    //    `filter_seq!(Detection, Direct, SrcId::None)`
+    //    Both give a `FilterStep`, matching the pixelated hits of the detector as an alternative
+    (Detection, $src_id:expr) => {
+        $crate::detection::detection_filter(None, $src_id)
+    };
+    (Detection, $event_type:ident, $src_id:expr) => {
+        $crate::detection::detection_filter(Some($crate::detection::Detection::$event_type), $src_id)
+    };
+    // Processing events have no source: `filter_seq!(Processing, Digitization, SrcId::None)`
+    (Processing, $src_id:expr) => {{
+        use $crate::raw::{Pipeline, RawField};
+        assert!($src_id.is_none(), "Processing events do not have associated SrcId");
+        $crate::filter::BitsMatch::new(Pipeline::mask(), Pipeline::Processing.encode())
+    }};
+    (Processing, $event_type:ident, $src_id:expr) => {{
+        use $crate::raw::RawField;
+        let mut bits_match = $crate::filter_seq!(Processing, $src_id);
+        bits_match.mask  |= $crate::processing::Processing::mask();
+        bits_match.value |= $crate::processing::Processing::$event_type.encode();
+        bits_match
+    }};
+    // Voxel tags match any voxel or a single index: `filter_seq!(Voxel)` or `filter_seq!(Voxel, 42)`
+    (Voxel) => {{
+        use $crate::raw::{Pipeline, RawField};
+        $crate::filter::BitsMatch::new(Pipeline::mask(), Pipeline::Voxel.encode())
+    }};
+    (Voxel, $index:expr) => {{
+        let mut bits_match = $crate::filter_seq!(Voxel);
+        bits_match.mask  |= $crate::voxel::Voxel::MASK;
+        bits_match.value |= $crate::voxel::Voxel::new($index).index();
+        bits_match
+    }};
+    // Transport events have no source either: `filter_seq!(Transport, Split, SrcId::None)`
+    (Transport, $src_id:expr) => {{
+        use $crate::raw::{Pipeline, RawField};
+        assert!($src_id.is_none(), "Transport events do not have associated SrcId");
+        $crate::filter::BitsMatch::new(Pipeline::mask(), Pipeline::Transport.encode())
+    }};
+    (Transport, $event_type:ident, $src_id:expr) => {{
+        use $crate::raw::RawField;
+        let mut bits_match = $crate::filter_seq!(Transport, $src_id);
+        bits_match.mask  |= $crate::transport::Transport::mask();
+        bits_match.value |= $crate::transport::Transport::$event_type.encode();
+        bits_match
+    }};
+
     // Single event filter
     // 1. Generic EventType: filter_seq!(Pipeline | EventType | SrcId)
     // i.e. `filter_seq!(MCRT | _ | MatSurfId(u16))` or `filter_seq!(Emission | Laser | LightId(u16))
     ($pipeline:ident, $src_id:expr) => {{
         use $crate::raw::{Pipeline, RawField};
-        use $crate::{filter_mcrt_seq, filter_emit_seq, filter_detect_seq};
         use $crate::filter::BitsMatch;
         // TODO: Check if ident is MCRT, then SrcId matches Surf, Mat or MatSurf Ids
 
         match Pipeline::$pipeline {
             Pipeline::Emission => {
-                let (mut mask, mut value) = filter_emit_seq!($src_id);
-                mask = mask   | Pipeline::mask();
-                value = value | Pipeline::Emission.encode();
+                let (mut mask, mut value) = $crate::filter_emit_seq!($src_id);
+                mask |= Pipeline::mask();
+                value |= Pipeline::Emission.encode();
                 BitsMatch::new(mask, value)
             },
             Pipeline::MCRT => {
                 panic!("MCRT event filtering requires SuperType and SubType specification")
             },
-            Pipeline::Detection => {
-                let (mut mask, mut value) = filter_detect_seq!($src_id);
-                mask = mask   | Pipeline::mask();
-                value = value | Pipeline::Detection.encode();
-                BitsMatch::new(mask, value)
-            },
             _ => {
                 panic!("Unsupported pipeline type {} in filter_seq! macro", stringify!($pipeline));
             }
@@ -143,27 +1061,20 @@ macro_rules! filter_seq {
     }};
     ($pipeline:ident, $type:ident, $src_id:expr) => {{
         use $crate::raw::{Pipeline, RawField};
-        use $crate::{filter_mcrt_seq, filter_emit_seq, filter_detect_seq};
         use $crate::filter::BitsMatch;
         // TODO: Check if ident is MCRT, then SrcId matches Surf, Mat or MatSurf Ids
 
         match Pipeline::$pipeline {
             Pipeline::Emission => {
-                let (mut mask, mut value) = filter_emit_seq!($type, $src_id);
-                mask = mask   | Pipeline::mask();
-                value = value | Pipeline::Emission.encode();
+                let (mut mask, mut value) = $crate::filter_emit_seq!($type, $src_id);
+                mask |= Pipeline::mask();
+                value |= Pipeline::Emission.encode();
                 BitsMatch::new(mask, value)
             },
             Pipeline::MCRT => {
-                let (mut mask, mut value) = filter_mcrt_seq!($type, $src_id);
-                mask = mask   | Pipeline::mask();
-                value = value | Pipeline::MCRT.encode();
-                BitsMatch::new(mask, value)
-            },
-            Pipeline::Detection => {
-                let (mut mask, mut value) = filter_detect_seq!($type, $src_id);
-                mask = mask   | Pipeline::mask();
-                value = value | Pipeline::Detection.encode();
+                let (mut mask, mut value) = $crate::filter_mcrt_seq!($type, $src_id);
+                mask |= Pipeline::mask();
+                value |= Pipeline::MCRT.encode();
                 BitsMatch::new(mask, value)
             },
             _ => {
@@ -177,27 +1088,20 @@ macro_rules! filter_seq {
     //      `filter_seq!(MCRT | Material | Absorption | MatId(u16))`
     ($pipeline:ident, $supertype:ident, $subtype:ident, $src_id:expr) => {{
         use $crate::raw::{Pipeline, RawField};
-        use $crate::{filter_mcrt_seq, filter_emit_seq, filter_detect_seq};
         use $crate::filter::BitsMatch;
         // TODO: Check if ident is MCRT, then SrcId matches Surf, Mat or MatSurf Ids
 
         match Pipeline::$pipeline {
             Pipeline::Emission => {
-                let (mut mask, mut value) = filter_emit_seq!($supertype, $subtype, $src_id);
-                mask  = mask  | Pipeline::mask();
-                value = value | Pipeline::Emission.encode();
+                let (mut mask, mut value) = $crate::filter_emit_seq!($supertype, $subtype, $src_id);
+                mask  |= Pipeline::mask();
+                value |= Pipeline::Emission.encode();
                 BitsMatch::new(mask, value)
             },
             Pipeline::MCRT => {
-                let (mut mask, mut value) = filter_mcrt_seq!($supertype, $subtype, $src_id);
-                mask  = mask  | Pipeline::mask();
-                value = value | Pipeline::MCRT.encode();
-                BitsMatch::new(mask, value)
-            },
-            Pipeline::Detection => {
-                let (mut mask, mut value) = filter_detect_seq!($supertype, $subtype, $src_id);
-                mask  = mask  | Pipeline::mask();
-                value = value | Pipeline::Detection.encode();
+                let (mut mask, mut value) = $crate::filter_mcrt_seq!($supertype, $subtype, $src_id);
+                mask  |= Pipeline::mask();
+                value |= Pipeline::MCRT.encode();
                 BitsMatch::new(mask, value)
             },
             _ => {
@@ -213,27 +1117,20 @@ macro_rules! filter_seq {
     ($pipeline:ident, $supertype:ident, $subtype:ident, $scatter:ident, $dir:ident, $src_id:expr) => {{
         use $crate::raw::{Pipeline, RawField};
         use $crate::filter::BitsMatch;
-        use $crate::{filter_mcrt_seq, filter_emit_seq, filter_detect_seq};
         // TODO: Check if ident is MCRT, then SrcId matches Surf, Mat or MatSurf Ids
         eprintln!("Filtering seq: {} | {} | {} | {} | {} | {}", stringify!($pipeline), stringify!($supertype), stringify!($subtype), stringify!($scatter), stringify!($dir), stringify!($src_id));
 
         match Pipeline::$pipeline {
             Pipeline::Emission => {
-                let (mut mask, mut value) = filter_emit_seq!($supertype, $subtype, $scatter, $dir, $src_id);
-                mask  = mask  | Pipeline::mask();
-                value = value | Pipeline::Emission.encode();
+                let (mut mask, mut value) = $crate::filter_emit_seq!($supertype, $subtype, $scatter, $dir, $src_id);
+                mask  |= Pipeline::mask();
+                value |= Pipeline::Emission.encode();
                 BitsMatch::new(mask, value)
             },
             Pipeline::MCRT => {
-                let (mut mask, mut value) = filter_mcrt_seq!($supertype, $subtype, $scatter, $dir, $src_id);
-                mask  = mask  | Pipeline::mask();
-                value = value | Pipeline::MCRT.encode();
-                BitsMatch::new(mask, value)
-            },
-            Pipeline::Detection => {
-                let (mut mask, mut value) = filter_detect_seq!($supertype, $subtype, $scatter, $dir, $src_id);
-                mask  = mask  | Pipeline::mask();
-                value = value | Pipeline::Detection.encode();
+                let (mut mask, mut value) = $crate::filter_mcrt_seq!($supertype, $subtype, $scatter, $dir, $src_id);
+                mask  |= Pipeline::mask();
+                value |= Pipeline::MCRT.encode();
                 BitsMatch::new(mask, value)
             },
             _ => {
@@ -252,11 +1149,40 @@ macro_rules! filter_seq {
     };
 }
 
+// Set of event filters for `find_forward_uid_perm`, each one taking the arguments of `filter_seq!`
+// i.e. `filter_perm![(MCRT, Interface, Refraction, SrcId::Surf(1)), (Detection, SrcId::None)]`,
+// or written in the pipe syntax of `filter::parse`, with numeric source ids
+// i.e. `filter_perm![MCRT|Interface|*|Surf(1), MCRT|Material|{Elastic, Inelastic}|*|*|Mat(2)]`
+#[macro_export]
+macro_rules! filter_perm {
+    ($( ( $($spec:tt)+ ) ),* $(,)?) => {
+        vec![
+            $($crate::filter::FilterStep::from($crate::filter_seq!($($spec)+))),*
+        ]
+    };
+    ($($pattern:tt)+) => {{
+        let expr: String = stringify!($($pattern)+).split_whitespace().collect();
+        $crate::filter::parse_perm(&expr).unwrap_or_else(|err| panic!("Invalid filter_perm!: {}", err))
+    }};
+}
+
+#[macro_export]
+macro_rules! check_src_literal {
+    ($pipeline:ident, $kind:ident) => {
+        const {
+            assert!(
+                $crate::filter::src_kind_allowed($crate::raw::Pipeline::$pipeline, $crate::SrcKind::$kind),
+                concat!(stringify!($pipeline), " events cannot be filtered by a ", stringify!($kind), " source")
+            )
+        }
+    };
+}
+
 #[macro_export]
 macro_rules! filter_mcrt_seq {
     // 1. Generic EventType: filter_seq!(Pipeline::MCRT | EventType | SrcId)
     ($event_type:ident, $src_id:expr) => {
-        if ($src_id != SrcId::None) {
+        if !$src_id.is_none() {
             assert!(
                 matches!($src_id, SrcId::Mat(_)| SrcId::Surf(_) | SrcId::MatSurf(_)),
                 "MCRT events can only be filtered by MatId, SurfId, or MatSurfId"
@@ -265,9 +1191,32 @@ macro_rules! filter_mcrt_seq {
         // This format might be supported only for Custom singlet codec
         //panic!("MCRT event filtering requires SuperType and SubType specification");
     };
+    (Custom, _, $src_id:expr) => {{
+        use $crate::raw::*;
+        if !$src_id.is_none() {
+            assert!(
+                matches!($src_id, SrcId::Mat(_) | SrcId::Surf(_) | SrcId::MatSurf(_)),
+                "MCRT events can only be filtered by MatId, SurfId, or MatSurfId"
+            );
+        }
+        let mut mask = MCRT::mask();
+        let mut value = MCRT::Custom.encode();
+        if let Some(id) = $src_id.id() {
+            mask  |= SrcId::mask();
+            value |= id as u32;
+        }
+        (mask, value)
+    }};
+    (Custom, $code:expr, $src_id:expr) => {{
+        use $crate::raw::*;
+        let code: u16 = $code;
+        assert!(code <= MAX_MCRT_CUSTOM, "Custom MCRT code {} exceeds {}", code, MAX_MCRT_CUSTOM);
+        let (mask, value) = $crate::filter_mcrt_seq!(Custom, _, $src_id);
+        (mask | MCRT_CUSTOM_MASK, value | ((code as u32) << MCRT_CUSTOM_SHIFT))
+    }};
     ($supertype:ident, $subtype:ident, $src_id:expr) => {{
         use $crate::raw::*;
-        if ($src_id != SrcId::None) {
+        if !$src_id.is_none() {
             assert!(
                 matches!($src_id, SrcId::Mat(_) | SrcId::Surf(_) | SrcId::MatSurf(_)),
                 "MCRT events can only be filtered by MatId, SurfId, or MatSurfId"
@@ -279,16 +1228,15 @@ macro_rules! filter_mcrt_seq {
             mask  |= $supertype::mask();
             value |= $supertype::$subtype.encode();
         }
-        if ($src_id != SrcId::None) {
+        if let Some(id) = $src_id.id() {
             mask  |= SrcId::mask();
-            // FIXME: Use encode() function, but the default in RawField trait requires Into<u8>
-            value |= (*$src_id as u32);
+            value |= id as u32;
         }
         (mask, value)
     }};
     ($supertype:ident, $subtype:ident, $scatter:ident, $dir:ident, $src_id:expr) => {{
         use $crate::raw::*;
-        if ($src_id != SrcId::None) {
+        if !$src_id.is_none() {
             assert!(
                 matches!($src_id, SrcId::Mat(_) | SrcId::Surf(_) | SrcId::MatSurf(_)),
                 "MCRT events can only be filtered by MatId, SurfId, or MatSurfId"
@@ -308,9 +1256,9 @@ macro_rules! filter_mcrt_seq {
             mask  |= ScatterDir::mask();
             value |= ScatterDir::$dir.encode();
         }
-        if ($src_id != SrcId::None) {
+        if let Some(id) = $src_id.id() {
             mask  |= SrcId::mask();
-            value |= (*$src_id as u32); // Fixup with encode() function
+            value |= id as u32;
         }
         (mask, value)
     }};
@@ -320,31 +1268,31 @@ macro_rules! filter_mcrt_seq {
 macro_rules! filter_emit_seq {
     // 1. Generic EventType: filter_seq!(Pipeline::MCRT | EventType | SrcId)
     ($src_id:expr) => {{
-        if $src_id != SrcId::None {
-            assert!(matches!($src_id,  SrcId::Light(_)), "Emission events can only be filtered by LightId");
-
-            (SrcId::mask(), *$src_id as u32)
-        } else {
-            (0, 0)
+        match $src_id.id() {
+            Some(id) => {
+                assert!(matches!($src_id, SrcId::Light(_)), "Emission events can only be filtered by LightId");
+                (SrcId::mask(), id as u32)
+            }
+            None => (0, 0),
         }
     }};
     ($event_type:tt, $src_id:expr) => {{
-        if $src_id != SrcId::None {
-            assert!(matches!($src_id,  SrcId::Light(_)), "Emission events can only be filtered by LightId");
-
-            (SrcId::mask(), *$src_id as u32)
-        } else {
-            (0, 0)
+        match $src_id.id() {
+            Some(id) => {
+                assert!(matches!($src_id, SrcId::Light(_)), "Emission events can only be filtered by LightId");
+                (SrcId::mask(), id as u32)
+            }
+            None => (0, 0),
         }
     }};
     ($supertype:ident, $subtype:ident, $src_id:expr) => {{
         use $crate::SrcId;
-        if $src_id != SrcId::None {
-            assert!(matches!($src_id, SrcId::Light(_)), "Emission events can only be filtered by LightId");
-
-            (SrcId::mask(), *$src_id as u32 )
-        } else {
-            (0, 0)
+        match $src_id.id() {
+            Some(id) => {
+                assert!(matches!($src_id, SrcId::Light(_)), "Emission events can only be filtered by LightId");
+                (SrcId::mask(), id as u32)
+            }
+            None => (0, 0),
         }
     }};
     ($supertype:ident, $subtype:ident, $scatter:ident, $dir:ident, $src_id:expr) => {
@@ -353,21 +1301,478 @@ macro_rules! filter_emit_seq {
 }
 
 #[macro_export]
-macro_rules! filter_detect_seq {
-    // 1. Generic EventType: filter_seq!(Pipeline::MCRT | EventType | SrcId)
-    ($src_id:expr) => {{
-        // TODO: Complete implementation and SrcId::Detector
-        assert!(matches!($src_id, SrcId::None), "Detection events do not have associated SrcId");
-        (0, 0)
+macro_rules! filter_custom_seq {
+    ($pipeline:ty, $src_id:expr) => {{
+        use $crate::raw::{Pipeline, RawField};
+        use $crate::custom::CustomPipeline;
+        use $crate::filter::BitsMatch;
+        use $crate::SrcId;
+        let mut mask  = Pipeline::mask();
+        let mut value = (<$pipeline as CustomPipeline>::CODE as u32) << Pipeline::shift();
+        if let Some(id) = $src_id.id() {
+            mask  |= SrcId::mask();
+            value |= id as u32;
+        }
+        BitsMatch::new(mask, value)
     }};
-    ($event_type:tt, $src_id:expr) => {
-        // TODO: Complete implementation and SrcId::Detector
-        (0, 0)
-    };
-    ($supertype:ident, $subtype:ident, $src_id:expr) => {{
-        (0, 0)
+    ($pipeline:ty, $supertype:ident, $src_id:expr) => {{
+        let mut bits_match = $crate::filter_custom_seq!($pipeline, $src_id);
+        bits_match.mask  |= 0x00C00000;
+        bits_match.value |= ($supertype::SUPERTYPE as u32) << 22;
+        bits_match
     }};
-    ($supertype:ident, $subtype:ident, $scatter:ident, $dir:ident, $src_id:expr) => {
-        (0, 0)
-    };
+    ($pipeline:ty, $supertype:ident, $subtype:ident, $src_id:expr) => {{
+        use $crate::raw::RawField;
+        let mut bits_match = $crate::filter_custom_seq!($pipeline, $supertype, $src_id);
+        bits_match.mask  |= $supertype::mask();
+        bits_match.value |= $supertype::$subtype.encode();
+        bits_match
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_bits_eq(lhs: BitsMatch, rhs: BitsMatch) {
+        assert_eq!((lhs.mask, lhs.value), (rhs.mask, rhs.value), "{:?} != {:?}", lhs, rhs);
+    }
+
+    // Steps of a parsed sequence
+    fn parse_steps(expr: &str) -> Result<Vec<FilterStep>, String> {
+        match parse(expr)? {
+            FilterExpr::Match(steps) => Ok(steps),
+            expr => panic!("Parsed {:?} instead of a sequence", expr),
+        }
+    }
+
+    #[test]
+    fn parse_matches_filter_seq_macro() {
+        let parsed = parse_steps("MCRT|Interface|Refraction|Surf(3) -> MCRT|Material|Elastic|HenyeyGreenstein|Any|Mat(2) -> Detection|Direct|Detector(1)")
+            .expect("Unable to parse filter");
+        let expected: Vec<FilterStep> = vec![
+            filter_seq!(MCRT, Interface, Refraction, SrcId::Surf(3)).into(),
+            filter_seq!(MCRT, Material, Elastic, HenyeyGreenstein, Any, SrcId::Mat(2)).into(),
+            filter_seq!(Detection, Direct, SrcId::Detector(1)),
+        ];
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn permutation_filters() {
+        use crate::detection::Detection;
+        use crate::emission::Emission;
+        use crate::{EventId, mcrt_event};
+
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let surf_id = ledger.with_surf("lens".to_string(), None).unwrap();
+        let mat_id = ledger.with_mat("tissue".to_string()).unwrap();
+        let refraction = EventId::new_mcrt(mcrt_event!(Interface, Refraction), surf_id);
+        let mie = EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Any), mat_id);
+        let detection = EventId::new_detection(Detection::Direct, SrcId::Detector(0));
+        let chain = |events: &[&EventId], ledger: &mut Ledger| {
+            let mut uid = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+            for event in events {
+                uid = ledger.insert(uid, (*event).clone()).unwrap();
+            }
+            uid
+        };
+        let refracted_first = chain(&[&refraction, &mie, &detection], &mut ledger);
+        let scattered_first = chain(&[&mie, &refraction, &detection], &mut ledger);
+        let twice_scattered = chain(&[&mie, &mie, &detection], &mut ledger);
+
+        let patterns = filter_perm![
+            (MCRT, Material, Elastic, Mie, Any, SrcId::None),
+            (MCRT, Interface, Refraction, SrcId::None),
+        ];
+        let mut found = find_forward_uid_perm(&ledger, patterns.clone());
+        found.sort();
+        assert_eq!(found, vec![refracted_first, scattered_first]);
+        assert!(chain_matches_perm(&ledger.get_chain(scattered_first), &patterns));
+        assert!(!chain_matches_steps(&ledger.get_chain(refracted_first), &patterns));
+
+        // Each pattern takes its own event, while an event matching both patterns can be either
+        let mcrt = parse_steps("MCRT").unwrap()[0].alternatives[0];
+        let twice = vec![mcrt, filter_seq!(MCRT, Material, Elastic, Mie, Any, SrcId::None)];
+        assert!(chain_matches_perm(&ledger.get_chain(twice_scattered), &twice));
+        assert_eq!(find_forward_uid_perm(&ledger, vec![mcrt, mcrt, mcrt]), Vec::<Uid>::new());
+        assert_eq!(find_forward_uid_perm(&ledger, Vec::<BitsMatch>::new()).len(), 3);
+
+        // Pipe syntax, with alternatives
+        let piped = filter_perm![MCRT|Material|Elastic|Mie|Any, MCRT|Interface|Refraction];
+        assert_eq!(piped, patterns);
+        let either = filter_perm![MCRT|Material|{Elastic, Inelastic}|*|*|Mat(0), MCRT|Interface|*|Surf(0), Detection];
+        assert_eq!(either.len(), 3);
+        let mut found = find_forward_uid_perm(&ledger, either);
+        found.sort();
+        assert_eq!(found, vec![refracted_first, scattered_first]);
+        assert!(parse_perm("MCRT|Material|{Elastic|*").is_err());
+    }
+
+    #[test]
+    fn quantified_steps() {
+        use crate::detection::Detection;
+        use crate::emission::Emission;
+        use crate::{EventId, mcrt_event};
+
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("tissue".to_string()).unwrap();
+        let surf_id = ledger.with_surf("lens".to_string(), None).unwrap();
+        let mie = EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Any), mat_id);
+        let refraction = EventId::new_mcrt(mcrt_event!(Interface, Refraction), surf_id);
+        let detection = EventId::new_detection(Detection::Direct, SrcId::Detector(0));
+        // Leaves of the chains scattering 0 to 5 times after a refraction
+        let leaves: Vec<Uid> = (0..6)
+            .map(|n| {
+                let mut uid = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+                uid = ledger.insert(uid, refraction.clone()).unwrap();
+                for _ in 0..n {
+                    uid = ledger.insert(uid, mie.clone()).unwrap();
+                }
+                ledger.insert(uid, detection.clone()).unwrap()
+            })
+            .collect();
+        let find = |steps: Vec<FilterStep>| {
+            let mut found = find_forward_uid_seq(&ledger, steps);
+            found.sort();
+            found
+        };
+
+        let exactly_3 = filter_seq!({3}, MCRT, Material, Elastic, Mie, Any, SrcId::None);
+        assert_eq!(find(vec![exactly_3]), vec![leaves[3]]);
+        let between = filter_seq!({2, 4}, MCRT, Material, Elastic, Mie, Any, SrcId::None);
+        assert_eq!(find(vec![between]), leaves[2..=4].to_vec());
+        let at_least_4 = filter_seq!({4,}, MCRT, Material, Elastic, Mie, Any, SrcId::None);
+        assert_eq!(find(vec![at_least_4]), leaves[4..].to_vec());
+
+        // Counted between the refraction and the detection, none being optional
+        let refracted: FilterStep = filter_seq!(MCRT, Interface, Refraction, SrcId::None).into();
+        let detected = filter_seq!(Detection, SrcId::None);
+        let none = filter_seq!({0}, MCRT, Material, Elastic, Mie, Any, SrcId::None);
+        assert_eq!(find(vec![refracted.clone(), none, detected.clone()]), vec![leaves[0]]);
+        let optional = vec![refracted, filter_seq!({0, 1}, MCRT, Material, Elastic, Mie, Any, SrcId::None), detected];
+        assert_eq!(find(optional.clone()), leaves[..2].to_vec());
+        assert!(chain_matches_steps(&ledger.get_chain(leaves[1]), &optional));
+        assert!(!chain_matches_steps(&ledger.get_chain(leaves[2]), &optional));
+
+        // Plain filters keep matching at least once
+        let plain = [filter_seq!(MCRT, Material, Elastic, Mie, Any, SrcId::None)];
+        assert_eq!(find(plain.iter().map(|bits_match| (*bits_match).into()).collect()), leaves[1..].to_vec());
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_filtering() {
+        use crate::detection::Detection;
+        use crate::emission::Emission;
+        use crate::{EventId, mcrt_event};
+
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_ids: Vec<SrcId> = (0..8).map(|i| ledger.with_mat(format!("layer{}", i)).unwrap()).collect();
+        // Chains of up to 4 scatterings through every combination of layers, under a single start event
+        let start = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+        let mut frontier = vec![start];
+        for _ in 0..4 {
+            let mut next_frontier = Vec::new();
+            for uid in frontier.iter().take(200) {
+                ledger.insert(*uid, EventId::new_detection(Detection::Direct, SrcId::Detector(0))).unwrap();
+                for mat_id in &mat_ids {
+                    let event = EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), *mat_id);
+                    next_frontier.push(ledger.insert(*uid, event).unwrap());
+                }
+            }
+            frontier = next_frontier;
+        }
+        let seq = vec![
+            filter_seq!(MCRT, Material, Elastic, Mie, Forward, SrcId::Mat(3)).into(),
+            filter_seq!(Detection, SrcId::None),
+        ];
+        let quantified = vec![filter_seq!({2,}, MCRT, Material, Elastic, Mie, Forward, SrcId::Mat(3))];
+        // Workers share the ledger by reference, its lookups only read (the spill cache is locked)
+        fn assert_sync<T: Sync>() {}
+        assert_sync::<Ledger>();
+        for steps in [seq, quantified] {
+            let mut sequential = find_forward_uid_seq(&ledger, steps.clone());
+            let mut parallel = par_find_forward_uid_seq(&ledger, steps);
+            assert!(!sequential.is_empty());
+            sequential.sort();
+            parallel.sort();
+            assert_eq!(parallel, sequential);
+        }
+    }
+
+    #[test]
+    fn captured_steps() {
+        use crate::detection::Detection;
+        use crate::emission::Emission;
+        use crate::{EventId, mcrt_event};
+
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let surf_id = ledger.with_surf("lens".to_string(), None).unwrap();
+        let mat_id = ledger.with_mat("tissue".to_string()).unwrap();
+        let start = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+        let refraction = ledger.insert(start, EventId::new_mcrt(mcrt_event!(Interface, Refraction), surf_id)).unwrap();
+        let mie = EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), mat_id);
+        let scatter1 = ledger.insert(refraction, mie.clone()).unwrap();
+        let scatter2 = ledger.insert(scatter1, mie.clone()).unwrap();
+        let detection = ledger.insert(scatter2, EventId::new_detection(Detection::Direct, SrcId::Detector(0))).unwrap();
+        ledger.insert(refraction, EventId::new_detection(Detection::Direct, SrcId::Detector(1))).unwrap();
+
+        let steps = vec![
+            filter_seq!(MCRT, Interface, Refraction, SrcId::None).into(),
+            filter_seq!({1,}, MCRT, Material, Elastic, Mie, Forward, SrcId::None),
+            filter_seq!(Detection, SrcId::None),
+        ];
+        let matches = find_forward_uid_seq_matches(&ledger, steps.clone());
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].terminal, detection);
+        assert_eq!(matches[0].chain, vec![start, refraction, scatter1, scatter2, detection]);
+        assert_eq!(matches[0].captures, vec![(0, refraction), (1, scatter1), (1, scatter2), (2, detection)]);
+        assert_eq!(matches[0].step(1), vec![scatter1, scatter2]);
+
+        // Each step ends as early as possible
+        let mcrt = parse_steps("MCRT").unwrap()[0].alternatives[0];
+        let captures = chain_captures(&matches[0].chain, &[mcrt.into(), mcrt.into()]).unwrap();
+        assert_eq!(captures, vec![(0, refraction), (1, scatter1), (1, scatter2)]);
+        assert_eq!(chain_captures(&matches[0].chain, &[FilterStep::repeat(mcrt, 4, None)]), None);
+    }
+
+    #[test]
+    fn boolean_expressions() {
+        use crate::emission::Emission;
+        use crate::{EventId, mcrt_event};
+
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let surf_id = ledger.with_surf("window".to_string(), None).unwrap();
+        let mat_id = ledger.with_mat("sample".to_string()).unwrap();
+        let refraction = EventId::new_mcrt(mcrt_event!(Interface, Refraction), surf_id);
+        let raman = EventId::new_mcrt(mcrt_event!(Material, Inelastic, Raman, Forward), mat_id);
+        let mie = EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), mat_id);
+        let mut chain = |events: &[&EventId]| {
+            let mut uid = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+            for event in events {
+                uid = ledger.insert(uid, (*event).clone()).unwrap();
+            }
+            uid
+        };
+        let refracted_raman = chain(&[&refraction, &raman]);
+        let refracted_mie = chain(&[&refraction, &mie]);
+        let raman_only = chain(&[&raman]);
+        let mie_only = chain(&[&mie]);
+
+        let refracted = parse("MCRT|Interface|Refraction|Surf(0)").unwrap();
+        let raman_scattered = parse("MCRT|Material|Inelastic|Raman").unwrap();
+        let find = |expr: &FilterExpr| {
+            let mut found = find_forward_uid_expr(&ledger, expr);
+            found.sort();
+            found
+        };
+        let sorted = |mut uids: Vec<Uid>| {
+            uids.sort();
+            uids
+        };
+        let without_raman = refracted.clone().and(!raman_scattered.clone());
+        assert_eq!(find(&without_raman), vec![refracted_mie]);
+        assert_eq!(find(&!without_raman.clone()), sorted(vec![refracted_raman, raman_only, mie_only]));
+        assert_eq!(find(&refracted.clone().or(raman_scattered.clone())), sorted(vec![refracted_raman, refracted_mie, raman_only]));
+        assert_eq!(find(&!(!raman_scattered)), sorted(vec![refracted_raman, raman_only]));
+        for leaf in [refracted_raman, refracted_mie, raman_only, mie_only] {
+            assert_eq!(without_raman.matches(&ledger.get_chain(leaf)), leaf == refracted_mie);
+        }
+        assert_eq!(find(&FilterExpr::And(Vec::new())).len(), 4);
+        assert!(find(&FilterExpr::Or(Vec::new())).is_empty());
+    }
+
+    #[test]
+    fn src_kind_validation() {
+        let light_id = SrcId::Light(2);
+        let err = try_filter_seq!(MCRT, Interface, Refraction, light_id).unwrap_err();
+        assert_eq!(err, SrcKindMismatch { pipeline: raw::Pipeline::MCRT, src_id: light_id });
+        assert_eq!(err.to_string(), "MCRT events cannot be filtered by the Light source Light(2)");
+        assert!(try_filter_seq!(Detection, Direct, SrcId::Mat(0)).is_err());
+        // The detector expression is only evaluated once
+        let mut detectors = [SrcId::Detector(1)].into_iter();
+        let step = filter_seq!(Detection, Direct, detectors.next().unwrap());
+        assert_eq!(step, filter_seq!(Detection, Direct, SrcId::Detector(1)));
+
+        let bits_match = try_filter_seq!(Emission, light_id).expect("Light sources are valid for emission");
+        assert_bits_eq(bits_match, crate::filter_seq!(Emission, SrcId::Light(2)));
+        let mat_id = SrcId::Mat(1);
+        let bits_match = try_filter_seq!(MCRT, Material, Elastic, Mie, Any, mat_id).unwrap();
+        assert_bits_eq(bits_match, crate::filter_seq!(MCRT, Material, Elastic, Mie, Any, SrcId::Mat(1)));
+        assert!(src_kind_allowed(raw::Pipeline::Transport, SrcKind::None));
+        assert!(!src_kind_allowed(raw::Pipeline::Transport, SrcKind::Light));
+    }
+
+    #[test]
+    fn parse_wildcards() {
+        let parsed = parse_steps("MCRT|Material|Inelastic|*|*|*").expect("Unable to parse filter");
+        assert_bits_eq(parsed[0].alternatives[0], BitsMatch::new(0x0FF00000, 0x03900000));
+        let parsed = parse_steps("MCRT|Material|*|*|Backward").expect("Unable to parse filter");
+        assert_bits_eq(parsed[0].alternatives[0], BitsMatch::new(0x0FC30000, 0x03830000));
+        let parsed = parse_steps("*|*|MatSurf(7)").expect("Unable to parse filter");
+        assert_bits_eq(parsed[0].alternatives[0], BitsMatch::new(0x0000FFFF, 0x00000007));
+        let parsed = parse_steps("Emission|PointSource|Light(1)").expect("Unable to parse filter");
+        assert_bits_eq(parsed[0].alternatives[0], BitsMatch::new(0x0F1FFFFF, 0x01020001));
+        let parsed = parse_steps("Detection|Rejected|*").expect("Unable to parse filter");
+        assert_eq!(parsed[0], filter_seq!(Detection, Rejected, SrcId::None));
+        let parsed = parse_steps("Detection|*|Detector(4)").expect("Unable to parse filter");
+        assert_bits_eq(parsed[0].alternatives[0], BitsMatch::new(0x0F80FFFF, 0x05000004));
+        assert_bits_eq(parsed[0].alternatives[1], BitsMatch::new(0x0F8000FF, 0x05800004));
+        let parsed = parse_steps("Processing|Digitization").expect("Unable to parse filter");
+        assert_bits_eq(parsed[0].alternatives[0], filter_seq!(Processing, Digitization, SrcId::None));
+        let parsed = parse_steps("MCRT|Material|Roulette|Survived").expect("Unable to parse filter");
+        assert_bits_eq(parsed[0].alternatives[0], filter_seq!(MCRT, Material, Roulette, Survived, SrcId::None));
+        let parsed = parse_steps("MCRT|Material|Roulette|Survived|5|Mat(2)").expect("Unable to parse filter");
+        assert_bits_eq(parsed[0].alternatives[0], crate::mcrt::boost_class_bits_match(5, SrcId::Mat(2)));
+        assert!(parse("MCRT|Material|Roulette|Killed|8").is_err());
+        let parsed = parse_steps("Processing|SpectralFilterReject").expect("Unable to parse filter");
+        assert_bits_eq(parsed[0].alternatives[0], filter_seq!(Processing, SpectralFilterReject, SrcId::None));
+
+        let parsed = parse_steps("Voxel|1200").expect("Unable to parse filter");
+        assert_bits_eq(parsed[0].alternatives[0], filter_seq!(Voxel, 1200));
+        assert!(parse("Voxel|0x1000000").is_err());
+
+        let parsed = parse_steps("Transport|Split").expect("Unable to parse filter");
+        assert_bits_eq(parsed[0].alternatives[0], filter_seq!(Transport, Split, SrcId::None));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse("MCRT|Material|Elastic|Raman").is_err());
+        assert!(parse("MCRT|Interface|Refraction|Forward").is_err());
+        assert!(parse("MCRT|*|Refraction").is_err());
+        assert!(parse("Detection|Any|Any").is_err());
+        assert!(parse("MCRT||Mat(1)").is_err());
+        assert!(parse("MCRT|Material|Absorption|Mat(water)").is_err());
+        assert!(parse("MCRT|Material|{Elastic|*").is_err());
+        assert!(parse("MCRT|Material|{Elastic,}|*").is_err());
+    }
+
+    #[test]
+    fn parse_braced_alternatives() {
+        // Side and Backward share the high bit of the direction
+        let parsed = parse_steps("MCRT|Material|Elastic|*|{Side, Backward}").expect("Unable to parse filter");
+        assert_bits_eq(parsed[0].alternatives[0], BitsMatch::new(0x0FF20000, 0x03A20000));
+        let parsed = parse_steps("MCRT|Material|Elastic|{HenyeyGreenstein,Mie,Rayleigh,SphericalCdf}|*|Mat(3)").unwrap();
+        assert_bits_eq(parsed[0].alternatives[0], parse_steps("MCRT|Material|Elastic|*|*|Mat(3)").unwrap()[0].alternatives[0]);
+
+        // Alternatives which don't fit a single mask stay alternatives of the step
+        let parsed = parse_steps("MCRT|Material|{Elastic,Inelastic}|*|*|Mat(3) -> Detection").expect("Unable to parse filter");
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].alternatives.len(), 2);
+        assert_bits_eq(parsed[0].alternatives[1], parse_steps("MCRT|Material|Inelastic|*|*|Mat(3)").unwrap()[0].alternatives[0]);
+        assert!(parsed[0].matches(0x03900003) && parsed[0].matches(0x03A00003));
+        assert!(!parsed[0].matches(0x03800003));
+        let parsed = parse_steps("{Emission,Processing} -> MCRT|*|*|{Mat(1),Mat(2)}").unwrap();
+        assert_eq!(parsed.iter().map(|step| step.alternatives.len()).collect::<Vec<_>>(), vec![2, 2]);
+    }
+
+    #[test]
+    fn parse_resolves_names() {
+        let mut ledger = Ledger::new();
+        ledger.with_mat("air".to_string()).unwrap();
+        let water_id = ledger.with_mat("water".to_string()).unwrap();
+        let FilterExpr::Match(parsed) = parse_with_ledger("MCRT|Material|Inelastic|*|*|Mat(water) -> Detection", &ledger)
+            .expect("Unable to parse filter")
+        else {
+            panic!("Expected a sequence");
+        };
+        assert_bits_eq(parsed[0].alternatives[0], BitsMatch::new(0x0FF0FFFF, 0x03900000 | water_id.id().unwrap() as u32));
+        assert!(parse_with_ledger("MCRT|Material|Absorption|Mat(oil)", &ledger).is_err());
+    }
+
+    #[test]
+    fn parse_nested_groups() {
+        use crate::EventId;
+        use crate::detection::Detection;
+        use crate::emission::Emission;
+
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let housing = ledger.with_matsurf("housing".to_string(), "steel".to_string(), Some("probe".to_string())).unwrap();
+        let core = ledger.with_matsurf("core".to_string(), "silica".to_string(), Some("probe/fiber/core".to_string())).unwrap();
+        let cladding = ledger.with_matsurf("cladding".to_string(), "polymer".to_string(), Some("/probe/fiber/cladding/".to_string())).unwrap();
+        let sample = ledger.with_matsurf("sample".to_string(), "tissue".to_string(), Some("sample".to_string())).unwrap();
+        assert_eq!(ledger.group_src_ids("probe/fiber"), vec![cladding, core]);
+        assert_eq!(ledger.group_src_ids("probe").len(), 3);
+        assert!(ledger.group_src_ids("probe/fib").is_empty());
+        assert_eq!(ledger.group_of(&cladding), Some("probe/fiber/cladding"));
+
+        let mut leaves = Vec::new();
+        for src_id in [housing, core, cladding, sample] {
+            let uid = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+            let uid = ledger.insert(uid, EventId::new_mcrt(crate::mcrt_event!(Interface, Refraction), src_id)).unwrap();
+            leaves.push(ledger.insert(uid, EventId::new_detection(Detection::Direct, SrcId::Detector(0))).unwrap());
+        }
+        let find = |expr: &str| {
+            let mut found = find_forward_uid_expr(&ledger, &parse_with_ledger(expr, &ledger).expect("Unable to parse filter"));
+            found.sort();
+            found
+        };
+        // The sources of the group are alternatives of a single step
+        let Ok(FilterExpr::Match(parsed)) = parse_with_ledger("MCRT|Interface|Refraction|Grp(probe/fiber) -> Detection", &ledger) else {
+            panic!("Expected a sequence");
+        };
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].alternatives.len(), 2);
+        let mut expected = vec![leaves[1], leaves[2]];
+        expected.sort();
+        assert_eq!(find("MCRT|Interface|Refraction|Grp(probe/fiber) -> Detection"), expected);
+        assert_eq!(find("MCRT|Interface|*|Grp(probe) -> Detection").len(), 3);
+        // Along with braced alternatives and in any stage, without expanding every combination
+        assert_eq!(find("MCRT|{Interface,Reflector}|*|Grp(probe) -> Detection").len(), 3);
+        assert_eq!(find("MCRT|Interface|*|{Grp(probe/fiber),MatSurf(sample)} -> Detection").len(), 3);
+        assert!(parse_with_ledger("MCRT|Interface|*|Grp(lens)", &ledger).is_err());
+        assert!(parse("MCRT|Interface|*|Grp(probe)").is_err());
+    }
+
+    #[test]
+    fn aliased_sources() {
+        use crate::EventId;
+        use crate::detection::Detection;
+        use crate::emission::Emission;
+
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let water_id = ledger.with_mat("water".to_string()).unwrap();
+        ledger.with_mat("air".to_string()).unwrap();
+        let saline_id = ledger.with_mat("saline".to_string()).unwrap();
+        let mut leaves: Vec<Uid> = [water_id, saline_id]
+            .into_iter()
+            .map(|mat_id| {
+                let uid = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+                let uid = ledger.insert(uid, EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Any), mat_id)).unwrap();
+                ledger.insert(uid, EventId::new_detection(Detection::Direct, SrcId::Detector(0))).unwrap()
+            })
+            .collect();
+        leaves.sort();
+        let find = |bits_match: BitsMatch| {
+            let mut found = find_forward_uid_seq(&ledger, vec![bits_match]);
+            found.sort();
+            found
+        };
+        let on_water = filter_seq!(MCRT, Material, Elastic, Mie, Any, water_id);
+        assert_eq!(find(on_water), vec![leaves[0]]);
+
+        ledger.alias_src(water_id, saline_id).unwrap();
+        let find = |bits_match: BitsMatch| {
+            let mut found = find_forward_uid_seq(&ledger, vec![bits_match]);
+            found.sort();
+            found
+        };
+        // Either source stands for both, whichever way the filter is built
+        assert_eq!(find(on_water), leaves);
+        assert_eq!(find(filter_seq!(MCRT, Material, Elastic, Mie, Any, saline_id)), leaves);
+        assert_eq!(find(filter_seq!(MCRT, Material, Elastic, Mie, Any, SrcId::Mat(1))).len(), 0);
+        assert_eq!(find_forward_uid_expr(&ledger, &FilterExpr::seq(vec![on_water])).len(), 2);
+        assert_eq!(find_forward_uid_seq_matches(&ledger, vec![on_water]).len(), 2);
+        let parsed = parse_with_ledger("MCRT|Material|Elastic|*|*|Mat(saline)", &ledger).unwrap();
+        assert_eq!(find_forward_uid_expr(&ledger, &parsed).len(), 2);
+    }
 }
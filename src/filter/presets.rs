@@ -0,0 +1,133 @@
+use crate::filter::{BitsMatch, FilterExpr, FilterStep};
+use crate::ledger::Ledger;
+use crate::raw::{self, Elastic, Inelastic, Material, Pipeline, RawField};
+use crate::SrcId;
+
+// ----------------------------------------------------
+// Ready-made filters for the common questions
+// ----------------------------------------------------
+// Presets are plain `FilterExpr`, searched with `filter::find_forward_uid_expr` and combined with
+// any other expression. The photons which must not interact in some way, i.e. never scatter, are
+// the negation of a sequence on that interaction.
+
+fn detection() -> BitsMatch {
+    BitsMatch::new(Pipeline::mask(), Pipeline::Detection.encode())
+}
+
+fn material(material: Material) -> BitsMatch {
+    BitsMatch::new(
+        Pipeline::mask() | raw::MCRT::mask() | Material::mask(),
+        Pipeline::MCRT.encode() | raw::MCRT::Material.encode() | material.encode(),
+    )
+}
+
+// Interface and reflector events on `src_id`, the MCRT events of surfaces
+fn surface_events(src_id: &SrcId) -> Vec<BitsMatch> {
+    let mask = Pipeline::mask() | raw::MCRT::mask() | SrcId::mask();
+    let id = src_id.id().map(|id| id as u32).unwrap_or_default();
+    [raw::MCRT::Interface, raw::MCRT::Reflector]
+        .iter()
+        .map(|supertype| BitsMatch::new(mask, Pipeline::MCRT.encode() | supertype.encode() | id))
+        .collect()
+}
+
+// Detected photons which never scattered in a material
+pub fn ballistic_detected() -> FilterExpr {
+    let scattered = FilterStep::any_of(vec![material(Material::Elastic), material(Material::Inelastic)]);
+    FilterExpr::seq(vec![detection()]).and(!FilterExpr::seq(vec![scattered]))
+}
+
+// Detected photons after at least `n` elastic scattering events
+pub fn detected_after_elastic(n: u32) -> FilterExpr {
+    FilterExpr::seq(vec![FilterStep::repeat(material(Material::Elastic), n, None), detection().into()])
+}
+
+// Detected photons emitted by fluorescence along their chain
+pub fn fluorescence_detected() -> FilterExpr {
+    let fluorescence = BitsMatch::new(
+        Pipeline::mask() | raw::MCRT::mask() | Material::mask() | Inelastic::mask(),
+        Pipeline::MCRT.encode() | raw::MCRT::Material.encode() | Material::Inelastic.encode() | Inelastic::Fluorescence.encode(),
+    );
+    FilterExpr::seq(vec![fluorescence, detection()])
+}
+
+// Photons interacting with the surface of a source, or of the sources merged with it
+pub fn touched_src(ledger: &Ledger, src_id: &SrcId) -> FilterExpr {
+    let alternatives = ledger.merged_srcs(src_id).iter().flat_map(surface_events).collect();
+    FilterExpr::seq(vec![FilterStep::any_of(alternatives)])
+}
+
+pub fn touched_src_by_name(ledger: &Ledger, name: &str) -> Result<FilterExpr, String> {
+    let src_id = ledger.src_id_by_name(name).ok_or_else(|| format!("Unknown source name: {}", name))?;
+    Ok(touched_src(ledger, &src_id))
+}
+
+// Photons interacting with a surface of the group `path` or of its subgroups
+pub fn touched_group(ledger: &Ledger, path: &str) -> Result<FilterExpr, String> {
+    let src_ids = ledger.group_src_ids(path);
+    if src_ids.is_empty() {
+        return Err(format!("Unknown group: {}", path));
+    }
+    let alternatives = src_ids.iter().flat_map(surface_events).collect();
+    Ok(FilterExpr::seq(vec![FilterStep::any_of(alternatives)]))
+}
+
+// Elastic scattering of a given phase function, i.e. to extend `detected_after_elastic`
+pub fn elastic(phase_function: Elastic) -> BitsMatch {
+    let bits_match = material(Material::Elastic);
+    BitsMatch::new(bits_match.mask | Elastic::mask(), bits_match.value | phase_function.encode())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::find_forward_uid_expr;
+    use crate::ledger::Uid;
+    use crate::detection::Detection;
+    use crate::emission::Emission;
+    use crate::{EventId, mcrt_event};
+
+    #[test]
+    fn presets_select_chains() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let tissue = ledger.with_mat("tissue".to_string()).unwrap();
+        let lens = ledger.with_surf("lens".to_string(), Some("probe/optics".to_string())).unwrap();
+        let window = ledger.with_surf("window".to_string(), Some("probe".to_string())).unwrap();
+        let mie = EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), tissue);
+        let hg = EventId::new_mcrt(mcrt_event!(Material, Elastic, HenyeyGreenstein, Any), tissue);
+        let fluorescence = EventId::new_mcrt(mcrt_event!(Material, Inelastic, Fluorescence, Any), tissue);
+        let refraction = |src_id| EventId::new_mcrt(mcrt_event!(Interface, Refraction), src_id);
+        let detect = EventId::new_detection(Detection::Direct, SrcId::Detector(0));
+
+        let chain = |ledger: &mut Ledger, events: &[EventId]| {
+            let mut uid = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+            for event in events {
+                uid = ledger.insert(uid, event.clone()).unwrap();
+            }
+            uid
+        };
+        let ballistic = chain(&mut ledger, &[refraction(lens), detect.clone()]);
+        let twice = chain(&mut ledger, &[mie.clone(), hg.clone(), detect.clone()]);
+        let fluorescent = chain(&mut ledger, &[refraction(window), fluorescence, mie.clone(), detect.clone()]);
+        let absorbed = chain(&mut ledger, &[mie.clone(), hg, mie]);
+        let find = |expr: FilterExpr| {
+            let mut found: Vec<Uid> = find_forward_uid_expr(&ledger, &expr);
+            found.sort();
+            found
+        };
+
+        assert_eq!(find(ballistic_detected()), vec![ballistic]);
+        assert_eq!(find(detected_after_elastic(2)), vec![twice]);
+        assert_eq!(find(detected_after_elastic(1)).len(), 2);
+        assert_eq!(find(fluorescence_detected()), vec![fluorescent]);
+        assert_eq!(find(touched_group(&ledger, "probe").unwrap()), vec![ballistic, fluorescent]);
+        assert_eq!(find(touched_src_by_name(&ledger, "lens").unwrap()), vec![ballistic]);
+        assert!(touched_group(&ledger, "stage").is_err());
+
+        let mie_twice = FilterExpr::seq(vec![FilterStep::repeat(elastic(Elastic::Mie), 2, None)]);
+        assert_eq!(find(mie_twice), vec![absorbed]);
+        // Combined with the other expressions
+        assert_eq!(find(detected_after_elastic(1).and(!fluorescence_detected())), vec![twice]);
+    }
+}
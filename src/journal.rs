@@ -0,0 +1,272 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::RawEvent;
+use crate::aev::{invalid_data, replay_frame};
+use crate::ledger::{Ledger, SrcTable, SrcTableDelta, Uid};
+
+// ----------------------------------------------------
+// Ledger journal (.jsonl)
+// ----------------------------------------------------
+// Incremental alternative to `write_ledger_to_json`, written while the simulation runs. Each line
+// is a self-contained JSON record, either the sources, written in full when the journal starts:
+// {"sources": SrcTable}
+// then only their changes whenever sources are registered, see `SrcTableDelta`. The full table is
+// written again only if a change isn't a registration, i.e. the ledger settings changed:
+// {"src_delta": SrcTableDelta}
+// or a block of (prev_seq, raw event) links, in insertion order as for the aev frames:
+// {"links": [[prev_seq, raw], ...]}
+// Every record is flushed once written, such that an interrupted run leaves a journal readable up
+// to its last complete record.
+// The ledger files of `Ledger::stream_to` are made of the same records, with blocks of spilled
+// entries instead of links and the rest of the ledger as last record, see `stream`.
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", bound = "")]
+pub(crate) enum Record<E: RawEvent = u32> {
+    Sources(Box<SrcTable>),
+    SrcDelta(Box<SrcTableDelta>),
+    Links(Vec<(u32, u32)>),
+    // (seq_id, raw event, next_seq_id) entries of a spilled block, with the width of its raw events
+    Entries { event_bytes: u8, triples: Vec<(u32, u64, u32)> },
+    // The ledger without its entries (start events, timestamps, child roots, ...)
+    Ledger(Box<Ledger<E>>),
+}
+
+// Record of `src_table`, only its changes since the `written` sources when they are registrations
+pub(crate) fn sources_record<E: RawEvent>(src_table: SrcTable, written: &mut Option<SrcTable>) -> Record<E> {
+    let record = match written.as_ref().and_then(|sources| src_table.delta_since(sources)) {
+        Some(delta) => Record::SrcDelta(Box::new(delta)),
+        None => Record::Sources(Box::new(src_table.clone())),
+    };
+    *written = Some(src_table);
+    record
+}
+
+// Line of a record, terminated by a newline
+pub(crate) fn encode_record<E: RawEvent>(record: &Record<E>) -> io::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+pub(crate) fn decode_record<E: RawEvent>(line: &[u8]) -> io::Result<Record<E>> {
+    serde_json::from_slice(line).map_err(|err| invalid_data(format!("Invalid journal record: {}", err)))
+}
+
+// ----------------------------------------------------
+// Writer
+// ----------------------------------------------------
+pub struct JournalWriter<W: Write> {
+    writer: W,
+    block_links: usize,
+    links: Vec<(u32, u32)>,
+    // Last sources written, the next changes are written relative to them
+    written_sources: Option<SrcTable>,
+}
+
+impl JournalWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, ledger: &Ledger) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), ledger)
+    }
+}
+
+impl<W: Write> JournalWriter<W> {
+    pub const DEFAULT_BLOCK_LINKS: usize = 4096;
+
+    // Write the sources currently registered in `ledger`
+    pub fn new(writer: W, ledger: &Ledger) -> io::Result<Self> {
+        let mut journal = Self {
+            writer,
+            block_links: Self::DEFAULT_BLOCK_LINKS,
+            links: Vec::new(),
+            written_sources: None,
+        };
+        journal.write_sources(&ledger.src_table())?;
+        Ok(journal)
+    }
+
+    // Number of links per record, at most that many links are lost if the run is interrupted
+    pub fn with_block_links(mut self, block_links: usize) -> Self {
+        assert!(block_links > 0, "Block size must be at least one link");
+        self.block_links = block_links;
+        self
+    }
+
+    // Links pending before the change are written first, such that the sources of their events
+    // are known when replaying them
+    pub fn write_sources(&mut self, src_table: &SrcTable) -> io::Result<()> {
+        self.write_links()?;
+        let record = sources_record(src_table.clone(), &mut self.written_sources);
+        self.write_record(&record)
+    }
+
+    // Log an entry with the Uid returned by `Ledger::insert` or `Ledger::insert_start`
+    pub fn write(&mut self, uid: &Uid) -> io::Result<()> {
+        self.links.push((uid.seq_id, uid.event));
+        if self.links.len() >= self.block_links {
+            self.write_links()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.write_links()?;
+        self.writer.flush()
+    }
+
+    fn write_links(&mut self) -> io::Result<()> {
+        if self.links.is_empty() {
+            return Ok(());
+        }
+        let links = std::mem::take(&mut self.links);
+        self.write_record(&Record::Links(links))
+    }
+
+    fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        self.writer.write_all(&encode_record(record)?)?;
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Drop for JournalWriter<W> {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            warn!("Failed to write the last links of the journal: {}", err);
+        }
+    }
+}
+
+// ----------------------------------------------------
+// Reader
+// ----------------------------------------------------
+pub fn read_journal<P: AsRef<Path>>(path: P) -> io::Result<Ledger> {
+    read_journal_from(BufReader::new(File::open(path)?))
+}
+
+// Replay the records into a Ledger. A truncated last record, left by an interrupted run, is skipped.
+pub fn read_journal_from<R: BufRead>(reader: R) -> io::Result<Ledger> {
+    let mut ledger = Ledger::new();
+    let mut sources: Option<SrcTable> = None;
+    read_records(reader, |record: Record| {
+        match record {
+            Record::Sources(src_table) => {
+                ledger.set_src_table((*src_table).clone());
+                sources = Some(*src_table);
+            }
+            Record::SrcDelta(delta) => ledger.set_src_table(apply_delta(&mut sources, *delta)?.clone()),
+            Record::Links(links) => {
+                for (prev_seq, raw) in links {
+                    replay_frame(&mut ledger, prev_seq, raw)?;
+                }
+            }
+            Record::Entries { .. } | Record::Ledger(_) => {
+                return Err(invalid_data("Ledger file written by `Ledger::stream_to`, read it with `stream::read_stream`".to_string()));
+            }
+        }
+        Ok(())
+    })?;
+    Ok(ledger)
+}
+
+// Pass each record to `on_record` in order, skipping a truncated last record
+pub(crate) fn read_records<E: RawEvent, R: BufRead>(
+    reader: R,
+    mut on_record: impl FnMut(Record<E>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut lines = reader.lines().peekable();
+    while let Some(line) = lines.next() {
+        match decode_record(line?.as_bytes()) {
+            Ok(record) => on_record(record)?,
+            Err(err) if lines.peek().is_none() => {
+                warn!("Skipped the truncated last record: {}", err);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn apply_delta(sources: &mut Option<SrcTable>, delta: SrcTableDelta) -> io::Result<&SrcTable> {
+    let src_table = sources
+        .as_mut()
+        .ok_or_else(|| invalid_data("Source changes before the sources".to_string()))?;
+    src_table.apply(delta);
+    Ok(src_table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EventId, SrcId, mcrt_event};
+    use crate::detection::Detection;
+    use crate::emission::Emission;
+    use crate::recorder::Recorder;
+
+    #[test]
+    fn journal_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.jsonl");
+        let mut recorder = Recorder::new(Ledger::new());
+        let light_id = recorder.ledger_mut().with_light("laser".to_string()).unwrap();
+        let journal = JournalWriter::create(&path, recorder.ledger()).unwrap().with_block_links(4);
+        let mut recorder = recorder.with_sink(journal);
+
+        let uid = recorder.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap().unwrap();
+        // Registered after the journal was created, still recorded before the events using it
+        let mat_id = recorder.ledger_mut().with_mat("water".to_string()).unwrap();
+        let mut leaf = uid;
+        for _ in 0..10 {
+            leaf = recorder.insert(leaf, EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+        }
+        leaf = recorder.insert(leaf, EventId::new_detection(Detection::Direct, SrcId::Detector(0))).unwrap();
+        let ledger = recorder.into_ledger();
+
+        let replayed = read_journal(&path).unwrap();
+        assert_eq!(replayed.src_table(), ledger.src_table());
+        assert_eq!(replayed.get_chain(leaf), ledger.get_chain(leaf));
+        assert_eq!(replayed.names(&mat_id), ledger.names(&mat_id));
+
+        // Interrupted while writing the last block
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let last_links = contents.rfind("{\"links\"").unwrap();
+        let truncated = &contents[..last_links + 10];
+        let replayed = read_journal_from(truncated.as_bytes()).unwrap();
+        assert_eq!(replayed.names(&mat_id), ledger.names(&mat_id));
+        assert!(replayed.entries().count() > 4 && replayed.entries().count() < ledger.entries().count());
+        assert!(read_journal_from("{\"links\": [\n{}\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn journal_appends_source_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.jsonl");
+        let mut recorder = Recorder::new(Ledger::new());
+        let light_id = recorder.ledger_mut().with_light("laser".to_string()).unwrap();
+        let journal = JournalWriter::create(&path, recorder.ledger()).unwrap();
+        let mut recorder = recorder.with_sink(journal);
+
+        let mut leaf = recorder.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap().unwrap();
+        for mat in 0..20 {
+            let mat_id = recorder.ledger_mut().with_mat(format!("mat{}", mat)).unwrap();
+            leaf = recorder.insert(leaf, EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+        }
+        let ledger = recorder.into_ledger();
+
+        // Each source and audit entry is written once, not again with every later registration
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().filter(|line| line.starts_with("{\"sources\"")).count(), 1);
+        assert!(contents.lines().filter(|line| line.starts_with("{\"src_delta\"")).count() >= 20);
+        assert_eq!(contents.matches("\"laser\"").count(), 2);
+        assert_eq!(contents.matches("\"mat7\"").count(), 2);
+
+        let replayed = read_journal(&path).unwrap();
+        assert_eq!(replayed.src_table(), ledger.src_table());
+        assert_eq!(replayed.get_chain(leaf), ledger.get_chain(leaf));
+        assert!(read_journal_from(contents.lines().nth(1).unwrap().as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{Decode, EventId, EventType};
+use crate::ledger::Uid;
+use crate::detection::Detection;
+use crate::emission::Emission;
+use crate::processing::Processing;
+use crate::transport::Transport;
+use crate::mcrt::{self, MCRT, ScatterDir};
+
+// Flat, field-less classification of events, used as grouping key for histograms, legends and
+// plotting color maps. The declaration order follows the encoding order, such that sorting by
+// EventKind sorts by pipeline, then supertype, then subtype.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Granularity {
+    Pipeline,
+    SuperType,
+    // Leaf event type, ignoring the scatter direction
+    SubType,
+    // Leaf event type including the scatter direction
+    Full,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EventKind {
+    None,
+    // Emission
+    Emission,
+    PencilBeam,
+    GaussianBeam,
+    PointSource,
+    PlaneSource,
+    PlaneWave,
+    Laser,
+    Led,
+    Isotropic,
+    Collimated,
+    PulsedLaser,
+    SecondaryReEmission,
+    // MCRT
+    MCRT,
+    Interface,
+    Reflection,
+    Refraction,
+    FresnelSplit,
+    ReEmittance,
+    Reflector,
+    Diffuse,
+    Specular,
+    Composite,
+    RetroReflective,
+    CompositeRetroReflective,
+    Material,
+    Absorption,
+    Raman,
+    RamanAny,
+    RamanForward,
+    RamanSide,
+    RamanBackward,
+    Fluorescence,
+    FluorescenceAny,
+    FluorescenceForward,
+    FluorescenceSide,
+    FluorescenceBackward,
+    HenyeyGreenstein,
+    HenyeyGreensteinAny,
+    HenyeyGreensteinForward,
+    HenyeyGreensteinSide,
+    HenyeyGreensteinBackward,
+    Mie,
+    MieAny,
+    MieForward,
+    MieSide,
+    MieBackward,
+    Rayleigh,
+    RayleighAny,
+    RayleighForward,
+    RayleighSide,
+    RayleighBackward,
+    SphericalCdf,
+    SphericalCdfAny,
+    SphericalCdfForward,
+    SphericalCdfSide,
+    SphericalCdfBackward,
+    Roulette,
+    RouletteSurvived,
+    RouletteKilled,
+    // Detection
+    Detection,
+    Direct,
+    Rejected,
+    TimeGated,
+    Coincidence,
+    Saturated,
+    // Processing
+    Processing,
+    Filtering,
+    Digitization,
+    SpectralFilterPass,
+    SpectralFilterReject,
+    NeutralDensity,
+    BeamsplitterTransmitted,
+    BeamsplitterReflected,
+    FiberCouplingLoss,
+    AdcDigitization,
+    // Transport
+    Transport,
+    Split,
+    PeriodicBoundary,
+    MirrorBoundary,
+    OpenBoundary,
+    MaxDepth,
+    // Voxel tags
+    Voxel,
+    // Other pipelines
+    Custom,
+}
+
+impl EventKind {
+    // Every kind, in declaration order
+    pub const ALL: &'static [EventKind] = &[
+        EventKind::None, EventKind::Emission, EventKind::PencilBeam, EventKind::GaussianBeam, EventKind::PointSource,
+        EventKind::PlaneSource, EventKind::PlaneWave, EventKind::Laser, EventKind::Led, EventKind::Isotropic,
+        EventKind::Collimated, EventKind::PulsedLaser, EventKind::SecondaryReEmission, EventKind::MCRT, EventKind::Interface, EventKind::Reflection,
+        EventKind::Refraction, EventKind::FresnelSplit, EventKind::ReEmittance, EventKind::Reflector, EventKind::Diffuse, EventKind::Specular,
+        EventKind::Composite, EventKind::RetroReflective, EventKind::CompositeRetroReflective, EventKind::Material, EventKind::Absorption,
+        EventKind::Raman, EventKind::RamanAny, EventKind::RamanForward, EventKind::RamanSide, EventKind::RamanBackward,
+        EventKind::Fluorescence, EventKind::FluorescenceAny, EventKind::FluorescenceForward, EventKind::FluorescenceSide, EventKind::FluorescenceBackward,
+        EventKind::HenyeyGreenstein, EventKind::HenyeyGreensteinAny, EventKind::HenyeyGreensteinForward, EventKind::HenyeyGreensteinSide, EventKind::HenyeyGreensteinBackward,
+        EventKind::Mie, EventKind::MieAny, EventKind::MieForward, EventKind::MieSide, EventKind::MieBackward,
+        EventKind::Rayleigh, EventKind::RayleighAny, EventKind::RayleighForward, EventKind::RayleighSide, EventKind::RayleighBackward,
+        EventKind::SphericalCdf, EventKind::SphericalCdfAny, EventKind::SphericalCdfForward, EventKind::SphericalCdfSide, EventKind::SphericalCdfBackward,
+        EventKind::Roulette, EventKind::RouletteSurvived, EventKind::RouletteKilled,
+        EventKind::Detection, EventKind::Direct, EventKind::Rejected, EventKind::TimeGated, EventKind::Coincidence,
+        EventKind::Saturated, EventKind::Processing, EventKind::Filtering,
+        EventKind::Digitization, EventKind::SpectralFilterPass, EventKind::SpectralFilterReject, EventKind::NeutralDensity,
+        EventKind::BeamsplitterTransmitted, EventKind::BeamsplitterReflected, EventKind::FiberCouplingLoss, EventKind::AdcDigitization,
+        EventKind::Transport, EventKind::Split, EventKind::PeriodicBoundary, EventKind::MirrorBoundary, EventKind::OpenBoundary,
+        EventKind::MaxDepth, EventKind::Voxel, EventKind::Custom,
+    ];
+
+    pub fn from_event(event_id: &EventId, granularity: Granularity) -> Self {
+        Self::from_event_type(&event_id.event_type, granularity)
+    }
+
+    pub fn from_raw(raw: u32, granularity: Granularity) -> Self {
+        Self::from_event_type(&EventId::decode(raw).event_type, granularity)
+    }
+
+    pub fn from_event_type(event_type: &EventType, granularity: Granularity) -> Self {
+        match event_type {
+            EventType::None           => EventKind::None,
+            EventType::Emission(emission) => match granularity {
+                Granularity::Pipeline | Granularity::SuperType => EventKind::Emission,
+                _ => match emission {
+                    Emission::PencilBeam   => EventKind::PencilBeam,
+                    Emission::GaussianBeam => EventKind::GaussianBeam,
+                    Emission::PointSource  => EventKind::PointSource,
+                    Emission::PlaneSource  => EventKind::PlaneSource,
+                    Emission::PlaneWave    => EventKind::PlaneWave,
+                    Emission::Laser        => EventKind::Laser,
+                    Emission::Led          => EventKind::Led,
+                    Emission::Isotropic    => EventKind::Isotropic,
+                    Emission::Collimated   => EventKind::Collimated,
+                    Emission::PulsedLaser  => EventKind::PulsedLaser,
+                    Emission::SecondaryReEmission => EventKind::SecondaryReEmission,
+                },
+            },
+            EventType::MCRT(mcrt_event) => Self::from_mcrt(mcrt_event, granularity),
+            EventType::Detection(detection) => match granularity {
+                Granularity::Pipeline | Granularity::SuperType => EventKind::Detection,
+                _ => match detection {
+                    Detection::Direct   => EventKind::Direct,
+                    Detection::Rejected => EventKind::Rejected,
+                    Detection::TimeGated   => EventKind::TimeGated,
+                    Detection::Coincidence => EventKind::Coincidence,
+                    Detection::Saturated   => EventKind::Saturated,
+                },
+            },
+            EventType::Processing(processing) => match granularity {
+                Granularity::Pipeline | Granularity::SuperType => EventKind::Processing,
+                _ => match processing {
+                    Processing::Filtering    => EventKind::Filtering,
+                    Processing::Digitization => EventKind::Digitization,
+                    Processing::SpectralFilterPass      => EventKind::SpectralFilterPass,
+                    Processing::SpectralFilterReject    => EventKind::SpectralFilterReject,
+                    Processing::NeutralDensity          => EventKind::NeutralDensity,
+                    Processing::BeamsplitterTransmitted => EventKind::BeamsplitterTransmitted,
+                    Processing::BeamsplitterReflected   => EventKind::BeamsplitterReflected,
+                    Processing::FiberCouplingLoss       => EventKind::FiberCouplingLoss,
+                    Processing::AdcDigitization         => EventKind::AdcDigitization,
+                },
+            },
+            EventType::Transport(transport) => match granularity {
+                Granularity::Pipeline | Granularity::SuperType => EventKind::Transport,
+                _ => match transport {
+                    Transport::Split            => EventKind::Split,
+                    Transport::PeriodicBoundary => EventKind::PeriodicBoundary,
+                    Transport::MirrorBoundary   => EventKind::MirrorBoundary,
+                    Transport::OpenBoundary     => EventKind::OpenBoundary,
+                    Transport::MaxDepth         => EventKind::MaxDepth,
+                },
+            },
+            EventType::Voxel(_)       => EventKind::Voxel,
+            EventType::Custom(_)      => EventKind::Custom,
+        }
+    }
+
+    fn from_mcrt(mcrt_event: &MCRT, granularity: Granularity) -> Self {
+        match (granularity, mcrt_event) {
+            (Granularity::Pipeline, _)               => EventKind::MCRT,
+            (Granularity::SuperType, MCRT::Interface(_)) => EventKind::Interface,
+            (Granularity::SuperType, MCRT::Reflector(_)) => EventKind::Reflector,
+            (Granularity::SuperType, MCRT::Material(_))  => EventKind::Material,
+            (_, MCRT::Custom(_))                         => EventKind::Custom,
+            (_, MCRT::Interface(interface)) => match interface {
+                mcrt::Interface::Reflection  => EventKind::Reflection,
+                mcrt::Interface::Refraction  => EventKind::Refraction,
+                mcrt::Interface::FresnelSplit => EventKind::FresnelSplit,
+                mcrt::Interface::ReEmittance => EventKind::ReEmittance,
+            },
+            (_, MCRT::Reflector(reflector)) => match reflector {
+                mcrt::Reflector::Diffuse                  => EventKind::Diffuse,
+                mcrt::Reflector::Specular                 => EventKind::Specular,
+                mcrt::Reflector::Composite                => EventKind::Composite,
+                mcrt::Reflector::RetroReflective          => EventKind::RetroReflective,
+                mcrt::Reflector::CompositeRetroReflective => EventKind::CompositeRetroReflective,
+            },
+            (_, MCRT::Material(mcrt::Material::Absorption)) => EventKind::Absorption,
+            (Granularity::Full, MCRT::Material(mcrt::Material::Roulette(roulette))) => match roulette {
+                mcrt::Roulette::Survived { .. } => EventKind::RouletteSurvived,
+                mcrt::Roulette::Killed          => EventKind::RouletteKilled,
+            },
+            (_, MCRT::Material(mcrt::Material::Roulette(_))) => EventKind::Roulette,
+            (granularity, MCRT::Material(mcrt::Material::Inelastic(inelastic))) => match inelastic {
+                mcrt::Inelastic::Raman(dir) => Self::scatter(granularity, dir, [
+                    EventKind::Raman, EventKind::RamanAny, EventKind::RamanForward, EventKind::RamanSide, EventKind::RamanBackward,
+                ]),
+                mcrt::Inelastic::Fluorescence(dir) => Self::scatter(granularity, dir, [
+                    EventKind::Fluorescence, EventKind::FluorescenceAny, EventKind::FluorescenceForward, EventKind::FluorescenceSide, EventKind::FluorescenceBackward,
+                ]),
+            },
+            (granularity, MCRT::Material(mcrt::Material::Elastic(elastic))) => match elastic {
+                mcrt::Elastic::HenyeyGreenstein(dir) => Self::scatter(granularity, dir, [
+                    EventKind::HenyeyGreenstein, EventKind::HenyeyGreensteinAny, EventKind::HenyeyGreensteinForward, EventKind::HenyeyGreensteinSide, EventKind::HenyeyGreensteinBackward,
+                ]),
+                mcrt::Elastic::Mie(dir) => Self::scatter(granularity, dir, [
+                    EventKind::Mie, EventKind::MieAny, EventKind::MieForward, EventKind::MieSide, EventKind::MieBackward,
+                ]),
+                mcrt::Elastic::Rayleigh(dir) => Self::scatter(granularity, dir, [
+                    EventKind::Rayleigh, EventKind::RayleighAny, EventKind::RayleighForward, EventKind::RayleighSide, EventKind::RayleighBackward,
+                ]),
+                mcrt::Elastic::SphericalCdf(dir) => Self::scatter(granularity, dir, [
+                    EventKind::SphericalCdf, EventKind::SphericalCdfAny, EventKind::SphericalCdfForward, EventKind::SphericalCdfSide, EventKind::SphericalCdfBackward,
+                ]),
+            },
+        }
+    }
+
+    // Pick the kind of a scattering event from [subtype, any, forward, side, backward]
+    fn scatter(granularity: Granularity, dir: &ScatterDir, kinds: [EventKind; 5]) -> Self {
+        match (granularity, dir) {
+            (Granularity::Full, ScatterDir::Any)      => kinds[1],
+            (Granularity::Full, ScatterDir::Forward)  => kinds[2],
+            (Granularity::Full, ScatterDir::Side)     => kinds[3],
+            (Granularity::Full, ScatterDir::Backward) => kinds[4],
+            _ => kinds[0],
+        }
+    }
+
+    // Short human readable label, i.e. "Mie/Forward"
+    pub fn label(&self) -> String {
+        let name = format!("{:?}", self);
+        for dir in ["Any", "Forward", "Side", "Backward"] {
+            if let Some(scatter) = name.strip_suffix(dir)
+                && !scatter.is_empty()
+            {
+                return format!("{}/{}", scatter, dir);
+            }
+        }
+        name
+    }
+}
+
+impl std::fmt::Display for EventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.label())
+    }
+}
+
+// Elastic and inelastic material events, whose count is the scattering order of a chain
+pub fn is_scatter(raw_event: u32) -> bool {
+    matches!(
+        EventId::decode(raw_event).event_type,
+        EventType::MCRT(MCRT::Material(mcrt::Material::Elastic(_) | mcrt::Material::Inelastic(_)))
+    )
+}
+
+// Scattering order of each chain histogrammed with its weight, i.e. 1 to count the chains or the
+// number of photons which took it
+pub fn scatter_order_histogram<'a, I: IntoIterator<Item = (&'a [Uid], usize)>>(weighted_chains: I) -> BTreeMap<usize, usize> {
+    let mut orders = BTreeMap::new();
+    for (chain, weight) in weighted_chains {
+        let order = chain.iter().filter(|uid| is_scatter(uid.event)).count();
+        *orders.entry(order).or_default() += weight;
+    }
+    orders
+}
+
+impl FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(label: &str) -> Result<Self, Self::Err> {
+        EventKind::ALL
+            .iter()
+            .find(|kind| kind.label() == label)
+            .copied()
+            .ok_or_else(|| format!("Unknown event kind: {}", label))
+    }
+}
+
+// ----------------------------------------------------
+// Run-length summary of a chain
+// ----------------------------------------------------
+// Consecutive events of the same kind collapse into a single run, such that a long diffuse chain
+// reads as "PencilBeam, 14×HenyeyGreenstein, Absorption". Summaries are hashable and ordered, to
+// group chains by their shape, and serialize as their text form.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KindRun {
+    pub kind: EventKind,
+    pub count: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ChainSummary {
+    pub runs: Vec<KindRun>,
+}
+
+impl ChainSummary {
+    pub fn summarize(raw_events: impl IntoIterator<Item = u32>, granularity: Granularity) -> Self {
+        let mut runs: Vec<KindRun> = Vec::new();
+        for raw_event in raw_events {
+            let kind = EventKind::from_raw(raw_event, granularity);
+            match runs.last_mut() {
+                Some(run) if run.kind == kind => run.count += 1,
+                _ => runs.push(KindRun { kind, count: 1 }),
+            }
+        }
+        ChainSummary { runs }
+    }
+
+    // Number of events of the chain
+    pub fn len(&self) -> usize {
+        self.runs.iter().map(|run| run.count).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+}
+
+impl std::fmt::Display for ChainSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (idx, run) in self.runs.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+            if run.count > 1 {
+                write!(f, "{}×", run.count)?;
+            }
+            write!(f, "{}", run.kind)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ChainSummary {
+    type Err = String;
+
+    fn from_str(summary: &str) -> Result<Self, Self::Err> {
+        let mut runs = Vec::new();
+        for run in summary.split(',').map(str::trim).filter(|run| !run.is_empty()) {
+            let (count, kind) = match run.split_once('×') {
+                Some((count, kind)) => (
+                    count.trim().parse::<usize>().map_err(|_| format!("Invalid run count in `{}`", run))?,
+                    kind.trim(),
+                ),
+                None => (1, run),
+            };
+            runs.push(KindRun { kind: kind.parse()?, count });
+        }
+        Ok(ChainSummary { runs })
+    }
+}
+
+impl Serialize for ChainSummary {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ChainSummary {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Encode, SrcId, mcrt_event};
+
+    #[test]
+    fn event_kind_granularity() {
+        let raw_event = EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), SrcId::Mat(1)).encode();
+        assert_eq!(EventKind::from_raw(raw_event, Granularity::Pipeline), EventKind::MCRT);
+        assert_eq!(EventKind::from_raw(raw_event, Granularity::SuperType), EventKind::Material);
+        assert_eq!(EventKind::from_raw(raw_event, Granularity::SubType), EventKind::Mie);
+        assert_eq!(EventKind::from_raw(raw_event, Granularity::Full), EventKind::MieForward);
+
+        let event_id = EventId::new_emission(Emission::PlaneWave, SrcId::Light(0));
+        assert_eq!(EventKind::from_event(&event_id, Granularity::SuperType), EventKind::Emission);
+        assert_eq!(EventKind::from_event(&event_id, Granularity::Full), EventKind::PlaneWave);
+    }
+
+    #[test]
+    fn event_kind_labels_and_order() {
+        assert_eq!(EventKind::MieForward.to_string(), "Mie/Forward");
+        assert_eq!(EventKind::RamanAny.to_string(), "Raman/Any");
+        assert_eq!(EventKind::Refraction.to_string(), "Refraction");
+        assert_eq!(EventKind::Absorption.to_string(), "Absorption");
+        assert!(EventKind::Emission < EventKind::MCRT);
+        assert!(EventKind::MCRT < EventKind::Detection);
+        assert!(EventKind::ALL.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(EventKind::ALL.last(), Some(&EventKind::Custom));
+    }
+
+    #[test]
+    fn chain_summary_runs() {
+        let emission = EventId::new_emission(Emission::PencilBeam, SrcId::Light(0)).encode();
+        let forward = EventId::new_mcrt(mcrt_event!(Material, Elastic, HenyeyGreenstein, Forward), SrcId::Mat(1)).encode();
+        let backward = EventId::new_mcrt(mcrt_event!(Material, Elastic, HenyeyGreenstein, Backward), SrcId::Mat(1)).encode();
+        let absorption = EventId::new_mcrt(mcrt_event!(Material, Absorption), SrcId::Mat(1)).encode();
+        let mut chain = vec![emission];
+        chain.extend([forward; 10]);
+        chain.push(backward);
+        chain.extend([forward; 3]);
+        chain.push(absorption);
+
+        let summary = ChainSummary::summarize(chain.clone(), Granularity::SubType);
+        assert_eq!(summary.to_string(), "PencilBeam, 14×HenyeyGreenstein, Absorption");
+        assert_eq!(summary.len(), chain.len());
+        let full = ChainSummary::summarize(chain, Granularity::Full);
+        assert_eq!(full.runs.len(), 5);
+        assert_eq!(full.runs[2], KindRun { kind: EventKind::HenyeyGreensteinBackward, count: 1 });
+
+        let json = serde_json::to_string(&full).unwrap();
+        assert_eq!(json, "\"PencilBeam, 10×HenyeyGreenstein/Forward, HenyeyGreenstein/Backward, 3×HenyeyGreenstein/Forward, Absorption\"");
+        assert_eq!(serde_json::from_str::<ChainSummary>(&json).unwrap(), full);
+        assert!("3×Unknown".parse::<ChainSummary>().is_err());
+    }
+}
@@ -5,48 +5,85 @@ use serde_with::{DisplayFromStr, serde_as};
 use std::collections::HashMap;
 use std::str::FromStr;
 
-use crate::SrcId;
-use crate::{Encode, EventId, RawEvent};
+use crate::{SrcId, SrcKind};
+use crate::raw::{Pipeline, RawField};
+use crate::custom::CodeRegistry;
+use crate::error::{DecodeError, LedgerError};
+use crate::filter::{BitsMatch, FilterStep};
+use crate::kind::{ChainSummary, Granularity, is_scatter};
+use crate::recorder::{SamplingPolicy, splitmix64};
+use crate::wavelength::{Channel, WavelengthChannel};
+use crate::detection::{self, DetectorGeometry};
+use crate::mcrt::{Interface, MCRT, ScatterBinning, ScatterDir};
+use crate::{Decode, Encode, EventId, EventType, RawEvent, TryDecode};
 use serde_json;
 use std::fs::File;
 
 use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
 use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+mod compact;
+mod concurrent;
+mod hybrid;
+mod merge;
+
+pub use compact::SeqIdRemap;
+pub use concurrent::ConcurrentLedger;
+pub use merge::UidRemap;
+use hybrid::Spill;
 
 // ----------------------------------------------------
 // Definition of Unique IDentifier (Uid) and methods/traits
 // ----------------------------------------------------
 
+// Entries are generic over the width of their raw event, see `RawEvent`, u32 unless stated otherwise
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
-pub struct Uid {
+#[serde(bound = "")]
+pub struct Uid<E: RawEvent = u32> {
     pub seq_id: u32,
-    #[serde(serialize_with = "array_bytes::ser_hexify_prefixed", deserialize_with = "array_bytes::de_dehexify")]
-    pub event: u32, // u32 Event
+    #[serde(serialize_with = "E::ser_hex", deserialize_with = "E::de_hex")]
+    pub event: E,
 }
 
-impl Hash for Uid {
+impl<E: RawEvent> Hash for Uid<E> {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.encode().hash(state);
+        self.seq_id.hash(state);
+        self.event.raw().into().hash(state);
     }
 }
 
-impl std::fmt::Debug for Uid {
+// Zero-padded hex of the raw event, i.e. 0x{:08X} for u32 events
+fn event_hex<E: RawEvent>(event: &E) -> String {
+    format!("0x{:0width$X}", event.raw().into(), width = 2 * std::mem::size_of::<E::Raw>())
+}
+
+fn parse_event_hex<E: RawEvent>(hex: &str) -> Result<E, String> {
+    let raw = u64::from_str_radix(hex.trim_start_matches("0x"), 16).map_err(|e| e.to_string())?;
+    E::Raw::try_from(raw)
+        .map(E::from_raw)
+        .map_err(|_| format!("{} exceeds the {} bytes of the raw event", hex, std::mem::size_of::<E::Raw>()))
+}
+
+impl<E: RawEvent> std::fmt::Debug for Uid<E> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Uid(seq_id: {}, event: 0x{:08X})",
-            self.seq_id, self.event
+            "Uid(seq_id: {}, event: {})",
+            self.seq_id, event_hex(&self.event)
         )
     }
 }
 
-impl std::fmt::Display for Uid {
+impl<E: RawEvent> std::fmt::Display for Uid<E> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}, 0x{:08X}", self.seq_id, self.event)
+        write!(f, "{}, {}", self.seq_id, event_hex(&self.event))
     }
 }
 
-impl FromStr for Uid {
+impl<E: RawEvent> FromStr for Uid<E> {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let parts: Vec<&str> = s.split(", ").collect();
@@ -56,7 +93,7 @@ impl FromStr for Uid {
         }
         let seq_id = parts[0].parse::<u32>()
             .map_err(|e| format!("Failed to parse seq_id: {}", e))?;
-        let event = u32::from_str_radix(parts[1].trim_start_matches("0x"), 16)
+        let event = parse_event_hex(parts[1])
             .map_err(|e| format!("Failed to parse event: {}", e))?;
         Ok(Uid { seq_id, event })
     }
@@ -89,6 +126,7 @@ impl FromStr for SrcId {
             "Surf" => Ok(SrcId::Surf(id_value)),
             "MatSurf" => Ok(SrcId::MatSurf(id_value)),
             "Light" => Ok(SrcId::Light(id_value)),
+            "Detector" => Ok(SrcId::Detector(id_value)),
             _ => Err(format!("Unknown SrcId type: {}", id_type)),
         }
     }
@@ -127,18 +165,354 @@ pub enum SrcName {
     Detector(String),
 }
 
-impl ToString for SrcName {
-    fn to_string(&self) -> String {
+impl SrcName {
+    pub fn kind(&self) -> SrcKind {
+        match self {
+            SrcName::Light(_)    => SrcKind::Light,
+            SrcName::Surf(_)     => SrcKind::Surf,
+            SrcName::MatSurf(_)  => SrcKind::MatSurf,
+            SrcName::Mat(_)      => SrcKind::Mat,
+            SrcName::Detector(_) => SrcKind::Detector,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            SrcName::Light(name) | SrcName::Surf(name) | SrcName::MatSurf(name) | SrcName::Mat(name) | SrcName::Detector(name) => name,
+        }
+    }
+}
+
+impl std::fmt::Display for SrcName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            SrcName::Light(name) => name.clone(),
-            SrcName::Surf(name) => name.clone(),
-            SrcName::MatSurf(name) => name.clone(),
-            SrcName::Mat(name) => name.clone(),
-            SrcName::Detector(name) => name.clone(),
+            SrcName::Light(name) => write!(f, "{}", name),
+            SrcName::Surf(name) => write!(f, "{}", name),
+            SrcName::MatSurf(name) => write!(f, "{}", name),
+            SrcName::Mat(name) => write!(f, "{}", name),
+            SrcName::Detector(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+
+// Step of a chain, see `Ledger::describe_chain`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainEntry {
+    pub seq_id: u32,
+    pub event_id: EventId,
+    // Registered source of the event, see `Ledger::event_src`, and its names. The names are empty
+    // for unregistered sources.
+    pub src_id: SrcId,
+    pub names: Vec<SrcName>,
+}
+
+impl ChainEntry {
+    pub fn uid(&self) -> Uid {
+        Uid::new(self.seq_id, self.event_id.encode())
+    }
+}
+
+// i.e. "2: MCRT(Material(Elastic(Mie(Forward)))) Mat(0) [water]"
+impl std::fmt::Display for ChainEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<String> = self.names.iter().map(|name| name.to_string()).collect();
+        write!(f, "{}: {:?} {} [{}]", self.seq_id, self.event_id.event_type, self.src_id, names.join(", "))
+    }
+}
+
+
+// ----------------------------------------------------
+// Run metadata
+// ----------------------------------------------------
+// Describes the simulation run that produced the ledger, stored in the header of every format
+// (ledger JSON and the SrcTable of event streams), such that a file is self-describing.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct RunMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rng_seed: Option<u64>,
+    // Hash of the scene description, to check which geometry the src ids refer to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scene_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub engine_version: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, String>,
+}
+
+impl RunMetadata {
+    pub fn new(run_id: String) -> Self {
+        RunMetadata { run_id: Some(run_id), ..Default::default() }
+    }
+
+    pub fn with_seed(mut self, rng_seed: u64) -> Self {
+        self.rng_seed = Some(rng_seed);
+        self
+    }
+
+    pub fn with_scene_hash(mut self, scene_hash: String) -> Self {
+        self.scene_hash = Some(scene_hash);
+        self
+    }
+
+    pub fn with_engine_version(mut self, engine_version: String) -> Self {
+        self.engine_version = Some(engine_version);
+        self
+    }
+
+    pub fn with_entry(mut self, key: String, value: String) -> Self {
+        self.extra.insert(key, value);
+        self
+    }
+
+    // Fill the fields left unset with the ones of a merged run, keeping the set ones
+    pub fn merge(&mut self, other: &RunMetadata) {
+        self.run_id = self.run_id.take().or_else(|| other.run_id.clone());
+        self.rng_seed = self.rng_seed.or(other.rng_seed);
+        self.scene_hash = self.scene_hash.take().or_else(|| other.scene_hash.clone());
+        self.engine_version = self.engine_version.take().or_else(|| other.engine_version.clone());
+        for (key, value) in &other.extra {
+            self.extra.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+}
+
+// ----------------------------------------------------
+// Audit log
+// ----------------------------------------------------
+// Wall clock (microseconds since the unix epoch) of the registration of each source and of the start
+// and stop of event recording, to debug clock skew and missing segments across merged ledgers.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AuditEvent {
+    SrcRegistered { src_id: SrcId, name: SrcName },
+    RecordingStarted,
+    RecordingStopped,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub unix_micros: u64,
+    pub event: AuditEvent,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct AuditLog {
+    entries: Vec<AuditEntry>,
+}
+
+impl AuditLog {
+    pub fn entries(&self) -> &[AuditEntry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| !matches!(entry.event, AuditEvent::SrcRegistered { .. }))
+            .is_some_and(|entry| entry.event == AuditEvent::RecordingStarted)
+    }
+
+    // (start, stop) wall clock of each recording segment, stop is None while still recording
+    pub fn recording_segments(&self) -> Vec<(u64, Option<u64>)> {
+        let mut segments: Vec<(u64, Option<u64>)> = Vec::new();
+        for entry in &self.entries {
+            match entry.event {
+                AuditEvent::RecordingStarted => segments.push((entry.unix_micros, None)),
+                AuditEvent::RecordingStopped => {
+                    if let Some(segment) = segments.last_mut() {
+                        segment.1 = Some(entry.unix_micros);
+                    }
+                }
+                AuditEvent::SrcRegistered { .. } => {}
+            }
+        }
+        segments
+    }
+
+    // Entries of the audit log of a merged ledger, with its sources rewritten to the merged ones
+    fn merge(&mut self, other: &AuditLog, srcs: &BTreeMap<SrcId, SrcId>) {
+        self.entries.extend(other.entries.iter().map(|entry| {
+            let mut entry = entry.clone();
+            if let AuditEvent::SrcRegistered { src_id, .. } = &mut entry.event {
+                *src_id = srcs.get(src_id).cloned().unwrap_or(*src_id);
+            }
+            entry
+        }));
+        self.entries.sort_by_key(|entry| entry.unix_micros);
+    }
+
+    fn record(&mut self, event: AuditEvent) {
+        let unix_micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_micros() as u64)
+            .unwrap_or(0);
+        self.entries.push(AuditEntry { unix_micros, event });
+    }
+}
+
+// ----------------------------------------------------
+// Optional timestamps of ledger entries
+// ----------------------------------------------------
+// Stored in a parallel array indexed by the seq_id allocated to each entry (index 0 is the root
+// and stays unused), such that recording time costs a single f32 per entry.
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeBase {
+    // Simulation time provided by the caller through `insert_at`/`insert_start_at`
+    Simulation,
+    // Seconds elapsed since timestamps were enabled, recorded automatically on insert
+    WallClock,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Timestamps {
+    pub base: TimeBase,
+    values: Vec<f32>,
+}
+
+impl Timestamps {
+    pub fn new(base: TimeBase) -> Self {
+        Self { base, values: vec![0.0] }
+    }
+
+    pub fn get(&self, seq_id: u32) -> Option<f32> {
+        if seq_id == 0 {
+            return None;
+        }
+        self.values.get(seq_id as usize).cloned()
+    }
+
+    // A seq_id stamped already keeps the time of its first insertion
+    fn set(&mut self, seq_id: u32, time: f32) {
+        let idx = seq_id as usize;
+        if idx >= self.values.len() {
+            let last = self.values.last().cloned().unwrap_or(0.0);
+            self.values.resize(idx, last);
+            self.values.push(time);
+        }
+    }
+}
+
+// ----------------------------------------------------
+// Optional sampling probabilities of ledger entries
+// ----------------------------------------------------
+// Annotations of the stochastic choices made by the simulation, i.e. the probability of a biased
+// scattering direction and the weight multiplier compensating it, to audit importance sampling
+// schemes. Keyed as the timestamps by the seq_id allocated to each entry. Photons with identical
+// histories share an entry: the first annotation is kept, later ones are counted and flag the
+// entry as varying if they differ.
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ChoiceProbability {
+    // Probability with which the choice was sampled
+    pub probability: f64,
+    // Factor applied to the photon weight by the choice, 1 for analog sampling
+    pub weight_multiplier: f64,
+}
+
+impl ChoiceProbability {
+    pub fn analog(probability: f64) -> Self {
+        Self { probability, weight_multiplier: 1.0 }
+    }
+
+    pub fn biased(probability: f64, weight_multiplier: f64) -> Self {
+        Self { probability, weight_multiplier }
+    }
+
+    fn approx_eq(&self, other: &ChoiceProbability) -> bool {
+        let close = |a: f64, b: f64| (a - b).abs() <= 1e-9 * a.abs().max(b.abs()).max(1.0);
+        close(self.probability, other.probability) && close(self.weight_multiplier, other.weight_multiplier)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct EntryProbability {
+    pub choice: ChoiceProbability,
+    // Number of annotated photons through the entry
+    pub samples: u64,
+    pub varying: bool,
+}
+
+// ----------------------------------------------------
+// External photon packet ids of ledger entries
+// ----------------------------------------------------
+// Downstream simulations (i.e. detector electronics) refer to photons by their own packet ids.
+// Photons with identical histories share a UID, so a UID can carry several packet ids while each
+// packet id maps to a single UID. Only the packet -> UID map is serialized, the reverse map is
+// rebuilt on load.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PacketTags<E: RawEvent = u32> {
+    uids: BTreeMap<u64, Uid<E>>,
+    packets: HashMap<Uid<E>, Vec<u64>>,
+}
+
+impl<E: RawEvent> Default for PacketTags<E> {
+    fn default() -> Self {
+        Self { uids: BTreeMap::new(), packets: HashMap::new() }
+    }
+}
+
+impl<E: RawEvent> PacketTags<E> {
+    pub fn is_empty(&self) -> bool {
+        self.uids.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.uids.len()
+    }
+
+    fn insert(&mut self, uid: Uid<E>, packet_id: u64) -> Result<(), LedgerError> {
+        match self.uids.get(&packet_id) {
+            Some(tagged) if *tagged == uid => Ok(()),
+            Some(tagged) => Err(LedgerError::PacketTagged { packet_id, uid: tagged.to_string() }),
+            None => {
+                self.uids.insert(packet_id, uid);
+                self.packets.entry(uid).or_default().push(packet_id);
+                Ok(())
+            }
+        }
+    }
+
+    // Tags with their UID rewritten, dropping the ones `uid` doesn't map, i.e. of removed entries
+    fn remap(&self, uid: impl Fn(&Uid<E>) -> Option<Uid<E>>) -> Self {
+        let mut tags = Self::default();
+        for (packet_id, tagged) in &self.uids {
+            if let Some(tagged) = uid(tagged) {
+                tags.uids.insert(*packet_id, tagged);
+                tags.packets.entry(tagged).or_default().push(*packet_id);
+            }
         }
+        tags
+    }
+}
+
+impl<E: RawEvent> Serialize for PacketTags<E> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.uids.iter().map(|(packet_id, uid)| (packet_id, uid.to_string())))
     }
 }
 
+impl<'de, E: RawEvent> Deserialize<'de> for PacketTags<E> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let tagged = BTreeMap::<u64, String>::deserialize(deserializer)?;
+        let mut tags = PacketTags::default();
+        for (packet_id, uid) in tagged {
+            let uid = Uid::<E>::from_str(&uid).map_err(serde::de::Error::custom)?;
+            tags.insert(uid, packet_id).map_err(serde::de::Error::custom)?;
+        }
+        Ok(tags)
+    }
+}
 
 // ----------------------------------------------------
 // Definition of Ledger struct and methods
@@ -150,149 +524,1068 @@ impl ToString for SrcName {
 //   - insert events and build the event chain
 //   - query events, using the next/prev maps as a doubled linked list
 
+// Spilled entries are read back one group at a time while writing, such that a spilling ledger is
+// written without loading its entries back in memory
 pub fn write_ledger_to_json<P>(ledger: &Ledger, file_path: P) -> Result<(), serde_json::Error>
 where
     P: AsRef<std::path::Path>,
 {
-    // Write the JSON string to a file
-    let file = File::create(file_path).expect("Unable to create file");
-    serde_json::to_writer_pretty(file, ledger)
+    use serde::ser::{SerializeMap, Serializer};
+    use std::io::Write;
+
+    let mut writer = std::io::BufWriter::new(File::create(file_path).map_err(serde_json::Error::io)?);
+    if ledger.spilled_len() == 0 {
+        serde_json::to_writer_pretty(&mut writer, ledger)?;
+    } else {
+        // Without its spilled entries, the rest of the ledger is within the memory budget
+        let serde_json::Value::Object(fields) = serde_json::to_value(ledger)? else {
+            unreachable!("A ledger serializes to a JSON object");
+        };
+        let mut serializer = serde_json::Serializer::pretty(&mut writer);
+        let mut map = serializer.serialize_map(Some(fields.len()))?;
+        for (key, value) in &fields {
+            if key == "next" {
+                map.serialize_entry(key, &NextGroups(ledger))?;
+            } else {
+                map.serialize_entry(key, value)?;
+            }
+        }
+        map.end()?;
+    }
+    writer.flush().map_err(serde_json::Error::io)
+}
+
+pub fn read_ledger_from_json<P>(file_path: P) -> Result<Ledger, serde_json::Error>
+where
+    P: AsRef<std::path::Path>,
+{
+    // NOTE: Read the whole file before parsing, `de_dehexify` expects a borrowed value which
+    // `serde_json::from_reader` can't provide
+    let contents = std::fs::read_to_string(file_path).map_err(serde_json::Error::io)?;
+    parse_ledger_json(&contents)
+}
+
+// Parse a ledger, failing on events which don't decode rather than panicking once they are read
+pub fn parse_ledger_json(contents: &str) -> Result<Ledger, serde_json::Error> {
+    let ledger: Ledger = serde_json::from_str(contents)?;
+    ledger.validate_events().map_err(<serde_json::Error as serde::de::Error>::custom)?;
+    Ok(ledger)
+}
+
+// Reproducible random subset of `n` UIDs, keeping their relative order. All UIDs are returned if
+// there are no more than `n`.
+pub fn sample_uids(uids: &[Uid], n: usize, seed: u64) -> Vec<Uid> {
+    if uids.len() <= n {
+        return uids.to_vec();
+    }
+    // Partial Fisher-Yates shuffle over the indices
+    let mut state = seed;
+    let mut indices: Vec<usize> = (0..uids.len()).collect();
+    for i in 0..n {
+        let j = i + (splitmix64(&mut state) % (uids.len() - i) as u64) as usize;
+        indices.swap(i, j);
+    }
+    let mut picked = indices[..n].to_vec();
+    picked.sort_unstable();
+    picked.into_iter().map(|idx| uids[idx]).collect()
 }
 
 #[serde_as]
 #[derive(Serialize, Deserialize)]
-pub struct Ledger {
+#[serde(bound = "")]
+pub struct Ledger<E: RawEvent = u32> {
     grps: HashMap<String, SrcId>, // Key: Group name
-    #[serde_as(as = "HashMap<DisplayFromStr, _>")]
+    #[serde_as(as = "SrcRecords")]
     src_map: HashMap<SrcId, Vec<SrcName>>, // Value: Material name, object name, light name.
-    start_events: Vec<Uid>,
+    // (alias, canonical) pairs of the sources merged after the run, see `alias_src`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    aliases: Vec<(SrcId, SrcId)>,
+    start_events: Vec<Uid<E>>,
 
     next_mat_id: u16,
     next_surf_id: u16,
     next_matsurf_id: u16,
     next_light_id: u16,
+    // Optional for ledgers written before detectors were registered
+    #[serde(default)]
+    next_detector_id: u16,
+    // Sources past the 16-bit ids, only held by wide events, see `with_wide_src`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    wide_srcs: BTreeMap<u32, SrcName>,
+    #[serde(default, skip_serializing_if = "LedgerConfig::is_default")]
+    config: LedgerConfig,
 
     // Use a nested map: (seq_id -> (uid -> next_seq_id)) instead of (seq_id, uid) -> next_seq_id in order to
     // retrieve be able to do a depth search based on seq_id
     #[serde_as(as = "BTreeMap<_, HexInnerMap>")]
-    next: BTreeMap<u32, BTreeMap<u32, u32>>,
+    next: BTreeMap<u32, BTreeMap<E, u32>>,
     // TODO: Display of Uid represent event:u32 in hex format `0x{:08X}
     #[serde_as(as = "BTreeMap<_, DisplayFromStr>")]
-    prev: BTreeMap<u32, Uid>,
+    prev: BTreeMap<u32, Uid<E>>,
     next_seq_id: u32,
+
+    // Labels of custom pipeline codes, optional for ledgers written before it was introduced
+    #[serde(default, skip_serializing_if = "CodeRegistry::is_empty")]
+    code_registry: CodeRegistry,
+
+    // Wavelength bands of the channels tagged on emission and inelastic events. Key: channel id
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    channels: BTreeMap<u8, WavelengthChannel>,
+
+    // Rows x cols of the array detectors recording pixel indices. Key: detector id
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    detector_geometries: BTreeMap<u16, DetectorGeometry>,
+
+    // Opt-in per-entry timestamps, see `enable_timestamps`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamps: Option<Timestamps>,
+    #[serde(skip)]
+    clock_start: Option<Instant>,
+
+    // Sampling applied by the Recorder, such that analyses can correct for it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sampling_policy: Option<SamplingPolicy>,
+    // Scatter depth past which the Recorder truncates chains with a `Transport::MaxDepth` event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_depth: Option<u32>,
+    // Angular bins of the recorded scatter directions, the default ones if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scatter_binning: Option<ScatterBinning>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<RunMetadata>,
+    #[serde(default, skip_serializing_if = "AuditLog::is_empty")]
+    audit: AuditLog,
+
+    // Secondary photons (i.e. fluorescence re-emission) start a new root linked to the event of
+    // their parent photon, see `insert_child_root`. Key: seq_id of the child root
+    #[serde_as(as = "BTreeMap<_, DisplayFromStr>")]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    parents: BTreeMap<u32, Uid<E>>,
+    #[serde_as(as = "BTreeMap<DisplayFromStr, Vec<DisplayFromStr>>")]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    child_roots: BTreeMap<Uid<E>, Vec<Uid<E>>>,
+
+    // External packet ids of leaf entries, see `tag_packet`
+    #[serde(default, skip_serializing_if = "PacketTags::is_empty")]
+    packet_tags: PacketTags<E>,
+
+    // Sampling probabilities of the entries, see `annotate_probability`. Key: allocated seq_id
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    probabilities: BTreeMap<u32, EntryProbability>,
+
+    // Number of inserts of each entry, i.e. of photons taking the transition, see `get_count`.
+    // Key: allocated seq_id, as the probabilities, rather than a copy of the entry
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    counts: BTreeMap<u32, u64>,
+    // Summed photon weights of the transitions, see `insert_weighted`. Key: allocated seq_id
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    weights: BTreeMap<u32, f64>,
+
+    // `chain_depth` of the entries inserted while a `max_depth` is set, such that the Recorder
+    // doesn't walk the whole chain on every insert. Key: allocated seq_id
+    #[serde(skip)]
+    depths: BTreeMap<u32, u32>,
+
+    #[serde(skip)]
+    subscriptions: Vec<Subscription<E>>,
+    #[serde(skip)]
+    next_subscription_id: usize,
+
+    // Cold entries moved to disk under a memory budget, see `enable_spill`
+    #[serde(skip)]
+    spill: Option<Spill<E>>,
+    // Bumped whenever the content of `src_table` changes, such that streams can re-sync it
+    #[serde(skip)]
+    src_revision: u64,
 }
 
+// ----------------------------------------------------
+// On-insert subscriptions
+// ----------------------------------------------------
+// A subscription fires when a newly inserted entry matches the last BitsMatch of its sequence and
+// the chain leading to it contains the preceding ones in order, i.e. the same sequence semantics
+// as `find_forward_uid_seq`. The callback receives the chain, from the start event to the entry.
 
-impl Ledger {
-    pub fn new() -> Self {
+pub type SubscriptionCallback<E = u32> = Box<dyn FnMut(&[Uid<E>]) + Send + Sync>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(usize);
+
+struct Subscription<E: RawEvent> {
+    id: SubscriptionId,
+    filter: Vec<FilterStep>,
+    callback: SubscriptionCallback<E>,
+}
+
+impl<E: RawEvent> Subscription<E> {
+    fn matches(&self, chain: &[Uid<E>]) -> bool {
+        // Greedy match of the steps in reverse, starting with the last step on the last entry
+        let mut steps = self.filter.iter().rev().peekable();
+        let mut entries = chain.iter().rev();
+        match (steps.next(), entries.next()) {
+            (Some(step), Some(uid)) if step.matches_event(&uid.event) => {}
+            _ => return false,
+        }
+        for uid in entries {
+            if let Some(step) = steps.peek()
+                && step.matches_event(&uid.event)
+            {
+                steps.next();
+            }
+        }
+        steps.peek().is_none()
+    }
+}
+
+// Branches following a `mcrt::Interface::FresnelSplit` event, told apart by the interface event
+// of the child starting them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FresnelBranch {
+    Reflected,
+    Transmitted,
+}
+
+impl FresnelBranch {
+    fn of(raw_event: u32) -> Option<Self> {
+        let interface_mask = Pipeline::mask() | crate::raw::MCRT::mask() | crate::raw::Interface::mask();
+        let interface = |event: Interface| Pipeline::MCRT.encode() | MCRT::Interface(event).encode();
+        match raw_event & interface_mask {
+            value if value == interface(Interface::Reflection) => Some(FresnelBranch::Reflected),
+            value if value == interface(Interface::Refraction) => Some(FresnelBranch::Transmitted),
+            _ => None,
+        }
+    }
+}
+
+fn is_fresnel_split(raw_event: u32) -> bool {
+    let interface_mask = Pipeline::mask() | crate::raw::MCRT::mask() | crate::raw::Interface::mask();
+    raw_event & interface_mask == Pipeline::MCRT.encode() | MCRT::Interface(Interface::FresnelSplit).encode()
+}
+
+// ----------------------------------------------------
+// Ranges of the source ids
+// ----------------------------------------------------
+// Ids of each source kind are allocated within their range of the 16 bits of the SrcId field, Mat,
+// Surf, Light and Detector ids upwards from the start of their range and MatSurf ids downwards from
+// its end. Mat and Surf ids share the ids of MCRT events with MatSurf ids, hence where their ranges
+// overlap the MatSurf one they meet in between, as large as the scene needs. The default ranges span
+// the whole 16 bits, a scene with many material-surface pairs can reserve them a range of their own:
+//     let config = LedgerConfig { matsurf_range: 0..=49_999, mat_range: 50_000..=65_535, ..Default::default() };
+// Id u16::MAX is only allocated downwards and id 0 upwards, such that the counters never wrap around.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LedgerConfig {
+    pub mat_range: RangeInclusive<u16>,
+    pub surf_range: RangeInclusive<u16>,
+    pub matsurf_range: RangeInclusive<u16>,
+    pub light_range: RangeInclusive<u16>,
+    pub detector_range: RangeInclusive<u16>,
+}
+
+impl Default for LedgerConfig {
+    fn default() -> Self {
+        Self {
+            mat_range: 0..=u16::MAX,
+            surf_range: 0..=u16::MAX,
+            matsurf_range: 0..=u16::MAX,
+            light_range: 0..=u16::MAX,
+            detector_range: 0..=u16::MAX,
+        }
+    }
+}
+
+impl LedgerConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn range(&self, kind: SrcKind) -> Option<&RangeInclusive<u16>> {
+        match kind {
+            SrcKind::Mat => Some(&self.mat_range),
+            SrcKind::Surf => Some(&self.surf_range),
+            SrcKind::MatSurf => Some(&self.matsurf_range),
+            SrcKind::Light => Some(&self.light_range),
+            SrcKind::Detector => Some(&self.detector_range),
+            SrcKind::None => None,
+        }
+    }
+
+    fn validate(&self) -> Result<(), LedgerError> {
+        for kind in [SrcKind::Mat, SrcKind::Surf, SrcKind::MatSurf, SrcKind::Light, SrcKind::Detector] {
+            if self.range(kind).is_some_and(|range| range.is_empty()) {
+                return Err(LedgerError::EmptySrcRange(kind));
+            }
+        }
+        Ok(())
+    }
+
+    // Whether Mat or Surf ids of `range` can meet the MatSurf ids
+    fn shares_matsurf_ids(&self, range: &RangeInclusive<u16>) -> bool {
+        range.start() <= self.matsurf_range.end() && self.matsurf_range.start() <= range.end()
+    }
+}
+
+// Snapshot of the registered sources and id counters of a Ledger, without any events. Used as
+// header of event streams, such that a Ledger can be rebuilt from the streamed events.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SrcTable {
+    grps: HashMap<String, SrcId>,
+    #[serde_as(as = "SrcRecords")]
+    src_map: HashMap<SrcId, Vec<SrcName>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    aliases: Vec<(SrcId, SrcId)>,
+
+    next_mat_id: u16,
+    next_surf_id: u16,
+    next_matsurf_id: u16,
+    next_light_id: u16,
+    // Optional for ledgers written before detectors were registered
+    #[serde(default)]
+    next_detector_id: u16,
+    // Sources past the 16-bit ids, only held by wide events, see `with_wide_src`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    wide_srcs: BTreeMap<u32, SrcName>,
+    #[serde(default, skip_serializing_if = "LedgerConfig::is_default")]
+    config: LedgerConfig,
+
+    #[serde(default, skip_serializing_if = "CodeRegistry::is_empty")]
+    code_registry: CodeRegistry,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    channels: BTreeMap<u8, WavelengthChannel>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    detector_geometries: BTreeMap<u16, DetectorGeometry>,
+    // Not a source, but the stream readers need it to interpret the recorded events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sampling_policy: Option<SamplingPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_depth: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scatter_binning: Option<ScatterBinning>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<RunMetadata>,
+    #[serde(default, skip_serializing_if = "AuditLog::is_empty")]
+    audit: AuditLog,
+}
+
+// Changes of a SrcTable since an earlier one of the same run, as made by registering sources: the
+// new or renamed sources, the ones merged away, the id counters and the appended audit entries.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct SrcTableDelta {
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    grps: HashMap<String, SrcId>,
+    #[serde_as(as = "SrcRecords")]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    src_map: HashMap<SrcId, Vec<SrcName>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    removed_srcs: Vec<SrcId>,
+    // All the aliases, only when they changed as re-aliasing rewrites the earlier ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    aliases: Option<Vec<(SrcId, SrcId)>>,
+
+    next_mat_id: u16,
+    next_surf_id: u16,
+    next_matsurf_id: u16,
+    next_light_id: u16,
+    next_detector_id: u16,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    wide_srcs: BTreeMap<u32, SrcName>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    audit: Vec<AuditEntry>,
+}
+
+impl SrcTable {
+    // None when the changes go past registering sources, i.e. the settings changed or the audit log
+    // was replaced, then the full table has to be written again
+    pub(crate) fn delta_since(&self, prev: &SrcTable) -> Option<SrcTableDelta> {
+        let same_settings = self.config == prev.config
+            && self.code_registry == prev.code_registry
+            && self.channels == prev.channels
+            && self.detector_geometries == prev.detector_geometries
+            && self.sampling_policy == prev.sampling_policy
+            && self.max_depth == prev.max_depth
+            && self.scatter_binning == prev.scatter_binning
+            && self.metadata == prev.metadata;
+        let prev_audit = prev.audit.entries();
+        let appended_audit = self.audit.entries().starts_with(prev_audit);
+        let kept_grps = prev.grps.keys().all(|grp| self.grps.contains_key(grp));
+        let kept_wide_srcs = prev.wide_srcs.keys().all(|id| self.wide_srcs.contains_key(id));
+        if !(same_settings && appended_audit && kept_grps && kept_wide_srcs) {
+            return None;
+        }
+
+        Some(SrcTableDelta {
+            grps: changed_entries(&self.grps, &prev.grps),
+            src_map: changed_entries(&self.src_map, &prev.src_map),
+            removed_srcs: prev.src_map.keys().filter(|src_id| !self.src_map.contains_key(src_id)).copied().collect(),
+            aliases: (self.aliases != prev.aliases).then(|| self.aliases.clone()),
+            next_mat_id: self.next_mat_id,
+            next_surf_id: self.next_surf_id,
+            next_matsurf_id: self.next_matsurf_id,
+            next_light_id: self.next_light_id,
+            next_detector_id: self.next_detector_id,
+            wide_srcs: self
+                .wide_srcs
+                .iter()
+                .filter(|(id, name)| prev.wide_srcs.get(id) != Some(name))
+                .map(|(id, name)| (*id, name.clone()))
+                .collect(),
+            audit: self.audit.entries()[prev_audit.len()..].to_vec(),
+        })
+    }
+
+    pub(crate) fn apply(&mut self, delta: SrcTableDelta) {
+        self.grps.extend(delta.grps);
+        for src_id in &delta.removed_srcs {
+            self.src_map.remove(src_id);
+        }
+        self.src_map.extend(delta.src_map);
+        if let Some(aliases) = delta.aliases {
+            self.aliases = aliases;
+        }
+        self.next_mat_id = delta.next_mat_id;
+        self.next_surf_id = delta.next_surf_id;
+        self.next_matsurf_id = delta.next_matsurf_id;
+        self.next_light_id = delta.next_light_id;
+        self.next_detector_id = delta.next_detector_id;
+        self.wide_srcs.extend(delta.wide_srcs);
+        self.audit.entries.extend(delta.audit);
+    }
+}
+
+fn changed_entries<K: Clone + Eq + Hash, V: Clone + PartialEq>(map: &HashMap<K, V>, prev: &HashMap<K, V>) -> HashMap<K, V> {
+    map.iter()
+        .filter(|(key, value)| prev.get(key) != Some(value))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+impl<E: RawEvent> Default for Ledger<E> {
+    fn default() -> Self {
         Self {
             grps: HashMap::new(),
             src_map: HashMap::new(),
+            aliases: Vec::new(),
             start_events: Vec::new(),
             next_mat_id: 0,
             next_surf_id: 0,
             next_matsurf_id: u16::MAX,
             next_light_id: 0,
+            next_detector_id: 0,
+            wide_srcs: BTreeMap::new(),
+            config: LedgerConfig::default(),
             next: BTreeMap::new(),
             prev: BTreeMap::new(),
             next_seq_id: 0,
+            code_registry: CodeRegistry::new(),
+            channels: BTreeMap::new(),
+            detector_geometries: BTreeMap::new(),
+            timestamps: None,
+            clock_start: None,
+            sampling_policy: None,
+            max_depth: None,
+            scatter_binning: None,
+            metadata: None,
+            audit: AuditLog::default(),
+            parents: BTreeMap::new(),
+            child_roots: BTreeMap::new(),
+            packet_tags: PacketTags::default(),
+            probabilities: BTreeMap::new(),
+            counts: BTreeMap::new(),
+            weights: BTreeMap::new(),
+            depths: BTreeMap::new(),
+            subscriptions: Vec::new(),
+            next_subscription_id: 0,
+            spill: None,
+            src_revision: 0,
         }
     }
+}
 
-    pub fn with_light(&mut self, light_name: String) -> SrcId {
-        let light_id = SrcId::Light(self.next_light_id);
-        self.next_light_id += 1;
-        match self.src_map.get_mut(&light_id) {
-            Some(_value) => {
-                panic!("Light ID {} already exists in src_map", *light_id);
-                //value.push(SrcName::Light(light_name))
-            }
-            None => {
-                self.src_map
-                    .insert(light_id.clone(), vec![SrcName::Light(light_name)]);
-            }
-        };
-        light_id
-    }
+// ----------------------------------------------------
+// Recording and traversal of the entries, for any width of raw events
+// ----------------------------------------------------
+// Filters (BitsMatch) are applied with `RawEvent::matches`, the decoding and source features below
+// are specific to u32 events.
 
-    pub fn with_surf(&mut self, obj_name: String, grp: Option<String>) -> SrcId {
-        let src_id = if let Some(grp_name) = grp {
-            let src_id = match self.grps.get(&grp_name) {
-                Some(src_id) => src_id.clone(),
-                None => {
-                    // Create new SurfId
-                    let surf_id = SrcId::Surf(self.next_surf_id);
-                    self.next_surf_id += 1;
-                    self.grps.insert(grp_name.clone(), surf_id.clone());
-                    surf_id
-                }
-            };
+impl<E: RawEvent> Ledger<E> {
+    // Fails with `LedgerError::SeqIdsExhausted` once every seq_id was allocated, as `insert`
+    pub fn insert_start(&mut self, start_event: EventId) -> Result<Uid<E>, LedgerError> {
+        self.insert_start_timed(E::from_event(&start_event), None)
+    }
 
-            let grp_src_id = match src_id {
-                SrcId::Surf(_) => src_id,
-                SrcId::MatSurf(_) => src_id,
-                SrcId::Mat(_) => {
-                    let matsurf_id = self.next_matsurf_id;
-                    self.next_matsurf_id -= 1;
+    // Same as `insert_start`, recording the simulation `time` of the event if timestamps are enabled
+    pub fn insert_start_at(&mut self, start_event: EventId, time: f32) -> Result<Uid<E>, LedgerError> {
+        self.insert_start_timed(E::from_event(&start_event), Some(time))
+    }
 
-                    warn!(
-                        "Discarding {:?} and allocate MatSurf({}), moving Map({:?}) to Map(Mat({}))",
-                        src_id, matsurf_id, src_id, matsurf_id
-                    );
-                    if let Some(mat_names) = self.src_map.remove(&SrcId::Mat(*src_id)) {
-                        self.src_map.insert(SrcId::Mat(matsurf_id), mat_names);
-                    } else {
-                        panic!("Material ID {} not found in src_map", *src_id);
-                    }
+    // Same as `insert_start` with an already encoded event, i.e. a `RawEvent64` using its extension fields
+    pub fn insert_start_raw(&mut self, start_event: E) -> Result<Uid<E>, LedgerError> {
+        self.insert_start_timed(start_event, None)
+    }
 
-                    SrcId::MatSurf(matsurf_id)
-                }
-                SrcId::Light(_) => {
-                    panic!("Group name {} already used for a light source", grp_name);
-                }
-                SrcId::None => {
-                    panic!("Group name {} registered an invalid None source", grp_name);
-                }
-            };
+    // Same as `insert_start`, with the initial `weight` of the photon, see `insert_weighted`
+    pub fn insert_start_weighted(&mut self, start_event: EventId, weight: f64) -> Result<Uid<E>, LedgerError> {
+        let uid = self.insert_start_timed(E::from_event(&start_event), None)?;
+        self.add_weight(&uid, weight);
+        Ok(uid)
+    }
 
-            grp_src_id
-        } else {
-            let surf_id = SrcId::Surf(self.next_surf_id);
-            self.next_surf_id += 1;
-            surf_id
-        };
+    // Each start event allocates a seq_id of its own, the first one 1, such that the chains of
+    // different start events never share a group
+    fn insert_start_timed(&mut self, start_event: E, time: Option<f32>) -> Result<Uid<E>, LedgerError> {
+        let uid = Uid { seq_id: 0, event: start_event };
 
-        match self.src_map.get_mut(&src_id) {
-            Some(value) => value.push(SrcName::Surf(obj_name)),
-            None => {
-                self.src_map
-                    .insert(src_id.clone(), vec![SrcName::Surf(obj_name)]);
+        if !self.contains(&uid) {
+            if self.remaining_seq_ids() == 0 {
+                return Err(LedgerError::SeqIdsExhausted);
             }
-        };
+            let next_seq_id = self.next_seq_id.max(1);
+            self.insert_entry(uid, next_seq_id);
+            self.start_events.push(uid);
+            self.stamp(next_seq_id, 0, time);
+            self.next_seq_id = next_seq_id + 1;
+            self.notify(uid);
+        }
+        self.count(&uid);
 
-        self.check_ids();
+        Ok(uid)
+    }
+
+    // Fails with `LedgerError::SeqIdsExhausted` rather than wrapping around, once every seq_id was
+    // allocated, which only happens for extremely complex simulation scenes
+    pub fn insert(&mut self, prev_event: Uid<E>, event: EventId) -> Result<Uid<E>, LedgerError> {
+        self.insert_timed(prev_event, E::from_event(&event), None)
+    }
+
+    // Same as `insert`, recording the simulation `time` of the event if timestamps are enabled
+    pub fn insert_at(&mut self, prev_event: Uid<E>, event: EventId, time: f32) -> Result<Uid<E>, LedgerError> {
+        self.insert_timed(prev_event, E::from_event(&event), Some(time))
+    }
+
+    // Same as `insert` with an already encoded event, see `insert_start_raw`
+    pub fn insert_raw(&mut self, prev_event: Uid<E>, event: E) -> Result<Uid<E>, LedgerError> {
+        self.insert_timed(prev_event, event, None)
+    }
+
+    // Same as `insert`, adding the `weight` of the photon to the one flowing through the transition
+    pub fn insert_weighted(&mut self, prev_event: Uid<E>, event: EventId, weight: f64) -> Result<Uid<E>, LedgerError> {
+        let uid = self.insert_timed(prev_event, E::from_event(&event), None)?;
+        self.add_weight(&uid, weight);
+        Ok(uid)
+    }
+
+    fn insert_timed(&mut self, prev_event: Uid<E>, event: E, time: Option<f32>) -> Result<Uid<E>, LedgerError> {
+        // Push a new entry in next with the new_event UID if it doesn't exist already and
+        //    set count to 1
+        // Obs: seq_id=0 is reserved for root identification, hence all new events with no
+        // previous cause start with seq_id=0
+        let next_seq_id = self
+            .get_next_seq_id(&prev_event)
+            .ok_or_else(|| LedgerError::UnknownUid(prev_event.to_string()))?;
+
+        let uid = Uid { seq_id: next_seq_id, event };
+        if self.remaining_seq_ids() == 0 && !self.contains(&uid) {
+            return Err(LedgerError::SeqIdsExhausted);
+        }
+
+        // NOTE: This is the only portion of the Ledger that needs to be accessed concurrently, see
+        // `ConcurrentLedger` for inserts from several threads without Arc<Mutex>
+        if self.insert_entry(uid, self.next_seq_id) {
+            self.stamp(self.next_seq_id, next_seq_id, time);
+            if self.max_depth.is_some() {
+                self.depths.insert(self.next_seq_id, self.chain_depth(uid));
+            }
+            self.next_seq_id += 1;
+            self.notify(uid);
+            self.spill_cold_entries()?;
+        }
+        self.count(&uid);
+
+        Ok(uid)
+    }
+
+    // Start the chain of a secondary photon emitted as a consequence of `parent`, i.e. the
+    // re-emission following a fluorescence absorption. The child root gets a seq_id of its own
+    // without a previous entry, such that `get_chain` stops at it, while `get_parent` and the
+    // `*_across` traversals follow the link to the parent.
+    pub fn insert_child_root(&mut self, parent: Uid<E>, event: EventId) -> Result<Uid<E>, LedgerError> {
+        self.insert_child_root_timed(parent, event, None)
+    }
+
+    pub fn insert_child_root_at(&mut self, parent: Uid<E>, event: EventId, time: f32) -> Result<Uid<E>, LedgerError> {
+        self.insert_child_root_timed(parent, event, Some(time))
+    }
+
+    fn insert_child_root_timed(&mut self, parent: Uid<E>, event: EventId, time: Option<f32>) -> Result<Uid<E>, LedgerError> {
+        let parent_next_seq_id = self
+            .get_next_seq_id(&parent)
+            .ok_or_else(|| LedgerError::UnknownUid(parent.to_string()))?;
+
+        let raw_event = E::from_event(&event);
+        if let Some(uid) = self.get_child_roots(&parent).iter().find(|uid| uid.event == raw_event).cloned() {
+            self.count(&uid);
+            return Ok(uid);
+        }
+
+        // The child root allocates its own seq_id and the one of its subsequent events
+        if self.remaining_seq_ids() < 2 {
+            return Err(LedgerError::SeqIdsExhausted);
+        }
+        let uid = Uid { seq_id: self.next_seq_id, event: raw_event };
+        self.insert_entry(uid, self.next_seq_id + 1);
+        self.stamp(self.next_seq_id + 1, parent_next_seq_id, time);
+        if self.max_depth.is_some() {
+            self.depths.insert(self.next_seq_id + 1, self.chain_depth(uid));
+        }
+        self.next_seq_id += 2;
+
+        self.parents.insert(uid.seq_id, parent);
+        self.child_roots.entry(parent).or_default().push(uid);
+        self.notify(uid);
+        self.count(&uid);
+        self.spill_cold_entries()?;
+
+        Ok(uid)
+    }
+
+    fn add_weight(&mut self, uid: &Uid<E>, weight: f64) {
+        if let Some(next_seq_id) = self.get_next_seq_id(uid) {
+            *self.weights.entry(next_seq_id).or_default() += weight;
+        }
+    }
+
+    fn count(&mut self, uid: &Uid<E>) {
+        if let Some(next_seq_id) = self.get_next_seq_id(uid) {
+            *self.counts.entry(next_seq_id).or_default() += 1;
+        }
+    }
+
+    // Number of seq_ids left to allocate, each new entry taking one and each child root two
+    pub fn remaining_seq_ids(&self) -> u32 {
+        u32::MAX - self.next_seq_id.max(2)
+    }
+
+    // Number of photons which took the transition to `uid`, i.e. of inserts of the entry. 0 for
+    // entries not in the ledger, or recorded before the counts were.
+    pub fn get_count(&self, uid: &Uid<E>) -> u64 {
+        self.get_next_seq_id(uid).and_then(|next_seq_id| self.counts.get(&next_seq_id)).cloned().unwrap_or(0)
+    }
+
+    // Photon weight which flowed through the transition to `uid`, summed over the weighted inserts
+    // only. 0.0 for entries never inserted with a weight.
+    pub fn get_weight(&self, uid: &Uid<E>) -> f64 {
+        self.get_next_seq_id(uid).and_then(|next_seq_id| self.weights.get(&next_seq_id)).cloned().unwrap_or(0.0)
+    }
+
+    pub fn has_weights(&self) -> bool {
+        !self.weights.is_empty()
+    }
+
+    fn insert_entry(&mut self, uid: Uid<E>, next_seq_id: u32) -> bool {
+        if self.get_next_seq_id(&uid).is_none() {
+            self.next
+                .entry(uid.seq_id)
+                .or_default()
+                .insert(uid.event, next_seq_id);
+            self.prev.insert(next_seq_id, uid);
+            true
+        } else {
+            false
+        }
+    }
+
+    // Call `callback` whenever a new entry completes the `filter` sequence, see `Subscription`
+    pub fn subscribe<S, F>(&mut self, filter: Vec<S>, callback: F) -> SubscriptionId
+    where
+        S: Into<FilterStep>,
+        F: FnMut(&[Uid<E>]) + Send + Sync + 'static,
+    {
+        let filter: Vec<FilterStep> = filter.into_iter().map(Into::into).collect();
+        assert!(!filter.is_empty(), "Subscription requires at least one BitsMatch");
+        assert!(
+            filter.iter().all(|step| step.min == 1 && step.max.is_none()),
+            "Subscriptions match each step at least once, repeat counts are not supported"
+        );
+        let id = SubscriptionId(self.next_subscription_id);
+        self.next_subscription_id += 1;
+        self.subscriptions.push(Subscription {
+            id,
+            filter,
+            callback: Box::new(callback),
+        });
+        id
+    }
+
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        let len = self.subscriptions.len();
+        self.subscriptions.retain(|subscription| subscription.id != id);
+        self.subscriptions.len() != len
+    }
+
+    fn notify(&mut self, uid: Uid<E>) {
+        if self.subscriptions.is_empty() {
+            return;
+        }
+        let last_step_matches = self.subscriptions.iter().any(|subscription| {
+            let step = subscription.filter.last().unwrap();
+            step.matches_event(&uid.event)
+        });
+        if !last_step_matches {
+            return;
+        }
+        let chain = self.get_chain(uid);
+        for subscription in self.subscriptions.iter_mut() {
+            if subscription.matches(&chain) {
+                (subscription.callback)(&chain);
+            }
+        }
+    }
+
+    // Associate the external `packet_id` with `uid`, usually the leaf of the photon's chain
+    pub fn tag_packet(&mut self, uid: Uid<E>, packet_id: u64) -> Result<(), LedgerError> {
+        if !self.contains(&uid) {
+            return Err(LedgerError::UnknownUid(uid.to_string()));
+        }
+        self.packet_tags.insert(uid, packet_id)
+    }
+
+    pub fn packet_uid(&self, packet_id: u64) -> Option<Uid<E>> {
+        self.packet_tags.uids.get(&packet_id).cloned()
+    }
+
+    pub fn packet_ids(&self, uid: &Uid<E>) -> &[u64] {
+        self.packet_tags.packets.get(uid).map(|ids| ids.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn packet_tags(&self) -> &PacketTags<E> {
+        &self.packet_tags
+    }
+
+    // Record the timestamp of the entry allocating `seq_id`. Without an explicit time, wall clock
+    // mode reads the clock and simulation mode inherits the time of the parent entry `parent_seq_id`
+    fn stamp(&mut self, seq_id: u32, parent_seq_id: u32, time: Option<f32>) {
+        let Some(timestamps) = self.timestamps.as_mut() else {
+            return;
+        };
+        let time = match (time, timestamps.base) {
+            (Some(time), _) => time,
+            (None, TimeBase::WallClock) => {
+                self.clock_start.get_or_insert_with(Instant::now).elapsed().as_secs_f32()
+            }
+            (None, TimeBase::Simulation) => timestamps.get(parent_seq_id).unwrap_or(0.0),
+        };
+        timestamps.set(seq_id, time);
+    }
+
+    pub fn get_start_events(&self) -> &Vec<Uid<E>> {
+        &self.start_events
+    }
+
+    pub fn contains(&self, uid: &Uid<E>) -> bool {
+        self.get_next_seq_id(uid).is_some()
+    }
+
+    pub fn get_next_seq_id(&self, uid: &Uid<E>) -> Option<u32> {
+        match self.next.get(&uid.seq_id) {
+            Some(map) if map.contains_key(&uid.event) => map.get(&uid.event).cloned(),
+            _ => self.spill.as_ref()?.store.get_next_seq_id(uid),
+        }
+    }
+
+    pub fn get_next(&self, uid: &Uid<E>) -> Vec<Uid<E>> {
+        let mut next_uids = Vec::new();
+        if let Some(next_seq_id) = self.get_next_seq_id(uid) {
+            if let Some(map) = self.next.get(&next_seq_id) {
+                for next_event in map.keys() {
+                    let next_uid = Uid { seq_id: next_seq_id, event: *next_event };
+                    next_uids.push(next_uid);
+                }
+            }
+            if let Some(spill) = self.spill.as_ref() {
+                next_uids.extend(spill.store.group(next_seq_id).into_iter().map(|(event, _)| Uid { seq_id: next_seq_id, event }));
+                next_uids.sort_unstable_by_key(|next_uid| next_uid.event);
+            }
+        }
+        next_uids
+    }
+
+    pub fn get_prev(&self, seq_id: u32) -> Option<Uid<E>> {
+        match self.prev.get(&seq_id) {
+            Some(uid) => Some(*uid),
+            None => self.spill.as_ref()?.store.get_prev(seq_id),
+        }
+    }
 
-        src_id
+    pub fn get_chain(&self, last_uid: Uid<E>) -> Vec<Uid<E>> {
+        let mut chain = Vec::new();
+        chain.push(last_uid);
+        let mut seq_id = last_uid.seq_id;
+        while let Some(uid) = self.get_prev(seq_id) {
+            chain.push(uid);
+            seq_id = uid.seq_id;
+        }
+        chain.reverse();
+        chain
+    }
+
+    pub fn get_child_roots(&self, uid: &Uid<E>) -> &[Uid<E>] {
+        self.child_roots.get(uid).map(|uids| uids.as_slice()).unwrap_or(&[])
+    }
+
+    // Children of `uid` in the event tree: its subsequent entries, i.e. the reflected and
+    // refracted branches of an interface event, then the child roots of the secondary photons.
+    // The successors share a seq_id group rather than a list, hence the owned Vec.
+    pub fn get_children(&self, uid: &Uid<E>) -> Vec<Uid<E>> {
+        let mut children = self.get_next(uid);
+        children.extend_from_slice(self.get_child_roots(uid));
+        children
+    }
+
+    // Every entry descending from `uid` through `get_children`, depth first, `uid` excluded
+    pub fn get_subtree(&self, uid: &Uid<E>) -> Vec<Uid<E>> {
+        let mut subtree = Vec::new();
+        let mut stack = self.get_children(uid);
+        stack.reverse();
+        while let Some(child) = stack.pop() {
+            subtree.push(child);
+            stack.extend(self.get_children(&child).into_iter().rev());
+        }
+        subtree
+    }
+
+    // Fails on the first entry whose event doesn't decode, i.e. read from a corrupted file or written
+    // by a newer layout, see `parse_ledger_json`
+    pub fn validate_events(&self) -> Result<(), DecodeError> {
+        self.entries().try_for_each(|uid| EventId::try_decode(uid.event.word()).map(|_| ()))
+    }
+
+    // Every entry of the ledger, ordered by seq_id. Spilled entries, if any, come first.
+    pub fn entries(&self) -> impl Iterator<Item = Uid<E>> + '_ {
+        let spilled = self.spill.as_ref().map(|spill| spill.store.entries()).unwrap_or_default();
+        spilled
+            .into_iter()
+            .map(|(seq_id, event, _)| Uid { seq_id, event })
+            .chain(
+                self.next
+                    .iter()
+                    .flat_map(|(seq_id, map)| map.keys().map(|event| Uid { seq_id: *seq_id, event: *event })),
+            )
+    }
+
+    // Entries without any subsequent event, i.e. the last event of each chain
+    pub fn leaves(&self) -> Vec<Uid<E>> {
+        let spilled = self.spill.as_ref().map(|spill| spill.store.entries()).unwrap_or_default();
+        let spilled_groups: std::collections::BTreeSet<u32> = spilled.iter().map(|entry| entry.0).collect();
+        let is_leaf = |next_seq_id: &u32| !self.next.contains_key(next_seq_id) && !spilled_groups.contains(next_seq_id);
+        spilled
+            .iter()
+            .filter(|(_, _, next_seq_id)| is_leaf(next_seq_id))
+            .map(|(seq_id, event, _)| Uid { seq_id: *seq_id, event: *event })
+            .chain(self.next.iter().flat_map(|(seq_id, map)| {
+                map.iter()
+                    .filter(|(_, next_seq_id)| is_leaf(next_seq_id))
+                    .map(|(event, _)| Uid { seq_id: *seq_id, event: *event })
+            }))
+            .collect()
+    }
+
+    // Chain of every leaf, ordered from the start event to the leaf
+    pub fn chains(&self) -> impl Iterator<Item = Vec<Uid<E>>> + '_ {
+        self.leaves().into_iter().map(|uid| self.get_chain(uid))
+    }
+
+    // Number of scattering events (see `kind::is_scatter`) of the chain leading to `uid`, `uid`
+    // included, i.e. 0 for a ballistic photon even if refracted or reflected on its way. Same depth
+    // as the one the Recorder truncates at `max_depth`. The walk stops at the first entry whose
+    // depth was cached at insert, see `depths`.
+    pub fn chain_depth(&self, uid: Uid<E>) -> u32 {
+        if let Some(depth) = self.get_next_seq_id(&uid).and_then(|next_seq_id| self.depths.get(&next_seq_id)) {
+            return *depth;
+        }
+        let mut depth = is_scatter(uid.event.word()) as u32;
+        let mut seq_id = uid.seq_id;
+        // The depth cached for a seq_id is the one of the entry which allocated it, i.e. its prev
+        while let Some(prev) = self.get_prev(seq_id) {
+            if let Some(prev_depth) = self.depths.get(&seq_id) {
+                return depth + prev_depth;
+            }
+            depth += is_scatter(prev.event.word()) as u32;
+            seq_id = prev.seq_id;
+        }
+        depth
+    }
+
+    // Number of chains (leaves) per `chain_depth`
+    pub fn depth_histogram(&self) -> BTreeMap<u32, usize> {
+        let mut histogram = BTreeMap::new();
+        for leaf in self.leaves() {
+            *histogram.entry(self.chain_depth(leaf)).or_default() += 1;
+        }
+        histogram
+    }
+
+    // Replace the sources, keeping the entries, i.e. when replaying a stream re-syncing its sources
+    pub(crate) fn set_src_table(&mut self, src_table: SrcTable) {
+        self.grps = src_table.grps;
+        self.src_map = src_table.src_map;
+        self.aliases = src_table.aliases;
+        self.next_mat_id = src_table.next_mat_id;
+        self.next_surf_id = src_table.next_surf_id;
+        self.next_matsurf_id = src_table.next_matsurf_id;
+        self.next_light_id = src_table.next_light_id;
+        self.next_detector_id = src_table.next_detector_id;
+        self.wide_srcs = src_table.wide_srcs;
+        self.config = src_table.config;
+        self.code_registry = src_table.code_registry;
+        self.channels = src_table.channels;
+        self.detector_geometries = src_table.detector_geometries;
+        self.sampling_policy = src_table.sampling_policy;
+        self.max_depth = src_table.max_depth;
+        self.scatter_binning = src_table.scatter_binning;
+        self.metadata = src_table.metadata;
+        self.audit = src_table.audit;
+        self.src_revision += 1;
+    }
+
+    // Revision of the sources, changes whenever `src_table` would return a different table
+    pub fn src_revision(&self) -> u64 {
+        self.src_revision
+    }
+
+    pub fn src_table(&self) -> SrcTable {
+        SrcTable {
+            grps: self.grps.clone(),
+            src_map: self.src_map.clone(),
+            aliases: self.aliases.clone(),
+            next_mat_id: self.next_mat_id,
+            next_surf_id: self.next_surf_id,
+            next_matsurf_id: self.next_matsurf_id,
+            next_light_id: self.next_light_id,
+            next_detector_id: self.next_detector_id,
+            wide_srcs: self.wide_srcs.clone(),
+            config: self.config.clone(),
+            code_registry: self.code_registry.clone(),
+            channels: self.channels.clone(),
+            detector_geometries: self.detector_geometries.clone(),
+            sampling_policy: self.sampling_policy.clone(),
+            max_depth: self.max_depth,
+            scatter_binning: self.scatter_binning,
+            metadata: self.metadata.clone(),
+            audit: self.audit.clone(),
+        }
+    }
+}
+
+// ----------------------------------------------------
+// Registration of the sources, for any width of raw events
+// ----------------------------------------------------
+// Sources are registered with the 16-bit ids of SrcId in every ledger. Wide events holding a larger
+// source id (see `raw64`) register the sources past them with `with_wide_src`.
+
+impl<E: RawEvent> Ledger<E> {
+    fn audit_registration(&mut self, src_id: SrcId) {
+        if let Some(name) = self.src_map.get(&src_id).and_then(|names| names.last()) {
+            let event = AuditEvent::SrcRegistered { src_id, name: name.clone() };
+            self.audit.record(event);
+            self.src_revision += 1;
+        }
+    }
+
+    // Fails with `LedgerError::SrcIdsExhausted` once the light ids are exhausted, as the other sources
+    pub fn with_light(&mut self, light_name: String) -> Result<SrcId, LedgerError> {
+        // Ids already taken, i.e. by a merged source, are skipped as for the detectors
+        let light_id = loop {
+            let light_id = SrcId::Light(self.allocate_id(SrcKind::Light)?);
+            if !self.src_map.contains_key(&light_id) {
+                break light_id;
+            }
+        };
+        self.src_map.insert(light_id, vec![SrcName::Light(light_name)]);
+        self.audit_registration(light_id);
+        Ok(light_id)
+    }
+
+    // Detector ids have their own range, such that detection events can be attributed. Array
+    // detectors recording pixel indices are limited to `detection::MAX_PIXELATED_DETECTOR`
+    pub fn with_detector(&mut self, detector_name: String) -> Result<SrcId, LedgerError> {
+        // Ledgers written before the counter was stored restart it at 0
+        while self.src_map.contains_key(&SrcId::Detector(self.next_detector_id)) && self.next_detector_id < u16::MAX {
+            self.next_detector_id += 1;
+        }
+        let detector_id = SrcId::Detector(self.allocate_id(SrcKind::Detector)?);
+        self.src_map.insert(detector_id, vec![SrcName::Detector(detector_name)]);
+        self.audit_registration(detector_id);
+        Ok(detector_id)
+    }
+
+    pub fn with_surf(&mut self, obj_name: String, grp: Option<String>) -> Result<SrcId, LedgerError> {
+        let src_id = if let Some(grp_name) = grp.map(group_path).transpose()? {
+            let src_id = match self.grps.get(&grp_name) {
+                Some(src_id) => *src_id,
+                None => {
+                    // Create new SurfId
+                    let surf_id = SrcId::Surf(self.allocate_id(SrcKind::Surf)?);
+                    self.grps.insert(grp_name.clone(), surf_id);
+                    surf_id
+                }
+            };
+
+            match src_id {
+                SrcId::Surf(_) => src_id,
+                SrcId::MatSurf(_) => src_id,
+                SrcId::Mat(_) => {
+                    let matsurf_id = self.allocate_id(SrcKind::MatSurf)?;
+
+                    warn!(
+                        "Discarding {:?} and allocate MatSurf({}), moving Map({:?}) to Map(Mat({}))",
+                        src_id, matsurf_id, src_id, matsurf_id
+                    );
+                    let mat_names = self.src_map.remove(&src_id).ok_or(LedgerError::UnknownSrc(src_id))?;
+                    self.src_map.insert(SrcId::Mat(matsurf_id), mat_names);
+
+                    SrcId::MatSurf(matsurf_id)
+                }
+                SrcId::Light(_) | SrcId::Detector(_) | SrcId::None => {
+                    return Err(LedgerError::GroupConflict { group: grp_name, src_id });
+                }
+            }
+        } else {
+            SrcId::Surf(self.allocate_id(SrcKind::Surf)?)
+        };
+
+        match self.src_map.get_mut(&src_id) {
+            Some(value) => value.push(SrcName::Surf(obj_name)),
+            None => {
+                self.src_map
+                    .insert(src_id, vec![SrcName::Surf(obj_name)]);
+            }
+        };
+
+        self.audit_registration(src_id);
+
+        Ok(src_id)
     }
 
     // NOTE: Materials are not grouped, only objects are
     // FIXME: Is `with_mat` necessary? Materials are always paird with surfaces, apart from
     // boundary, which can also be considered a special case of a surface
-    pub fn with_mat(&mut self, mat_name: String) -> SrcId {
-        let mat_id = SrcId::Mat(self.next_mat_id);
-        self.next_mat_id += 1;
+    pub fn with_mat(&mut self, mat_name: String) -> Result<SrcId, LedgerError> {
+        let mat_id = SrcId::Mat(self.allocate_id(SrcKind::Mat)?);
 
         match self.src_map.get_mut(&mat_id) {
             Some(value) => value.push(SrcName::Mat(mat_name)),
             None => {
                 self.src_map
-                    .insert(mat_id.clone(), vec![SrcName::Mat(mat_name)]);
+                    .insert(mat_id, vec![SrcName::Mat(mat_name)]);
             }
         };
 
-        self.check_ids();
+        self.audit_registration(mat_id);
 
-        mat_id
+        Ok(mat_id)
     }
 
     pub fn with_matsurf(
@@ -300,24 +1593,22 @@ impl Ledger {
         obj_name: String,
         mat_name: String,
         grp: Option<String>,
-    ) -> SrcId {
-        let src_id = if let Some(grp_name) = grp {
+    ) -> Result<SrcId, LedgerError> {
+        let src_id = if let Some(grp_name) = grp.map(group_path).transpose()? {
             let src_id = match self.grps.get(&grp_name) {
-                Some(src_id) => src_id.clone(),
+                Some(src_id) => *src_id,
                 None => {
                     // Create new MatId
-                    let surf_id = SrcId::MatSurf(self.next_matsurf_id);
-                    self.next_matsurf_id -= 1;
-                    self.grps.insert(grp_name.clone(), surf_id.clone());
+                    let surf_id = SrcId::MatSurf(self.allocate_id(SrcKind::MatSurf)?);
+                    self.grps.insert(grp_name.clone(), surf_id);
                     surf_id
                 }
             };
 
-            let grp_src_id = match src_id {
+            match src_id {
                 SrcId::MatSurf(_) => src_id,
                 SrcId::Surf(_) | SrcId::Mat(_) => {
-                    let matsurf_id = self.next_matsurf_id;
-                    self.next_matsurf_id -= 1;
+                    let matsurf_id = self.allocate_id(SrcKind::MatSurf)?;
 
                     match src_id {
                         SrcId::Surf(_) => {
@@ -325,40 +1616,28 @@ impl Ledger {
                                 "Discarding {:?} and allocate MatSurf({}), moving Map({:?}) to Map(Surf({}))",
                                 src_id, matsurf_id, src_id, matsurf_id
                             );
-                            if let Some(surf_names) = self.src_map.remove(&src_id) {
-                                self.src_map.insert(SrcId::Surf(matsurf_id), surf_names);
-                            } else {
-                                panic!("Surface ID {} not found in src_map", *src_id);
-                            }
+                            let surf_names = self.src_map.remove(&src_id).ok_or(LedgerError::UnknownSrc(src_id))?;
+                            self.src_map.insert(SrcId::Surf(matsurf_id), surf_names);
                         }
                         SrcId::Mat(_) => {
                             warn!(
                                 "Discarding {:?} and allocate MatSurf({}), moving Map({:?}) to Map(Mat({}))",
                                 src_id, matsurf_id, src_id, matsurf_id
                             );
-                            if let Some(surf_names) = self.src_map.remove(&src_id) {
-                                self.src_map.insert(SrcId::Mat(matsurf_id), surf_names);
-                            } else {
-                                panic!("Surface ID {} not found in src_map", *src_id);
-                            }
+                            let mat_names = self.src_map.remove(&src_id).ok_or(LedgerError::UnknownSrc(src_id))?;
+                            self.src_map.insert(SrcId::Mat(matsurf_id), mat_names);
                         }
                         _ => {}
                     };
 
                     SrcId::MatSurf(matsurf_id)
                 }
-                SrcId::Light(_) => {
-                    panic!("Group name {} already used for a light source", grp_name);
-                }
-                SrcId::None => {
-                    panic!("Group name {} registered an invalid None source", grp_name);
+                SrcId::Light(_) | SrcId::Detector(_) | SrcId::None => {
+                    return Err(LedgerError::GroupConflict { group: grp_name, src_id });
                 }
-            };
-            grp_src_id
+            }
         } else {
-            let surf_id = SrcId::MatSurf(self.next_matsurf_id);
-            self.next_matsurf_id -= 1;
-            surf_id
+            SrcId::MatSurf(self.allocate_id(SrcKind::MatSurf)?)
         };
 
         let matsurf_name = format!("{}:{}", obj_name, mat_name);
@@ -366,127 +1645,986 @@ impl Ledger {
             Some(value) => value.push(SrcName::MatSurf(matsurf_name)),
             None => {
                 self.src_map
-                    .insert(src_id.clone(), vec![SrcName::MatSurf(matsurf_name)]);
+                    .insert(src_id, vec![SrcName::MatSurf(matsurf_name)]);
             }
         };
 
-        self.check_ids();
+        self.audit_registration(src_id);
+
+        Ok(src_id)
+    }
+
+    // Sources of the group `path` and of every group nested under it, i.e. "probe" resolves to
+    // the sources of "probe", "probe/fiber" and "probe/fiber/cladding". Sorted, empty if unknown.
+    pub fn group_src_ids(&self, path: &str) -> Vec<SrcId> {
+        let path = path.trim().trim_matches('/');
+        let mut src_ids: Vec<SrcId> = self
+            .grps
+            .iter()
+            .filter(|(grp_name, _)| {
+                grp_name.strip_prefix(path).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .flat_map(|(_, src_id)| self.merged_srcs(src_id))
+            .collect();
+        src_ids.sort();
+        src_ids.dedup();
+        src_ids
+    }
 
-        src_id
+    // Group registered for a source, with its full path
+    pub fn group_of(&self, src_id: &SrcId) -> Option<&str> {
+        self.grps.iter().find(|(_, grp_id)| *grp_id == src_id).map(|(grp_name, _)| grp_name.as_str())
     }
 
-    pub fn insert_start(&mut self, start_event: EventId) -> Uid {
-        let uid = Uid::new(0, start_event.encode());
+    // Every registered source with its names, in no particular order
+    pub fn sources(&self) -> impl Iterator<Item = (&SrcId, &[SrcName])> + '_ {
+        self.src_map.iter().map(|(src_id, names)| (src_id, names.as_slice()))
+    }
 
-        if self.insert_entry(uid.clone(), 1) {
-            self.start_events.push(uid.clone());
+    // Registered sources of one kind with their names, i.e. `SrcKind::Mat` for the materials,
+    // ordered by id
+    pub fn sources_of_kind(&self, kind: SrcKind) -> impl Iterator<Item = (SrcId, &[SrcName])> + '_ {
+        let mut sources: Vec<(SrcId, &[SrcName])> = self
+            .src_map
+            .iter()
+            .filter(|(src_id, _)| src_id.kind() == kind)
+            .map(|(src_id, names)| (*src_id, names.as_slice()))
+            .collect();
+        sources.sort_by_key(|(src_id, _)| *src_id);
+        sources.into_iter()
+    }
+
+    pub fn names(&self, src_id: &SrcId) -> &[SrcName] {
+        self.src_map.get(&self.canonical_src(src_id)).map(|names| names.as_slice()).unwrap_or(&[])
+    }
+
+    // Registered source of a decoded event, resolving aliases. MCRT events only store the id, so
+    // the registered kind (Mat, Surf or MatSurf) has to be guessed from the event type.
+    pub fn event_src(&self, event_id: &EventId) -> SrcId {
+        self.registered_src(event_id)
+            .map(|src_id| self.canonical_src(&src_id))
+            .unwrap_or(event_id.src_id)
+    }
+
+    // Same as `event_src`, without resolving aliases. None if the source isn't registered.
+    fn registered_src(&self, event_id: &EventId) -> Option<SrcId> {
+        let mut candidates = vec![event_id.src_id];
+        if let (EventType::MCRT(mcrt_event), SrcId::MatSurf(id)) = (&event_id.event_type, event_id.src_id) {
+            match mcrt_event {
+                MCRT::Interface(_) | MCRT::Custom(_) => candidates.extend([SrcId::Surf(id), SrcId::Mat(id)]),
+                MCRT::Reflector(_) => candidates.push(SrcId::Surf(id)),
+                MCRT::Material(_)  => candidates.push(SrcId::Mat(id)),
+            }
         }
+        candidates
+            .into_iter()
+            .find(|src_id| self.src_map.contains_key(&self.canonical_src(src_id)))
+    }
+
+    // Names of the source of a decoded event, see `event_src`
+    pub fn event_names(&self, event_id: &EventId) -> &[SrcName] {
+        self.names(&self.event_src(event_id))
+    }
 
-        if self.next_seq_id == 0 {
-            self.next_seq_id = 2;
+    // Merge `alias` into `canonical` after the run, i.e. two materials found to be physically the
+    // same. The names of the alias move to the canonical source, while the recorded events keep the
+    // alias id: name lookups resolve to the canonical source and `filter::parse_with_ledger`
+    // expands a stage on either source into both.
+    pub fn alias_src(&mut self, canonical: SrcId, alias: SrcId) -> Result<(), LedgerError> {
+        let (canonical, alias) = (self.canonical_src(&canonical), self.canonical_src(&alias));
+        if canonical == alias {
+            return Err(LedgerError::SrcAlreadyMerged { alias, canonical });
         }
+        if canonical.kind() != alias.kind() {
+            return Err(LedgerError::AliasKindMismatch { alias, canonical });
+        }
+        let alias_names = self.src_map.remove(&alias).ok_or(LedgerError::UnknownSrc(alias))?;
+        match self.src_map.get_mut(&canonical) {
+            Some(names) => names.extend(alias_names),
+            None => {
+                self.src_map.insert(alias, alias_names);
+                return Err(LedgerError::UnknownSrc(canonical));
+            }
+        }
+        // Sources merged into the alias earlier now resolve to the canonical source directly
+        for (_, target) in self.aliases.iter_mut().filter(|(_, target)| *target == alias) {
+            *target = canonical;
+        }
+        self.aliases.push((alias, canonical));
+        self.src_revision += 1;
+        Ok(())
+    }
 
-        uid
+    // Same as `alias_src`, with the sources given by name, see `src_id_by_name`
+    pub fn alias_src_by_name(&mut self, canonical: &str, alias: &str) -> Result<(), LedgerError> {
+        let canonical = self.src_id_by_name(canonical).ok_or_else(|| LedgerError::UnknownSrcName(canonical.to_string()))?;
+        let alias = self.src_id_by_name(alias).ok_or_else(|| LedgerError::UnknownSrcName(alias.to_string()))?;
+        self.alias_src(canonical, alias)
     }
 
-    // WARN: next_seq_id increment overflows silently in release mode, however that is unlikely to
-    // happen unless the simulation scene is extremely complex
-    pub fn insert(&mut self, prev_event: Uid, event: EventId) -> Uid {
-        // Push a new entry in next with the new_event UID if it doesn't exist already and
-        //    set count to 1
-        // Obs: seq_id=0 is reserved for root identification, hence all new events with no
-        // previous cause start with seq_id=0
-        let next_seq_id = self
-            .get_next_seq_id(&prev_event)
-            .ok_or("Previous event not found in ledger")
-            .unwrap();
+    // Source an alias was merged into, the source itself otherwise
+    pub fn canonical_src(&self, src_id: &SrcId) -> SrcId {
+        self.aliases
+            .iter()
+            .find(|(alias, _)| alias == src_id)
+            .map(|(_, canonical)| *canonical)
+            .unwrap_or(*src_id)
+    }
+
+    // Canonical source of `src_id` followed by every source merged into it
+    pub fn merged_srcs(&self, src_id: &SrcId) -> Vec<SrcId> {
+        let canonical = self.canonical_src(src_id);
+        let mut src_ids = vec![canonical];
+        src_ids.extend(self.aliases.iter().filter(|(_, target)| *target == canonical).map(|(alias, _)| *alias));
+        src_ids
+    }
+
+    // Find the SrcId registered with `name`. Material-surface pairs are registered as `obj:mat`
+    // and can be found either by the full name, or by the object or material name alone as long as
+    // a single pair matches it, None if the name is ambiguous.
+    pub fn src_id_by_name(&self, name: &str) -> Option<SrcId> {
+        let mut partial_matches = Vec::new();
+        for (src_id, src_names) in &self.src_map {
+            for src_name in src_names {
+                let full_name = src_name.to_string();
+                if full_name == name {
+                    return Some(*src_id);
+                }
+                if let SrcName::MatSurf(_) = src_name
+                    && full_name.split(':').any(|part| part == name)
+                    && !partial_matches.contains(src_id)
+                {
+                    partial_matches.push(*src_id);
+                }
+            }
+        }
+        match partial_matches[..] {
+            [src_id] => Some(src_id),
+            _ => None,
+        }
+    }
+
+    // Ids left to register sources of each kind within their range, see `LedgerConfig`. Mat and Surf
+    // ids share the ids left between their ranges and the MatSurf one.
+    pub fn remaining_mat_ids(&self) -> u16 {
+        self.remaining_upwards(self.next_mat_id, &self.config.mat_range, true)
+    }
+
+    pub fn remaining_surf_ids(&self) -> u16 {
+        self.remaining_upwards(self.next_surf_id, &self.config.surf_range, true)
+    }
+
+    pub fn remaining_matsurf_ids(&self) -> u16 {
+        let mut floor = self.config.matsurf_range.start().saturating_sub(1);
+        if self.config.shares_matsurf_ids(&self.config.mat_range) {
+            floor = floor.max(self.next_mat_id);
+        }
+        if self.config.shares_matsurf_ids(&self.config.surf_range) {
+            floor = floor.max(self.next_surf_id);
+        }
+        self.next_matsurf_id.saturating_sub(floor)
+    }
+
+    pub fn remaining_light_ids(&self) -> u16 {
+        self.remaining_upwards(self.next_light_id, &self.config.light_range, false)
+    }
+
+    pub fn remaining_detector_ids(&self) -> u16 {
+        self.remaining_upwards(self.next_detector_id, &self.config.detector_range, false)
+    }
+
+    fn remaining_upwards(&self, next_id: u16, range: &RangeInclusive<u16>, meets_matsurf: bool) -> u16 {
+        let mut end = range.end().saturating_add(1);
+        if meets_matsurf && self.config.shares_matsurf_ids(range) {
+            end = end.min(self.next_matsurf_id);
+        }
+        end.saturating_sub(next_id)
+    }
+
+    // Next id of the `kind` range, failing rather than overlapping another range or wrapping around
+    fn allocate_id(&mut self, kind: SrcKind) -> Result<u16, LedgerError> {
+        let remaining = match kind {
+            SrcKind::Mat => self.remaining_mat_ids(),
+            SrcKind::Surf => self.remaining_surf_ids(),
+            SrcKind::MatSurf => self.remaining_matsurf_ids(),
+            SrcKind::Light => self.remaining_light_ids(),
+            SrcKind::Detector => self.remaining_detector_ids(),
+            SrcKind::None => 0,
+        };
+        if remaining == 0 {
+            return Err(LedgerError::SrcIdsExhausted(kind));
+        }
+        Ok(match kind {
+            SrcKind::Mat => {
+                self.next_mat_id += 1;
+                self.next_mat_id - 1
+            }
+            SrcKind::Surf => {
+                self.next_surf_id += 1;
+                self.next_surf_id - 1
+            }
+            SrcKind::MatSurf => {
+                self.next_matsurf_id -= 1;
+                self.next_matsurf_id + 1
+            }
+            SrcKind::Light => {
+                self.next_light_id += 1;
+                self.next_light_id - 1
+            }
+            SrcKind::Detector => {
+                self.next_detector_id += 1;
+                self.next_detector_id - 1
+            }
+            SrcKind::None => unreachable!(),
+        })
+    }
+
+    // Source of a wide event, once the 16-bit ids of its kind are exhausted. The ids past the 16
+    // bits of SrcId up to `E::MAX_SRC_ID` are shared by every kind. Fails with
+    // `LedgerError::SrcIdsExhausted` once they are exhausted, or right away for u32 events.
+    pub fn with_wide_src(&mut self, src_name: SrcName) -> Result<u32, LedgerError> {
+        let kind = src_name.kind();
+        let id = self.wide_srcs.last_key_value().map_or(u16::MAX as u32 + 1, |(id, _)| id + 1);
+        if id > E::MAX_SRC_ID {
+            return Err(LedgerError::SrcIdsExhausted(kind));
+        }
+        self.wide_srcs.insert(id, src_name);
+        self.src_revision += 1;
+        Ok(id)
+    }
+
+    pub fn wide_src(&self, id: u32) -> Option<&SrcName> {
+        self.wide_srcs.get(&id)
+    }
+
+    pub fn wide_src_id_by_name(&self, name: &str) -> Option<u32> {
+        self.wide_srcs.iter().find(|(_, src_name)| src_name.name() == name).map(|(id, _)| *id)
+    }
+}
+
+impl Ledger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Empty Ledger allocating the source ids within the ranges of `config`, see `LedgerConfig`
+    pub fn with_config(config: LedgerConfig) -> Result<Self, LedgerError> {
+        config.validate()?;
+        let mut ledger = Self::new();
+        ledger.next_mat_id = *config.mat_range.start();
+        ledger.next_surf_id = *config.surf_range.start();
+        ledger.next_matsurf_id = *config.matsurf_range.end();
+        ledger.next_light_id = *config.light_range.start();
+        ledger.next_detector_id = *config.detector_range.start();
+        ledger.config = config;
+        Ok(ledger)
+    }
+
+    pub fn config(&self) -> &LedgerConfig {
+        &self.config
+    }
+
+    // Empty Ledger with the sources of `src_table` registered
+    pub fn from_src_table(src_table: SrcTable) -> Self {
+        let mut ledger = Self::new();
+        ledger.set_src_table(src_table);
+        ledger
+    }
+
+    pub fn enable_timestamps(&mut self, base: TimeBase) {
+        if self.next_seq_id != 0 {
+            warn!("Timestamps enabled after events were inserted, earlier entries are stamped with 0");
+        }
+        self.timestamps = Some(Timestamps::new(base));
+        self.clock_start = Some(Instant::now());
+    }
+
+    pub fn timestamps(&self) -> Option<&Timestamps> {
+        self.timestamps.as_ref()
+    }
+
+    pub fn set_sampling_policy(&mut self, policy: Option<SamplingPolicy>) {
+        if self.next_seq_id != 0 {
+            warn!("Sampling policy changed after events were inserted");
+        }
+        self.sampling_policy = policy;
+        self.src_revision += 1;
+    }
+
+    pub fn sampling_policy(&self) -> Option<&SamplingPolicy> {
+        self.sampling_policy.as_ref()
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: Option<u32>) {
+        if self.next_seq_id != 0 {
+            warn!("Max depth changed after events were inserted");
+        }
+        self.max_depth = max_depth;
+        self.src_revision += 1;
+    }
+
+    pub fn max_depth(&self) -> Option<u32> {
+        self.max_depth
+    }
+
+    pub fn set_scatter_binning(&mut self, binning: Option<ScatterBinning>) {
+        if self.next_seq_id != 0 {
+            warn!("Scatter binning changed after events were inserted");
+        }
+        self.scatter_binning = binning;
+        self.src_revision += 1;
+    }
+
+    // Bins the scatter directions were recorded with, the default ones for older ledgers
+    pub fn scatter_binning(&self) -> ScatterBinning {
+        self.scatter_binning.unwrap_or_default()
+    }
+
+    // Range of scattering angles of a scattering event, given the recorded bins
+    pub fn scatter_range(&self, raw_event: u32) -> Option<(f64, f64)> {
+        is_scatter(raw_event).then(|| self.scatter_binning().range(ScatterDir::decode(raw_event)))
+    }
+
+    pub fn set_metadata(&mut self, metadata: RunMetadata) {
+        self.metadata = Some(metadata);
+        self.src_revision += 1;
+    }
+
+    pub fn metadata(&self) -> Option<&RunMetadata> {
+        self.metadata.as_ref()
+    }
+
+    pub fn audit(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    // Mark the start and stop of event recording in the audit log, called by the Recorder
+    pub fn start_recording(&mut self) {
+        if !self.audit.is_recording() {
+            self.audit.record(AuditEvent::RecordingStarted);
+            self.src_revision += 1;
+        }
+    }
+
+    pub fn stop_recording(&mut self) {
+        if self.audit.is_recording() {
+            self.audit.record(AuditEvent::RecordingStopped);
+            self.src_revision += 1;
+        }
+    }
+
+    // Register the wavelength band [min_nm, max_nm) as the next channel
+    pub fn with_channel(&mut self, name: String, min_nm: f64, max_nm: f64) -> Channel {
+        assert!(min_nm < max_nm, "Empty wavelength band for channel {}", name);
+        let id = self.channels.len() as u8 + 1;
+        assert!(id <= Channel::MAX, "No wavelength channel left for {}", name);
+        self.channels.insert(id, WavelengthChannel { name, min_nm, max_nm });
+        self.src_revision += 1;
+        Channel::new(id)
+    }
+
+    pub fn channel(&self, channel: &Channel) -> Option<&WavelengthChannel> {
+        self.channels.get(&channel.id())
+    }
+
+    pub fn channel_by_name(&self, name: &str) -> Option<Channel> {
+        self.channels
+            .iter()
+            .find(|(_, channel)| channel.name == name)
+            .map(|(id, _)| Channel::new(*id))
+    }
+
+    // Channel whose band contains `wavelength_nm`, i.e. to tag a newly emitted photon
+    pub fn channel_for(&self, wavelength_nm: f64) -> Option<Channel> {
+        self.channels
+            .iter()
+            .find(|(_, channel)| channel.contains(wavelength_nm))
+            .map(|(id, _)| Channel::new(*id))
+    }
+
+    // Register the rows x cols layout of an array detector, whose detection events carry the
+    // index of the pixel hit, see `EventId::with_pixel`
+    pub fn with_detector_geometry(&mut self, detector_id: SrcId, rows: u16, cols: u16) -> DetectorGeometry {
+        let SrcId::Detector(id) = detector_id else {
+            panic!("Detector geometry registered for {:?}, expected a detector", detector_id);
+        };
+        assert!(id <= detection::MAX_PIXELATED_DETECTOR, "Pixelated detector id {} exceeds 8 bits", id);
+        let geometry = DetectorGeometry::new(rows, cols);
+        self.detector_geometries.insert(id, geometry);
+        self.src_revision += 1;
+        geometry
+    }
+
+    pub fn detector_geometry(&self, detector_id: &SrcId) -> Option<&DetectorGeometry> {
+        match detector_id {
+            SrcId::Detector(id) => self.detector_geometries.get(id),
+            _ => None,
+        }
+    }
+
+    // BitsMatch alternatives for the detection events within a rectangular region of a registered
+    // array detector
+    pub fn pixel_region_filter(
+        &self,
+        detector_id: &SrcId,
+        rows: RangeInclusive<u16>,
+        cols: RangeInclusive<u16>,
+    ) -> Option<Vec<BitsMatch>> {
+        let geometry = self.detector_geometry(detector_id)?;
+        Some(detection::pixel_region_bits_matches(detector_id.id()?, geometry, rows, cols))
+    }
+
+    pub fn code_registry(&self) -> &CodeRegistry {
+        &self.code_registry
+    }
+
+    pub fn code_registry_mut(&mut self) -> &mut CodeRegistry {
+        self.src_revision += 1;
+        &mut self.code_registry
+    }
+
+    // Record the probability (and weight multiplier) of the stochastic choice which led to `uid`
+    pub fn annotate_probability(&mut self, uid: &Uid, choice: ChoiceProbability) -> Result<(), LedgerError> {
+        let seq_id = self.get_next_seq_id(uid).ok_or_else(|| LedgerError::UnknownUid(uid.to_string()))?;
+        self.probabilities
+            .entry(seq_id)
+            .and_modify(|entry| {
+                entry.samples += 1;
+                entry.varying |= !entry.choice.approx_eq(&choice);
+            })
+            .or_insert(EntryProbability { choice, samples: 1, varying: false });
+        Ok(())
+    }
+
+    pub fn get_probability(&self, uid: &Uid) -> Option<&EntryProbability> {
+        let seq_id = self.get_next_seq_id(uid)?;
+        self.probabilities.get(&seq_id)
+    }
+
+    pub fn has_probabilities(&self) -> bool {
+        !self.probabilities.is_empty()
+    }
+
+    pub fn get_timestamp(&self, uid: &Uid) -> Option<f32> {
+        let seq_id = self.get_next_seq_id(uid)?;
+        self.timestamps.as_ref()?.get(seq_id)
+    }
+
+    // Every entry with its timestamp, spilled entries included, ordered by seq_id
+    fn timed_entries(&self) -> Vec<(Uid, f32)> {
+        let Some(timestamps) = self.timestamps.as_ref() else {
+            warn!("Timestamps are not enabled for this ledger");
+            return Vec::new();
+        };
+        let spilled = self.spill.as_ref().map(|spill| spill.store.entries()).unwrap_or_default();
+        let in_memory = self
+            .next
+            .iter()
+            .flat_map(|(seq_id, map)| map.iter().map(|(event, next_seq_id)| (*seq_id, *event, *next_seq_id)));
+        spilled
+            .into_iter()
+            .chain(in_memory)
+            .filter_map(|(seq_id, event, next_seq_id)| Some((Uid { seq_id, event }, timestamps.get(next_seq_id)?)))
+            .collect()
+    }
+
+    // All ledger entries whose timestamp falls in [t_start, t_end)
+    pub fn get_time_window(&self, t_start: f32, t_end: f32) -> Vec<Uid> {
+        self.timed_entries()
+            .into_iter()
+            .filter(|(_, time)| *time >= t_start && *time < t_end)
+            .map(|(uid, _)| uid)
+            .collect()
+    }
+
+    // Number of new entries per time bin of `bin_width`, starting at t=0, to monitor event rates.
+    // Empty unless `bin_width` is positive.
+    pub fn get_entry_rate(&self, bin_width: f32) -> Vec<usize> {
+        if !(bin_width > 0.0 && bin_width.is_finite()) {
+            warn!("Bin width must be positive, got {}", bin_width);
+            return Vec::new();
+        }
+        let mut bins = Vec::new();
+        for (_, time) in self.timed_entries() {
+            let bin = (time.max(0.0) / bin_width) as usize;
+            if bin >= bins.len() {
+                bins.resize(bin + 1, 0);
+            }
+            bins[bin] += 1;
+        }
+        bins
+    }
+
+    // Subtree of each child of `uid` listed separately, i.e. the copies following a
+    // `Transport::Split` event. Each branch starts with the child, followed by its descendants in
+    // breadth-first order. Copies continuing with the same event share their entry, hence a branch.
+    pub fn branches(&self, uid: &Uid) -> Vec<Vec<Uid>> {
+        self.get_next(uid)
+            .into_iter()
+            .map(|child| {
+                let mut branch = vec![child];
+                let mut idx = 0;
+                while idx < branch.len() {
+                    let next_uids = self.get_next(&branch[idx]);
+                    branch.extend(next_uids);
+                    idx += 1;
+                }
+                branch
+            })
+            .collect()
+    }
+
+    // Record a Fresnel split at the interface `surf_id`, followed by its reflected and transmitted
+    // children. Returns the (reflected, transmitted) entries each branch continues from.
+    pub fn insert_fresnel_split(&mut self, prev_event: Uid, surf_id: SrcId) -> Result<(Uid, Uid), LedgerError> {
+        let split = self.insert(prev_event, EventId::new_mcrt(MCRT::Interface(Interface::FresnelSplit), surf_id))?;
+        let reflected = self.insert(split, EventId::new_mcrt(MCRT::Interface(Interface::Reflection), surf_id))?;
+        let transmitted = self.insert(split, EventId::new_mcrt(MCRT::Interface(Interface::Refraction), surf_id))?;
+        Ok((reflected, transmitted))
+    }
+
+    // Branch of the nearest Fresnel split `uid` descends from, with the child starting that branch
+    pub fn fresnel_branch(&self, uid: &Uid) -> Option<(FresnelBranch, Uid)> {
+        let chain = self.get_chain(*uid);
+        chain
+            .windows(2)
+            .rev()
+            .find(|pair| is_fresnel_split(pair[0].event))
+            .and_then(|pair| Some((FresnelBranch::of(pair[1].event)?, pair[1])))
+    }
+
+    // First entry of the other branch of the nearest Fresnel split `uid` descends from
+    pub fn fresnel_sibling(&self, uid: &Uid) -> Option<Uid> {
+        let (branch, child) = self.fresnel_branch(uid)?;
+        let split = self.get_prev(child.seq_id)?;
+        self.get_next(&split)
+            .into_iter()
+            .find(|sibling| FresnelBranch::of(sibling.event).is_some_and(|other| other != branch))
+    }
+
+    // Parent event of a child root, None for any other entry
+    pub fn get_parent(&self, uid: &Uid) -> Option<Uid> {
+        if !self.contains(uid) {
+            return None;
+        }
+        self.parents.get(&uid.seq_id).cloned()
+    }
+
+    // Same as `get_next`, including the child roots of secondary photons
+    pub fn get_next_across(&self, uid: &Uid) -> Vec<Uid> {
+        let mut next_uids = self.get_next(uid);
+        next_uids.extend_from_slice(self.get_child_roots(uid));
+        next_uids
+    }
+
+    // Same as `get_chain`, continuing through the parents of child roots up to the primary photon
+    pub fn get_chain_across(&self, last_uid: Uid) -> Vec<Uid> {
+        let mut chain = self.get_chain(last_uid);
+        while let Some(parent) = self.get_parent(&chain[0]) {
+            let mut parent_chain = self.get_chain(parent);
+            parent_chain.append(&mut chain);
+            chain = parent_chain;
+        }
+        chain
+    }
+
+    // Every entry with its decoded event and source names, None if the source isn't registered.
+    // Entries sharing a raw event are only decoded and looked up once.
+    pub fn iter_decoded(&self) -> impl Iterator<Item = (Uid, EventId, Option<&[SrcName]>)> + '_ {
+        let mut decoded: HashMap<u32, (EventId, Option<&[SrcName]>)> = HashMap::new();
+        self.entries().map(move |uid| {
+            let (event_id, names) = decoded.entry(uid.event).or_insert_with(|| {
+                let event_id = uid.event.decode();
+                let names = self.event_names(&event_id);
+                (event_id, (!names.is_empty()).then_some(names))
+            });
+            (uid, event_id.clone(), *names)
+        })
+    }
+
+    // Every entry whose event matches a single mask/value, regardless of its position in the chain
+    // Entries matching any alternative of `step`, its repeat counts don't apply to single events
+    pub fn find_events<S: Into<FilterStep>>(&self, step: S) -> Vec<Uid> {
+        let step = step.into();
+        self.entries().filter(|uid| step.matches(uid.event)).collect()
+    }
+
+    #[cfg(feature = "parallel")]
+    pub fn par_chains(&self) -> impl rayon::iter::ParallelIterator<Item = Vec<Uid>> + '_ {
+        use rayon::iter::{IntoParallelIterator, ParallelIterator};
+        self.leaves().into_par_iter().map(|uid| self.get_chain(uid))
+    }
+
+    // Event graph of the whole ledger, nodes are numbered in the order of the chains' entries
+    #[cfg(feature = "graph")]
+    pub fn to_petgraph(&self) -> petgraph::graph::DiGraph<EventId, ()> {
+        self.to_petgraph_with(None, None).0
+    }
+
+    // Event graph of the chains matching `filter` (see `find_forward_uid_seq`), adding the whole
+    // chains which keep the graph within `max_nodes` nodes. Edges link each entry to its subsequent events
+    // and to the child roots it started. Returns the UID of each node, indexed by `NodeIndex::index`.
+    #[cfg(feature = "graph")]
+    pub fn to_petgraph_with(
+        &self,
+        filter: Option<Vec<BitsMatch>>,
+        max_nodes: Option<usize>,
+    ) -> (petgraph::graph::DiGraph<EventId, ()>, Vec<Uid>) {
+        use petgraph::graph::{DiGraph, NodeIndex};
+
+        let leaves = match filter {
+            Some(filter) => crate::filter::find_forward_uid_seq(self, filter),
+            None => self.leaves(),
+        };
+        let max_nodes = max_nodes.unwrap_or(usize::MAX);
+        let mut graph = DiGraph::new();
+        let mut nodes: HashMap<Uid, NodeIndex> = HashMap::new();
+        let mut uids = Vec::new();
+        for leaf in leaves {
+            let chain = self.get_chain_across(leaf);
+            let new_nodes = chain.iter().filter(|uid| !nodes.contains_key(uid)).count();
+            if uids.len() + new_nodes > max_nodes {
+                continue;
+            }
+            for uid in chain {
+                nodes.entry(uid).or_insert_with(|| {
+                    uids.push(uid);
+                    graph.add_node(uid.event.decode())
+                });
+            }
+        }
+        for uid in &uids {
+            let targets = self.get_next(uid).into_iter().chain(self.get_child_roots(uid).iter().cloned());
+            for target in targets {
+                if let Some(target) = nodes.get(&target) {
+                    graph.add_edge(nodes[uid], *target, ());
+                }
+            }
+        }
+        (graph, uids)
+    }
+
+    // Reproducible random subset of `n` chains, in the order of their leaves
+    pub fn sample_chains(&self, n: usize, seed: u64) -> Vec<Vec<Uid>> {
+        sample_uids(&self.leaves(), n, seed)
+            .into_iter()
+            .map(|uid| self.get_chain(uid))
+            .collect()
+    }
+
+    // All chains starting with an Emission event and ending with a Detection event, ordered from
+    // the emission to the detection
+    pub fn complete_chains(&self) -> Vec<Vec<Uid>> {
+        let is_pipeline = |uid: &Uid, pipeline: Pipeline| (uid.event & Pipeline::mask()) == pipeline.encode();
+        self.entries()
+            .filter(|uid| is_pipeline(uid, Pipeline::Detection))
+            .map(|uid| self.get_chain(uid))
+            .filter(|chain| chain.first().is_some_and(|uid| is_pipeline(uid, Pipeline::Emission)))
+            .collect()
+    }
+
+    // Group the chains by their sequence of events masked with `mask`, i.e. `Pipeline::mask()` to
+    // only tell pipelines apart. Classes are ordered by decreasing multiplicity, then signature.
+    pub fn dedup_chains(&self, mask: u32) -> Vec<ChainClass> {
+        let mut classes: HashMap<Vec<u32>, ChainClass> = HashMap::new();
+        for leaf in self.leaves() {
+            let signature: Vec<u32> = self.get_chain(leaf).iter().map(|uid| uid.event & mask).collect();
+            classes
+                .entry(signature)
+                .and_modify(|class| class.multiplicity += 1)
+                .or_insert_with_key(|signature| ChainClass { signature: signature.clone(), multiplicity: 1, representative: leaf });
+        }
+        let mut classes: Vec<ChainClass> = classes.into_values().collect();
+        classes.sort_by(|a, b| b.multiplicity.cmp(&a.multiplicity).then_with(|| a.signature.cmp(&b.signature)));
+        classes
+    }
+
+    // Run-length summary of the chain ending in `leaf`, see `ChainSummary`
+    pub fn summarize_chain(&self, leaf: Uid, granularity: Granularity) -> ChainSummary {
+        ChainSummary::summarize(self.get_chain(leaf).iter().map(|uid| uid.event), granularity)
+    }
+
+    // Decoded events of the chain ending in `leaf`, with the names of their sources, i.e. to print
+    // a photon history one step per line. Steps which don't decode, i.e. from a corrupted ledger,
+    // are errors without failing the rest of the chain.
+    pub fn describe_chain(&self, leaf: Uid) -> Vec<Result<ChainEntry, DecodeError>> {
+        self.get_chain(leaf).into_iter().map(|uid| self.describe_entry(uid)).collect()
+    }
+
+    pub fn describe_entry(&self, uid: Uid) -> Result<ChainEntry, DecodeError> {
+        let event_id = EventId::try_decode(uid.event)?;
+        let src_id = self.event_src(&event_id);
+        let names = self.names(&src_id).to_vec();
+        Ok(ChainEntry { seq_id: uid.seq_id, event_id, src_id, names })
+    }
+
+    // Index-based copy of the entries for GPU kernels, see `FlatBuffers`
+    pub fn to_flat_buffers(&self) -> FlatBuffers {
+        let mut order: Vec<Uid> = Vec::new();
+        let mut index: HashMap<Uid, u32> = HashMap::new();
+        let mut prev: Vec<u32> = Vec::new();
+        let mut queue: std::collections::VecDeque<(Uid, u32)> =
+            self.start_events.iter().map(|uid| (*uid, FlatBuffers::NO_PREV)).collect();
+        while let Some((uid, prev_idx)) = queue.pop_front() {
+            if index.contains_key(&uid) {
+                continue;
+            }
+            let idx = order.len() as u32;
+            index.insert(uid, idx);
+            order.push(uid);
+            prev.push(prev_idx);
+            queue.extend(self.get_next(&uid).into_iter().map(|next| (next, idx)));
+            queue.extend(self.get_child_roots(&uid).iter().map(|root| (*root, FlatBuffers::NO_PREV)));
+        }
+
+        let mut buffers = FlatBuffers {
+            events: order.iter().map(|uid| uid.event).collect(),
+            prev,
+            next_offsets: vec![0],
+            next: Vec::new(),
+            chain_offsets: vec![0],
+            chain_entries: Vec::new(),
+        };
+        for uid in &order {
+            let next = self.get_next(uid);
+            buffers.next.extend(next.iter().map(|next| index[next]));
+            buffers.next_offsets.push(buffers.next.len() as u32);
+            if next.is_empty() {
+                let start = buffers.chain_entries.len();
+                let mut idx = index[uid];
+                while idx != FlatBuffers::NO_PREV {
+                    buffers.chain_entries.push(idx);
+                    idx = buffers.prev[idx as usize];
+                }
+                buffers.chain_entries[start..].reverse();
+                buffers.chain_offsets.push(buffers.chain_entries.len() as u32);
+            }
+        }
+        buffers
+    }
+
+    // Position of the ledger to compute the next `delta_since` from
+    pub fn checkpoint(&self) -> LedgerCheckpoint {
+        LedgerCheckpoint {
+            next_seq_id: self.next_seq_id,
+            start_events: self.start_events.len(),
+            src_revision: self.src_revision,
+        }
+    }
+
+    // Sources and links added since `checkpoint`, see `LedgerDelta`
+    pub fn delta_since(&self, checkpoint: &LedgerCheckpoint) -> LedgerDelta {
+        let spilled = self.spill.as_ref().map(|spill| spill.store.entries()).unwrap_or_default();
+        let mut links: Vec<(u32, u32, u32)> = spilled
+            .into_iter()
+            .chain(self.next.iter().flat_map(|(seq_id, map)| {
+                map.iter().map(|(event, next_seq_id)| (*seq_id, *event, *next_seq_id))
+            }))
+            .filter(|(_, _, next_seq_id)| *next_seq_id >= checkpoint.next_seq_id)
+            .collect();
+        links.sort_by_key(|(seq_id, _, next_seq_id)| (*next_seq_id, *seq_id));
+        LedgerDelta {
+            sources: (self.src_revision != checkpoint.src_revision).then(|| self.src_table()),
+            links,
+            child_roots: self.parents.range(checkpoint.next_seq_id..).map(|(seq_id, parent)| (*seq_id, *parent)).collect(),
+            base: *checkpoint,
+            checkpoint: self.checkpoint(),
+        }
+    }
+
+    // Add the sources and links of a delta, returns the number of new entries. Fails if a link
+    // refers to an entry missing from this ledger, i.e. an earlier delta was skipped.
+    pub fn apply_delta(&mut self, delta: LedgerDelta) -> Result<usize, LedgerError> {
+        if self.next_seq_id < delta.base.next_seq_id || self.start_events.len() < delta.base.start_events {
+            return Err(LedgerError::DeltaOutOfOrder { seq_id: delta.base.next_seq_id, reached: self.next_seq_id });
+        }
+        if let Some(src_table) = delta.sources {
+            self.set_src_table(src_table);
+        }
+        let child_roots: BTreeMap<u32, Uid> = delta.child_roots.into_iter().collect();
+        let mut added = 0;
+        for (seq_id, event, next_seq_id) in delta.links {
+            let uid = Uid { seq_id, event };
+            let parent = child_roots.get(&seq_id);
+            if seq_id != 0 && parent.is_none() && self.get_prev(seq_id).is_none() && !self.parents.contains_key(&seq_id) {
+                return Err(LedgerError::DeltaOutOfOrder { seq_id, reached: self.next_seq_id });
+            }
+            if self.insert_entry(uid, next_seq_id) {
+                if seq_id == 0 {
+                    self.start_events.push(uid);
+                }
+                if let Some(parent) = parent {
+                    self.parents.insert(seq_id, *parent);
+                    self.child_roots.entry(*parent).or_default().push(uid);
+                }
+                self.notify(uid);
+                self.spill_cold_entries()?;
+                added += 1;
+            }
+        }
+        self.next_seq_id = self.next_seq_id.max(delta.checkpoint.next_seq_id);
+        Ok(added)
+    }
+
+}
+
+// Groups nest as '/' separated paths, i.e. "probe/fiber/cladding", each path registering a source
+// of its own. Surrounding separators and whitespace are dropped, such that " probe/fiber/" and
+// "probe/fiber" are the same group.
+fn group_path(grp_name: String) -> Result<String, LedgerError> {
+    let segments: Vec<&str> = grp_name.trim().trim_matches('/').split('/').map(str::trim).collect();
+    if segments.iter().any(|segment| segment.is_empty()) {
+        return Err(LedgerError::InvalidGroup(grp_name));
+    }
+    Ok(segments.join("/"))
+}
+
+// ----------------------------------------------------
+// Incremental deltas of a running ledger
+// ----------------------------------------------------
+// A monitoring process keeps a mirror of the ledger of a running simulation by applying the deltas
+// computed from successive checkpoints, without transferring the whole ledger again:
+//     let delta = ledger.delta_since(&checkpoint);
+//     checkpoint = delta.checkpoint;
+//     mirror.apply_delta(delta)?;
+// Entries are identified by the next seq_id they allocated, which only grows, so the links of a
+// delta are the (seq_id, raw event, next_seq_id) triples allocated since the checkpoint, along with
+// the parents of new child roots. Sources are sent whole whenever they changed. Timestamps,
+// probabilities, transition counts and weights, and packet tags are not included.
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LedgerCheckpoint {
+    pub next_seq_id: u32,
+    pub start_events: usize,
+    pub src_revision: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LedgerDelta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sources: Option<SrcTable>,
+    // (seq_id, raw event, next_seq_id), ordered by next_seq_id
+    pub links: Vec<(u32, u32, u32)>,
+    // (seq_id, parent) of the new child roots
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub child_roots: Vec<(u32, Uid)>,
+    // Checkpoint the delta was computed from, and of the ledger once the delta is applied
+    pub base: LedgerCheckpoint,
+    pub checkpoint: LedgerCheckpoint,
+}
+
+impl LedgerDelta {
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_none() && self.links.is_empty() && self.child_roots.is_empty()
+    }
+}
+
+// Chains sharing a signature, see `Ledger::dedup_chains`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainClass {
+    // Masked raw events, from the start event to the leaf
+    pub signature: Vec<u32>,
+    pub multiplicity: usize,
+    // Leaf of the first chain of the class, in the order of `leaves`
+    pub representative: Uid,
+}
+
+// ----------------------------------------------------
+// Flat buffers of the ledger, for GPU kernels
+// ----------------------------------------------------
+// Plain u32 arrays which can be uploaded as storage buffers. The entries are numbered in
+// breadth-first order from the start events, such that `prev[i] < i` and a kernel processing the
+// entries by increasing index always sees the previous entry first:
+// - events[i]: raw event of entry i
+// - prev[i]: index of the previous entry, NO_PREV for start events and child roots
+// - next[next_offsets[i]..next_offsets[i + 1]]: indices of the subsequent entries of entry i,
+//   next_offsets has one more element than events (CSR layout)
+// - chain_entries[chain_offsets[c]..chain_offsets[c + 1]]: indices of the entries of chain c,
+//   from its start event to its leaf, chains ordered by leaf index
+// Child roots start chains of their own, their parent entry is kept by the Ledger only.
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FlatBuffers {
+    pub events: Vec<u32>,
+    pub prev: Vec<u32>,
+    pub next_offsets: Vec<u32>,
+    pub next: Vec<u32>,
+    pub chain_offsets: Vec<u32>,
+    pub chain_entries: Vec<u32>,
+}
 
-        let uid = Uid::new(next_seq_id, event.encode());
+impl FlatBuffers {
+    pub const NO_PREV: u32 = u32::MAX;
 
-        // FIXME: This is the only portion of the Ledger that needs to be accessed concurently.
-        // Then we should encapsulate this section to run it atomically, then the Ledger can
-        // implement Send + Sync traits safely without Arc<Mutex>
-        if self.insert_entry(uid.clone(), self.next_seq_id) {
-            self.next_seq_id += 1;
-        }
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
 
-        uid
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
     }
 
-    fn insert_entry(&mut self, uid: Uid, next_seq_id: u32) -> bool {
-        if None == self.get_next_seq_id(&uid) {
-            if self.next.get(&uid.seq_id) == None {
-                self.next.insert(uid.seq_id, BTreeMap::new());
-            }
-            self.next
-                .get_mut(&uid.seq_id)
-                .unwrap()
-                .insert(uid.event, next_seq_id);
-            self.prev.insert(next_seq_id, uid.clone());
-            true
-        } else {
-            false
-        }
+    pub fn chain_count(&self) -> usize {
+        self.chain_offsets.len() - 1
     }
 
-    pub fn get_start_events(&self) -> &Vec<Uid> {
-        &self.start_events
+    pub fn chain(&self, chain: usize) -> &[u32] {
+        &self.chain_entries[self.chain_offsets[chain] as usize..self.chain_offsets[chain + 1] as usize]
     }
+}
 
-    pub fn get_next_seq_id(&self, uid: &Uid) -> Option<u32> {
-        match self.next.get(&uid.seq_id) {
-            None => None,
-            Some(map) => map.get(&uid.event).cloned(),
-        }
+// ----------------------------------------------------
+// Frozen, shareable view of a Ledger
+// ----------------------------------------------------
+// Cloning a LedgerView only bumps the reference count, such that analysis threads can each hold
+// a handle and run any of the `&self` query methods of the Ledger without locking.
+
+#[derive(Clone)]
+pub struct LedgerView {
+    ledger: Arc<Ledger>,
+}
+
+impl LedgerView {
+    pub fn new(ledger: Ledger) -> Self {
+        Self { ledger: Arc::new(ledger) }
     }
-    pub fn get_next(&self, uid: &Uid) -> Vec<Uid> {
-        let mut next_uids = Vec::new();
-        if let Some(next_seq_id) = self.get_next_seq_id(&uid) {
-            if let Some(map) = self.next.get(&next_seq_id) {
-                for (next_event, _) in map {
-                    let next_uid = Uid::new(next_seq_id, *next_event);
-                    next_uids.push(next_uid);
-                }
-            }
-        }
-        next_uids
+
+    // Recover the Ledger if this is the last handle to it
+    pub fn try_into_ledger(self) -> Result<Ledger, Self> {
+        Arc::try_unwrap(self.ledger).map_err(|ledger| Self { ledger })
     }
+}
 
-    pub fn get_prev(&self, seq_id: u32) -> Option<Uid> {
-        self.prev.get(&seq_id).cloned()
+impl std::ops::Deref for LedgerView {
+    type Target = Ledger;
+    fn deref(&self) -> &Self::Target {
+        &self.ledger
     }
+}
 
-    pub fn get_chain(&self, last_uid: Uid) -> Vec<Uid> {
-        let mut chain = Vec::new();
-        chain.push(last_uid.clone());
-        let mut seq_id = last_uid.seq_id;
-        while let Some(uid) = self.get_prev(seq_id) {
-            chain.push(uid.clone());
-            seq_id = uid.seq_id;
-        }
-        chain.reverse();
-        chain
+impl From<Ledger> for LedgerView {
+    fn from(ledger: Ledger) -> Self {
+        Self::new(ledger)
     }
+}
 
-    fn check_ids(&self) {
-        if self.next_mat_id >= self.next_matsurf_id {
-            warn!("Material ID and Material-Surface ID ranges are overlapping");
-        }
-        if self.next_surf_id >= self.next_matsurf_id {
-            warn!("Surface ID and Material-Surface ID ranges are overlapping");
-        }
+impl Ledger {
+    pub fn freeze(self) -> LedgerView {
+        LedgerView::new(self)
     }
 }
 
 // ----------------------------------------------------
 // Helper methods and structs
 // ----------------------------------------------------
-// - Custom serializer/deserializer for BTreeMap<u32, u32> with hex keys
+// - Custom serializer/deserializer for BTreeMap<RawEvent, u32> with hex keys
+// - Typed records of the sources, instead of "Mat(3)" map keys
+// - Next map of a spilling ledger, read back group by group
 
 pub struct HexInnerMap;
 
-impl SerializeAs<BTreeMap<u32, u32>> for HexInnerMap {
-    fn serialize_as<S>(value: &BTreeMap<u32, u32>, serializer: S) -> Result<S::Ok, S::Error>
+impl<E: RawEvent> SerializeAs<BTreeMap<E, u32>> for HexInnerMap {
+    fn serialize_as<S>(value: &BTreeMap<E, u32>, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
@@ -494,15 +2632,43 @@ impl SerializeAs<BTreeMap<u32, u32>> for HexInnerMap {
 
         let mut map = serializer.serialize_map(Some(value.len()))?;
         for (k, v) in value {
-            let key = format!("0x{:08X}", k); // hex key
+            let key = event_hex(k);
             map.serialize_entry(&key, v)?;
         }
         map.end()
     }
 }
 
-impl<'de> DeserializeAs<'de, BTreeMap<u32, u32>> for HexInnerMap {
-    fn deserialize_as<D>(deserializer: D) -> Result<BTreeMap<u32, u32>, D::Error>
+// Same serialization as the `next` field, merging the spilled entries of each group with the ones
+// in memory, see `write_ledger_to_json`
+struct NextGroups<'a, E: RawEvent>(&'a Ledger<E>);
+
+impl<E: RawEvent> Serialize for NextGroups<'_, E> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeMap;
+
+        let ledger = self.0;
+        let mut seq_ids: std::collections::BTreeSet<u32> = ledger.next.keys().cloned().collect();
+        if let Some(spill) = ledger.spill.as_ref() {
+            seq_ids.extend(spill.store.group_ids());
+        }
+        let mut map = serializer.serialize_map(Some(seq_ids.len()))?;
+        for seq_id in seq_ids {
+            let mut group = ledger.next.get(&seq_id).cloned().unwrap_or_default();
+            if let Some(spill) = ledger.spill.as_ref() {
+                group.extend(spill.store.group(seq_id));
+            }
+            map.serialize_entry(&seq_id, &serde_with::ser::SerializeAsWrap::<_, HexInnerMap>::new(&group))?;
+        }
+        map.end()
+    }
+}
+
+impl<'de, E: RawEvent> DeserializeAs<'de, BTreeMap<E, u32>> for HexInnerMap {
+    fn deserialize_as<D>(deserializer: D) -> Result<BTreeMap<E, u32>, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
@@ -510,13 +2676,13 @@ impl<'de> DeserializeAs<'de, BTreeMap<u32, u32>> for HexInnerMap {
         use std::collections::BTreeMap as StdBTreeMap;
         use std::fmt;
 
-        struct HexInnerVisitor;
+        struct HexInnerVisitor<E>(std::marker::PhantomData<E>);
 
-        impl<'de> Visitor<'de> for HexInnerVisitor {
-            type Value = BTreeMap<u32, u32>;
+        impl<'de, E: RawEvent> Visitor<'de> for HexInnerVisitor<E> {
+            type Value = BTreeMap<E, u32>;
 
             fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
-                f.write_str("map with hex-encoded u32 keys")
+                f.write_str("map with hex-encoded raw event keys")
             }
 
             fn visit_map<A>(self, mut access: A) -> Result<Self::Value, A::Error>
@@ -525,7 +2691,7 @@ impl<'de> DeserializeAs<'de, BTreeMap<u32, u32>> for HexInnerMap {
             {
                 let mut out = StdBTreeMap::new();
                 while let Some((k, v)) = access.next_entry::<String, u32>()? {
-                    let key = u32::from_str_radix(&k[2..10], 16)
+                    let key = parse_event_hex(&k)
                         .map_err(|e| A::Error::custom(format!("invalid hex key {k}: {e}")))?;
                     out.insert(key, v);
                 }
@@ -533,16 +2699,137 @@ impl<'de> DeserializeAs<'de, BTreeMap<u32, u32>> for HexInnerMap {
             }
         }
 
-        deserializer.deserialize_map(HexInnerVisitor)
+        deserializer.deserialize_map(HexInnerVisitor(std::marker::PhantomData))
+    }
+}
+
+// Sources are written as an array of records, sorted by kind and id:
+// [{"kind": "Mat", "id": 3, "names": [{"kind": "Mat", "name": "water"}]}, ...]
+// The names keep their own kind, i.e. surfaces grouped with a MatSurf source stay Surf names.
+// Ledgers written with the former {"Mat(3)": [{"Mat": "water"}]} map are still read.
+pub struct SrcRecords;
+
+#[derive(Serialize, Deserialize)]
+struct SrcRecord {
+    kind: SrcKind,
+    id: Option<u16>,
+    names: Vec<SrcNameRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SrcNameRecord {
+    kind: SrcKind,
+    name: String,
+}
+
+#[serde_as]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SrcMapRepr {
+    Records(Vec<SrcRecord>),
+    Legacy(#[serde_as(as = "HashMap<DisplayFromStr, _>")] HashMap<SrcId, Vec<SrcName>>),
+}
+
+impl SrcNameRecord {
+    fn new(src_name: &SrcName) -> Self {
+        let (kind, name) = match src_name {
+            SrcName::Light(name)    => (SrcKind::Light, name),
+            SrcName::Surf(name)     => (SrcKind::Surf, name),
+            SrcName::MatSurf(name)  => (SrcKind::MatSurf, name),
+            SrcName::Mat(name)      => (SrcKind::Mat, name),
+            SrcName::Detector(name) => (SrcKind::Detector, name),
+        };
+        SrcNameRecord { kind, name: name.clone() }
+    }
+
+    fn src_name(self) -> Result<SrcName, String> {
+        match self.kind {
+            SrcKind::Light    => Ok(SrcName::Light(self.name)),
+            SrcKind::Surf     => Ok(SrcName::Surf(self.name)),
+            SrcKind::MatSurf  => Ok(SrcName::MatSurf(self.name)),
+            SrcKind::Mat      => Ok(SrcName::Mat(self.name)),
+            SrcKind::Detector => Ok(SrcName::Detector(self.name)),
+            SrcKind::None     => Err(format!("Source name {} without a kind", self.name)),
+        }
+    }
+}
+
+impl SrcRecord {
+    fn src_id(&self) -> Result<SrcId, String> {
+        match (self.kind, self.id) {
+            (SrcKind::None, _)           => Ok(SrcId::None),
+            (SrcKind::Mat, Some(id))      => Ok(SrcId::Mat(id)),
+            (SrcKind::Surf, Some(id))     => Ok(SrcId::Surf(id)),
+            (SrcKind::MatSurf, Some(id))  => Ok(SrcId::MatSurf(id)),
+            (SrcKind::Light, Some(id))    => Ok(SrcId::Light(id)),
+            (SrcKind::Detector, Some(id)) => Ok(SrcId::Detector(id)),
+            (kind, None)                  => Err(format!("Source of kind {} without an id", kind)),
+        }
+    }
+}
+
+impl SerializeAs<HashMap<SrcId, Vec<SrcName>>> for SrcRecords {
+    fn serialize_as<S>(value: &HashMap<SrcId, Vec<SrcName>>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut sources: Vec<(&SrcId, &Vec<SrcName>)> = value.iter().collect();
+        sources.sort_by_key(|(src_id, _)| (src_id.kind().to_string(), src_id.id()));
+        serializer.collect_seq(sources.into_iter().map(|(src_id, names)| SrcRecord {
+            kind: src_id.kind(),
+            id: src_id.id(),
+            names: names.iter().map(SrcNameRecord::new).collect(),
+        }))
+    }
+}
+
+impl<'de> DeserializeAs<'de, HashMap<SrcId, Vec<SrcName>>> for SrcRecords {
+    fn deserialize_as<D>(deserializer: D) -> Result<HashMap<SrcId, Vec<SrcName>>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        match SrcMapRepr::deserialize(deserializer)? {
+            SrcMapRepr::Legacy(src_map) => Ok(src_map),
+            SrcMapRepr::Records(records) => records
+                .into_iter()
+                .map(|record| {
+                    let src_id = record.src_id()?;
+                    let names = record.names.into_iter().map(SrcNameRecord::src_name).collect::<Result<_, _>>()?;
+                    Ok((src_id, names))
+                })
+                .collect::<Result<_, String>>()
+                .map_err(D::Error::custom),
+        }
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use tempfile::tempdir;
     use std::fs;
 
+    // Ledger with a "laser" light and a "water" material, returned with their ids
+    pub(crate) fn laser_in_water() -> (Ledger, SrcId, SrcId) {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        (ledger, light_id, mat_id)
+    }
+
+    // Same, holding a single chain: PencilBeam emission, forward Mie scattering in water and direct
+    // detection by Detector(0)
+    pub(crate) fn detected_chain() -> (Ledger, [Uid; 3]) {
+        let (mut ledger, light_id, mat_id) = laser_in_water();
+        let start = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let scatter = ledger.insert(start, EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+        let detection = EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(0));
+        let detected = ledger.insert(scatter, detection).unwrap();
+        (ledger, [start, scatter, detected])
+    }
+
     #[test]
     fn produce_src_id() {
         let surfs = vec![
@@ -561,7 +2848,7 @@ mod tests {
         let mut ledger = Ledger::new();
 
         for mat in mats {
-            let src_id = ledger.with_mat(mat.clone());
+            let src_id = ledger.with_mat(mat.clone()).unwrap();
             assert!(ledger.src_map.contains_key(&src_id));
             assert_eq!(
                 ledger
@@ -576,7 +2863,7 @@ mod tests {
         }
 
         for surf in surfs {
-            let src_id = ledger.with_surf(surf.clone(), None);
+            let src_id = ledger.with_surf(surf.clone(), None).unwrap();
             assert!(ledger.src_map.contains_key(&src_id));
             assert_eq!(
                 ledger
@@ -591,7 +2878,7 @@ mod tests {
         }
 
         for (obj, mat) in objects {
-            let src_id = ledger.with_matsurf(obj.clone(), mat.clone(), None);
+            let src_id = ledger.with_matsurf(obj.clone(), mat.clone(), None).unwrap();
             assert!(ledger.src_map.contains_key(&src_id));
             let expected_name = format!("{}:{}", obj.clone(), mat.clone());
             assert_eq!(
@@ -610,34 +2897,49 @@ mod tests {
         println!("Ledger src_map: {:?}", ledger.src_map);
     }
 
+    #[test]
+    fn src_id_lookup_by_name() {
+        let mut ledger = Ledger::new();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let surf_id = ledger.with_surf("probe".to_string(), None).unwrap();
+        let matsurf_id = ledger.with_matsurf("cube".to_string(), "glass".to_string(), None).unwrap();
+
+        assert_eq!(ledger.src_id_by_name("water"), Some(mat_id));
+        assert_eq!(ledger.src_id_by_name("probe"), Some(surf_id));
+        assert_eq!(ledger.src_id_by_name("cube:glass"), Some(matsurf_id));
+        assert_eq!(ledger.src_id_by_name("glass"), Some(matsurf_id));
+        assert_eq!(ledger.src_id_by_name("air"), None);
+        // Ambiguous once another pair shares the material
+        let sphere_id = ledger.with_matsurf("sphere".to_string(), "glass".to_string(), None).unwrap();
+        assert_eq!(ledger.src_id_by_name("glass"), None);
+        assert_eq!(ledger.src_id_by_name("sphere"), Some(sphere_id));
+        assert_eq!(ledger.src_id_by_name("cube:glass"), Some(matsurf_id));
+        assert_eq!(ledger.names(&mat_id), &[SrcName::Mat("water".to_string())]);
+        assert!(ledger.names(&SrcId::Light(0)).is_empty());
+
+        let air_id = ledger.with_mat("air".to_string()).unwrap();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mats: Vec<SrcId> = ledger.sources_of_kind(SrcKind::Mat).map(|(src_id, _)| src_id).collect();
+        assert_eq!(mats, vec![mat_id, air_id]);
+        assert_eq!(ledger.sources_of_kind(SrcKind::Light).collect::<Vec<_>>(), vec![(light_id, &[SrcName::Light("laser".to_string())][..])]);
+        assert_eq!(ledger.sources_of_kind(SrcKind::MatSurf).count(), 2);
+        assert_eq!(ledger.sources_of_kind(SrcKind::Detector).count(), 0);
+    }
+
     #[test]
     fn insert_events() {
         let mut ledger = Ledger::new();
-        let emission_event = EventId {
-            event_type: crate::EventType::Emission(crate::emission::Emission::PointSource),
-            src_id: SrcId::Light(2),
-        };
-        let uid1 = ledger.insert_start(emission_event);
+        let emission_event = EventId::new(crate::EventType::Emission(crate::emission::Emission::PointSource), SrcId::Light(2));
+        let uid1 = ledger.insert_start(emission_event).unwrap();
         assert_eq!(uid1.seq_id, 0);
-        let mcrt_event = EventId {
-            event_type: crate::EventType::MCRT(crate::mcrt_event!(
-                Material,
-                Elastic,
-                HenyeyGreenstein,
-                Forward
-            )),
-            src_id: SrcId::Mat(2),
-        };
-        let uid2 = ledger.insert(uid1.clone(), mcrt_event);
+        let mcrt_event = EventId::new(crate::EventType::MCRT(crate::mcrt_event!(Material, Elastic, HenyeyGreenstein, Forward)), SrcId::Mat(2));
+        let uid2 = ledger.insert(uid1, mcrt_event).unwrap();
         assert_eq!(uid2.seq_id, 1);
-        let mcrt_event = EventId {
-            event_type: crate::EventType::MCRT(crate::mcrt_event!(Material, Elastic, Mie, Forward)),
-            src_id: SrcId::Mat(2),
-        };
-        let uid3 = ledger.insert(uid2.clone(), mcrt_event);
+        let mcrt_event = EventId::new(crate::EventType::MCRT(crate::mcrt_event!(Material, Elastic, Mie, Forward)), SrcId::Mat(2));
+        let uid3 = ledger.insert(uid2, mcrt_event).unwrap();
         assert_eq!(uid3.seq_id, 2);
         // Check the chain
-        let chain = ledger.get_chain(uid3.clone());
+        let chain = ledger.get_chain(uid3);
         println!("Chain: {:?}", chain);
         println!(
             "Chain: {:?}",
@@ -656,32 +2958,929 @@ mod tests {
         assert_eq!(chain[2], uid3);
     }
 
+    #[test]
+    fn timestamped_entries() {
+        let mut ledger = Ledger::new();
+        ledger.enable_timestamps(TimeBase::Simulation);
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let uid1 = ledger.insert_start_at(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id), 0.5).unwrap();
+        let mat_id = ledger.with_mat("air".to_string()).unwrap();
+        let uid2 = ledger.insert_at(uid1, EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id), 2.5).unwrap();
+        // Without explicit time the entry inherits the time of its cause
+        let uid3 = ledger.insert(uid2, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id)).unwrap();
+
+        assert_eq!(ledger.get_timestamp(&uid1), Some(0.5));
+        assert_eq!(ledger.get_timestamp(&uid2), Some(2.5));
+        assert_eq!(ledger.get_timestamp(&uid3), Some(2.5));
+        assert_eq!(ledger.get_time_window(1.0, 3.0), vec![uid2, uid3]);
+        assert_eq!(ledger.get_entry_rate(1.0), vec![1, 0, 2]);
+        assert!(ledger.get_entry_rate(0.0).is_empty());
+
+        // Spilled entries are counted as well
+        let dir = tempfile::tempdir().unwrap();
+        let mut spilled = Ledger::new();
+        spilled.enable_timestamps(TimeBase::Simulation);
+        spilled.enable_spill_in(dir.path(), 2 * crate::spill::ENTRY_BYTES).unwrap();
+        let mut leaf = spilled.insert_start_at(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id), 0.5).unwrap();
+        for time in 1..6 {
+            leaf = spilled.insert_at(leaf, EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id), time as f32).unwrap();
+        }
+        assert!(spilled.prev.len() < 6);
+        assert_eq!(spilled.get_time_window(1.0, 4.0).len(), 3);
+        assert_eq!(spilled.get_entry_rate(2.0), vec![2, 2, 2]);
+
+        let json = serde_json::to_string(&ledger).expect("Unable to serialize ledger");
+        let stored_ledger: Ledger = serde_json::from_str(&json).expect("Unable to parse ledger");
+        assert_eq!(ledger.timestamps, stored_ledger.timestamps);
+    }
+
+    #[test]
+    fn complete_emission_detection_chains() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let detector_id = SrcId::Detector(0);
+        let uid1 = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let uid2 = ledger.insert(uid1, EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+        let uid3 = ledger.insert(uid2, EventId::new_detection(crate::detection::Detection::Direct, detector_id)).unwrap();
+        // Absorbed photon does not reach the detector
+        ledger.insert(uid2, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        let uid4 = ledger.insert(uid1, EventId::new_detection(crate::detection::Detection::Direct, detector_id)).unwrap();
+
+        let chains = ledger.complete_chains();
+        assert_eq!(chains.len(), 2);
+        assert!(chains.contains(&vec![uid1, uid2, uid3]));
+        assert!(chains.contains(&vec![uid1, uid4]));
+    }
+
+    #[test]
+    fn chains_of_leaves() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let uid1 = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let uid2 = ledger.insert(uid1, EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+        let uid3 = ledger.insert(uid2, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        let uid4 = ledger.insert(uid1, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id)).unwrap();
+
+        let mut chains: Vec<_> = ledger.chains().collect();
+        chains.sort();
+        let mut expected = vec![vec![uid1, uid2, uid3], vec![uid1, uid4]];
+        expected.sort();
+        assert_eq!(chains, expected);
+
+        #[cfg(feature = "parallel")]
+        {
+            use rayon::iter::ParallelIterator;
+            let mut par_chains: Vec<_> = ledger.par_chains().collect();
+            par_chains.sort();
+            assert_eq!(par_chains, chains);
+        }
+    }
+
+    #[test]
+    fn chain_depths() {
+        for max_depth in [None, Some(10)] {
+            let mut ledger = Ledger::new();
+            ledger.set_max_depth(max_depth);
+            let light_id = ledger.with_light("laser".to_string()).unwrap();
+            let mat_id = ledger.with_mat("water".to_string()).unwrap();
+            let detected = |detector| EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(detector));
+            let surf_id = ledger.with_surf("lens".to_string(), None).unwrap();
+            let start = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+            let ballistic = ledger.insert(start, detected(0)).unwrap();
+            let refracted = ledger.insert(start, EventId::new_mcrt(crate::mcrt_event!(Interface, Refraction), surf_id)).unwrap();
+            let refracted = ledger.insert(refracted, detected(0)).unwrap();
+            let mut diffuse = start;
+            for _ in 0..3 {
+                diffuse = ledger.insert(diffuse, EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, HenyeyGreenstein, Any), mat_id)).unwrap();
+            }
+            let absorbed = ledger.insert(diffuse, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id)).unwrap();
+            let diffuse = ledger.insert(diffuse, detected(1)).unwrap();
+
+            assert_eq!(ledger.chain_depth(start), 0);
+            assert_eq!(ledger.chain_depth(ballistic), 0);
+            assert_eq!(ledger.chain_depth(refracted), 0);
+            assert_eq!(ledger.chain_depth(diffuse), 3);
+            // Absorption is an interaction, but not a scattering
+            assert_eq!(ledger.chain_depth(absorbed), 3);
+            assert_eq!(ledger.depth_histogram(), BTreeMap::from([(0, 2), (3, 2)]));
+            // Cached at insert only for the Recorder, which truncates at `max_depth`
+            assert_eq!(ledger.depths.len(), if max_depth.is_some() { 8 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn sample_chains_reproducible() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        for i in 0..20 {
+            let mat_id = ledger.with_mat(format!("mat{}", i)).unwrap();
+            let uid = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+            let uid = ledger.insert(uid, EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+            ledger.insert(uid, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        }
+        assert_eq!(ledger.leaves().len(), 20);
+
+        let sample = ledger.sample_chains(5, 42);
+        assert_eq!(sample.len(), 5);
+        assert_eq!(sample, ledger.sample_chains(5, 42));
+        assert_ne!(sample, ledger.sample_chains(5, 7));
+        assert!(sample.iter().all(|chain| chain.len() == 3));
+        assert_eq!(ledger.sample_chains(100, 42).len(), 20);
+    }
+
+    #[test]
+    fn tag_packets() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let uid1 = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let uid2 = ledger.insert(uid1, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id)).unwrap();
+
+        ledger.tag_packet(uid2, 1001).expect("Unable to tag packet");
+        ledger.tag_packet(uid2, 1002).expect("Unable to tag packet");
+        assert!(ledger.tag_packet(uid2, 1001).is_ok());
+        assert!(matches!(ledger.tag_packet(uid1, 1001), Err(LedgerError::PacketTagged { packet_id: 1001, .. })));
+        assert_eq!(ledger.tag_packet(Uid::new(42, 0), 1003), Err(LedgerError::UnknownUid(Uid::new(42, 0).to_string())));
+
+        assert_eq!(ledger.packet_uid(1002), Some(uid2));
+        assert_eq!(ledger.packet_uid(1003), None);
+        assert_eq!(ledger.packet_ids(&uid2), &[1001, 1002]);
+        assert!(ledger.packet_ids(&uid1).is_empty());
+
+        let json = serde_json::to_string(&ledger).expect("Unable to serialize ledger");
+        let ledger: Ledger = serde_json::from_str(&json).expect("Unable to deserialize ledger");
+        assert_eq!(ledger.packet_uid(1001), Some(uid2));
+        assert_eq!(ledger.packet_ids(&uid2), &[1001, 1002]);
+    }
+
+    #[test]
+    fn split_branches() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let uid1 = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let split = ledger.insert(uid1, EventId::new_transport(crate::transport::Transport::Split)).unwrap();
+        let copy1 = ledger.insert(split, EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+        let copy1_next = ledger.insert(copy1, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        let copy2 = ledger.insert(split, EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Backward), mat_id)).unwrap();
+        let copy3 = ledger.insert(split, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        // A copy repeating the history of another one shares its entries
+        assert_eq!(ledger.insert(split, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id)).unwrap(), copy3);
+
+        let mut branches = ledger.branches(&split);
+        branches.sort();
+        let mut expected = vec![vec![copy1, copy1_next], vec![copy2], vec![copy3]];
+        expected.sort();
+        assert_eq!(branches, expected);
+        for chain in ledger.chains() {
+            assert_eq!(&chain[..2], &[uid1, split]);
+        }
+        assert!(ledger.branches(&copy3).is_empty());
+    }
+
+    #[test]
+    fn run_metadata() {
+        let mut ledger = Ledger::new();
+        assert_eq!(ledger.metadata(), None);
+        let metadata = RunMetadata::new("run-42".to_string())
+            .with_seed(0xC0FFEE)
+            .with_scene_hash("3f2a9c".to_string())
+            .with_engine_version("aetherus 0.4.1".to_string())
+            .with_entry("operator".to_string(), "lab-b".to_string());
+        ledger.set_metadata(metadata.clone());
+
+        let json: serde_json::Value = serde_json::to_value(&ledger).unwrap();
+        assert_eq!(json["metadata"]["rng_seed"], 0xC0FFEE);
+        assert_eq!(json["metadata"]["extra"]["operator"], "lab-b");
+        let restored: Ledger = serde_json::from_value(json).unwrap();
+        assert_eq!(restored.metadata(), Some(&metadata));
+        assert_eq!(Ledger::from_src_table(ledger.src_table()).metadata(), Some(&metadata));
+    }
+
+    #[test]
+    fn audit_log() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let events: Vec<&AuditEvent> = ledger.audit().entries().iter().map(|entry| &entry.event).collect();
+        assert_eq!(events, [
+            &AuditEvent::SrcRegistered { src_id: light_id, name: SrcName::Light("laser".to_string()) },
+            &AuditEvent::SrcRegistered { src_id: mat_id, name: SrcName::Mat("water".to_string()) },
+        ]);
+        assert!(!ledger.audit().is_recording());
+
+        ledger.start_recording();
+        ledger.start_recording();
+        ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        assert!(ledger.audit().is_recording());
+        ledger.stop_recording();
+        ledger.start_recording();
+        let segments = ledger.audit().recording_segments();
+        assert_eq!(segments.len(), 2);
+        assert!(segments[0].1.is_some_and(|stop| stop >= segments[0].0));
+        assert_eq!(segments[1].1, None);
+
+        let json = serde_json::to_string(&ledger).unwrap();
+        let restored: Ledger = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.audit(), ledger.audit());
+    }
+
+    #[test]
+    fn dedup_masked_chains() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let start = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let detection = EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(0));
+        let mut leaves = Vec::new();
+        let forward = crate::mcrt_event!(Material, Elastic, Mie, Forward);
+        let backward = crate::mcrt_event!(Material, Elastic, Mie, Backward);
+        for scatter in [forward, backward] {
+            let uid = ledger.insert(start, EventId::new_mcrt(scatter, mat_id)).unwrap();
+            leaves.push(ledger.insert(uid, detection.clone()).unwrap());
+        }
+        ledger.insert(start, detection.clone()).unwrap();
+
+        let classes = ledger.dedup_chains(Pipeline::mask());
+        assert_eq!(classes.len(), 2);
+        assert_eq!(classes[0].multiplicity, 2);
+        assert_eq!(classes[0].signature, vec![0x01000000, 0x03000000, 0x05000000]);
+        assert!(leaves.contains(&classes[0].representative));
+        assert_eq!(classes[1].signature, vec![0x01000000, 0x05000000]);
+        // Unmasked, every chain is its own class
+        assert_eq!(ledger.dedup_chains(u32::MAX).iter().map(|class| class.multiplicity).sum::<usize>(), 3);
+        assert!(ledger.dedup_chains(u32::MAX).iter().all(|class| class.multiplicity == 1));
+    }
+
+    #[cfg(feature = "graph")]
+    #[test]
+    fn petgraph_conversion() {
+        use petgraph::algo::{has_path_connecting, toposort};
+
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let start = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let fluorescence = crate::mcrt_event!(Material, Inelastic, Fluorescence, Any);
+        let uid1 = ledger.insert(start, EventId::new_mcrt(fluorescence, mat_id)).unwrap();
+        let uid2 = ledger.insert(start, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        let child = ledger.insert_child_root(uid1, EventId::new_emission(crate::emission::Emission::PointSource, light_id)).unwrap();
+
+        let graph = ledger.to_petgraph();
+        assert_eq!((graph.node_count(), graph.edge_count()), (4, 3));
+        assert!(toposort(&graph, None).is_ok());
+
+        let (graph, uids) = ledger.to_petgraph_with(None, None);
+        let node = |uid: Uid| petgraph::graph::NodeIndex::new(uids.iter().position(|node| *node == uid).unwrap());
+        assert!(has_path_connecting(&graph, node(start), node(child), None));
+        assert!(!has_path_connecting(&graph, node(uid2), node(child), None));
+        assert_eq!(graph[node(uid2)].encode(), uid2.event);
+
+        let filter = vec![BitsMatch::new(u32::MAX, uid2.event)];
+        let (graph, uids) = ledger.to_petgraph_with(Some(filter), None);
+        assert_eq!(uids, vec![start, uid2]);
+        assert_eq!(graph.edge_count(), 1);
+        assert_eq!(ledger.to_petgraph_with(None, Some(2)).0.node_count(), 2);
+    }
+
+    #[test]
+    fn flat_buffers_layout() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let start = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let fluorescence = crate::mcrt_event!(Material, Inelastic, Fluorescence, Any);
+        let uid1 = ledger.insert(start, EventId::new_mcrt(fluorescence, mat_id)).unwrap();
+        let uid2 = ledger.insert(uid1, EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(0))).unwrap();
+        let uid3 = ledger.insert(start, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        let child = ledger.insert_child_root(uid1, EventId::new_emission(crate::emission::Emission::PointSource, light_id)).unwrap();
+
+        let buffers = ledger.to_flat_buffers();
+        assert_eq!(buffers.len(), 5);
+        assert_eq!(buffers.next_offsets.len(), 6);
+        assert!(buffers.prev.iter().enumerate().all(|(idx, prev)| *prev == FlatBuffers::NO_PREV || (*prev as usize) < idx));
+        assert_eq!(buffers.prev.iter().filter(|prev| **prev == FlatBuffers::NO_PREV).count(), 2);
+
+        // Same chains of events as the ledger
+        let mut chains: Vec<Vec<u32>> = (0..buffers.chain_count())
+            .map(|chain| buffers.chain(chain).iter().map(|idx| buffers.events[*idx as usize]).collect())
+            .collect();
+        chains.sort();
+        let mut expected: Vec<Vec<u32>> = ledger.chains().map(|chain| chain.iter().map(|uid| uid.event).collect()).collect();
+        expected.sort();
+        assert_eq!(chains, expected);
+
+        let idx = |uid: Uid| buffers.events.iter().position(|event| *event == uid.event).unwrap();
+        let next = &buffers.next[buffers.next_offsets[idx(start)] as usize..buffers.next_offsets[idx(start) + 1] as usize];
+        assert_eq!(next.len(), 2);
+        assert!(next.contains(&(idx(uid1) as u32)) && next.contains(&(idx(uid3) as u32)));
+        assert_eq!(buffers.prev[idx(uid2)], idx(uid1) as u32);
+        assert_eq!(buffers.prev[idx(child)], FlatBuffers::NO_PREV);
+    }
+
+    #[test]
+    fn children_and_subtree() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let surf_id = ledger.with_surf("lens".to_string(), None).unwrap();
+        let mat_id = ledger.with_mat("dye".to_string()).unwrap();
+        let start = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let split = ledger.insert(start, EventId::new_mcrt(crate::mcrt_event!(Interface, FresnelSplit), surf_id)).unwrap();
+        let reflected = ledger.insert(split, EventId::new_mcrt(crate::mcrt_event!(Interface, Reflection), surf_id)).unwrap();
+        let refracted = ledger.insert(split, EventId::new_mcrt(crate::mcrt_event!(Interface, Refraction), surf_id)).unwrap();
+        let absorbed = ledger.insert(refracted, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        let child = ledger.insert_child_root(absorbed, EventId::new_emission(crate::emission::Emission::PointSource, light_id)).unwrap();
+
+        let (mut children, mut branches) = (ledger.get_children(&split), vec![reflected, refracted]);
+        children.sort();
+        branches.sort();
+        assert_eq!(children, branches);
+        assert_eq!(ledger.get_children(&absorbed), vec![child]);
+        let subtree = ledger.get_subtree(&split);
+        assert_eq!(subtree.len(), 4);
+        assert!(subtree.iter().position(|uid| *uid == refracted) < subtree.iter().position(|uid| *uid == child));
+        assert!(ledger.get_subtree(&reflected).is_empty());
+        // Each branch still resolves to its own chain
+        assert_eq!(ledger.get_chain(reflected), vec![start, split, reflected]);
+        assert_eq!(ledger.get_chain(absorbed), vec![start, split, refracted, absorbed]);
+    }
+
+    #[test]
+    fn separate_start_events() {
+        let mut ledger = Ledger::new();
+        let laser_id = ledger.with_light("laser".to_string()).unwrap();
+        let lamp_id = ledger.with_light("lamp".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let scatter = EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id);
+        let laser = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, laser_id)).unwrap();
+        let lamp = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PointSource, lamp_id)).unwrap();
+        let laser_scattered = ledger.insert(laser, scatter.clone()).unwrap();
+        let lamp_scattered = ledger.insert(lamp, scatter).unwrap();
+
+        // The same event after different start events is a different entry
+        assert_ne!(ledger.get_next_seq_id(&laser), ledger.get_next_seq_id(&lamp));
+        assert_ne!(laser_scattered, lamp_scattered);
+        assert_eq!(ledger.get_chain(laser_scattered), vec![laser, laser_scattered]);
+        assert_eq!(ledger.get_chain(lamp_scattered), vec![lamp, lamp_scattered]);
+        assert_eq!(ledger.get_children(&laser), vec![laser_scattered]);
+        assert_eq!(ledger.get_children(&lamp), vec![lamp_scattered]);
+        assert_eq!(ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, laser_id)).unwrap(), laser);
+        assert_eq!(ledger.get_start_events(), &vec![laser, lamp]);
+    }
+
+    #[test]
+    fn describe_chains() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let start = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let scattered = ledger.insert(start, EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+        let detected = ledger.insert(scattered, EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(3))).unwrap();
+
+        let steps = ledger.describe_chain(detected).into_iter().collect::<Result<Vec<ChainEntry>, _>>().unwrap();
+        assert_eq!(steps.iter().map(ChainEntry::uid).collect::<Vec<_>>(), ledger.get_chain(detected));
+        assert_eq!(steps[1].seq_id, scattered.seq_id);
+        assert_eq!(steps[1].event_id, EventId::decode(scattered.event));
+        assert_eq!(steps[1].src_id, mat_id);
+        assert_eq!(steps[1].names, vec![SrcName::Mat("water".to_string())]);
+        assert!(steps[2].names.is_empty());
+        assert_eq!(steps[1].to_string(), format!("{}: MCRT(Material(Elastic(Mie(Forward)))) Mat(0) [water]", scattered.seq_id));
+        assert!(steps[0].to_string().ends_with("Light(0) [laser]"), "{}", steps[0]);
+
+        // Unknown detection code
+        let corrupted = ledger.insert_raw(scattered, 0x050F0002).unwrap();
+        let steps = ledger.describe_chain(corrupted);
+        assert!(steps[1].is_ok());
+        assert_eq!(steps[2], Err(DecodeError::UnknownCode { field: "Detection", code: 15, raw: 0x050F0002 }));
+    }
+
+    #[test]
+    fn transition_counts() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let emission = EventId::new_emission(crate::emission::Emission::PencilBeam, light_id);
+        let forward = EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id);
+        let absorption = EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id);
+        let re_emission = EventId::new_emission(crate::emission::Emission::PointSource, light_id);
+        let mut absorbed = Uid::new(0, 0);
+        for photon in 0..10 {
+            let start = ledger.insert_start(emission.clone()).unwrap();
+            let scattered = ledger.insert(start, forward.clone()).unwrap();
+            if photon % 5 == 0 {
+                absorbed = ledger.insert(scattered, absorption.clone()).unwrap();
+                ledger.insert_child_root(absorbed, re_emission.clone()).unwrap();
+            }
+        }
+        let start = ledger.get_start_events()[0];
+        let scattered = ledger.get_next(&start)[0];
+        let child = ledger.get_child_roots(&absorbed)[0];
+        assert_eq!(ledger.get_count(&start), 10);
+        assert_eq!(ledger.get_count(&scattered), 10);
+        assert_eq!(ledger.get_count(&absorbed), 2);
+        assert_eq!(ledger.get_count(&child), 2);
+        assert_eq!(ledger.get_count(&Uid::new(99, start.event)), 0);
+
+        let json = serde_json::to_string(&ledger).unwrap();
+        let next_seq_id = ledger.get_next_seq_id(&scattered).unwrap().to_string();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&json).unwrap()["counts"][next_seq_id], 10);
+        let mut restored: Ledger = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.get_count(&absorbed), 2);
+
+        // Counts of the same transitions add up, and follow the entries when renumbered
+        restored.merge(ledger).unwrap();
+        assert_eq!(restored.get_count(&scattered), 20);
+        assert_eq!(restored.get_count(&child), 4);
+        let remap = restored.compact();
+        assert_eq!(restored.get_count(&remap.uid(&child).unwrap()), 4);
+    }
+
+    #[test]
+    fn transition_weights() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let emission = EventId::new_emission(crate::emission::Emission::PencilBeam, light_id);
+        let forward = EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id);
+        let roulette = EventId::new_mcrt(MCRT::Material(crate::mcrt::Material::Roulette(crate::mcrt::Roulette::Survived { boost_class: 1 })), mat_id);
+        let mut leaf = Uid::new(0, 0);
+        for _ in 0..4 {
+            let start = ledger.insert_start_weighted(emission.clone(), 1.0).unwrap();
+            let scattered = ledger.insert_weighted(start, forward.clone(), 0.5).unwrap();
+            leaf = ledger.insert_weighted(scattered, roulette.clone(), 2.0).unwrap();
+        }
+        // Unweighted inserts count the photon without adding to the weight
+        let start = ledger.insert_start(emission.clone()).unwrap();
+        let scattered = ledger.insert(start, forward.clone()).unwrap();
+        assert!(ledger.has_weights());
+        assert_eq!(ledger.get_weight(&start), 4.0);
+        assert_eq!(ledger.get_weight(&scattered), 2.0);
+        assert_eq!(ledger.get_count(&scattered), 5);
+        assert_eq!(ledger.get_weight(&leaf), 8.0);
+        assert_eq!(ledger.get_weight(&Uid::new(99, start.event)), 0.0);
+        assert!(ledger.insert_weighted(Uid::new(99, start.event), forward, 1.0).is_err());
+
+        let json = serde_json::to_string(&ledger).unwrap();
+        let next_seq_id = ledger.get_next_seq_id(&leaf).unwrap().to_string();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&json).unwrap()["weights"][next_seq_id], 8.0);
+        let mut restored: Ledger = serde_json::from_str(&json).unwrap();
+        restored.merge(ledger).unwrap();
+        assert_eq!(restored.get_weight(&leaf), 16.0);
+        assert!(!Ledger::<u32>::new().has_weights());
+    }
+
+    #[test]
+    fn recoverable_errors() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let start = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let unknown = Uid::new(7, start.event);
+        let detection = EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(0));
+        assert_eq!(ledger.insert(unknown, detection.clone()), Err(LedgerError::UnknownUid(unknown.to_string())));
+        assert!(ledger.insert_child_root(unknown, detection.clone()).is_err());
+        assert_eq!(
+            ledger.with_surf("cladding".to_string(), Some("probe//cladding".to_string())),
+            Err(LedgerError::InvalidGroup("probe//cladding".to_string()))
+        );
+
+        // The failed calls left the ledger untouched
+        assert_eq!(ledger.entries().count(), 1);
+        assert_eq!(ledger.with_surf("cladding".to_string(), Some("probe/cladding".to_string())), Ok(SrcId::Surf(0)));
+        assert!(ledger.insert(start, detection).is_ok());
+        let err = LedgerError::GroupConflict { group: "probe".to_string(), src_id: light_id };
+        assert_eq!(err.to_string(), "Group name `probe` is already used for Light(0)");
+
+        // A light id already taken is skipped rather than overwritten
+        ledger.next_light_id = 0;
+        assert_eq!(ledger.with_light("lamp".to_string()), Ok(SrcId::Light(1)));
+        assert_eq!(ledger.names(&light_id), [SrcName::Light("laser".to_string())]);
+    }
+
+    #[test]
+    fn id_exhaustion() {
+        let mut ledger = Ledger::new();
+        assert_eq!(ledger.remaining_mat_ids(), u16::MAX);
+        assert_eq!(ledger.remaining_matsurf_ids(), u16::MAX);
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        assert_eq!(ledger.remaining_light_ids(), u16::MAX - 1);
+
+        // Materials meeting the downward MatSurf range
+        ledger.next_mat_id = u16::MAX - 2;
+        ledger.with_matsurf("cube".to_string(), "glass".to_string(), None).unwrap();
+        assert_eq!(ledger.remaining_mat_ids(), 1);
+        assert_eq!(ledger.with_mat("water".to_string()), Ok(SrcId::Mat(u16::MAX - 2)));
+        let sources = ledger.sources().count();
+        assert_eq!(ledger.with_mat("air".to_string()), Err(LedgerError::SrcIdsExhausted(SrcKind::Mat)));
+        assert_eq!(ledger.with_matsurf("box".to_string(), "air".to_string(), None), Err(LedgerError::SrcIdsExhausted(SrcKind::MatSurf)));
+        assert_eq!(ledger.sources().count(), sources);
+        assert_eq!(ledger.remaining_surf_ids(), u16::MAX - 1);
+        assert!(ledger.with_surf("lens".to_string(), None).is_ok());
+        assert_eq!(
+            LedgerError::SrcIdsExhausted(SrcKind::Mat).to_string(),
+            "No Mat id left, the id ranges would overlap"
+        );
+
+        // The last seq_id is never allocated, such that the counter can't wrap around
+        let start = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        ledger.next_seq_id = u32::MAX - 2;
+        let absorption = EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), SrcId::Mat(0));
+        let detection = EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(0));
+        let absorbed = ledger.insert(start, absorption.clone()).unwrap();
+        assert_eq!(ledger.remaining_seq_ids(), 1);
+        let re_emission = EventId::new_emission(crate::emission::Emission::PointSource, light_id);
+        assert_eq!(ledger.insert_child_root(absorbed, re_emission), Err(LedgerError::SeqIdsExhausted));
+        let detected = ledger.insert(start, detection.clone()).unwrap();
+        assert_eq!(ledger.insert(detected, detection.clone()), Err(LedgerError::SeqIdsExhausted));
+        let lamp = EventId::new_emission(crate::emission::Emission::PointSource, light_id);
+        assert_eq!(ledger.insert_start(lamp.clone()), Err(LedgerError::SeqIdsExhausted));
+        assert_eq!(ledger.insert_start_weighted(lamp.clone(), 1.0), Err(LedgerError::SeqIdsExhausted));
+        // Known entries are still found
+        assert_eq!(ledger.insert(start, absorption.clone()), Ok(absorbed));
+        assert_eq!(ledger.get_count(&absorbed), 2);
+
+        let mut rank = Ledger::new();
+        let rank_start = rank.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, SrcId::Light(0))).unwrap();
+        rank.insert(rank_start, detection.clone()).unwrap();
+        let mut full = Ledger::new();
+        full.next_seq_id = u32::MAX - 1;
+        assert_eq!(full.merge(rank).map(|_| ()), Err(LedgerError::SeqIdsExhausted));
+        assert_eq!(full.entries().count(), 0);
+        let concurrent = ConcurrentLedger::new(ledger);
+        assert_eq!(concurrent.insert(detected, detection), Err(LedgerError::SeqIdsExhausted));
+        assert_eq!(concurrent.insert(start, absorption), Ok(absorbed));
+        assert_eq!(concurrent.insert_start(lamp), Err(LedgerError::SeqIdsExhausted));
+    }
+
+    #[test]
+    fn config_ranges() {
+        let config = LedgerConfig {
+            matsurf_range: 0..=49_999,
+            mat_range: 50_000..=u16::MAX,
+            light_range: 10..=11,
+            ..Default::default()
+        };
+        let mut ledger = Ledger::with_config(config.clone()).unwrap();
+        assert_eq!(ledger.with_matsurf("cube".to_string(), "glass".to_string(), None), Ok(SrcId::MatSurf(49_999)));
+        assert_eq!(ledger.with_mat("water".to_string()).unwrap(), SrcId::Mat(50_000));
+        assert_eq!(ledger.with_surf("lens".to_string(), None), Ok(SrcId::Surf(0)));
+        assert_eq!(ledger.remaining_mat_ids(), u16::MAX - 50_001);
+        // Surfaces still share the MatSurf ids, the materials don't
+        assert_eq!(ledger.remaining_surf_ids(), 49_998 - 1);
+        assert_eq!(ledger.remaining_matsurf_ids(), 49_998 - 1);
+        assert_eq!(ledger.with_light("laser".to_string()).unwrap(), SrcId::Light(10));
+        assert_eq!(ledger.remaining_light_ids(), 1);
+
+        // The ranges are kept along with the sources
+        let mut ledger: Ledger = serde_json::from_str(&serde_json::to_string(&ledger).unwrap()).unwrap();
+        assert_eq!(ledger.config(), &config);
+        assert_eq!(Ledger::from_src_table(ledger.src_table()).config(), &config);
+        assert_eq!(ledger.with_light("led".to_string()), Ok(SrcId::Light(11)));
+        assert_eq!(ledger.with_light("lamp".to_string()), Err(LedgerError::SrcIdsExhausted(SrcKind::Light)));
+        assert!(!serde_json::to_string(&Ledger::new()).unwrap().contains("config"));
+
+        // Merged sources are allocated within the ranges of the ledger, not the ones of the merged ledger
+        let mut merged = Ledger::with_config(config.clone()).unwrap();
+        let mut rank = Ledger::new();
+        let laser_id = rank.with_light("laser".to_string()).unwrap();
+        let detector_id = rank.with_detector("camera".to_string()).unwrap();
+        let remap = merged.merge(rank).unwrap();
+        assert_eq!(remap.src(&laser_id), Some(SrcId::Light(10)));
+        assert_eq!(remap.src(&detector_id), Some(SrcId::Detector(0)));
+        let mut rank = Ledger::new();
+        for light in ["led", "lamp"] {
+            rank.with_light(light.to_string()).unwrap();
+        }
+        assert_eq!(merged.merge(rank).map(|_| ()), Err(LedgerError::SrcIdsExhausted(SrcKind::Light)));
+
+        let (start, end) = (5, 4);
+        let empty = LedgerConfig { detector_range: start..=end, ..Default::default() };
+        assert_eq!(Ledger::with_config(empty).map(|_| ()), Err(LedgerError::EmptySrcRange(SrcKind::Detector)));
+    }
+
+    #[test]
+    fn delta_mirror() {
+        let mut ledger = Ledger::new();
+        let mut mirror = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let start = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let delta = ledger.delta_since(&LedgerCheckpoint::default());
+        let mut checkpoint = delta.checkpoint;
+        assert_eq!(mirror.apply_delta(delta), Ok(1));
+
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let scatter = ledger.insert(start, EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+        let child = ledger.insert_child_root(scatter, EventId::new_emission(crate::emission::Emission::PointSource, light_id)).unwrap();
+        ledger.insert(child, EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(0))).unwrap();
+        ledger.insert_start(EventId::new_emission(crate::emission::Emission::PlaneWave, light_id)).unwrap();
+        let delta = ledger.delta_since(&checkpoint);
+        assert!(delta.sources.is_some());
+        assert_eq!(delta.links.len(), 4);
+
+        // Deltas are applied in order, skipping one leaves links to unknown entries
+        let json = serde_json::to_string(&delta).unwrap();
+        let delta: LedgerDelta = serde_json::from_str(&json).unwrap();
+        assert!(matches!(Ledger::new().apply_delta(delta.clone()), Err(LedgerError::DeltaOutOfOrder { .. })));
+        checkpoint = delta.checkpoint;
+        assert_eq!(mirror.apply_delta(delta), Ok(4));
+        assert!(ledger.delta_since(&checkpoint).is_empty());
+
+        let mut entries: Vec<Uid> = ledger.entries().collect();
+        let mut mirrored: Vec<Uid> = mirror.entries().collect();
+        entries.sort();
+        mirrored.sort();
+        assert_eq!(entries, mirrored);
+        assert_eq!(mirror.get_start_events(), ledger.get_start_events());
+        assert_eq!(mirror.get_child_roots(&scatter), ledger.get_child_roots(&scatter));
+        assert_eq!(mirror.names(&mat_id), ledger.names(&mat_id));
+        assert_eq!(mirror.checkpoint(), ledger.checkpoint());
+    }
+
+    #[test]
+    fn wide_raw_events() {
+        use crate::raw64::{RawEvent64, wide_src_bits_match};
+
+        let mut ledger: Ledger<RawEvent64> = Ledger::default();
+        let start = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, SrcId::Light(0))).unwrap();
+        let scatter = EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), SrcId::Mat(1));
+        let uid1 = ledger.insert(start, scatter.clone()).unwrap();
+        let uid2 = ledger.insert(uid1, scatter.clone()).unwrap();
+        assert_eq!(uid2.event, RawEvent64(0x03a50001));
+        assert_eq!(ledger.get_chain(uid2), vec![start, uid1, uid2]);
+        assert_eq!(ledger.leaves(), vec![uid2]);
+        assert_eq!(uid2.to_string(), "2, 0x0000000003A50001");
+        assert_eq!(Uid::<RawEvent64>::from_str(&uid2.to_string()), Ok(uid2));
+        assert!(uid2.event.matches(&BitsMatch::new(0x0FFF0000, 0x03a50000)));
+
+        // Sources past the 16 bits of SrcId, once the ids of their kind are exhausted
+        let config = LedgerConfig { mat_range: 1..=1, ..LedgerConfig::default() };
+        let mut wide_ledger: Ledger<RawEvent64> = Ledger { config, next_mat_id: 1, ..Ledger::default() };
+        let mat_id = wide_ledger.with_mat("water".to_string()).unwrap();
+        assert_eq!(wide_ledger.with_mat("ice".to_string()), Err(LedgerError::SrcIdsExhausted(SrcKind::Mat)));
+        let ice_id = wide_ledger.with_wide_src(SrcName::Mat("ice".to_string())).unwrap();
+        assert_eq!(ice_id, 0x10000);
+        assert_eq!(wide_ledger.wide_src(ice_id), Some(&SrcName::Mat("ice".to_string())));
+        assert_eq!(wide_ledger.wide_src_id_by_name("ice"), Some(ice_id));
+        assert_eq!(wide_ledger.src_id_by_name("water"), Some(mat_id));
+        assert!(Ledger::new().with_wide_src(SrcName::Mat("ice".to_string())).is_err());
+
+        // Wide sources sharing the low 16 bits of a source don't alias
+        let start = wide_ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, SrcId::Light(0))).unwrap();
+        let near = wide_ledger.insert_raw(start, RawEvent64::extended(&scatter, 0x002345, 0)).unwrap();
+        let far = wide_ledger.insert_raw(near, RawEvent64::extended(&scatter, 0x012345, 0)).unwrap();
+        let near_filter = crate::filter_seq!(MCRT, Material, Elastic, SrcId::Mat(0x2345));
+        assert!(near.event.matches(&near_filter));
+        assert!(!far.event.matches(&near_filter));
+        let far_filter = wide_src_bits_match(near_filter, 0x012345);
+        assert!(!near.event.matches(&far_filter));
+        assert!(far.event.matches(&far_filter));
+        assert_eq!(far.event.try_decode(), Err(DecodeError::WideSrcId { src_id: 0x012345, raw: far.event.0 }));
+        assert_eq!(far.event.decode_wide().unwrap().1, 0x012345);
+        let matched = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = matched.clone();
+        wide_ledger.subscribe(vec![far_filter], move |chain| sink.lock().unwrap().push(*chain.last().unwrap()));
+        wide_ledger.insert_raw(far, RawEvent64::extended(&scatter, 0x002345, 0)).unwrap();
+        let far_again = wide_ledger.insert_raw(far, RawEvent64::extended(&scatter, 0x012345, 0)).unwrap();
+        assert_eq!(*matched.lock().unwrap(), vec![far_again]);
+
+        let json = serde_json::to_string(&ledger).unwrap();
+        let read: Ledger<RawEvent64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.get_chain(uid2), ledger.get_chain(uid2));
+        let json = serde_json::to_string(&wide_ledger).unwrap();
+        let read: Ledger<RawEvent64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.wide_src(ice_id), Some(&SrcName::Mat("ice".to_string())));
+
+        let dir = tempdir().unwrap();
+        ledger.enable_spill_in(dir.path(), 2 * crate::spill::ENTRY_BYTES).unwrap();
+        assert!(ledger.spilled_len() > 0);
+        assert_eq!(ledger.get_chain(uid2), vec![start, uid1, uid2]);
+    }
+
+    #[test]
+    fn fresnel_split_siblings() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let surf_id = ledger.with_surf("lens".to_string(), None).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let uid1 = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let (reflected, transmitted) = ledger.insert_fresnel_split(uid1, surf_id).unwrap();
+        let scattered = ledger.insert(transmitted, EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+
+        assert_eq!(ledger.fresnel_branch(&scattered), Some((FresnelBranch::Transmitted, transmitted)));
+        assert_eq!(ledger.fresnel_sibling(&scattered), Some(reflected));
+        assert_eq!(ledger.fresnel_sibling(&reflected), Some(transmitted));
+        assert_eq!(ledger.fresnel_branch(&uid1), None);
+        assert_eq!(ledger.branches(&ledger.get_prev(reflected.seq_id).unwrap()).len(), 2);
+
+        // Nested splits resolve to the nearest one
+        let (inner_reflected, inner_transmitted) = ledger.insert_fresnel_split(scattered, surf_id).unwrap();
+        assert_eq!(ledger.fresnel_sibling(&inner_reflected), Some(inner_transmitted));
+    }
+
+    #[test]
+    fn src_map_records() {
+        let mut ledger = Ledger::new();
+        ledger.with_light("laser".to_string()).unwrap();
+        let surf_id = ledger.with_matsurf("lens".to_string(), "glass".to_string(), Some("optics".to_string())).unwrap();
+        assert_eq!(ledger.with_surf("mount".to_string(), Some("optics".to_string())).unwrap(), surf_id);
+
+        let json: serde_json::Value = serde_json::to_value(&ledger).unwrap();
+        assert_eq!(json["src_map"][0]["kind"], "Light");
+        assert_eq!(json["src_map"][0]["id"], 0);
+        assert_eq!(json["src_map"][0]["names"][0]["name"], "laser");
+        assert_eq!(json["src_map"][1]["kind"], "MatSurf");
+        assert_eq!(json["src_map"][1]["names"][1]["kind"], "Surf");
+
+        let restored: Ledger = serde_json::from_value(json).unwrap();
+        assert_eq!(restored.names(&surf_id), ledger.names(&surf_id));
+
+        // Ledgers written with the former map of "Kind(id)" keys
+        let mut legacy: serde_json::Value = serde_json::to_value(&ledger).unwrap();
+        legacy["src_map"] = serde_json::json!({"Light(0)": [{"Light": "laser"}], "Mat(2)": [{"Mat": "water"}]});
+        let restored: Ledger = serde_json::from_value(legacy).unwrap();
+        assert_eq!(restored.names(&SrcId::Mat(2)), &[SrcName::Mat("water".to_string())]);
+    }
+
+    #[test]
+    fn detector_pixels() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let detector_id = SrcId::Detector(2);
+        let geometry = ledger.with_detector_geometry(detector_id, 16, 16);
+        let uid1 = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let hit = EventId::new_detection(crate::detection::Detection::Direct, detector_id);
+        let uid2 = ledger.insert(uid1, hit.clone().with_pixel(geometry.pixel(3, 4))).unwrap();
+        let uid3 = ledger.insert(uid1, hit.with_pixel(geometry.pixel(12, 4))).unwrap();
+
+        let region = ledger.pixel_region_filter(&detector_id, 0..=7, 0..=7).expect("Unregistered detector");
+        let hits: Vec<Uid> = region.into_iter().flat_map(|bits_match| ledger.find_events(bits_match)).collect();
+        assert_eq!(hits, vec![uid2]);
+        assert!(ledger.pixel_region_filter(&SrcId::Detector(1), 0..=7, 0..=7).is_none());
+        let region = ledger.pixel_region_filter(&detector_id, 8..=15, 0..=15).expect("Unregistered detector");
+        let hits: Vec<Uid> = region.into_iter().flat_map(|bits_match| ledger.find_events(bits_match)).collect();
+        assert_eq!(hits, vec![uid3]);
+
+        let restored = Ledger::from_src_table(serde_json::from_str(&serde_json::to_string(&ledger.src_table()).unwrap()).unwrap());
+        assert_eq!(restored.detector_geometry(&detector_id), Some(&geometry));
+    }
+
+    #[test]
+    fn find_single_events() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let uid1 = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let uid2 = ledger.insert(uid1, EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+        let uid3 = ledger.insert(uid2, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        let uid4 = ledger.insert(uid1, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id)).unwrap();
+
+        let absorptions = ledger.find_events(crate::filter_seq!(MCRT, Material, Absorption, mat_id));
+        assert_eq!(absorptions, vec![uid4, uid3]);
+        assert_eq!(ledger.find_events(crate::filter_seq!(Emission, light_id)), vec![uid1]);
+        assert!(ledger.find_events(crate::filter_seq!(Detection, SrcId::None)).is_empty());
+    }
+
+    #[test]
+    fn decoded_entries() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let uid1 = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let uid2 = ledger.insert(uid1, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), SrcId::Mat(3))).unwrap();
+
+        let decoded: Vec<(Uid, EventId, Option<&[SrcName]>)> = ledger.iter_decoded().collect();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].0, uid1);
+        assert_eq!(decoded[0].1.event_type, EventType::Emission(crate::emission::Emission::PencilBeam));
+        assert_eq!(decoded[0].2, Some(&[SrcName::Light("laser".to_string())][..]));
+        assert_eq!(decoded[1].0, uid2);
+        assert_eq!(decoded[1].1.src_id, SrcId::MatSurf(3));
+        assert_eq!(decoded[1].2, None);
+    }
+
+    #[test]
+    fn child_root_links_to_parent() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("dye".to_string()).unwrap();
+        let uid1 = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let uid2 = ledger.insert(uid1, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        let root = ledger.insert_child_root(uid2, EventId::new_emission(crate::emission::Emission::PointSource, light_id)).unwrap();
+        let uid3 = ledger.insert(root, EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+
+        assert_eq!(ledger.insert_child_root(uid2, EventId::new_emission(crate::emission::Emission::PointSource, light_id)).unwrap(), root);
+        assert_eq!(ledger.get_parent(&root), Some(uid2));
+        assert_eq!(ledger.get_parent(&uid3), None);
+        assert_eq!(ledger.get_child_roots(&uid2), &[root]);
+        assert!(ledger.get_next(&uid2).is_empty());
+        assert_eq!(ledger.get_next_across(&uid2), vec![root]);
+
+        assert_eq!(ledger.get_chain(uid3), vec![root, uid3]);
+        assert_eq!(ledger.get_chain_across(uid3), vec![uid1, uid2, root, uid3]);
+
+        let json = serde_json::to_string(&ledger).expect("Unable to serialize ledger");
+        let ledger: Ledger = serde_json::from_str(&json).expect("Unable to deserialize ledger");
+        assert_eq!(ledger.get_chain_across(uid3), vec![uid1, uid2, root, uid3]);
+    }
+
+    #[test]
+    fn subscription_fires_on_completed_sequence() {
+        use std::sync::Mutex;
+        use crate::filter_seq;
+
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let detected = Arc::new(Mutex::new(Vec::new()));
+        let detected_clone = detected.clone();
+        let id = ledger.subscribe(
+            vec![
+                filter_seq!(MCRT, Material, Elastic, Mie, Side, mat_id).into(),
+                filter_seq!(Detection, SrcId::None),
+            ],
+            move |chain| detected_clone.lock().unwrap().push(chain.to_vec()),
+        );
+
+        let uid1 = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        // Detection without scattering does not complete the sequence
+        ledger.insert(uid1, EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(0))).unwrap();
+        let uid2 = ledger.insert(uid1, EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Side), mat_id)).unwrap();
+        let uid3 = ledger.insert(uid2, EventId::new_mcrt(crate::mcrt_event!(Interface, Refraction), SrcId::Surf(0))).unwrap();
+        let uid4 = ledger.insert(uid3, EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(0))).unwrap();
+        // Already recorded entries do not fire again
+        ledger.insert(uid3, EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(0))).unwrap();
+        assert_eq!(*detected.lock().unwrap(), vec![vec![uid1, uid2, uid3, uid4]]);
+
+        assert!(ledger.unsubscribe(id));
+        assert!(!ledger.unsubscribe(id));
+    }
+
+    #[test]
+    fn ledger_view_shared_across_threads() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let uid1 = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let uid2 = ledger.insert(uid1, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        let view = ledger.freeze();
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let view = view.clone();
+                std::thread::spawn(move || view.get_chain(uid2))
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), vec![uid1, uid2]);
+        }
+        assert_eq!(view.src_id_by_name("water"), Some(mat_id));
+        assert!(view.try_into_ledger().is_ok());
+    }
+
+    #[test]
+    fn detector_registration() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let camera_id = ledger.with_detector("camera".to_string()).unwrap();
+        let spad_id = ledger.with_detector("spad".to_string()).unwrap();
+        assert_eq!((camera_id, spad_id), (SrcId::Detector(0), SrcId::Detector(1)));
+        assert_eq!(ledger.src_id_by_name("spad"), Some(spad_id));
+
+        let uid1 = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let detection = EventId::new_detection(crate::detection::Detection::Direct, spad_id);
+        ledger.insert(uid1, detection.clone()).unwrap();
+        assert_eq!(ledger.event_names(&detection), &[SrcName::Detector("spad".to_string())]);
+
+        let json = serde_json::to_string(&ledger).unwrap();
+        let mut stored_ledger: Ledger = serde_json::from_str(&json).unwrap();
+        assert_eq!(stored_ledger.names(&camera_id), &[SrcName::Detector("camera".to_string())]);
+        assert_eq!(stored_ledger.with_detector("pmt".to_string()).unwrap(), SrcId::Detector(2));
+
+        // Ledgers written before the detector registrations
+        let json = json.replace("\"next_detector_id\":2,", "");
+        assert!(!json.contains("next_detector_id"));
+        let mut stored_ledger: Ledger = serde_json::from_str(&json).unwrap();
+        assert_eq!(stored_ledger.with_detector("pmt".to_string()).unwrap(), SrcId::Detector(2));
+    }
+
     #[test]
     fn write_ledger_json() {
         let mut ledger = Ledger::new();
-        let surf_src_id = ledger.with_surf("surface1".to_string(), Some("group1".to_string()));
-        let mat_src_id = ledger.with_mat("material1".to_string());
+        let surf_src_id = ledger.with_surf("surface1".to_string(), Some("group1".to_string())).unwrap();
+        let mat_src_id = ledger.with_mat("material1".to_string()).unwrap();
+        ledger.code_registry_mut().register(9, 0x01, "Crossing::Exit".to_string());
         // TODO: Complete the entire implementation to test the json writer
-        let emission_event = EventId {
-            event_type: crate::EventType::Emission(crate::emission::Emission::PointSource),
-            src_id: SrcId::Light(1),
-        };
-        let uid1 = ledger.insert_start(emission_event);
+        let emission_event = EventId::new(crate::EventType::Emission(crate::emission::Emission::PointSource), SrcId::Light(1));
+        let uid1 = ledger.insert_start(emission_event).unwrap();
 
-        let mcrt_event = EventId {
-            event_type: crate::EventType::MCRT(crate::mcrt_event!(Interface, Refraction)),
-            src_id: surf_src_id,
-        };
-        let uid2 = ledger.insert(uid1.clone(), mcrt_event);
+        let mcrt_event = EventId::new(crate::EventType::MCRT(crate::mcrt_event!(Interface, Refraction)), surf_src_id);
+        let uid2 = ledger.insert(uid1, mcrt_event).unwrap();
 
         assert_eq!(uid2.seq_id, 1);
-        let mcrt_event = EventId {
-            event_type: crate::EventType::MCRT(crate::mcrt_event!(Material, Elastic, Mie, Forward)),
-            src_id: mat_src_id,
-        };
-        let uid3 = ledger.insert(uid2.clone(), mcrt_event);
+        let mcrt_event = EventId::new(crate::EventType::MCRT(crate::mcrt_event!(Material, Elastic, Mie, Forward)), mat_src_id);
+        let uid3 = ledger.insert(uid2, mcrt_event).unwrap();
 
-        let chain = ledger.get_chain(uid3.clone());
+        let chain = ledger.get_chain(uid3);
         println!(
             "Chain: {:?}",
             chain
@@ -722,5 +3921,10 @@ mod tests {
         assert_eq!(ledger.start_events, stored_ledger.start_events);
         assert_eq!(ledger.next, stored_ledger.next);
         assert_eq!(ledger.prev, stored_ledger.prev);
+        assert_eq!(ledger.code_registry, stored_ledger.code_registry);
+
+        // A missing file is an IO error rather than a panic
+        let missing = read_ledger_from_json(_persisted_dir.join("missing.json"));
+        assert!(missing.is_err_and(|err| err.is_io()));
     }
 }
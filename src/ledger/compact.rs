@@ -0,0 +1,177 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::RawEvent;
+use crate::raw::Pipeline;
+
+use super::{Ledger, Uid};
+
+// ----------------------------------------------------
+// Pruning and renumbering of the entries
+// ----------------------------------------------------
+// Both read the spilled entries back in memory first, see `Ledger::unspill`.
+
+// Old -> new seq_ids of a `Ledger::compact`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeqIdRemap(pub(super) BTreeMap<u32, u32>);
+
+impl SeqIdRemap {
+    pub fn seq_id(&self, seq_id: u32) -> Option<u32> {
+        self.0.get(&seq_id).cloned()
+    }
+
+    pub fn uid(&self, uid: &Uid) -> Option<Uid> {
+        Some(Uid::new(self.seq_id(uid.seq_id)?, uid.event))
+    }
+
+    // Whether every seq_id was kept
+    pub fn is_identity(&self) -> bool {
+        self.0.iter().all(|(old, new)| old == new)
+    }
+}
+
+impl Ledger {
+    // Remove the entries which don't lead to a detection event, i.e. the chains of absorbed or
+    // escaped photons, then renumber the seq_ids with `compact`. The chains of detected secondary
+    // photons keep the primary chain they branch from. Returns the old -> new seq_ids.
+    pub fn prune_undetected(&mut self) -> SeqIdRemap {
+        self.unspill();
+        let mut kept: HashSet<Uid> = HashSet::new();
+        let detections: Vec<Uid> = self.entries().filter(|uid| uid.event.try_pipeline() == Ok(Pipeline::Detection)).collect();
+        for detection in detections {
+            let mut current = Some(detection);
+            while let Some(uid) = current {
+                if !kept.insert(uid) {
+                    break;
+                }
+                current = self.get_prev(uid.seq_id).or_else(|| self.get_parent(&uid));
+            }
+        }
+
+        for (seq_id, group) in self.next.iter_mut() {
+            group.retain(|event, next_seq_id| {
+                let keep = kept.contains(&Uid { seq_id: *seq_id, event: *event });
+                if !keep {
+                    self.prev.remove(next_seq_id);
+                }
+                keep
+            });
+        }
+        self.next.retain(|seq_id, group| *seq_id == 0 || !group.is_empty());
+        self.start_events.retain(|uid| kept.contains(uid));
+        self.parents.retain(|seq_id, _| self.next.contains_key(seq_id));
+        self.child_roots.retain(|parent, roots| {
+            roots.retain(|root| kept.contains(root));
+            kept.contains(parent) && !roots.is_empty()
+        });
+        self.packet_tags = self.packet_tags.remap(|uid| kept.contains(uid).then_some(*uid));
+        self.compact()
+    }
+
+    // Renumber the seq_ids densely, i.e. after `prune_undetected` removed entries, keeping the order
+    // of allocation. The root (0) keeps its seq_id. Returns the old -> new seq_ids, to rewrite the
+    // UIDs referenced outside of the ledger (photon files, ...). The annotations of the removed
+    // entries (packet tags, probabilities, counts, ...) are dropped.
+    pub fn compact(&mut self) -> SeqIdRemap {
+        self.unspill();
+        let mut used: Vec<u32> = self.next.keys().cloned().collect();
+        used.extend(self.prev.keys().cloned());
+        used.push(0);
+        used.sort_unstable();
+        used.dedup();
+        let remap = SeqIdRemap(used.iter().enumerate().map(|(new, old)| (*old, new as u32)).collect());
+        let uid = |uid: &Uid| remap.uid(uid);
+
+        self.next = std::mem::take(&mut self.next)
+            .into_iter()
+            .map(|(seq_id, map)| {
+                let map = map.into_iter().filter_map(|(event, next_seq_id)| Some((event, remap.seq_id(next_seq_id)?))).collect();
+                (remap.0[&seq_id], map)
+            })
+            .collect();
+        self.prev = std::mem::take(&mut self.prev)
+            .into_iter()
+            .filter_map(|(seq_id, prev)| Some((remap.0[&seq_id], uid(&prev)?)))
+            .collect();
+        self.start_events = self.start_events.iter().filter_map(uid).collect();
+        self.parents = std::mem::take(&mut self.parents)
+            .into_iter()
+            .filter_map(|(seq_id, parent)| Some((remap.seq_id(seq_id)?, uid(&parent)?)))
+            .collect();
+        self.child_roots = std::mem::take(&mut self.child_roots)
+            .into_iter()
+            .filter_map(|(parent, roots)| Some((uid(&parent)?, roots.iter().filter_map(uid).collect())))
+            .collect();
+        self.packet_tags = self.packet_tags.remap(uid);
+        self.probabilities = std::mem::take(&mut self.probabilities)
+            .into_iter()
+            .filter_map(|(seq_id, probability)| Some((remap.seq_id(seq_id)?, probability)))
+            .collect();
+        self.counts = std::mem::take(&mut self.counts)
+            .into_iter()
+            .filter_map(|(seq_id, count)| Some((remap.seq_id(seq_id)?, count)))
+            .collect();
+        self.weights = std::mem::take(&mut self.weights)
+            .into_iter()
+            .filter_map(|(seq_id, weight)| Some((remap.seq_id(seq_id)?, weight)))
+            .collect();
+        self.depths = std::mem::take(&mut self.depths)
+            .into_iter()
+            .filter_map(|(seq_id, depth)| Some((remap.seq_id(seq_id)?, depth)))
+            .collect();
+        if let Some(timestamps) = self.timestamps.as_mut() {
+            timestamps.values = used.iter().map(|old| timestamps.values.get(*old as usize).cloned().unwrap_or(0.0)).collect();
+        }
+        if self.next_seq_id != 0 {
+            self.next_seq_id = used.len() as u32;
+        }
+        remap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EventId, SrcId};
+    use crate::ledger::{ChoiceProbability, TimeBase};
+
+    #[test]
+    fn compact_seq_ids() {
+        let mut ledger = Ledger::new();
+        ledger.enable_timestamps(TimeBase::Simulation);
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let uid1 = ledger.insert_start_at(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id), 0.0).unwrap();
+        let pruned = ledger.insert_at(uid1, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id), 1.0).unwrap();
+        let uid2 = ledger.insert_at(uid1, EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Any), mat_id), 2.0).unwrap();
+        let uid3 = ledger.insert_at(uid2, EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(0)), 3.0).unwrap();
+        ledger.tag_packet(uid3, 7).unwrap();
+        // Annotations of the absorbed chain are dropped with it
+        ledger.tag_packet(pruned, 8).unwrap();
+        ledger.annotate_probability(&pruned, ChoiceProbability::analog(0.5)).unwrap();
+        let lost_root = ledger.insert_child_root(pruned, EventId::new_emission(crate::emission::Emission::PointSource, light_id)).unwrap();
+        let detected_root = ledger.insert_child_root(uid2, EventId::new_emission(crate::emission::Emission::PointSource, light_id)).unwrap();
+        let uid5 = ledger.insert(detected_root, EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(0))).unwrap();
+        assert!(ledger.compact().is_identity());
+
+        let pruned_seq_id = ledger.get_next_seq_id(&pruned).unwrap();
+        let remap = ledger.prune_undetected();
+        assert!(!remap.is_identity());
+        assert_eq!(remap.seq_id(pruned_seq_id), None);
+        assert_eq!(remap.seq_id(lost_root.seq_id), None);
+        let uid3 = remap.uid(&uid3).unwrap();
+        let uid2 = remap.uid(&uid2).unwrap();
+        assert_eq!(ledger.get_chain(uid3), vec![uid1, uid2, uid3]);
+        assert_eq!(ledger.get_chain_across(remap.uid(&uid5).unwrap())[..2], [uid1, uid2]);
+        assert_eq!(ledger.get_child_roots(&uid2), [remap.uid(&detected_root).unwrap()]);
+        assert_eq!(ledger.get_timestamp(&uid3), Some(3.0));
+        assert_eq!(ledger.packet_uid(7), Some(uid3));
+        assert_eq!(ledger.packet_uid(8), None);
+        assert_eq!(ledger.get_next(&uid1), vec![uid2]);
+        assert_eq!(ledger.entries().count(), 5);
+        assert!(ledger.prune_undetected().is_identity());
+        // New entries keep allocating after the compacted ids
+        let uid4 = ledger.insert(uid3, EventId::new_processing(crate::processing::Processing::Digitization)).unwrap();
+        assert_eq!(ledger.get_chain(uid4).len(), 4);
+        assert_eq!(ledger.get_next_seq_id(&uid4), Some(7));
+    }
+}
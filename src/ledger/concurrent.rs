@@ -0,0 +1,471 @@
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock, RwLockReadGuard};
+
+use crate::error::LedgerError;
+use crate::{EventId, RawEvent};
+
+use super::{Ledger, Uid};
+
+// ----------------------------------------------------
+// Concurrent inserts from the photon tracing threads
+// ----------------------------------------------------
+// ConcurrentLedger is Send + Sync, the threads share it by reference (or Arc) and call `insert` and
+// `insert_start` on `&self`. New entries go to shards locked by seq_id, and seq_ids are allocated
+// from an atomic counter while holding the shard of the new entry, such that the same history
+// inserted from two threads is merged into a single entry. Start events and child roots get a
+// seq_id of their own, as in the Ledger. Seq_ids then depend on the interleaving of the threads,
+// while the chains don't.
+// The sources and settings of the wrapped Ledger are read-only. Its entries are only written when
+// the shards are flushed into it: by `into_ledger`, or as soon as the entries in memory exceed the
+// spill budget of the ledger (see `Ledger::enable_spill`), such that it spills them to disk.
+// The subscriptions are notified of the new entries when they are flushed, in allocation order.
+// Counts, weights and explicit times are sharded as the entries. Entries inserted without a time
+// are stamped when flushed, i.e. inherit the time of their cause with `TimeBase::Simulation`.
+
+const SHARDS: usize = 64;
+
+type Shard<E> = BTreeMap<u32, BTreeMap<E, u32>>;
+
+pub struct ConcurrentLedger<E: RawEvent = u32> {
+    ledger: RwLock<Ledger<E>>,
+    next: Vec<Mutex<Shard<E>>>,
+    // Key: allocated next_seq_id, sharded by it
+    prev: Vec<Mutex<BTreeMap<u32, Uid<E>>>>,
+    start_events: Mutex<Vec<Uid<E>>>,
+    child_roots: Mutex<BTreeMap<Uid<E>, Vec<Uid<E>>>>,
+    // Key: allocated next_seq_id of the entry, sharded by it
+    counts: Vec<Mutex<BTreeMap<u32, u64>>>,
+    weights: Vec<Mutex<BTreeMap<u32, f64>>>,
+    times: Vec<Mutex<BTreeMap<u32, f32>>>,
+    // Number of entries in the shards, i.e. not flushed to the ledger yet
+    len: AtomicUsize,
+    next_seq_id: AtomicU32,
+}
+
+impl<E: RawEvent> ConcurrentLedger<E> {
+    pub fn new(ledger: Ledger<E>) -> Self {
+        let next_seq_id = AtomicU32::new(ledger.next_seq_id.max(1));
+        Self {
+            ledger: RwLock::new(ledger),
+            next: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            prev: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            start_events: Mutex::default(),
+            child_roots: Mutex::default(),
+            counts: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            weights: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            times: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            len: AtomicUsize::new(0),
+            next_seq_id,
+        }
+    }
+
+    // Ledger the entries are inserted into, without the entries not flushed yet. Inserts wait for
+    // the guard to be dropped when they need to flush.
+    pub fn ledger(&self) -> RwLockReadGuard<'_, Ledger<E>> {
+        self.ledger.read().unwrap()
+    }
+
+    fn shard(seq_id: u32) -> usize {
+        seq_id as usize % SHARDS
+    }
+
+    fn allocate_seq_ids(&self, count: u32) -> Result<u32, LedgerError> {
+        self.next_seq_id
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |seq_id| seq_id.checked_add(count))
+            .map_err(|_| LedgerError::SeqIdsExhausted)
+    }
+
+    // Fails once the seq_ids are exhausted, as `Ledger::insert_start`
+    pub fn insert_start(&self, start_event: EventId) -> Result<Uid<E>, LedgerError> {
+        Ok(self.insert_start_timed(E::from_event(&start_event), None)?.0)
+    }
+
+    pub fn insert_start_at(&self, start_event: EventId, time: f32) -> Result<Uid<E>, LedgerError> {
+        Ok(self.insert_start_timed(E::from_event(&start_event), Some(time))?.0)
+    }
+
+    pub fn insert_start_weighted(&self, start_event: EventId, weight: f64) -> Result<Uid<E>, LedgerError> {
+        let (uid, next_seq_id) = self.insert_start_timed(E::from_event(&start_event), None)?;
+        self.add_weight(next_seq_id, weight);
+        Ok(uid)
+    }
+
+    fn insert_start_timed(&self, start_event: E, time: Option<f32>) -> Result<(Uid<E>, u32), LedgerError> {
+        let uid = Uid { seq_id: 0, event: start_event };
+        let ledger = self.ledger.read().unwrap();
+        let next_seq_id = match ledger.get_next_seq_id(&uid) {
+            Some(next_seq_id) => next_seq_id,
+            None => {
+                let mut next = self.next[Self::shard(0)].lock().unwrap();
+                match next.entry(0).or_default().entry(uid.event) {
+                    Entry::Occupied(entry) => *entry.get(),
+                    Entry::Vacant(entry) => {
+                        let seq_id = self.allocate_seq_ids(1)?;
+                        entry.insert(seq_id);
+                        self.new_entry(&ledger, seq_id, uid, time);
+                        self.start_events.lock().unwrap().push(uid);
+                        seq_id
+                    }
+                }
+            }
+        };
+        // Start events are never spilled, see `Ledger::spill_cold_entries`
+        self.count(next_seq_id);
+        Ok((uid, next_seq_id))
+    }
+
+    // Fails once the seq_ids are exhausted, as `Ledger::insert`
+    pub fn insert(&self, prev_event: Uid<E>, event: EventId) -> Result<Uid<E>, LedgerError> {
+        Ok(self.insert_timed(prev_event, E::from_event(&event), None)?.0)
+    }
+
+    pub fn insert_at(&self, prev_event: Uid<E>, event: EventId, time: f32) -> Result<Uid<E>, LedgerError> {
+        Ok(self.insert_timed(prev_event, E::from_event(&event), Some(time))?.0)
+    }
+
+    pub fn insert_weighted(&self, prev_event: Uid<E>, event: EventId, weight: f64) -> Result<Uid<E>, LedgerError> {
+        let (uid, next_seq_id) = self.insert_timed(prev_event, E::from_event(&event), None)?;
+        self.add_weight(next_seq_id, weight);
+        Ok(uid)
+    }
+
+    fn insert_timed(&self, prev_event: Uid<E>, event: E, time: Option<f32>) -> Result<(Uid<E>, u32), LedgerError> {
+        let ledger = self.ledger.read().unwrap();
+        let seq_id = self
+            .next_seq_id_in(&ledger, &prev_event)
+            .ok_or_else(|| LedgerError::UnknownUid(prev_event.to_string()))?;
+
+        let uid = Uid { seq_id, event };
+        let next_seq_id = match ledger.get_next_seq_id(&uid) {
+            Some(next_seq_id) => next_seq_id,
+            None => {
+                let mut next = self.next[Self::shard(uid.seq_id)].lock().unwrap();
+                match next.entry(uid.seq_id).or_default().entry(uid.event) {
+                    Entry::Occupied(entry) => *entry.get(),
+                    Entry::Vacant(entry) => {
+                        let next_seq_id = self.allocate_seq_ids(1)?;
+                        entry.insert(next_seq_id);
+                        self.new_entry(&ledger, next_seq_id, uid, time);
+                        next_seq_id
+                    }
+                }
+            }
+        };
+        self.count(next_seq_id);
+        self.flush_over_budget(ledger)?;
+        Ok((uid, next_seq_id))
+    }
+
+    // Same as `Ledger::insert_child_root`
+    pub fn insert_child_root(&self, parent: Uid<E>, event: EventId) -> Result<Uid<E>, LedgerError> {
+        self.insert_child_root_timed(parent, event, None)
+    }
+
+    pub fn insert_child_root_at(&self, parent: Uid<E>, event: EventId, time: f32) -> Result<Uid<E>, LedgerError> {
+        self.insert_child_root_timed(parent, event, Some(time))
+    }
+
+    fn insert_child_root_timed(&self, parent: Uid<E>, event: EventId, time: Option<f32>) -> Result<Uid<E>, LedgerError> {
+        let ledger = self.ledger.read().unwrap();
+        if self.next_seq_id_in(&ledger, &parent).is_none() {
+            return Err(LedgerError::UnknownUid(parent.to_string()));
+        }
+
+        let event = E::from_event(&event);
+        let uid = match ledger.get_child_roots(&parent).iter().find(|uid| uid.event == event) {
+            Some(uid) => *uid,
+            None => {
+                let mut child_roots = self.child_roots.lock().unwrap();
+                let roots = child_roots.entry(parent).or_default();
+                match roots.iter().find(|uid| uid.event == event) {
+                    Some(uid) => *uid,
+                    None => {
+                        // The child root allocates its own seq_id and the one of its subsequent events
+                        let seq_id = self.allocate_seq_ids(2)?;
+                        let uid = Uid { seq_id, event };
+                        self.next[Self::shard(seq_id)].lock().unwrap().entry(seq_id).or_default().insert(event, seq_id + 1);
+                        self.new_entry(&ledger, seq_id + 1, uid, time);
+                        roots.push(uid);
+                        uid
+                    }
+                }
+            }
+        };
+        self.count(uid.seq_id + 1);
+        self.flush_over_budget(ledger)?;
+        Ok(uid)
+    }
+
+    fn new_entry(&self, ledger: &Ledger<E>, next_seq_id: u32, uid: Uid<E>, time: Option<f32>) {
+        self.prev[Self::shard(next_seq_id)].lock().unwrap().insert(next_seq_id, uid);
+        if let Some(time) = time.filter(|_| ledger.timestamps.is_some()) {
+            self.times[Self::shard(next_seq_id)].lock().unwrap().insert(next_seq_id, time);
+        }
+        self.len.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count(&self, next_seq_id: u32) {
+        *self.counts[Self::shard(next_seq_id)].lock().unwrap().entry(next_seq_id).or_default() += 1;
+    }
+
+    fn add_weight(&self, next_seq_id: u32, weight: f64) {
+        *self.weights[Self::shard(next_seq_id)].lock().unwrap().entry(next_seq_id).or_default() += weight;
+    }
+
+    // Inserts of `uid` so far, including the ones of the wrapped Ledger
+    pub fn get_count(&self, uid: &Uid<E>) -> u64 {
+        let ledger = self.ledger.read().unwrap();
+        let Some(next_seq_id) = self.next_seq_id_in(&ledger, uid) else {
+            return 0;
+        };
+        let count = self.counts[Self::shard(next_seq_id)].lock().unwrap().get(&next_seq_id).cloned().unwrap_or(0);
+        ledger.get_count(uid) + count
+    }
+
+    pub fn get_weight(&self, uid: &Uid<E>) -> f64 {
+        let ledger = self.ledger.read().unwrap();
+        let Some(next_seq_id) = self.next_seq_id_in(&ledger, uid) else {
+            return 0.0;
+        };
+        let weight = self.weights[Self::shard(next_seq_id)].lock().unwrap().get(&next_seq_id).cloned().unwrap_or(0.0);
+        ledger.get_weight(uid) + weight
+    }
+
+    pub fn get_next_seq_id(&self, uid: &Uid<E>) -> Option<u32> {
+        self.next_seq_id_in(&self.ledger.read().unwrap(), uid)
+    }
+
+    fn next_seq_id_in(&self, ledger: &Ledger<E>, uid: &Uid<E>) -> Option<u32> {
+        ledger.get_next_seq_id(uid).or_else(|| {
+            let next = self.next[Self::shard(uid.seq_id)].lock().unwrap();
+            next.get(&uid.seq_id)?.get(&uid.event).cloned()
+        })
+    }
+
+    pub fn contains(&self, uid: &Uid<E>) -> bool {
+        self.get_next_seq_id(uid).is_some()
+    }
+
+    // Number of entries inserted concurrently and not flushed to the ledger yet
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Flush the shards once the entries in memory exceed the spill budget of the ledger
+    fn flush_over_budget(&self, ledger: RwLockReadGuard<'_, Ledger<E>>) -> Result<(), LedgerError> {
+        let Some(budget) = ledger.spill.as_ref().map(|spill| spill.budget_entries) else {
+            return Ok(());
+        };
+        if ledger.prev.len() + self.len() <= budget {
+            return Ok(());
+        }
+        drop(ledger);
+        let mut ledger = self.ledger.write().unwrap();
+        // Unless another thread flushed them meanwhile
+        if ledger.prev.len() + self.len() > budget {
+            self.flush(&mut ledger)?;
+        }
+        Ok(())
+    }
+
+    // Move the entries of the shards to `ledger`, which spills them if needed. Requires the write
+    // lock of the ledger, such that no insert is in progress.
+    fn flush(&self, ledger: &mut Ledger<E>) -> Result<(), LedgerError> {
+        let mut new_entries = Vec::new();
+        for shard in &self.next {
+            for (seq_id, group) in std::mem::take(&mut *shard.lock().unwrap()) {
+                for (event, next_seq_id) in group {
+                    ledger.next.entry(seq_id).or_default().insert(event, next_seq_id);
+                    new_entries.push((next_seq_id, Uid { seq_id, event }));
+                }
+            }
+        }
+        for shard in &self.prev {
+            ledger.prev.append(&mut shard.lock().unwrap());
+        }
+        for (parent, roots) in std::mem::take(&mut *self.child_roots.lock().unwrap()) {
+            for root in &roots {
+                ledger.parents.insert(root.seq_id, parent);
+            }
+            ledger.child_roots.entry(parent).or_default().extend(roots);
+        }
+        for shard in &self.counts {
+            for (next_seq_id, count) in std::mem::take(&mut *shard.lock().unwrap()) {
+                *ledger.counts.entry(next_seq_id).or_default() += count;
+            }
+        }
+        for shard in &self.weights {
+            for (next_seq_id, weight) in std::mem::take(&mut *shard.lock().unwrap()) {
+                *ledger.weights.entry(next_seq_id).or_default() += weight;
+            }
+        }
+        let mut times = BTreeMap::new();
+        for shard in &self.times {
+            times.append(&mut shard.lock().unwrap());
+        }
+        ledger.start_events.append(&mut self.start_events.lock().unwrap());
+        ledger.next_seq_id = ledger.next_seq_id.max(self.next_seq_id.load(Ordering::Relaxed));
+        self.len.store(0, Ordering::Relaxed);
+
+        // Allocation order, such that the cause of each entry is stamped and notified first
+        new_entries.sort_unstable_by_key(|(next_seq_id, _)| *next_seq_id);
+        for (next_seq_id, uid) in new_entries {
+            let parent_seq_id = match ledger.parents.get(&uid.seq_id) {
+                Some(parent) => ledger.get_next_seq_id(parent).unwrap_or(0),
+                None => uid.seq_id,
+            };
+            ledger.stamp(next_seq_id, parent_seq_id, times.get(&next_seq_id).cloned());
+            ledger.notify(uid);
+        }
+        Ok(ledger.spill_cold_entries()?)
+    }
+
+    // Fails if the entries couldn't be spilled, see `Ledger::enable_spill`
+    pub fn into_ledger(self) -> Result<Ledger<E>, LedgerError> {
+        let mut ledger = std::mem::take(&mut *self.ledger.write().unwrap());
+        self.flush(&mut ledger)?;
+        Ok(ledger)
+    }
+}
+
+impl<E: RawEvent> From<Ledger<E>> for ConcurrentLedger<E> {
+    fn from(ledger: Ledger<E>) -> Self {
+        Self::new(ledger)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SrcId;
+    use crate::ledger::TimeBase;
+    use tempfile::tempdir;
+
+    #[test]
+    fn concurrent_inserts() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<ConcurrentLedger>();
+
+        let sources = || {
+            let mut ledger = Ledger::new();
+            let light_id = ledger.with_light("laser".to_string()).unwrap();
+            let mat_id = ledger.with_mat("water".to_string()).unwrap();
+            (ledger, light_id, mat_id)
+        };
+        let (mut ledger, light_id, mat_id) = sources();
+        let emission = EventId::new_emission(crate::emission::Emission::PencilBeam, light_id);
+        let forward = EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id);
+        let backward = EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Backward), mat_id);
+        let detection = EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(0));
+        // Photon `n` scatters forward or backward following the bits of n % 16
+        let trace = |photon: usize, insert_start: &dyn Fn(&EventId) -> Uid, insert: &dyn Fn(Uid, &EventId) -> Uid| {
+            let mut uid = insert_start(&emission);
+            for bit in 0..4 {
+                uid = insert(uid, if (photon % 16) >> bit & 1 == 1 { &forward } else { &backward });
+            }
+            insert(uid, &detection)
+        };
+
+        let sequential = std::cell::RefCell::new(sources().0);
+        for photon in 0..64 {
+            trace(
+                photon,
+                &|event| sequential.borrow_mut().insert_start(event.clone()).unwrap(),
+                &|uid, event| sequential.borrow_mut().insert(uid, event.clone()).unwrap(),
+            );
+        }
+        let sequential = sequential.into_inner();
+        // An entry inserted before going concurrent is reused
+        let start = ledger.insert_start(emission.clone()).unwrap();
+        ledger.insert(start, forward.clone()).unwrap();
+
+        let concurrent = ConcurrentLedger::new(ledger);
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let concurrent = &concurrent;
+                scope.spawn(move || {
+                    for photon in (thread..64).step_by(4) {
+                        trace(photon, &|event| concurrent.insert_start(event.clone()).unwrap(), &|uid, event| concurrent.insert(uid, event.clone()).unwrap());
+                    }
+                });
+            }
+        });
+        assert_eq!(concurrent.len(), sequential.entries().count() - 2);
+        let ledger = concurrent.into_ledger().unwrap();
+
+        let events = |ledger: &Ledger| {
+            let mut chains: Vec<Vec<u32>> = ledger.chains().map(|chain| chain.iter().map(|uid| uid.event).collect()).collect();
+            chains.sort();
+            chains
+        };
+        assert_eq!(ledger.entries().count(), sequential.entries().count());
+        assert_eq!(ledger.get_start_events(), &vec![start]);
+        assert_eq!(events(&ledger), events(&sequential));
+        assert_eq!(events(&ledger).len(), 16);
+        assert_eq!(ledger.get_count(&start), 65);
+        assert_eq!(ledger.leaves().iter().map(|leaf| ledger.get_count(leaf)).sum::<u64>(), 64);
+    }
+
+    #[test]
+    fn concurrent_roots_and_spill() {
+        let mut ledger = Ledger::new();
+        ledger.enable_timestamps(TimeBase::Simulation);
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("dye".to_string()).unwrap();
+        let dir = tempdir().unwrap();
+        ledger.enable_spill_in(dir.path(), 8 * crate::spill::ENTRY_BYTES).unwrap();
+        let scatter = EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id);
+        let absorption = EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), mat_id);
+        let re_emission = EventId::new_emission(crate::emission::Emission::PointSource, light_id);
+
+        let concurrent = ConcurrentLedger::new(ledger);
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let concurrent = &concurrent;
+                let (scatter, absorption, re_emission) = (scatter.clone(), absorption.clone(), re_emission.clone());
+                scope.spawn(move || {
+                    for photon in (thread..32).step_by(4) {
+                        let beam = [crate::emission::Emission::PencilBeam, crate::emission::Emission::PlaneWave][photon % 2];
+                        let mut uid = concurrent.insert_start_weighted(EventId::new_emission(beam, light_id), 1.0).unwrap();
+                        for depth in 0..photon % 8 {
+                            uid = concurrent.insert_at(uid, scatter.clone(), depth as f32 + 1.0).unwrap();
+                        }
+                        let absorbed = concurrent.insert_weighted(uid, absorption.clone(), 0.5).unwrap();
+                        concurrent.insert_child_root(absorbed, re_emission.clone()).unwrap();
+                    }
+                });
+            }
+        });
+        // The entries were flushed to the ledger to be spilled, rather than kept in the shards
+        assert!(concurrent.len() <= 8);
+        let ledger = concurrent.into_ledger().unwrap();
+        assert!(ledger.spilled_len() > 0);
+
+        let starts = ledger.get_start_events().clone();
+        assert_eq!(starts.len(), 2);
+        assert_ne!(ledger.get_next_seq_id(&starts[0]), ledger.get_next_seq_id(&starts[1]));
+        for start in &starts {
+            assert_eq!(ledger.get_count(start), 16);
+            assert_eq!(ledger.get_weight(start), 16.0);
+        }
+        // Photons of the same depth share their history
+        let child_roots: Vec<Uid> = ledger.leaves().into_iter().filter(|leaf| ledger.get_parent(leaf).is_some()).collect();
+        assert_eq!(child_roots.len(), 8);
+        for leaf in &child_roots {
+            assert_eq!(leaf.event, <u32 as RawEvent>::from_event(&re_emission));
+            let absorbed = ledger.get_parent(leaf).unwrap();
+            assert_eq!(ledger.get_child_roots(&absorbed), &[*leaf]);
+            assert_eq!(ledger.get_count(leaf), 4);
+            assert_eq!(ledger.get_weight(&absorbed), 2.0);
+            let chain = ledger.get_chain(absorbed);
+            // The untimed absorption inherits the time of the last scattering
+            assert_eq!(ledger.get_timestamp(&absorbed), Some((chain.len() - 2) as f32));
+        }
+    }
+}
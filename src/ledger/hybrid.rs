@@ -0,0 +1,215 @@
+use log::warn;
+
+use crate::RawEvent;
+use crate::error::LedgerError;
+use crate::journal::{self, Record};
+use crate::spill::{self, SpillStore};
+
+use super::{Ledger, SrcTable, Uid};
+
+// ----------------------------------------------------
+// Hybrid in-memory/on-disk ledger
+// ----------------------------------------------------
+// Once the entries kept in memory exceed the budget, the groups with the lowest seq_ids, which
+// belong to the oldest photons, are spilled to a SpillStore until half of the budget is left. The
+// root group (0) always stays in memory. Lookups check the in-memory maps first and read spilled
+// blocks on demand, such that chains keep being inserted and queried transparently.
+// Streaming (`stream_to`) spills to an append-only ledger file kept after the run instead, see
+// `stream`, where the changes of the sources are written before a block whenever they changed.
+
+pub(super) struct Spill<E: RawEvent> {
+    pub(super) store: SpillStore<E>,
+    pub(super) budget_entries: usize,
+    // Revision and content of the sources last written to the ledger file of a stream
+    pub(super) src_revision: u64,
+    pub(super) written_sources: Option<SrcTable>,
+}
+
+impl<E: RawEvent> Ledger<E> {
+    // Keep at most `budget_bytes` of entries in memory, spilling the older ones to a temp file in
+    // the system temp directory, see `Spill`
+    pub fn enable_spill(&mut self, budget_bytes: usize) -> std::io::Result<()> {
+        self.enable_spill_in(std::env::temp_dir(), budget_bytes)
+    }
+
+    pub fn enable_spill_in<P: AsRef<std::path::Path>>(&mut self, dir: P, budget_bytes: usize) -> std::io::Result<()> {
+        if self.spill.is_some() {
+            warn!("Spilling is already enabled for this ledger");
+            return Ok(());
+        }
+        self.spill = Some(Spill {
+            store: SpillStore::create_in(dir)?,
+            budget_entries: (budget_bytes / spill::ENTRY_BYTES).max(2),
+            src_revision: self.src_revision,
+            written_sources: None,
+        });
+        self.spill_cold_entries()
+    }
+
+    // Write the entries to the append-only ledger file at `path` while the run goes on, keeping at
+    // most `budget_bytes` of them in memory as `enable_spill` does. `finish_stream` completes the
+    // file, which `stream::read_stream` reads back into a Ledger.
+    pub fn stream_to<P: AsRef<std::path::Path>>(&mut self, path: P, budget_bytes: usize) -> Result<(), LedgerError> {
+        if self.spill.is_some() {
+            return Err(LedgerError::Io("Spilling or streaming is already enabled for this ledger".to_string()));
+        }
+        let mut store = SpillStore::create_stream(path)?;
+        let mut written_sources = None;
+        store.append_record(&journal::sources_record(self.src_table(), &mut written_sources))?;
+        self.spill = Some(Spill {
+            store,
+            budget_entries: (budget_bytes / spill::ENTRY_BYTES).max(2),
+            src_revision: self.src_revision,
+            written_sources,
+        });
+        Ok(self.spill_cold_entries()?)
+    }
+
+    // Write the entries left in memory and the rest of the ledger (start events, timestamps, child
+    // roots, ...) to the ledger file of `stream_to`
+    pub fn finish_stream(mut self) -> Result<(), LedgerError> {
+        let Some(mut spill) = self.spill.take().filter(|spill| spill.store.is_stream()) else {
+            return Err(LedgerError::Io("Streaming is not enabled for this ledger".to_string()));
+        };
+        let mut block = Vec::new();
+        for (seq_id, group) in std::mem::take(&mut self.next) {
+            block.extend(group.into_iter().map(|(event, next_seq_id)| (seq_id, event, next_seq_id)));
+        }
+        self.prev.clear();
+        spill.store.spill(block)?;
+        spill.store.append_record(&Record::Ledger(Box::new(self)))?;
+        Ok(())
+    }
+
+    // Insert entries read from a ledger file, restoring the start events and the seq_id counter
+    // when the rest of the ledger wasn't written
+    pub(crate) fn restore_entries(&mut self, entries: impl IntoIterator<Item = (u32, E, u32)>) {
+        for (seq_id, event, next_seq_id) in entries {
+            self.next.entry(seq_id).or_default().insert(event, next_seq_id);
+            self.prev.insert(next_seq_id, Uid { seq_id, event });
+            self.next_seq_id = self.next_seq_id.max(next_seq_id + 1);
+        }
+        if self.start_events.is_empty()
+            && let Some(roots) = self.next.get(&0)
+        {
+            self.start_events = roots.keys().map(|event| Uid { seq_id: 0, event: *event }).collect();
+        }
+    }
+
+    // Number of entries currently spilled to disk
+    pub fn spilled_len(&self) -> usize {
+        self.spill.as_ref().map_or(0, |spill| spill.store.len())
+    }
+
+    // Read every spilled entry back in memory and stop spilling, i.e. before serializing the ledger
+    pub fn unspill(&mut self) {
+        let Some(spill) = self.spill.take() else {
+            return;
+        };
+        for (seq_id, event, next_seq_id) in spill.store.entries() {
+            self.next.entry(seq_id).or_default().insert(event, next_seq_id);
+            self.prev.insert(next_seq_id, Uid { seq_id, event });
+        }
+    }
+
+    // Spill the oldest groups once the entries in memory exceed the budget. Start events (group 0)
+    // are never spilled, hence `insert_start` doesn't spill.
+    pub(super) fn spill_cold_entries(&mut self) -> std::io::Result<()> {
+        let src_table = match self.spill.as_ref() {
+            Some(spill) if spill.store.is_stream() && spill.src_revision != self.src_revision && self.prev.len() > spill.budget_entries => {
+                Some(self.src_table())
+            }
+            _ => None,
+        };
+        let Some(spill) = self.spill.as_mut() else {
+            return Ok(());
+        };
+        if self.prev.len() <= spill.budget_entries {
+            return Ok(());
+        }
+        let target = spill.budget_entries / 2;
+        let mut block = Vec::new();
+        while self.prev.len() - block.len() > target {
+            let Some(seq_id) = self.next.keys().find(|seq_id| **seq_id != 0).cloned() else {
+                break;
+            };
+            for (event, next_seq_id) in self.next.remove(&seq_id).unwrap() {
+                self.prev.remove(&next_seq_id);
+                self.depths.remove(&next_seq_id);
+                block.push((seq_id, event, next_seq_id));
+            }
+        }
+        if let Some(src_table) = src_table {
+            spill.store.append_record(&journal::sources_record(src_table, &mut spill.written_sources))?;
+            spill.src_revision = self.src_revision;
+        }
+        spill.store.spill(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EventId, SrcId};
+    use crate::ledger::{parse_ledger_json, read_ledger_from_json, write_ledger_to_json};
+    use tempfile::tempdir;
+
+    #[test]
+    fn spill_cold_entries() {
+        let build = |ledger: &mut Ledger| {
+            let light_id = ledger.with_light("laser".to_string()).unwrap();
+            let mat_id = ledger.with_mat("water".to_string()).unwrap();
+            let scatter = EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id);
+            let mut leaves = Vec::new();
+            for beam in [crate::emission::Emission::PencilBeam, crate::emission::Emission::PointSource] {
+                let uid = ledger.insert_start(EventId::new_emission(beam, light_id)).unwrap();
+                for depth in 0..40 {
+                    let mut leaf = uid;
+                    for _ in 0..=depth % 5 {
+                        leaf = ledger.insert(leaf, scatter.clone()).unwrap();
+                    }
+                    leaves.push(ledger.insert(leaf, EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(depth % 3))).unwrap());
+                }
+            }
+            leaves
+        };
+        let mut reference = Ledger::new();
+        let leaves = build(&mut reference);
+
+        let dir = tempdir().unwrap();
+        let mut ledger = Ledger::new();
+        ledger.enable_spill_in(dir.path(), 20 * crate::spill::ENTRY_BYTES).unwrap();
+        assert_eq!(build(&mut ledger), leaves);
+        assert!(ledger.spilled_len() > 0);
+        assert!(ledger.prev.len() <= 20);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        for leaf in &leaves {
+            assert_eq!(ledger.get_chain(*leaf), reference.get_chain(*leaf));
+        }
+        let start = reference.get_start_events()[0];
+        assert_eq!(ledger.get_next(&start), reference.get_next(&start));
+        let mut spilled_leaves = ledger.leaves();
+        spilled_leaves.sort_by_key(|uid| uid.seq_id);
+        assert_eq!(spilled_leaves, reference.leaves());
+        assert_eq!(ledger.entries().count(), reference.entries().count());
+
+        // The spilled entries are written along with the ones in memory
+        let json_dir = tempdir().unwrap();
+        write_ledger_to_json(&ledger, json_dir.path().join("ledger.json")).unwrap();
+        let written = read_ledger_from_json(json_dir.path().join("ledger.json")).unwrap();
+        assert_eq!(written.next, reference.next);
+        assert_eq!(written.get_start_events(), reference.get_start_events());
+
+        ledger.unspill();
+        assert_eq!(ledger.spilled_len(), 0);
+        assert_eq!(ledger.next, reference.next);
+        assert_eq!(ledger.prev, reference.prev);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        // An event with an unknown detection code fails the read instead of the later decoding
+        assert!(parse_ledger_json(&serde_json::to_string(&reference).unwrap()).is_ok());
+        reference.insert_raw(leaves[0], 0x050F0002).unwrap();
+        assert!(parse_ledger_json(&serde_json::to_string(&reference).unwrap()).is_err_and(|err| err.is_data()));
+    }
+}
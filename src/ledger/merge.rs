@@ -0,0 +1,394 @@
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{EventId, EventType, SrcId, SrcKind, TryDecode};
+use crate::detection;
+use crate::error::LedgerError;
+use crate::raw::RawField;
+
+use super::{Ledger, SeqIdRemap, Uid};
+
+// ----------------------------------------------------
+// Merging of the ledgers of several ranks or shards
+// ----------------------------------------------------
+// `Ledger::merge` unifies the sources of the merged ledger by name and reinserts its entries, the
+// returned UidRemap rewrites the UIDs recorded against it.
+
+// Old -> new UIDs of the ledger merged by `Ledger::merge`: both the seq_ids and the sources
+// recorded in the events change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UidRemap {
+    seq_ids: SeqIdRemap,
+    srcs: BTreeMap<SrcId, SrcId>,
+    events: HashMap<u32, u32>,
+}
+
+impl UidRemap {
+    pub fn seq_ids(&self) -> &SeqIdRemap {
+        &self.seq_ids
+    }
+
+    // Source a source of the merged ledger was unified with, or moved to
+    pub fn src(&self, src_id: &SrcId) -> Option<SrcId> {
+        self.srcs.get(src_id).cloned()
+    }
+
+    // Raw event with its source rewritten, for the events recorded in the merged ledger
+    pub fn event(&self, raw_event: u32) -> Option<u32> {
+        self.events.get(&raw_event).cloned()
+    }
+
+    pub fn uid(&self, uid: &Uid) -> Option<Uid> {
+        Some(Uid::new(self.seq_ids.seq_id(uid.seq_id)?, self.event(uid.event)?))
+    }
+
+    // Same as `uid` for the encoded UIDs of the photon records, see `Uid::encode`
+    pub fn encoded_uid(&self, encoded: u64) -> Option<u64> {
+        self.uid(&Uid::decode(encoded)).map(|uid| uid.encode())
+    }
+}
+
+impl Ledger {
+    // Merge the ledger of another MPI rank or thread shard into this one. Groups and sources are
+    // unified by name, the ones only `other` knows get new ids, and the events of `other` are
+    // inserted with their source rewritten, such that identical histories merge as usual.
+    // Returns the old -> new UIDs of `other`, to rewrite its photon records.
+    pub fn merge(&mut self, mut other: Ledger) -> Result<UidRemap, LedgerError> {
+        // At most as many seq_ids as `other` allocated, checked upfront to fail without merging part of it
+        if self.remaining_seq_ids() < other.next_seq_id {
+            return Err(LedgerError::SeqIdsExhausted);
+        }
+        // The channel ids of the events are kept as recorded, hence only meaningful with the same channels
+        if !self.channels.is_empty() && !other.channels.is_empty() && other.channels != self.channels {
+            return Err(LedgerError::ChannelMismatch);
+        }
+        other.unspill();
+        other.validate_events()?;
+        let srcs = self.merge_sources(&other)?;
+
+        // Every event of `other` is remapped before inserting any, to fail without merging part of it
+        let mut events = HashMap::new();
+        for uid in other.prev.values() {
+            if let std::collections::hash_map::Entry::Vacant(entry) = events.entry(uid.event) {
+                entry.insert(other.remap_event(uid.event, &srcs)?);
+            }
+        }
+        let mut seq_ids = BTreeMap::from([(0, 0)]);
+        let first_new_seq_id = self.next_seq_id.max(1);
+        {
+            let remap_event = |raw_event: u32| events[&raw_event];
+
+            // Allocation order, such that the group of each entry is remapped before the entry
+            for (old_next_seq_id, old_uid) in other.prev.range(1..) {
+                let event = remap_event(old_uid.event);
+                let next_seq_id = match other.parents.get(&old_uid.seq_id) {
+                    Some(parent) => {
+                        let parent = Uid::new(seq_ids[&parent.seq_id], remap_event(parent.event));
+                        let root = match self.get_child_roots(&parent).iter().find(|uid| uid.event == event) {
+                            Some(root) => *root,
+                            None => {
+                                let root = Uid::new(self.next_seq_id, event);
+                                self.insert_entry(root, self.next_seq_id + 1);
+                                self.next_seq_id += 2;
+                                self.parents.insert(root.seq_id, parent);
+                                self.child_roots.entry(parent).or_default().push(root);
+                                self.notify(root);
+                                self.spill_cold_entries()?;
+                                root
+                            }
+                        };
+                        seq_ids.insert(old_uid.seq_id, root.seq_id);
+                        self.get_next_seq_id(&root).unwrap()
+                    }
+                    None => {
+                        let uid = Uid::new(seq_ids[&old_uid.seq_id], event);
+                        match self.get_next_seq_id(&uid) {
+                            Some(next_seq_id) => next_seq_id,
+                            None => {
+                                let next_seq_id = self.next_seq_id.max(1);
+                                self.insert_entry(uid, next_seq_id);
+                                self.next_seq_id = next_seq_id + 1;
+                                if uid.seq_id == 0 {
+                                    self.start_events.push(uid);
+                                }
+                                self.notify(uid);
+                                self.spill_cold_entries()?;
+                                next_seq_id
+                            }
+                        }
+                    }
+                };
+                seq_ids.insert(*old_next_seq_id, next_seq_id);
+            }
+        }
+        let remap = UidRemap { seq_ids: SeqIdRemap(seq_ids), srcs, events };
+
+        for (old_seq_id, entry) in &other.probabilities {
+            let Some(seq_id) = remap.seq_ids.seq_id(*old_seq_id) else {
+                continue;
+            };
+            self.probabilities
+                .entry(seq_id)
+                .and_modify(|merged| {
+                    merged.samples += entry.samples;
+                    merged.varying |= entry.varying || !merged.choice.approx_eq(&entry.choice);
+                })
+                .or_insert(*entry);
+        }
+        for (old_seq_id, count) in &other.counts {
+            if let Some(seq_id) = remap.seq_ids.seq_id(*old_seq_id) {
+                *self.counts.entry(seq_id).or_default() += count;
+            }
+        }
+        for (old_seq_id, weight) in &other.weights {
+            if let Some(seq_id) = remap.seq_ids.seq_id(*old_seq_id) {
+                *self.weights.entry(seq_id).or_default() += weight;
+            }
+        }
+        for (packet_id, uid) in &other.packet_tags.uids {
+            let Some(uid) = remap.uid(uid) else {
+                continue;
+            };
+            if let Err(err) = self.packet_tags.insert(uid, *packet_id) {
+                warn!("Dropping the packet tag of the merged ledger: {}", err);
+            }
+        }
+        // Only the entries allocated by the merge take the timestamps of `other`
+        if let (Some(timestamps), Some(other_timestamps)) = (self.timestamps.as_mut(), other.timestamps.as_ref()) {
+            for (old_seq_id, seq_id) in remap.seq_ids.0.iter().filter(|(_, seq_id)| **seq_id >= first_new_seq_id) {
+                if let Some(time) = other_timestamps.get(*old_seq_id) {
+                    timestamps.set(*seq_id, time);
+                }
+            }
+        }
+        Ok(remap)
+    }
+
+    // `raw_event` of this ledger with its source rewritten to the merged one of `srcs`. Pixelated
+    // detection events keep their pixel bits, hence only take detector ids fitting their 8 bits.
+    fn remap_event(&self, raw_event: u32, srcs: &BTreeMap<SrcId, SrcId>) -> Result<u32, LedgerError> {
+        let event_id = EventId::try_decode(raw_event)?;
+        let Some(merged) = self.registered_src(&event_id).and_then(|src_id| srcs.get(&src_id)) else {
+            return Ok(raw_event);
+        };
+        let id = merged.id().unwrap_or(0);
+        Ok(match &event_id.event_type {
+            EventType::Emission(_) | EventType::MCRT(_) => (raw_event & !SrcId::mask()) | id as u32,
+            EventType::Detection(_) if event_id.pixel.is_some() => {
+                if id > detection::MAX_PIXELATED_DETECTOR {
+                    return Err(LedgerError::PixelatedDetectorId(*merged));
+                }
+                (raw_event & !(detection::MAX_PIXELATED_DETECTOR as u32)) | id as u32
+            }
+            EventType::Detection(_) => (raw_event & !SrcId::mask()) | id as u32,
+            _ => raw_event,
+        })
+    }
+
+    // Unify the groups and sources of `other` with the ones of this ledger, returns the
+    // other -> merged SrcIds
+    fn merge_sources(&mut self, other: &Ledger) -> Result<BTreeMap<SrcId, SrcId>, LedgerError> {
+        let mut srcs = BTreeMap::new();
+        // Groups first, as the objects of a group share its source
+        let mut grps: Vec<(&String, &SrcId)> = other.grps.iter().collect();
+        grps.sort();
+        for (grp_name, src_id) in grps {
+            let merged = match self.grps.get(grp_name) {
+                Some(merged) if merged.kind() == src_id.kind() => *merged,
+                Some(merged) => {
+                    return Err(LedgerError::GroupKindMismatch {
+                        group: grp_name.clone(),
+                        src_id: *merged,
+                        other_src_id: *src_id,
+                    });
+                }
+                None => {
+                    let merged = self.allocate_src(src_id)?;
+                    self.grps.insert(grp_name.clone(), merged);
+                    merged
+                }
+            };
+            srcs.insert(*src_id, merged);
+        }
+
+        // MatSurf first, the Mat and Surf names moved along with a group follow its MatSurf
+        let mut src_ids: Vec<&SrcId> = other.src_map.keys().collect();
+        src_ids.sort_by_key(|src_id| (src_id.kind() != SrcKind::MatSurf, **src_id));
+        for src_id in src_ids {
+            let names = &other.src_map[src_id];
+            let moved_to = match (src_id, srcs.get(&SrcId::MatSurf(src_id.id().unwrap_or_default()))) {
+                (SrcId::Mat(_), Some(SrcId::MatSurf(id))) => Some(SrcId::Mat(*id)),
+                (SrcId::Surf(_), Some(SrcId::MatSurf(id))) => Some(SrcId::Surf(*id)),
+                _ => None,
+            };
+            let known = srcs.get(src_id).cloned().or(moved_to).or_else(|| {
+                self.src_map
+                    .iter()
+                    .find(|(merged, merged_names)| merged.kind() == src_id.kind() && merged_names.first() == names.first())
+                    .map(|(merged, _)| *merged)
+            });
+            let merged = match known {
+                Some(merged) => merged,
+                None => self.allocate_src(src_id)?,
+            };
+            let merged_names = self.src_map.entry(merged).or_default();
+            for name in names {
+                if !merged_names.contains(name) {
+                    merged_names.push(name.clone());
+                }
+            }
+            srcs.insert(*src_id, merged);
+        }
+
+        for (alias, canonical) in &other.aliases {
+            let merged_alias = match srcs.get(alias) {
+                Some(merged) => *merged,
+                None => self.allocate_src(alias)?,
+            };
+            srcs.insert(*alias, merged_alias);
+            let merged_canonical = self.canonical_src(&srcs[canonical]);
+            if self.canonical_src(&merged_alias) != merged_canonical {
+                self.aliases.push((merged_alias, merged_canonical));
+            }
+        }
+
+        for (id, geometry) in &other.detector_geometries {
+            let merged = srcs.get(&SrcId::Detector(*id)).and_then(|src_id| src_id.id()).unwrap_or(*id);
+            self.detector_geometries.entry(merged).or_insert(*geometry);
+        }
+        if self.channels.is_empty() {
+            self.channels = other.channels.clone();
+        }
+        self.code_registry.merge(&other.code_registry);
+        match (self.metadata.as_mut(), other.metadata.as_ref()) {
+            (Some(metadata), Some(other_metadata)) => metadata.merge(other_metadata),
+            (None, Some(other_metadata)) => self.metadata = Some(other_metadata.clone()),
+            _ => {}
+        }
+        self.audit.merge(&other.audit, &srcs);
+
+        self.src_revision += 1;
+        Ok(srcs)
+    }
+
+    // New id of the kind of `src_id`
+    fn allocate_src(&mut self, src_id: &SrcId) -> Result<SrcId, LedgerError> {
+        Ok(match src_id {
+            SrcId::Mat(_) => SrcId::Mat(self.allocate_id(SrcKind::Mat)?),
+            SrcId::Surf(_) => SrcId::Surf(self.allocate_id(SrcKind::Surf)?),
+            SrcId::MatSurf(_) => SrcId::MatSurf(self.allocate_id(SrcKind::MatSurf)?),
+            SrcId::Light(_) => SrcId::Light(self.allocate_id(SrcKind::Light)?),
+            SrcId::Detector(_) => SrcId::Detector(self.allocate_id(SrcKind::Detector)?),
+            SrcId::None => *src_id,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RawEvent;
+    use crate::ledger::{AuditEvent, RunMetadata, SrcName};
+
+    #[test]
+    fn merge_ranks() {
+        let detection = EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(0));
+        let scatter = |mat_id| EventId::new_mcrt(crate::mcrt_event!(Material, Elastic, Mie, Forward), mat_id);
+
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let water_id = ledger.with_mat("water".to_string()).unwrap();
+        ledger.with_surf("lens".to_string(), Some("optics".to_string())).unwrap();
+        let start = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+        let scattered = ledger.insert(start, scatter(water_id)).unwrap();
+        let detected = ledger.insert(scattered, detection.clone()).unwrap();
+
+        // The other rank registered its sources in another order
+        let mut rank = Ledger::new();
+        let glass_id = rank.with_mat("glass".to_string()).unwrap();
+        let rank_water_id = rank.with_mat("water".to_string()).unwrap();
+        let rank_light_id = rank.with_light("laser".to_string()).unwrap();
+        let lens_id = rank.with_surf("lens".to_string(), Some("optics".to_string())).unwrap();
+        let rank_start = rank.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, rank_light_id)).unwrap();
+        let rank_scattered = rank.insert(rank_start, scatter(rank_water_id)).unwrap();
+        let rank_detected = rank.insert(rank_scattered, detection.clone()).unwrap();
+        let refracted = rank.insert(rank_start, EventId::new_mcrt(crate::mcrt_event!(Interface, Refraction), lens_id)).unwrap();
+        let absorbed = rank.insert(refracted, EventId::new_mcrt(crate::mcrt_event!(Material, Absorption), glass_id)).unwrap();
+        let child = rank.insert_child_root(absorbed, EventId::new_emission(crate::emission::Emission::PointSource, rank_light_id)).unwrap();
+        let child_detected = rank.insert(child, detection).unwrap();
+        rank.tag_packet(child_detected, 7).unwrap();
+
+        let entries = ledger.entries().count();
+        let remap = ledger.merge(rank).unwrap();
+        assert_eq!(remap.src(&rank_water_id), Some(water_id));
+        assert_eq!(remap.src(&glass_id), Some(SrcId::Mat(1)));
+        assert_eq!(ledger.group_src_ids("optics"), vec![SrcId::Surf(0)]);
+        assert_eq!(ledger.names(&SrcId::Surf(0)).len(), 1);
+
+        // The shared history is merged, the others are appended
+        assert_eq!(remap.uid(&rank_detected), Some(detected));
+        assert_eq!(remap.encoded_uid(rank_detected.encode()), Some(detected.encode()));
+        assert_eq!(ledger.entries().count(), entries + 4);
+        let absorbed = remap.uid(&absorbed).unwrap();
+        assert_eq!(ledger.event_names(&absorbed.event.decode()), &[SrcName::Mat("glass".to_string())]);
+        let child_detected = remap.uid(&child_detected).unwrap();
+        assert_eq!(ledger.get_chain_across(child_detected).len(), 5);
+        assert_eq!(ledger.packet_uid(7), Some(child_detected));
+    }
+
+    #[test]
+    fn merge_detectors_and_settings() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let camera_id = ledger.with_detector("camera".to_string()).unwrap();
+        ledger.with_channel("red".to_string(), 600.0, 700.0);
+        ledger.set_metadata(RunMetadata::new("rank0".to_string()));
+        let start = ledger.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, light_id)).unwrap();
+
+        let mut rank = Ledger::new();
+        let rank_light_id = rank.with_light("laser".to_string()).unwrap();
+        let pmt_id = rank.with_detector("pmt".to_string()).unwrap();
+        let rank_camera_id = rank.with_detector("camera".to_string()).unwrap();
+        let geometry = rank.with_detector_geometry(pmt_id, 4, 4);
+        rank.with_channel("red".to_string(), 600.0, 700.0);
+        rank.set_metadata(RunMetadata::new("rank1".to_string()).with_seed(7));
+        rank.code_registry_mut().register_name(9, "Voxel".to_string());
+        let rank_start = rank.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, rank_light_id)).unwrap();
+        let hit = EventId::new_detection(crate::detection::Detection::Direct, pmt_id).with_pixel(geometry.pixel(1, 2));
+        let rank_hit = rank.insert(rank_start, hit).unwrap();
+        let rank_camera_hit = rank.insert(rank_start, EventId::new_detection(crate::detection::Detection::Direct, rank_camera_id)).unwrap();
+        let audit_len = ledger.audit().entries().len() + rank.audit().entries().len();
+
+        let remap = ledger.merge(rank).unwrap();
+        // The detectors are unified by name, and the pixel of the hit is kept
+        let merged_pmt_id = remap.src(&pmt_id).unwrap();
+        assert_ne!(merged_pmt_id, camera_id);
+        assert_eq!(remap.src(&rank_camera_id), Some(camera_id));
+        let hit = remap.uid(&rank_hit).unwrap().event.decode();
+        assert_eq!((hit.src_id, hit.pixel), (merged_pmt_id, Some(geometry.pixel(1, 2))));
+        assert_eq!(remap.uid(&rank_camera_hit).unwrap().event.decode().src_id, camera_id);
+        assert_eq!(ledger.get_chain(remap.uid(&rank_hit).unwrap())[0], start);
+        assert_eq!(ledger.detector_geometry(&merged_pmt_id), Some(&geometry));
+        assert_eq!(ledger.detector_geometry(&pmt_id), None);
+
+        // Along with the settings the ledger doesn't have
+        assert_eq!(ledger.code_registry().pipeline_name(9), Some("Voxel"));
+        assert_eq!(ledger.metadata().unwrap().run_id.as_deref(), Some("rank0"));
+        assert_eq!(ledger.metadata().unwrap().rng_seed, Some(7));
+        assert_eq!(ledger.audit().entries().len(), audit_len);
+        assert!(ledger.audit().entries().iter().any(|entry| entry.event == AuditEvent::SrcRegistered { src_id: merged_pmt_id, name: SrcName::Detector("pmt".to_string()) }));
+
+        let mut infrared = Ledger::new();
+        infrared.with_channel("infrared".to_string(), 800.0, 1000.0);
+        assert_eq!(ledger.merge(infrared).map(|_| ()), Err(LedgerError::ChannelMismatch));
+
+        // An undecodable event fails the merge before any source is merged
+        let mut corrupted = Ledger::new();
+        let uid = corrupted.insert_start(EventId::new_emission(crate::emission::Emission::PencilBeam, SrcId::Light(0))).unwrap();
+        corrupted.with_mat("oil".to_string()).unwrap();
+        corrupted.insert_raw(uid, 0x050F0002).unwrap();
+        let src_table = ledger.src_table();
+        assert!(matches!(ledger.merge(corrupted), Err(LedgerError::Decode(_))));
+        assert_eq!(ledger.src_table(), src_table);
+    }
+}
@@ -1,12 +1,72 @@
+// Without the default `std` feature only the encoding core builds (raw fields, MCRT events, SrcId
+// and the codec traits), i.e. for event constants of no_std and kernel code
+#![cfg_attr(not(feature = "std"), no_std)]
+
 pub mod raw;
+#[cfg(feature = "std")]
+pub mod raw64;
+#[cfg(feature = "std")]
 pub mod emission;
+#[cfg(feature = "std")]
+pub mod detection;
+#[cfg(feature = "std")]
+pub mod processing;
+#[cfg(feature = "std")]
+pub mod transport;
+#[cfg(feature = "std")]
+pub mod voxel;
+#[cfg(feature = "std")]
+pub mod wavelength;
 pub mod mcrt;
+#[cfg(feature = "std")]
 pub mod ledger;
+pub mod error;
+#[cfg(feature = "std")]
+pub mod spill;
+#[cfg(feature = "std")]
 pub mod filter;
+#[cfg(feature = "std")]
+pub mod custom;
+#[cfg(feature = "std")]
+pub mod kind;
+#[cfg(feature = "std")]
+pub mod aev;
+#[cfg(feature = "std")]
+pub mod journal;
+#[cfg(feature = "std")]
+pub mod stream;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+#[cfg(feature = "std")]
+pub mod recorder;
+#[cfg(feature = "std")]
+pub mod query;
+#[cfg(feature = "std")]
+pub mod export;
+#[cfg(feature = "std")]
+pub mod compare;
+#[cfg(feature = "std")]
+pub mod likelihood;
+#[cfg(feature = "std")]
+pub mod taxonomy;
+#[cfg(feature = "std")]
+pub mod photons;
+#[cfg(feature = "std")]
+pub mod codegen;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "plots")]
+pub mod plots;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
 use raw::{Pipeline, RawField};
 use serde::{Deserialize, Serialize};
-use std::ops::Deref;
+use core::ops::Deref;
 use log::warn;
 
 // =======================================
@@ -20,91 +80,267 @@ pub trait Decode<T> {
     fn decode(raw: T) -> Self where Self: Sized;
 }
 
-pub trait RawEvent: std::hash::Hash + Clone + Eq + std::fmt::Debug + serde::Serialize + for<'de> serde::Deserialize<'de> {
+// Decoding of untrusted raw events (corrupted or newer-format files), failing on unknown codes
+// where `Decode` panics
+pub trait TryDecode<T>: Sized {
+    fn try_decode(raw: T) -> Result<Self, error::DecodeError>;
+}
+
+// `Decode` of the types implementing `TryDecode`, panicking with the decode error
+macro_rules! decode_or_panic {
+    ($($event:ty),* $(,)?) => {
+        $(
+            impl $crate::Decode<u32> for $event {
+                fn decode(raw: u32) -> Self {
+                    <$event as $crate::TryDecode<u32>>::try_decode(raw).unwrap_or_else(|err| panic!("{}", err))
+                }
+            }
+        )*
+    };
+}
+pub(crate) use decode_or_panic;
+
+// `Encode` of the types with a const `encode`, which method calls resolve to
+macro_rules! encode_from_const {
+    ($($event:ty),* $(,)?) => {
+        $(
+            impl $crate::Encode<u32> for $event {
+                fn encode(&self) -> u32 {
+                    <$event>::encode(self)
+                }
+            }
+        )*
+    };
+}
+pub(crate) use encode_from_const;
+
+// Raw word of an encoded event, the Uid and Ledger are generic over it. u32 is the standard layout,
+// the wide `RawEvent64` keeps it in its low 32 bits (`word`) and extends it in the high ones
+// (`ext_word`), see `raw64`.
+#[cfg(feature = "std")]
+pub trait RawEvent:
+    std::hash::Hash + Copy + Ord + std::fmt::Debug + Send + Sync + 'static + serde::Serialize + for<'de> serde::Deserialize<'de>
+{
+    type Raw: Copy + Into<u64> + TryFrom<u64>;
+    // Largest source id the event holds, sources past the 16 bits of SrcId are registered with
+    // `Ledger::with_wide_src`
+    const MAX_SRC_ID: u32 = u16::MAX as u32;
+
     fn pipeline(&self) -> Pipeline;
     fn decode(&self) -> EventId;
+    // Fallible versions of `pipeline` and `decode` for events read from untrusted sources
+    fn try_pipeline(&self) -> Result<Pipeline, error::DecodeError> {
+        Pipeline::try_decode(self.word())
+    }
+    fn try_decode(&self) -> Result<EventId, error::DecodeError> {
+        EventId::try_decode(self.word())
+    }
     fn id(&self) -> u16;
-    fn raw(&self) -> u32;
+    fn raw(&self) -> Self::Raw;
+    fn from_raw(raw: Self::Raw) -> Self;
+    fn from_event(event_id: &EventId) -> Self;
+    // Word of the u32 layout, which filters apply to
+    fn word(&self) -> u32;
+    // Extension of the u32 layout in wide events, matched by the `ext_mask` of filters
+    fn ext_word(&self) -> u32 {
+        0
+    }
+    fn matches(&self, bits_match: &filter::BitsMatch) -> bool {
+        (self.word() & bits_match.mask) == bits_match.value
+            && (self.ext_word() & bits_match.ext_mask) == bits_match.ext_value
+    }
+    // Hex representation of the event in serialized Uids
+    fn ser_hex<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>;
+    fn de_hex<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>;
 }
 
 // =======================================
 // Top level Event Type encoding and decoding
 // =======================================
-#[derive(Debug, PartialEq)]
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum EventType {
     None,
     Emission(emission::Emission),
     MCRT(mcrt::MCRT),
-    Detection,
-    Processing,
+    Detection(detection::Detection),
+    Processing(processing::Processing),
+    Transport(transport::Transport),
+    Voxel(voxel::Voxel),
+    Custom(custom::CustomEvent),
+}
+
+// Build the EventType of any pipeline, dispatching to the per-pipeline macros
+// i.e.
+// 1. event!(MCRT, Material, Elastic, Mie, Forward) -> EventType::MCRT(mcrt_event!(Material, Elastic, Mie, Forward))
+// 2. event!(Emission, PointSource) -> EventType::Emission(Emission::PointSource)
+// 3. event!(Detection, TimeGated) -> EventType::Detection(Detection::TimeGated)
+// 4. event!(Voxel, 42) -> EventType::Voxel(Voxel::new(42))
+#[macro_export]
+macro_rules! event {
+    (MCRT, $($mcrt:ident),+ $(,)?) => {
+        $crate::EventType::MCRT($crate::mcrt_event!($($mcrt),+))
+    };
+    (Emission, $emission:ident) => {
+        $crate::EventType::Emission($crate::emission_event!($emission))
+    };
+    (Detection, $detection:ident) => {
+        $crate::EventType::Detection($crate::detection::Detection::$detection)
+    };
+    (Processing, $processing:ident) => {
+        $crate::EventType::Processing($crate::processing::Processing::$processing)
+    };
+    (Transport, $transport:ident) => {
+        $crate::EventType::Transport($crate::transport::Transport::$transport)
+    };
+    (Voxel, $index:expr) => {
+        $crate::EventType::Voxel($crate::voxel::Voxel::new($index))
+    };
 }
 
 // EventId represents the EventType and *SrcId concatenated
-#[derive(Debug)]
+// Built through `EventId::new` or the `new_*` constructors and tagged with the `with_*` methods,
+// such that new optional fields don't break callers. Equality and hashing go through the raw
+// event, see `impl PartialEq for EventId`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct EventId {
     pub event_type: EventType,
     pub src_id:     SrcId,
+    // Wavelength channel of emission and inelastic events, see `wavelength`
+    pub channel:    Option<wavelength::Channel>,
+    // Temporal modulation of emission events, see `emission::Modulation`
+    pub modulation: Option<emission::Modulation>,
+    // Element/pixel hit on an array detector, see `detection::DetectorGeometry`
+    pub pixel:      Option<u16>,
+    // Domain face crossed by boundary transport events, see `transport::box_face`
+    pub face:       Option<u16>,
 }
 
-#[derive(Eq, PartialEq, Clone, Copy, Debug, Serialize, Deserialize, Hash)]
+#[derive(Eq, PartialEq, PartialOrd, Ord, Clone, Copy, Debug, Serialize, Deserialize, Hash)]
 pub enum SrcId {
     None,
     Mat(u16),
     Surf(u16),
     MatSurf(u16),
     Light(u16),
+    Detector(u16),
 }
 
-impl std::fmt::Display for SrcId {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for SrcId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             SrcId::None        => write!(f, "None"),
             SrcId::Mat(id)     => write!(f, "Mat({})", id),
             SrcId::Surf(id)    => write!(f, "Surf({})", id),
             SrcId::MatSurf(id) => write!(f, "MatSurf({})", id),
             SrcId::Light(id)   => write!(f, "Light({})", id),
+            SrcId::Detector(id) => write!(f, "Detector({})", id),
+        }
+    }
+}
+
+// Kind of source referenced by a SrcId, without the id
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Serialize, Deserialize, Hash)]
+pub enum SrcKind {
+    None,
+    Mat,
+    Surf,
+    MatSurf,
+    Light,
+    Detector,
+}
+
+impl core::fmt::Display for SrcKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl SrcId {
+    pub fn id(&self) -> Option<u16> {
+        match self {
+            SrcId::None        => None,
+            SrcId::Mat(id)     => Some(*id),
+            SrcId::Surf(id)    => Some(*id),
+            SrcId::MatSurf(id) => Some(*id),
+            SrcId::Light(id)   => Some(*id),
+            SrcId::Detector(id) => Some(*id),
+        }
+    }
+
+    pub fn kind(&self) -> SrcKind {
+        match self {
+            SrcId::None       => SrcKind::None,
+            SrcId::Mat(_)     => SrcKind::Mat,
+            SrcId::Surf(_)    => SrcKind::Surf,
+            SrcId::MatSurf(_) => SrcKind::MatSurf,
+            SrcId::Light(_)   => SrcKind::Light,
+            SrcId::Detector(_) => SrcKind::Detector,
+        }
+    }
+
+    pub fn is_none(&self) -> bool {
+        matches!(self, SrcId::None)
+    }
+
+    pub const fn encode(&self) -> u32 {
+        match self {
+            SrcId::None        => 0u32,
+            SrcId::Mat(id)     => *id as u32,
+            SrcId::Surf(id)    => *id as u32,
+            SrcId::MatSurf(id) => *id as u32,
+            SrcId::Light(id)   => *id as u32,
+            SrcId::Detector(id) => *id as u32,
         }
     }
 }
 
 impl RawField for SrcId {
-    fn mask() -> u32 { 0x0000FFFF }
-    fn shift() -> usize { 0 }
-    fn bitsize() -> usize { 16 }
+    const MASK: u32 = 0x0000FFFF;
+    const SHIFT: usize = 0;
+    const BITSIZE: usize = 16;
     // FIXME: The decode and encode implementations don't work because the default trait functions
     // required that Self is TryFrom<u8> and Into<u8>.
     fn decode(raw: u32) -> Self {
         let id = (raw & Self::mask()) as u16;
+        let pipe_code = ((raw & Pipeline::mask()) >> Pipeline::shift()) as u8;
+        if Pipeline::try_from(pipe_code).is_err() {
+            // Custom pipelines don't declare their source kind, use the superset
+            return SrcId::MatSurf(id);
+        }
         match Pipeline::decode(raw) {
             Pipeline::Emission => SrcId::Light(id),
             Pipeline::MCRT     => {
-                match raw::MCRT::decode(raw) {
-                    raw::MCRT::Interface => SrcId::MatSurf(id),
-                    raw::MCRT::Reflector => SrcId::Surf(id),
-                    raw::MCRT::Material  => SrcId::Mat(id),
+                // Unknown super types fall back to the superset, the event itself fails to decode
+                match raw::MCRT::try_decode(raw) {
+                    Ok(raw::MCRT::Reflector) => SrcId::Surf(id),
+                    Ok(raw::MCRT::Material)  => SrcId::Mat(id),
+                    _ => SrcId::MatSurf(id),
                 }
             },
-            Pipeline::Detection  => {
-                warn!("Detection pipeline does not have SrcId associated.");
-                SrcId::None
-            },
+            Pipeline::Detection  => SrcId::Detector(id),
             Pipeline::Processing => {
                 warn!("Processing pipeline does not have SrcId associated.");
                 SrcId::None
             },
+            Pipeline::Transport => {
+                warn!("Transport pipeline does not have SrcId associated.");
+                SrcId::None
+            },
+            Pipeline::Voxel => {
+                warn!("Voxel pipeline does not have SrcId associated.");
+                SrcId::None
+            },
         }
     }
     fn encode(&self) -> u32 {
-        match self {
-            SrcId::None        => 0u32,
-            SrcId::Mat(id)     => *id as u32,
-            SrcId::Surf(id)    => *id as u32,
-            SrcId::MatSurf(id) => *id as u32,
-            SrcId::Light(id)   => *id as u32,
-        }
+        SrcId::encode(self)
     }
 }
 
+// NOTE: Panics on SrcId::None, prefer the non-panicking `SrcId::id()`
 impl Deref for SrcId {
     type Target = u16;
     fn deref(&self) -> &Self::Target {
@@ -114,67 +350,240 @@ impl Deref for SrcId {
             Self::Surf(id)    => id,
             Self::MatSurf(id) => id,
             Self::Light(id)   => id,
+            Self::Detector(id) => id,
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl EventId {
     pub fn new(event_type: EventType, src_id: SrcId) -> Self {
         EventId {
             event_type,
             src_id,
+            channel: None,
+            modulation: None,
+            pixel: None,
+            face: None,
         }
     }
     pub fn new_emission(emission_event: emission::Emission, light_id: SrcId) -> Self {
-        EventId {
-            event_type: EventType::Emission(emission_event),
-            src_id: light_id,
-        }
+        EventId::new(EventType::Emission(emission_event), light_id)
     }
     pub fn new_mcrt(mcrt_event: mcrt::MCRT, matsurf_id: SrcId) -> Self {
-        EventId {
-            event_type: EventType::MCRT(mcrt_event),
-            src_id: matsurf_id,
-        }
+        EventId::new(EventType::MCRT(mcrt_event), matsurf_id)
+    }
+    pub fn new_detection(detection_event: detection::Detection, detector_id: SrcId) -> Self {
+        EventId::new(EventType::Detection(detection_event), detector_id)
+    }
+    pub fn new_processing(processing_event: processing::Processing) -> Self {
+        EventId::new(EventType::Processing(processing_event), SrcId::None)
+    }
+    pub fn new_transport(transport_event: transport::Transport) -> Self {
+        EventId::new(EventType::Transport(transport_event), SrcId::None)
+    }
+    // Tag an emission or inelastic event with the wavelength channel of the outgoing photon
+    pub fn with_channel(mut self, channel: wavelength::Channel) -> Self {
+        assert!(
+            wavelength::supports_channel(&self.event_type),
+            "Only emission and inelastic events carry a wavelength channel, not {:?}", self.event_type
+        );
+        assert!(
+            self.modulation.is_none_or(|modulation| modulation.pulse.is_none()),
+            "Pulse-indexed emission events cannot carry a wavelength channel"
+        );
+        self.channel = Some(channel);
+        self
+    }
+    // Temporal modulation of the source of an emission event
+    pub fn with_modulation(mut self, modulation: emission::Modulation) -> Self {
+        assert!(
+            matches!(self.event_type, EventType::Emission(_)),
+            "Only emission events carry a temporal modulation, not {:?}", self.event_type
+        );
+        assert!(
+            modulation.pulse.is_none() || self.channel.is_none(),
+            "Pulse-indexed emission events cannot carry a wavelength channel"
+        );
+        self.modulation = Some(modulation);
+        self
+    }
+    // Pixel of an array detector hit by a detection event, limits the detector id to 8 bits
+    pub fn with_pixel(mut self, pixel: u16) -> Self {
+        assert!(
+            matches!(self.event_type, EventType::Detection(_)),
+            "Only detection events carry a pixel index, not {:?}", self.event_type
+        );
+        let detector_id = self.src_id.id().unwrap_or(0);
+        assert!(
+            detector_id <= detection::MAX_PIXELATED_DETECTOR,
+            "Pixelated detector id {} exceeds 8 bits", detector_id
+        );
+        assert!(pixel <= detection::MAX_PIXEL, "Pixel index {} exceeds {}", pixel, detection::MAX_PIXEL);
+        self.pixel = Some(pixel);
+        self
+    }
+    // Face of the domain crossed by a boundary transport event
+    pub fn with_face(mut self, face: u16) -> Self {
+        assert!(
+            matches!(self.event_type, EventType::Transport(transport) if transport.is_boundary()),
+            "Only boundary crossing events carry a face index, not {:?}", self.event_type
+        );
+        assert!(face != transport::NO_FACE, "Face {} is reserved for crossings without a face", transport::NO_FACE);
+        self.face = Some(face);
+        self
+    }
+    pub fn new_voxel(voxel: voxel::Voxel) -> Self {
+        EventId::new(EventType::Voxel(voxel), SrcId::None)
     }
 }
 
-impl Decode<u32> for EventId {
-    fn decode(raw: u32) -> Self {
-        let pipeline = raw::Pipeline::decode(raw);
+#[cfg(feature = "std")]
+impl TryDecode<u32> for EventId {
+    fn try_decode(raw: u32) -> Result<Self, error::DecodeError> {
         let src_id_raw = (raw & 0xFFFF) as u16;
+        let pipe_code = ((raw & Pipeline::mask()) >> Pipeline::shift()) as u8;
+        if pipe_code == 0 {
+            return Err(error::DecodeError::UnknownCode { field: "Pipeline", code: pipe_code, raw });
+        }
+        if Pipeline::try_from(pipe_code).is_err() {
+            let mut event_id = EventId::new(EventType::Custom(custom::CustomEvent::decode(raw)), SrcId::MatSurf(src_id_raw));
+            event_id.channel = wavelength::channel_of(raw);
+            return Ok(event_id);
+        }
+        let pipeline = raw::Pipeline::decode(raw);
+        let pixel = detection::pixel_of(raw);
         let (event_type, src_id) = match pipeline {
             // TODO: Resolve correct SrcId type for MCRT rather than using the superset
-            raw::Pipeline::MCRT      => (EventType::MCRT(mcrt::MCRT::decode(raw)), SrcId::MatSurf(src_id_raw)),
-            raw::Pipeline::Emission  => (EventType::Emission(emission::Emission::decode(raw)), SrcId::Light(src_id_raw)),
-            raw::Pipeline::Detection => (EventType::Detection, SrcId::None),
-            _                        => panic!("Cannot decode {:?} pipeline event", pipeline),
+            raw::Pipeline::MCRT      => (EventType::MCRT(mcrt::MCRT::try_decode(raw)?), SrcId::MatSurf(src_id_raw)),
+            raw::Pipeline::Emission  => (EventType::Emission(emission::Emission::try_decode(raw)?), SrcId::Light(src_id_raw)),
+            raw::Pipeline::Detection => {
+                let detector_id = if pixel.is_some() { src_id_raw & detection::MAX_PIXELATED_DETECTOR } else { src_id_raw };
+                (EventType::Detection(detection::Detection::try_decode(raw)?), SrcId::Detector(detector_id))
+            }
+            raw::Pipeline::Processing => (EventType::Processing(processing::Processing::try_decode(raw)?), SrcId::None),
+            raw::Pipeline::Transport  => (EventType::Transport(transport::Transport::try_decode(raw)?), SrcId::None),
+            raw::Pipeline::Voxel      => (EventType::Voxel(voxel::Voxel::decode(raw)), SrcId::None),
+        };
+        // Pulse-indexed emission events use the channel bits for the pulse index
+        let modulation = match pipeline {
+            raw::Pipeline::Emission => emission::Modulation::try_decode(raw)?,
+            _ => None,
         };
-        EventId { event_type, src_id }
+        // and pixelated detection events for the top bits of the pixel index
+        let channel = if pixel.is_some() { None } else { wavelength::channel_of(raw) };
+        let face = transport::face_of(raw);
+        Ok(EventId { event_type, src_id, channel, modulation, pixel, face })
+    }
+}
+
+#[cfg(feature = "std")]
+decode_or_panic!(EventId);
+
+// Two EventIds are equal when they encode to the same raw event, such that `decode(encode(x)) == x`
+// although decoding can't recover the source kind of MCRT events (Mat and Surf decode as MatSurf).
+// Events without a type can't be encoded and compare field by field.
+#[cfg(feature = "std")]
+impl EventId {
+    fn canonical(&self) -> Option<u32> {
+        (self.event_type != EventType::None).then(|| self.encode())
     }
 }
 
+#[cfg(feature = "std")]
+impl PartialEq for EventId {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.canonical(), other.canonical()) {
+            (Some(raw), Some(other_raw)) => raw == other_raw,
+            (None, None) => {
+                (self.src_id, self.channel, self.modulation, self.pixel, self.face)
+                    == (other.src_id, other.channel, other.modulation, other.pixel, other.face)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Eq for EventId {}
+
+#[cfg(feature = "std")]
+impl core::hash::Hash for EventId {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        match self.canonical() {
+            Some(raw) => raw.hash(state),
+            None => (self.src_id, self.channel, self.modulation, self.pixel, self.face).hash(state),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
 impl Encode<u32> for EventId {
     fn encode(&self) -> u32 {
         let event_type_code = match &self.event_type {
             EventType::None               => panic!("Cannot encode None event type"),
             EventType::MCRT(mcrt_event)   => raw::Pipeline::MCRT.encode() | mcrt_event.encode(),
             EventType::Emission(emission) => raw::Pipeline::Emission.encode() | emission.encode(),
-            EventType::Detection          => raw::Pipeline::Detection.encode(),
-            _ => panic!("Cannot encode event type as MCRT event"),
+            EventType::Detection(detection) => raw::Pipeline::Detection.encode() | detection.encode(),
+            EventType::Processing(processing) => raw::Pipeline::Processing.encode() | processing.encode(),
+            EventType::Transport(transport) => raw::Pipeline::Transport.encode() | transport.encode(),
+            EventType::Voxel(voxel)       => raw::Pipeline::Voxel.encode() | voxel.encode(),
+            EventType::Custom(custom)     => custom.encode(),
         };
-        event_type_code | (*self.src_id as u32)
+        // Pulse indices and channels share the top nibble, see `emission::PULSE_MASK`
+        assert!(
+            self.channel.is_none() || self.modulation.is_none_or(|modulation| modulation.pulse.is_none()),
+            "Pulse-indexed emission events cannot carry a wavelength channel"
+        );
+        let channel_code = self.channel.map(|channel| channel.encode()).unwrap_or(0);
+        let modulation_code = self.modulation.map(|modulation| modulation.encode()).unwrap_or(0);
+        let pixel_code = self
+            .pixel
+            .map(|pixel| detection::PIXELATED | detection::scatter_pixel(pixel))
+            .unwrap_or(0);
+        let face_code = match (&self.event_type, self.face) {
+            (_, Some(face)) => face as u32,
+            (EventType::Transport(transport), None) if transport.is_boundary() => transport::NO_FACE as u32,
+            _ => 0,
+        };
+        channel_code | modulation_code | pixel_code | face_code | event_type_code | (self.src_id.id().unwrap_or(0) as u32)
+    }
+}
+
+// Compact representation of an EventId as the hex of its raw event, i.e. "0x03A50001", for fields
+// declared with `#[serde(with = "aetherus_events::event_hex")]`. The derived Serialize gives the
+// structured representation instead.
+#[cfg(feature = "std")]
+pub mod event_hex {
+    use serde::de::Error as _;
+    use serde::ser::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::{Encode, EventId, EventType, TryDecode};
+
+    pub fn serialize<S: Serializer>(event_id: &EventId, serializer: S) -> Result<S::Ok, S::Error> {
+        if event_id.event_type == EventType::None {
+            return Err(S::Error::custom("Cannot encode None event type"));
+        }
+        serializer.serialize_str(&format!("0x{:08X}", event_id.encode()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<EventId, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        let raw = u32::from_str_radix(hex.trim_start_matches("0x"), 16).map_err(D::Error::custom)?;
+        EventId::try_decode(raw).map_err(D::Error::custom)
     }
 }
 
 // NOTE: Implementing this seems superfluous to the EventId::decode(u32)
 // Only reason this could be useful if there are other desirable way to encode the events,
 // but that's doubtful since the encoding scheme is taylored for u32
+#[cfg(feature = "std")]
 impl RawEvent for u32 {
+    type Raw = u32;
 
     fn pipeline(&self) -> raw::Pipeline {
-        let pipe_code = ((self >> 24) & 0b1111) as u8;
-        Pipeline::try_from(pipe_code).unwrap()
+        Pipeline::decode(*self)
     }
     fn decode(&self) -> EventId {
         EventId::decode(*self)
@@ -185,9 +594,25 @@ impl RawEvent for u32 {
     fn raw(&self) -> u32 {
         *self
     }
+    fn from_raw(raw: u32) -> Self {
+        raw
+    }
+    fn from_event(event_id: &EventId) -> Self {
+        event_id.encode()
+    }
+    fn word(&self) -> u32 {
+        *self
+    }
+    fn ser_hex<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        array_bytes::ser_hexify_prefixed(self, serializer)
+    }
+    fn de_hex<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        array_bytes::de_dehexify(deserializer)
+    }
 }
 
 
+
 // --------------------------------------
 // Unit tests for encoding and decoding
 // --------------------------------------
@@ -225,6 +650,137 @@ mod tests {
         assert_eq!(event_id.src_id, SrcId::MatSurf(1));
     }
 
+    #[test]
+    fn src_id_accessors() {
+        assert_eq!(SrcId::Mat(3).id(), Some(3));
+        assert_eq!(SrcId::Light(0).kind(), SrcKind::Light);
+        assert_eq!(SrcId::None.id(), None);
+        assert_eq!(SrcId::None.kind(), SrcKind::None);
+        assert!(SrcId::None.is_none());
+        assert!(!SrcId::MatSurf(1).is_none());
+    }
+
+    #[test]
+    fn encoding_detection_event() {
+        let event_id = EventId::new(EventType::Detection(detection::Detection::Direct), SrcId::None);
+        assert_eq!(event_id.encode(), 0x05000000);
+        let event_id = EventId::new_detection(detection::Detection::Rejected, SrcId::Detector(2));
+        let raw_event = event_id.encode();
+        assert_eq!(raw_event, 0x05010002);
+        let decoded = EventId::decode(raw_event);
+        assert_eq!(decoded.event_type, EventType::Detection(detection::Detection::Rejected));
+        assert_eq!(decoded.src_id, SrcId::Detector(2));
+        let raw_event = EventId::new_detection(detection::Detection::Saturated, SrcId::Detector(7)).encode();
+        assert_eq!(raw_event, 0x05040007);
+        let decoded = EventId::decode(raw_event);
+        assert_eq!(decoded.event_type, EventType::Detection(detection::Detection::Saturated));
+        let decoded = EventId::decode(EventId::new_detection(detection::Detection::TimeGated, SrcId::Detector(1)).with_pixel(300).encode());
+        assert_eq!(decoded.event_type, EventType::Detection(detection::Detection::TimeGated));
+        assert_eq!(decoded.src_id, SrcId::Detector(1));
+    }
+
+    #[test]
+    fn event_macro() {
+        assert_eq!(event!(MCRT, Material, Elastic, Mie, Forward), EventType::MCRT(mcrt_event!(Material, Elastic, Mie, Forward)));
+        assert_eq!(event!(MCRT, Interface, Refraction), EventType::MCRT(mcrt::MCRT::Interface(mcrt::Interface::Refraction)));
+        assert_eq!(event!(Emission, PointSource), EventType::Emission(emission::Emission::PointSource));
+        assert_eq!(event!(Detection, TimeGated), EventType::Detection(detection::Detection::TimeGated));
+        assert_eq!(event!(Processing, Digitization), EventType::Processing(processing::Processing::Digitization));
+        assert_eq!(event!(Transport, Split), EventType::Transport(transport::Transport::Split));
+        assert_eq!(event!(Voxel, 42), EventType::Voxel(voxel::Voxel::new(42)));
+        let event_id = EventId::new(event!(Detection, Saturated), SrcId::Detector(7));
+        assert_eq!(event_id.encode(), 0x05040007);
+    }
+
+    #[test]
+    fn serialized_events() {
+        use std::collections::HashMap;
+
+        #[derive(Serialize, Deserialize)]
+        struct Compact {
+            #[serde(with = "event_hex")]
+            event: EventId,
+        }
+
+        let event_id = EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), SrcId::Mat(1));
+        let json = serde_json::to_string(&event_id).unwrap();
+        assert!(json.starts_with(r#"{"event_type":{"MCRT":{"Material":{"Elastic":{"Mie":"Forward"}}}},"src_id":{"Mat":1}"#));
+        assert_eq!(serde_json::from_str::<EventId>(&json).unwrap(), event_id);
+
+        let json = serde_json::to_string(&Compact { event: event_id.clone() }).unwrap();
+        assert_eq!(json, r#"{"event":"0x03A50001"}"#);
+        // The decoded source is the MCRT superset, equal to the Mat source it was encoded from
+        let compact: Compact = serde_json::from_str(&json).unwrap();
+        assert_eq!(compact.event.src_id, SrcId::MatSurf(1));
+        assert_eq!(compact.event, event_id);
+        assert!(serde_json::from_str::<Compact>(r#"{"event":"0x050F0002"}"#).is_err());
+
+        let mut counts: HashMap<EventId, usize> = HashMap::new();
+        *counts.entry(event_id.clone()).or_default() += 1;
+        *counts.entry(event_id).or_default() += 1;
+        *counts.entry(compact.event).or_default() += 1;
+        assert_eq!(counts.len(), 1);
+
+        // Every optional field survives the round trip
+        let events = [
+            EventId::new_mcrt(mcrt_event!(Interface, Refraction), SrcId::Surf(4)),
+            EventId::new_transport(transport::Transport::OpenBoundary),
+            EventId::new_transport(transport::Transport::PeriodicBoundary).with_face(0),
+            EventId::new_emission(emission::Emission::PulsedLaser, SrcId::Light(1))
+                .with_modulation(emission::Modulation::pulsed(Some(3))),
+            EventId::new_emission(emission::Emission::Laser, SrcId::Light(1)).with_channel(wavelength::Channel::new(2)),
+            EventId::new_detection(detection::Detection::Direct, SrcId::Detector(2)).with_pixel(700),
+        ];
+        for event_id in events {
+            assert_eq!(EventId::decode(event_id.encode()), event_id);
+        }
+        assert_ne!(
+            EventId::new_transport(transport::Transport::OpenBoundary),
+            EventId::new_transport(transport::Transport::OpenBoundary).with_face(0)
+        );
+    }
+
+    #[test]
+    fn fallible_decoding() {
+        let raw_event = EventId::new_detection(detection::Detection::Saturated, SrcId::Detector(7)).encode();
+        assert_eq!(EventId::try_decode(raw_event).unwrap().encode(), raw_event);
+        assert_eq!(raw_event.try_decode().map(|event_id| event_id.src_id), Ok(SrcId::Detector(7)));
+
+        // Detection code 15 and temporal mode 3 have no variant
+        let err = EventId::try_decode(0x050F0002).unwrap_err();
+        assert_eq!(err, error::DecodeError::UnknownCode { field: "Detection", code: 15, raw: 0x050F0002 });
+        assert_eq!(err.to_string(), "Unknown Detection code 15 in raw event 0x050F0002");
+        assert!(matches!(EventId::try_decode(0x01600000), Err(error::DecodeError::UnknownCode { field: "TemporalMode", .. })));
+        // Unknown pipelines are custom events rather than errors, only `pipeline` requires a known one
+        assert!(EventId::try_decode(0x0F000001).is_ok());
+        assert!(0x0F000001u32.try_pipeline().is_err());
+        assert_eq!(0x05000000u32.try_pipeline(), Ok(Pipeline::Detection));
+    }
+
+    #[test]
+    fn encoding_processing_event() {
+        let event_id = EventId::new_processing(processing::Processing::Digitization);
+        let raw_event = event_id.encode();
+        assert_eq!(raw_event, 0x07010000);
+        let decoded = EventId::decode(raw_event);
+        assert_eq!(decoded.event_type, EventType::Processing(processing::Processing::Digitization));
+        assert_eq!(decoded.src_id, SrcId::None);
+
+        let raw_event = EventId::new_processing(processing::Processing::BeamsplitterReflected).encode();
+        assert_eq!(raw_event, 0x07060000);
+        let decoded = EventId::decode(raw_event);
+        assert_eq!(decoded.event_type, EventType::Processing(processing::Processing::BeamsplitterReflected));
+    }
+
+    #[test]
+    fn encoding_transport_event() {
+        let raw_event = EventId::new_transport(transport::Transport::Split).encode();
+        assert_eq!(raw_event, 0x0B000000);
+        let decoded = EventId::decode(raw_event);
+        assert_eq!(decoded.event_type, EventType::Transport(transport::Transport::Split));
+        assert_eq!(decoded.src_id, SrcId::None);
+    }
+
     #[test]
     fn encoding_mcrt_event() {
         let mcrt_event = mcrt_event!(Material, Elastic, Mie, Any);
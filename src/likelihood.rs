@@ -0,0 +1,146 @@
+use crate::ledger::{Ledger, Uid};
+
+// ----------------------------------------------------
+// Chain likelihood from the annotated sampling probabilities
+// ----------------------------------------------------
+// Reconstructs the probability of sampling each chain and the weight the photon carried at its
+// end from the annotations recorded with `Recorder::insert_with_probability`. Entries without an
+// annotation are deterministic steps (probability 1, weight multiplier 1).
+// The weight audit averages the final weight over the annotated photons: an unbiased scheme keeps
+// it at 1 within its standard error. The number of photons of a chain is the sample count of its
+// leaf, hence chains ending in an unannotated entry are left out of the audit.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChainLikelihood {
+    pub probability: f64,
+    pub weight: f64,
+    pub annotated: usize,
+    pub unannotated: usize,
+    // Entries whose annotations differ across photons, the likelihood then uses the first one
+    pub varying: usize,
+}
+
+impl ChainLikelihood {
+    pub fn is_exact(&self) -> bool {
+        self.varying == 0
+    }
+}
+
+pub fn chain_likelihood(ledger: &Ledger, leaf: Uid) -> ChainLikelihood {
+    let mut likelihood = ChainLikelihood {
+        probability: 1.0,
+        weight: 1.0,
+        annotated: 0,
+        unannotated: 0,
+        varying: 0,
+    };
+    for uid in ledger.get_chain(leaf) {
+        match ledger.get_probability(&uid) {
+            Some(entry) => {
+                likelihood.probability *= entry.choice.probability;
+                likelihood.weight *= entry.choice.weight_multiplier;
+                likelihood.annotated += 1;
+                likelihood.varying += entry.varying as usize;
+            }
+            None => likelihood.unannotated += 1,
+        }
+    }
+    likelihood
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WeightAudit {
+    pub photons: u64,
+    pub mean_weight: f64,
+    pub std_error: f64,
+    // Chains taken into account, and left out as their leaf is not annotated
+    pub chains: usize,
+    pub skipped_chains: usize,
+    // Chains going through varying entries, whose weight is approximate
+    pub approximate_chains: usize,
+}
+
+impl WeightAudit {
+    // Whether the mean weight is 1 within `sigmas` standard errors
+    pub fn is_unbiased(&self, sigmas: f64) -> bool {
+        self.photons > 0 && (self.mean_weight - 1.0).abs() <= sigmas * self.std_error.max(f64::EPSILON)
+    }
+}
+
+pub fn audit_weights(ledger: &Ledger) -> WeightAudit {
+    let mut audit = WeightAudit {
+        photons: 0,
+        mean_weight: 0.0,
+        std_error: 0.0,
+        chains: 0,
+        skipped_chains: 0,
+        approximate_chains: 0,
+    };
+    let (mut sum, mut sum_sq) = (0.0, 0.0);
+    for leaf in ledger.leaves() {
+        let Some(photons) = ledger.get_probability(&leaf).map(|entry| entry.samples) else {
+            audit.skipped_chains += 1;
+            continue;
+        };
+        let likelihood = chain_likelihood(ledger, leaf);
+        audit.chains += 1;
+        audit.approximate_chains += !likelihood.is_exact() as usize;
+        audit.photons += photons;
+        sum += photons as f64 * likelihood.weight;
+        sum_sq += photons as f64 * likelihood.weight * likelihood.weight;
+    }
+    if audit.photons > 0 {
+        let n = audit.photons as f64;
+        audit.mean_weight = sum / n;
+        audit.std_error = ((sum_sq / n - audit.mean_weight * audit.mean_weight).max(0.0) / n).sqrt();
+    }
+    audit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detection::Detection;
+    use crate::emission::Emission;
+    use crate::ledger::ChoiceProbability;
+    use crate::ledger::tests::laser_in_water;
+    use crate::recorder::Recorder;
+    use crate::{EventId, SrcId, mcrt_event};
+
+    #[test]
+    fn biased_scattering_audit() {
+        let (ledger, light_id, mat_id) = laser_in_water();
+        let mut recorder = Recorder::new(ledger);
+        let forward = EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), mat_id);
+        let backward = EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Backward), mat_id);
+        let detection = EventId::new_detection(Detection::Direct, SrcId::Detector(0));
+
+        // Analog odds are 1:1, the scheme samples forward 3 times out of 4 and compensates the weight
+        let mut leaves = Vec::new();
+        for photon in 0..8 {
+            let start = recorder.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap().unwrap();
+            let scatter = if photon % 4 == 3 {
+                recorder.insert_with_probability(start, backward.clone(), ChoiceProbability::biased(0.25, 2.0)).unwrap()
+            } else {
+                recorder.insert_with_probability(start, forward.clone(), ChoiceProbability::biased(0.75, 2.0 / 3.0)).unwrap()
+            };
+            leaves.push(recorder.insert_with_probability(scatter, detection.clone(), ChoiceProbability::analog(1.0)).unwrap());
+        }
+        let ledger = recorder.into_ledger();
+
+        let likelihood = chain_likelihood(&ledger, leaves[3]);
+        assert_eq!((likelihood.probability, likelihood.weight), (0.25, 2.0));
+        assert_eq!((likelihood.annotated, likelihood.unannotated), (2, 1));
+        assert!(likelihood.is_exact());
+        assert_eq!(ledger.get_probability(&leaves[0]).unwrap().samples, 6);
+
+        let audit = audit_weights(&ledger);
+        assert_eq!((audit.photons, audit.chains, audit.skipped_chains), (8, 2, 0));
+        assert!((audit.mean_weight - 1.0).abs() < 1e-12);
+        assert!(audit.is_unbiased(3.0));
+
+        let json = serde_json::to_string(&ledger).unwrap();
+        let stored_ledger: Ledger = serde_json::from_str(&json).unwrap();
+        assert_eq!(chain_likelihood(&stored_ledger, leaves[3]), likelihood);
+    }
+}
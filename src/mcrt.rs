@@ -1,25 +1,34 @@
-use crate::raw::{self, RawField};
-use crate::{Encode, Decode};
+use serde::{Deserialize, Serialize};
+
+use crate::raw;
+use crate::{SrcId, TryDecode};
+use crate::error::DecodeError;
+#[cfg(feature = "std")]
+use crate::{filter::BitsMatch, raw::RawField};
 
 // NOTE: To simplify implementation for now, we will restrict to not allow MatSurf for now,
 // as some nuisances about grouping have not been resolved.
 
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug, Eq, Hash, Serialize, Deserialize)]
 pub enum MCRT {
     Interface(Interface),
     Reflector(Reflector),
     Material(Material),
+    // Interaction specific to an engine, with its code up to `raw::MAX_MCRT_CUSTOM`
+    Custom(u16),
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug, Eq, Hash, Serialize, Deserialize)]
 pub enum Interface {
     Reflection,
     Refraction,
+    // Followed by a Reflection and a Refraction child, one for each branch
+    FresnelSplit,
     ReEmittance,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug, Eq, Hash, Serialize, Deserialize)]
 pub enum Reflector {
     Diffuse,
     Specular,
@@ -28,20 +37,29 @@ pub enum Reflector {
     CompositeRetroReflective,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug, Eq, Hash, Serialize, Deserialize)]
 pub enum Material{
     Absorption,
     Inelastic(Inelastic),
     Elastic(Elastic),
+    Roulette(Roulette),
+}
+
+// Russian roulette on the photon weight. `boost_class` identifies the weight boost applied to the
+// survivor (i.e. the index of the survival probability used), up to `raw::MAX_BOOST_CLASS`.
+#[derive(Clone, Copy, PartialEq, Debug, Eq, Hash, Serialize, Deserialize)]
+pub enum Roulette {
+    Survived { boost_class: u8 },
+    Killed,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug, Eq, Hash, Serialize, Deserialize)]
 pub enum Inelastic {
     Raman(ScatterDir),
     Fluorescence(ScatterDir),
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug, Eq, Hash, Serialize, Deserialize)]
 pub enum Elastic {
     HenyeyGreenstein(ScatterDir),
     Mie(ScatterDir),
@@ -49,7 +67,7 @@ pub enum Elastic {
     SphericalCdf(ScatterDir),
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug, Eq, Hash, Serialize, Deserialize)]
 pub enum ScatterDir {
     Any,
     Forward,
@@ -57,14 +75,20 @@ pub enum ScatterDir {
     Backward,
 }
 
+impl Default for ScatterDir {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ScatterDir {
     pub fn new() -> Self {
         ScatterDir::Any
     }
     pub fn from(theta: f64) -> Self {
-        if theta < std::f64::consts::FRAC_PI_4 {
+        if theta < core::f64::consts::FRAC_PI_4 {
             ScatterDir::Forward
-        } else if theta < 3.0 * std::f64::consts::FRAC_PI_4 {
+        } else if theta < 3.0 * core::f64::consts::FRAC_PI_4 {
             ScatterDir::Side
         } else {
             ScatterDir::Backward
@@ -72,7 +96,7 @@ impl ScatterDir {
     }
     pub fn from_with_spec(theta: f64, intervals: [f64;4]) -> Self {
         assert_eq!(intervals[0], 0.0);
-        assert_eq!(intervals[3], std::f64::consts::PI);
+        assert_eq!(intervals[3], core::f64::consts::PI);
 
         if theta >= intervals[0] && theta < intervals[1] {
             ScatterDir::Forward
@@ -84,50 +108,100 @@ impl ScatterDir {
     }
 }
 
-impl Encode<u32> for MCRT {
-    fn encode(&self) -> u32 {
+// Angular bins [0, forward | side, side | backward, PI] used to classify the scattering angles,
+// stored in the ledger header such that analyses interpret the recorded directions consistently.
+// The default bins are the ones of `ScatterDir::from`.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ScatterBinning {
+    pub intervals: [f64; 4],
+}
+
+impl Default for ScatterBinning {
+    fn default() -> Self {
+        ScatterBinning {
+            intervals: [0.0, core::f64::consts::FRAC_PI_4, 3.0 * core::f64::consts::FRAC_PI_4, core::f64::consts::PI],
+        }
+    }
+}
+
+impl ScatterBinning {
+    #[cfg(feature = "std")]
+    pub fn new(intervals: [f64; 4]) -> Result<Self, String> {
+        if intervals[0] != 0.0 || intervals[3] != core::f64::consts::PI {
+            return Err(format!("Scatter bins must span [0, PI], got {:?}", intervals));
+        }
+        if intervals.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(format!("Scatter bins must be sorted, got {:?}", intervals));
+        }
+        Ok(ScatterBinning { intervals })
+    }
+
+    pub fn classify(&self, theta: f64) -> ScatterDir {
+        ScatterDir::from_with_spec(theta, self.intervals)
+    }
+
+    // Range of scattering angles of a direction, the whole [0, PI] for Any
+    pub fn range(&self, dir: ScatterDir) -> (f64, f64) {
+        match dir {
+            ScatterDir::Any      => (self.intervals[0], self.intervals[3]),
+            ScatterDir::Forward  => (self.intervals[0], self.intervals[1]),
+            ScatterDir::Side     => (self.intervals[1], self.intervals[2]),
+            ScatterDir::Backward => (self.intervals[2], self.intervals[3]),
+        }
+    }
+}
+
+impl MCRT {
+    pub const fn encode(&self) -> u32 {
         match self {
             MCRT::Interface(it) => raw::MCRT::Interface.encode() | it.encode(),
             MCRT::Reflector(rt) => raw::MCRT::Reflector.encode() | rt.encode(),
             MCRT::Material(mt)  => raw::MCRT::Material.encode() | mt.encode(),
+            MCRT::Custom(code)  => {
+                assert!(*code <= raw::MAX_MCRT_CUSTOM, "Custom MCRT code exceeds raw::MAX_MCRT_CUSTOM");
+                raw::MCRT::Custom.encode() | ((*code as u32) << raw::MCRT_CUSTOM_SHIFT)
+            }
         }
     }
 }
 
-impl Decode<u32> for MCRT {
-    fn decode(raw: u32) -> Self where Self: Sized {
-        let mcrt_type = raw::MCRT::decode(raw);
-        match mcrt_type {
-            raw::MCRT::Interface => MCRT::Interface(Interface::decode(raw)),
-            raw::MCRT::Reflector => MCRT::Reflector(Reflector::decode(raw)),
-            raw::MCRT::Material  => MCRT::Material(Material::decode(raw)),
-        }
+impl TryDecode<u32> for MCRT {
+    fn try_decode(raw: u32) -> Result<Self, DecodeError> {
+        let mcrt_type = raw::MCRT::try_decode(raw)?;
+        Ok(match mcrt_type {
+            raw::MCRT::Interface => MCRT::Interface(Interface::try_decode(raw)?),
+            raw::MCRT::Reflector => MCRT::Reflector(Reflector::try_decode(raw)?),
+            raw::MCRT::Material  => MCRT::Material(Material::try_decode(raw)?),
+            raw::MCRT::Custom    => MCRT::Custom(((raw & raw::MCRT_CUSTOM_MASK) >> raw::MCRT_CUSTOM_SHIFT) as u16),
+        })
     }
 }
 
-impl Encode<u32> for Interface {
-    fn encode(&self) -> u32 {
+impl Interface {
+    pub const fn encode(&self) -> u32 {
         match self {
             Interface::Reflection  => raw::Interface::Reflection.encode(),
             Interface::Refraction  => raw::Interface::Refraction.encode(),
+            Interface::FresnelSplit => raw::Interface::FresnelSplit.encode(),
             Interface::ReEmittance => raw::Interface::ReEmittance.encode(),
         }
     }
 }
 
-impl Decode<u32> for Interface {
-    fn decode(raw: u32) -> Self where Self: Sized {
-        let interface_type = raw::Interface::decode(raw);
-        match interface_type {
+impl TryDecode<u32> for Interface {
+    fn try_decode(raw: u32) -> Result<Self, DecodeError> {
+        let interface_type = raw::Interface::try_decode(raw)?;
+        Ok(match interface_type {
             raw::Interface::Reflection  => Interface::Reflection,
             raw::Interface::Refraction  => Interface::Refraction,
+            raw::Interface::FresnelSplit => Interface::FresnelSplit,
             raw::Interface::ReEmittance => Interface::ReEmittance,
-        }
+        })
     }
 }
 
-impl Encode<u32> for Reflector {
-    fn encode(&self) -> u32 {
+impl Reflector {
+    pub const fn encode(&self) -> u32 {
         match self {
             Reflector::Diffuse                  => raw::Reflector::Diffuse.encode(),
             Reflector::Specular                 => raw::Reflector::Specular.encode(),
@@ -138,42 +212,93 @@ impl Encode<u32> for Reflector {
     }
 }
 
-impl Decode<u32> for Reflector {
-    fn decode(raw: u32) -> Self where Self: Sized {
-        let reflect_type = raw::Reflector::decode(raw);
-        match reflect_type {
+impl TryDecode<u32> for Reflector {
+    fn try_decode(raw: u32) -> Result<Self, DecodeError> {
+        let reflect_type = raw::Reflector::try_decode(raw)?;
+        Ok(match reflect_type {
             raw::Reflector::Diffuse         => Reflector::Diffuse,
             raw::Reflector::Specular        => Reflector::Specular,
             raw::Reflector::Composite       => Reflector::Composite,
             raw::Reflector::RetroReflective => Reflector::RetroReflective,
             raw::Reflector::CompRetroRef    => Reflector::CompositeRetroReflective,
-        }
+        })
     }
 }
 
-impl Encode<u32> for Material {
-    fn encode(&self) -> u32 {
+impl Material {
+    pub const fn encode(&self) -> u32 {
         match self {
             Material::Absorption    => raw::Material::Absorption.encode(),
             Material::Inelastic(it) => raw::Material::Inelastic.encode() | it.encode(),
             Material::Elastic(et)   => raw::Material::Elastic.encode() | et.encode(),
+            Material::Roulette(rt)  => raw::Material::Roulette.encode() | rt.encode(),
         }
     }
 }
 
-impl Decode<u32> for Material {
-    fn decode(raw: u32) -> Self where Self: Sized {
-        let material_type = raw::Material::decode(raw);
-        match material_type {
+impl TryDecode<u32> for Material {
+    fn try_decode(raw: u32) -> Result<Self, DecodeError> {
+        let material_type = raw::Material::try_decode(raw)?;
+        Ok(match material_type {
             raw::Material::Absorption    => Material::Absorption,
-            raw::Material::Inelastic     => Material::Inelastic(Inelastic::decode(raw)),
-            raw::Material::Elastic       => Material::Elastic(Elastic::decode(raw)),
+            raw::Material::Inelastic     => Material::Inelastic(Inelastic::try_decode(raw)?),
+            raw::Material::Elastic       => Material::Elastic(Elastic::try_decode(raw)?),
+            raw::Material::Roulette      => Material::Roulette(Roulette::try_decode(raw)?),
+        })
+    }
+}
+
+impl Roulette {
+    pub const fn encode(&self) -> u32 {
+        match self {
+            Roulette::Survived { boost_class } => {
+                assert!(*boost_class <= raw::MAX_BOOST_CLASS, "Roulette boost class exceeds raw::MAX_BOOST_CLASS");
+                raw::Roulette::Survived.encode() | ((*boost_class as u32) << raw::BOOST_CLASS_SHIFT)
+            }
+            Roulette::Killed => raw::Roulette::Killed.encode(),
         }
     }
 }
 
-impl Encode<u32> for Inelastic {
-    fn encode(&self) -> u32 {
+impl TryDecode<u32> for Roulette {
+    fn try_decode(raw: u32) -> Result<Self, DecodeError> {
+        Ok(match raw::Roulette::try_decode(raw)? {
+            raw::Roulette::Survived => Roulette::Survived {
+                boost_class: ((raw & raw::BOOST_CLASS_MASK) >> raw::BOOST_CLASS_SHIFT) as u8,
+            },
+            raw::Roulette::Killed => Roulette::Killed,
+        })
+    }
+}
+
+/// Survivors of a roulette with the given boost class, i.e. to audit the weight game of a single
+/// survival probability, while `filter_seq!` matches the survivors of any class:
+/// ```
+/// use aetherus_events::mcrt::{Material, MCRT, Roulette, boost_class_bits_match};
+/// use aetherus_events::{Encode, EventId, RawEvent, SrcId, filter_seq};
+///
+/// let survived = EventId::new_mcrt(MCRT::Material(Material::Roulette(Roulette::Survived { boost_class: 2 })), SrcId::Mat(1));
+/// let raw_event = survived.encode();
+/// assert!(raw_event.matches(&filter_seq!(MCRT, Material, Roulette, Survived, SrcId::Mat(1))));
+/// assert!(raw_event.matches(&boost_class_bits_match(2, SrcId::Mat(1))));
+/// assert!(!raw_event.matches(&boost_class_bits_match(3, SrcId::Mat(1))));
+/// ```
+#[cfg(feature = "std")]
+pub fn boost_class_bits_match(boost_class: u8, src_id: SrcId) -> BitsMatch {
+    let event = MCRT::Material(Material::Roulette(Roulette::Survived { boost_class }));
+    let mut bits_match = BitsMatch::new(
+        raw::Pipeline::mask() | raw::MCRT::mask() | raw::Material::mask() | raw::Roulette::mask() | raw::BOOST_CLASS_MASK,
+        raw::Pipeline::MCRT.encode() | event.encode(),
+    );
+    if let Some(id) = src_id.id() {
+        bits_match.mask  |= SrcId::mask();
+        bits_match.value |= id as u32;
+    }
+    bits_match
+}
+
+impl Inelastic {
+    pub const fn encode(&self) -> u32 {
         match self {
             Inelastic::Raman(dir)        => raw::Inelastic::Raman.encode() | dir.encode(),
             Inelastic::Fluorescence(dir) => raw::Inelastic::Fluorescence.encode() | dir.encode(),
@@ -181,18 +306,18 @@ impl Encode<u32> for Inelastic {
     }
 }
 
-impl Decode<u32> for Inelastic {
-    fn decode(raw: u32) -> Self where Self: Sized {
-        let inelastic_type = raw::Inelastic::decode(raw);
-        match inelastic_type {
-            raw::Inelastic::Raman        => Inelastic::Raman(ScatterDir::decode(raw)),
-            raw::Inelastic::Fluorescence => Inelastic::Fluorescence(ScatterDir::decode(raw)),
-        }
+impl TryDecode<u32> for Inelastic {
+    fn try_decode(raw: u32) -> Result<Self, DecodeError> {
+        let inelastic_type = raw::Inelastic::try_decode(raw)?;
+        Ok(match inelastic_type {
+            raw::Inelastic::Raman        => Inelastic::Raman(ScatterDir::try_decode(raw)?),
+            raw::Inelastic::Fluorescence => Inelastic::Fluorescence(ScatterDir::try_decode(raw)?),
+        })
     }
 }
 
-impl Encode<u32> for Elastic {
-    fn encode(&self) -> u32 {
+impl Elastic {
+    pub const fn encode(&self) -> u32 {
         match self {
             Elastic::HenyeyGreenstein(dir) => raw::Elastic::HenyeyGreenstein.encode() | dir.encode(),
             Elastic::Mie(dir)              => raw::Elastic::Mie.encode()              | dir.encode(),
@@ -202,20 +327,20 @@ impl Encode<u32> for Elastic {
     }
 }
 
-impl Decode<u32> for Elastic {
-    fn decode(raw: u32) -> Self where Self: Sized {
-        let elastic_type = raw::Elastic::decode(raw);
-        match elastic_type {
-            raw::Elastic::HenyeyGreenstein => Elastic::HenyeyGreenstein(ScatterDir::decode(raw)),
-            raw::Elastic::Mie              => Elastic::Mie(ScatterDir::decode(raw)),
-            raw::Elastic::Rayleigh         => Elastic::Rayleigh(ScatterDir::decode(raw)),
-            raw::Elastic::SphericalCdf     => Elastic::SphericalCdf(ScatterDir::decode(raw)),
-        }
+impl TryDecode<u32> for Elastic {
+    fn try_decode(raw: u32) -> Result<Self, DecodeError> {
+        let elastic_type = raw::Elastic::try_decode(raw)?;
+        Ok(match elastic_type {
+            raw::Elastic::HenyeyGreenstein => Elastic::HenyeyGreenstein(ScatterDir::try_decode(raw)?),
+            raw::Elastic::Mie              => Elastic::Mie(ScatterDir::try_decode(raw)?),
+            raw::Elastic::Rayleigh         => Elastic::Rayleigh(ScatterDir::try_decode(raw)?),
+            raw::Elastic::SphericalCdf     => Elastic::SphericalCdf(ScatterDir::try_decode(raw)?),
+        })
     }
 }
 
-impl Encode<u32> for ScatterDir {
-    fn encode(&self) -> u32 {
+impl ScatterDir {
+    pub const fn encode(&self) -> u32 {
         match self {
             ScatterDir::Any      => raw::ScatterDir::Any.encode(),
             ScatterDir::Forward  => raw::ScatterDir::Forward.encode(),
@@ -225,14 +350,36 @@ impl Encode<u32> for ScatterDir {
     }
 }
 
-impl Decode<u32> for ScatterDir {
-    fn decode(raw: u32) -> Self where Self: Sized {
-        let dir_type = raw::ScatterDir::decode(raw);
-        match dir_type {
+impl TryDecode<u32> for ScatterDir {
+    fn try_decode(raw: u32) -> Result<Self, DecodeError> {
+        let dir_type = raw::ScatterDir::try_decode(raw)?;
+        Ok(match dir_type {
             raw::ScatterDir::Any      => ScatterDir::Any,
             raw::ScatterDir::Forward  => ScatterDir::Forward,
             raw::ScatterDir::Side     => ScatterDir::Side,
             raw::ScatterDir::Backward => ScatterDir::Backward,
+        })
+    }
+}
+
+crate::decode_or_panic!(MCRT, Interface, Reflector, Material, Roulette, Inelastic, Elastic, ScatterDir);
+crate::encode_from_const!(MCRT, Interface, Reflector, Material, Roulette, Inelastic, Elastic, ScatterDir);
+
+impl MCRT {
+    // Whole event word, i.e. for event constants of kernels and no_std code
+    //     const MIE_FORWARD: u32 = mcrt_event!(Material, Elastic, Mie, Forward).encode_event(SrcId::Mat(1));
+    pub const fn encode_event(&self, src_id: SrcId) -> u32 {
+        raw::Pipeline::MCRT.encode() | self.encode() | src_id.encode()
+    }
+
+    // Direction of the elastic and inelastic scattering events, None for the other events
+    pub const fn scatter_dir(&self) -> Option<ScatterDir> {
+        match self {
+            MCRT::Material(Material::Elastic(
+                Elastic::HenyeyGreenstein(dir) | Elastic::Mie(dir) | Elastic::Rayleigh(dir) | Elastic::SphericalCdf(dir),
+            ))
+            | MCRT::Material(Material::Inelastic(Inelastic::Raman(dir) | Inelastic::Fluorescence(dir))) => Some(*dir),
+            _ => None,
         }
     }
 }
@@ -240,7 +387,8 @@ impl Decode<u32> for ScatterDir {
 // Write a macro that given the sequence of super and sub types, build the MCRT Event
 // i.e.
 // 1. mcrt_event!(Interface, Reflection) -> MCRT::Interface(Interface::Reflection)
-// 2. mcrt_event!(Material,Elastic,Mie,Any) -> MCRT::Material(Material::Elastic(Elastic::Mie(ScatterDir::Any)))
+// 2. mcrt_event!(Material, Roulette, Killed) -> MCRT::Material(Material::Roulette(Roulette::Killed))
+// 3. mcrt_event!(Material,Elastic,Mie,Any) -> MCRT::Material(Material::Elastic(Elastic::Mie(ScatterDir::Any)))
 #[macro_export]
 macro_rules! mcrt_event {
     ($event_type:ident) => {
@@ -249,6 +397,9 @@ macro_rules! mcrt_event {
     ($subtype:ident, $sstype:ident) => {
         $crate::mcrt::MCRT::$subtype($crate::mcrt::$subtype::$sstype)
     };
+    ($stype:ident, $sstype:ident, $ssstype:ident) => {
+        $crate::mcrt::MCRT::$stype($crate::mcrt::$stype::$sstype($crate::mcrt::$sstype::$ssstype))
+    };
     ($stype:ident, $sstype:ident, $ssstype:ident, $dirtype:ident) => {
         $crate::mcrt::MCRT::$stype($crate::mcrt::$stype::$sstype($crate::mcrt::$sstype::$ssstype($crate::mcrt::ScatterDir::$dirtype)))
     };
@@ -257,12 +408,15 @@ macro_rules! mcrt_event {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{Decode, Encode};
     #[test]
     fn mcrt_event_macro() {
         let event1 = mcrt_event!(Interface, Reflection);
         assert_eq!(event1, MCRT::Interface(Interface::Reflection));
         let event2 = mcrt_event!(Material, Elastic, Mie, Any);
         assert_eq!(event2, MCRT::Material(Material::Elastic(Elastic::Mie(ScatterDir::Any))));
+        let event3 = mcrt_event!(Material, Roulette, Killed);
+        assert_eq!(event3, MCRT::Material(Material::Roulette(Roulette::Killed)));
     }
 
     #[test]
@@ -282,6 +436,10 @@ mod tests {
             MCRT::Material(Material::Elastic(Elastic::Mie(ScatterDir::Backward))),
             MCRT::Material(Material::Elastic(Elastic::Rayleigh(ScatterDir::Backward))),
             MCRT::Material(Material::Elastic(Elastic::SphericalCdf(ScatterDir::Backward))),
+            MCRT::Material(Material::Roulette(Roulette::Killed)),
+            MCRT::Material(Material::Roulette(Roulette::Survived { boost_class: 5 })),
+            MCRT::Interface(Interface::FresnelSplit),
+            MCRT::Custom(5),
         ];
         let enc_list = vec![
             0x03000001,
@@ -298,6 +456,10 @@ mod tests {
             0x03a7000c,
             0x03ab000d,
             0x03af000e,
+            0x03b0000f,
+            0x03bd0010,
+            0x03020011,
+            0x03c50012,
         ];
         for (enc, dec) in enc_list.iter().zip(dec_list.iter()) {
             let decoded_event = MCRT::decode(*enc);
@@ -305,4 +467,48 @@ mod tests {
             assert_eq!(*enc & 0x00ff0000, dec.encode());
         }
     }
+
+    #[test]
+    fn custom_interactions() {
+        use crate::filter::parse;
+        use crate::{EventId, EventType, RawEvent, filter_seq};
+
+        let raw_event = EventId::new_mcrt(MCRT::Custom(5), SrcId::Mat(1)).encode();
+        assert_eq!(raw_event, 0x03c50001);
+        assert_eq!(EventId::decode(raw_event).event_type, EventType::MCRT(MCRT::Custom(5)));
+
+        let code_filter = filter_seq!(MCRT, Custom, 5, SrcId::Mat(1));
+        assert!(raw_event.matches(&code_filter));
+        assert!(!0x03c60001u32.matches(&code_filter));
+        assert_eq!(parse("MCRT|Custom|5|Mat(1)").unwrap(), crate::filter::FilterExpr::seq(vec![code_filter]));
+        let any_filter = filter_seq!(MCRT, Custom, _, SrcId::None);
+        assert!(raw_event.matches(&any_filter) && 0x03c60002u32.matches(&any_filter));
+        assert!(!0x03800001u32.matches(&any_filter));
+        assert!(parse("MCRT|Custom|64").is_err());
+    }
+
+    #[test]
+    fn const_encoding() {
+        use crate::EventId;
+
+        const MIE_FORWARD: u32 = crate::mcrt_event!(Material, Elastic, Mie, Forward).encode_event(SrcId::Mat(1));
+        const KILLED: u32 = raw::Pipeline::MCRT.encode() | raw::MCRT::Material.encode() | raw::Material::Roulette.encode();
+        assert_eq!(MIE_FORWARD, 0x03a50001);
+        assert_eq!(KILLED, EventId::new_mcrt(mcrt_event!(Material, Roulette, Killed), SrcId::None).encode());
+        let survived = MCRT::Material(Material::Roulette(Roulette::Survived { boost_class: 3 }));
+        assert_eq!(<MCRT as Encode<u32>>::encode(&survived), survived.encode());
+    }
+
+    #[test]
+    fn scatter_binning() {
+        let default = ScatterBinning::default();
+        for theta in [0.1, 1.0, 2.0, 3.0] {
+            assert_eq!(default.classify(theta), ScatterDir::from(theta));
+        }
+        let narrow = ScatterBinning::new([0.0, 0.2, 2.9, std::f64::consts::PI]).unwrap();
+        assert_eq!(narrow.classify(0.5), ScatterDir::Side);
+        assert_eq!(narrow.range(ScatterDir::Backward), (2.9, std::f64::consts::PI));
+        assert!(ScatterBinning::new([0.0, 2.0, 1.0, std::f64::consts::PI]).is_err());
+        assert!(ScatterBinning::new([0.0, 1.0, 2.0, 3.0]).is_err());
+    }
 }
@@ -0,0 +1,266 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::RawEvent;
+use crate::aev::invalid_data;
+use crate::ledger::{Ledger, SrcTable, Uid};
+use crate::spill::{SpillStore, decode_entry, encode_entries};
+
+// ----------------------------------------------------
+// Memory-mapped ledger file (.aevm)
+// ----------------------------------------------------
+// Read-only layout for post-processing ledgers too large to deserialize in BTreeMaps. The entries
+// are stored in two sorted tables, such that lookups binary search the mapped file and only the
+// pages they touch are read. Little-endian layout:
+// | Magic "AEVM" (4) | Layout version (u16) | Raw event width in bytes (u8) | Reserved (u8) |
+// | Entry count (u64) | SrcTable JSON length (u64) |
+// | Next table: seq_id (u32) | event | next_seq_id (u32) | ..., sorted by (seq_id, event)
+// | Prev table: index of the entry in the next table (u32) | ..., sorted by next_seq_id
+// | SrcTable JSON |
+// Start events are the entries of group 0, hence ordered by event rather than by insertion.
+
+pub const MAGIC: [u8; 4] = *b"AEVM";
+pub const LAYOUT_VERSION: u16 = 1;
+
+const HEADER_BYTES: usize = 24;
+
+pub fn write_mapped<E: RawEvent, P: AsRef<Path>>(ledger: &Ledger<E>, path: P) -> io::Result<()> {
+    let mut entries: Vec<(u32, E, u32)> = ledger
+        .entries()
+        .map(|uid| (uid.seq_id, uid.event, ledger.get_next_seq_id(&uid).unwrap()))
+        .collect();
+    entries.sort_unstable();
+    // Start events all allocate seq_id 1, the entry the ledger resolves as previous one comes first
+    let mut prev_table: Vec<u32> = (0..entries.len() as u32).collect();
+    prev_table.sort_unstable_by_key(|idx| {
+        let (seq_id, event, next_seq_id) = entries[*idx as usize];
+        (next_seq_id, ledger.get_prev(next_seq_id) != Some(Uid { seq_id, event }))
+    });
+    let src_table = serde_json::to_vec(&ledger.src_table())?;
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&LAYOUT_VERSION.to_le_bytes())?;
+    writer.write_all(&[std::mem::size_of::<E::Raw>() as u8, 0])?;
+    writer.write_all(&(entries.len() as u64).to_le_bytes())?;
+    writer.write_all(&(src_table.len() as u64).to_le_bytes())?;
+    writer.write_all(&encode_entries(&entries))?;
+    for idx in prev_table {
+        writer.write_all(&idx.to_le_bytes())?;
+    }
+    writer.write_all(&src_table)?;
+    writer.flush()
+}
+
+// ----------------------------------------------------
+// Read-only view
+// ----------------------------------------------------
+// Same traversal methods as the Ledger, see `ledger::LedgerView` for a shareable in-memory ledger
+pub struct MappedLedgerView<E: RawEvent = u32> {
+    mmap: Mmap,
+    len: usize,
+    src_table: SrcTable,
+    _event: PhantomData<E>,
+}
+
+impl<E: RawEvent> MappedLedgerView<E> {
+    // NOTE: The file must not be modified while it is mapped, `write_mapped` truncates an existing
+    // file in place, hence remove it first to write it again while views are open
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: The mapping is read-only, see the note above about concurrent modifications
+        let mmap = unsafe { Mmap::map(&file)? };
+        if mmap.len() < HEADER_BYTES || mmap[..4] != MAGIC {
+            return Err(invalid_data("Not a memory-mapped ledger file".to_string()));
+        }
+        let version = u16::from_le_bytes([mmap[4], mmap[5]]);
+        if version > LAYOUT_VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported mapped ledger layout version {}, expected at most {}",
+                version, LAYOUT_VERSION
+            )));
+        }
+        let event_bytes = std::mem::size_of::<E::Raw>();
+        if mmap[6] as usize != event_bytes {
+            return Err(invalid_data(format!(
+                "Mapped ledger holds {} byte events, expected {} byte ones",
+                mmap[6], event_bytes
+            )));
+        }
+        let len = u64::from_le_bytes(mmap[8..16].try_into().unwrap()) as usize;
+        let src_table_len = u64::from_le_bytes(mmap[16..24].try_into().unwrap()) as usize;
+        let src_table_offset = HEADER_BYTES + len * (SpillStore::<E>::TRIPLE_BYTES + 4);
+        if mmap.len() != src_table_offset + src_table_len {
+            return Err(invalid_data(format!(
+                "Mapped ledger of {} entries has {} bytes, expected {}",
+                len,
+                mmap.len(),
+                src_table_offset + src_table_len
+            )));
+        }
+        let src_table = serde_json::from_slice(&mmap[src_table_offset..])?;
+        Ok(Self {
+            mmap,
+            len,
+            src_table,
+            _event: PhantomData,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn src_table(&self) -> &SrcTable {
+        &self.src_table
+    }
+
+    // Sources of the view as an empty Ledger, i.e. for the names and filters using them
+    pub fn src_ledger(&self) -> Ledger {
+        Ledger::from_src_table(self.src_table.clone())
+    }
+
+    pub fn get_start_events(&self) -> Vec<Uid<E>> {
+        self.group(0)
+    }
+
+    pub fn contains(&self, uid: &Uid<E>) -> bool {
+        self.get_next_seq_id(uid).is_some()
+    }
+
+    pub fn get_next_seq_id(&self, uid: &Uid<E>) -> Option<u32> {
+        let idx = self.partition_point(|idx| {
+            let (seq_id, event, _) = self.entry(idx);
+            (seq_id, event) < (uid.seq_id, uid.event)
+        });
+        match self.entry_checked(idx) {
+            Some((seq_id, event, next_seq_id)) if seq_id == uid.seq_id && event == uid.event => Some(next_seq_id),
+            _ => None,
+        }
+    }
+
+    pub fn get_next(&self, uid: &Uid<E>) -> Vec<Uid<E>> {
+        self.get_next_seq_id(uid).map(|next_seq_id| self.group(next_seq_id)).unwrap_or_default()
+    }
+
+    pub fn get_prev(&self, seq_id: u32) -> Option<Uid<E>> {
+        let pos = self.partition_point(|pos| self.entry(self.prev_idx(pos)).2 < seq_id);
+        if pos == self.len {
+            return None;
+        }
+        let (prev_seq_id, event, next_seq_id) = self.entry(self.prev_idx(pos));
+        (next_seq_id == seq_id).then_some(Uid { seq_id: prev_seq_id, event })
+    }
+
+    pub fn get_chain(&self, last_uid: Uid<E>) -> Vec<Uid<E>> {
+        let mut chain = vec![last_uid];
+        let mut seq_id = last_uid.seq_id;
+        while let Some(uid) = self.get_prev(seq_id) {
+            chain.push(uid);
+            seq_id = uid.seq_id;
+        }
+        chain.reverse();
+        chain
+    }
+
+    // Entries sharing `seq_id`, i.e. the events following the entry which allocated it
+    fn group(&self, seq_id: u32) -> Vec<Uid<E>> {
+        let start = self.partition_point(|idx| self.entry(idx).0 < seq_id);
+        (start..self.len)
+            .map(|idx| self.entry(idx))
+            .take_while(|entry| entry.0 == seq_id)
+            .map(|(seq_id, event, _)| Uid { seq_id, event })
+            .collect()
+    }
+
+    fn entry(&self, idx: usize) -> (u32, E, u32) {
+        let offset = HEADER_BYTES + idx * SpillStore::<E>::TRIPLE_BYTES;
+        decode_entry(&self.mmap[offset..offset + SpillStore::<E>::TRIPLE_BYTES])
+    }
+
+    fn entry_checked(&self, idx: usize) -> Option<(u32, E, u32)> {
+        (idx < self.len).then(|| self.entry(idx))
+    }
+
+    fn prev_idx(&self, pos: usize) -> usize {
+        let offset = HEADER_BYTES + self.len * SpillStore::<E>::TRIPLE_BYTES + pos * 4;
+        u32::from_le_bytes(self.mmap[offset..offset + 4].try_into().unwrap()) as usize
+    }
+
+    // First index of 0..len for which `is_before` is false, the tables being sorted accordingly
+    fn partition_point(&self, is_before: impl Fn(usize) -> bool) -> usize {
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if is_before(mid) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detection::Detection;
+    use crate::emission::Emission;
+    use crate::raw64::RawEvent64;
+    use crate::{EventId, SrcId, mcrt_event};
+
+    #[test]
+    fn mapped_view() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let mut leaves = Vec::new();
+        for beam in [Emission::PencilBeam, Emission::PointSource] {
+            let start = ledger.insert_start(EventId::new_emission(beam, light_id)).unwrap();
+            for depth in 0..30 {
+                let mut leaf = start;
+                for _ in 0..=depth % 4 {
+                    leaf = ledger.insert(leaf, EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+                }
+                leaves.push(ledger.insert(leaf, EventId::new_detection(Detection::Direct, SrcId::Detector(depth % 5))).unwrap());
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.aevm");
+        write_mapped(&ledger, &path).unwrap();
+        let view: MappedLedgerView = MappedLedgerView::open(&path).unwrap();
+        assert_eq!(view.len(), ledger.entries().count());
+        assert_eq!(view.src_table(), &ledger.src_table());
+        assert_eq!(view.src_ledger().names(&mat_id), ledger.names(&mat_id));
+
+        let mut start_events = ledger.get_start_events().clone();
+        start_events.sort_by_key(|uid| uid.event);
+        assert_eq!(view.get_start_events(), start_events);
+        for uid in ledger.entries() {
+            assert_eq!(view.get_next_seq_id(&uid), ledger.get_next_seq_id(&uid));
+            assert_eq!(view.get_next(&uid), ledger.get_next(&uid));
+        }
+        for leaf in &leaves {
+            assert_eq!(view.get_chain(*leaf), ledger.get_chain(*leaf));
+            assert!(view.get_next(leaf).is_empty());
+        }
+        let unknown = Uid { seq_id: u32::MAX - 1, event: leaves[0].event };
+        assert!(!view.contains(&unknown));
+        assert_eq!(view.get_prev(u32::MAX), None);
+
+        assert!(MappedLedgerView::<RawEvent64>::open(&path).is_err());
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(MappedLedgerView::<u32>::open(&path).is_err());
+    }
+}
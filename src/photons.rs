@@ -0,0 +1,561 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::filter::{self, FilterExpr};
+use crate::ledger::{Ledger, Uid};
+use crate::query::{CmpOp, Tokens, tokenize};
+
+// ----------------------------------------------------
+// Photon records dumped by the simulation
+// ----------------------------------------------------
+// One row per detected photon, linked to the ledger by the Uid of its last event. Depending on the
+// tool that wrote them, the uid column is stored as:
+// - Hex:     `100000003` or `0x100000003`, the array_bytes encoding of Uid::encode
+// - Decimal: `4294967299`
+// - Display: `1, 0x00000003` (Uid Display) or `0x00000001_00000003` (seq_id and event in hex)
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct PhotonRecord {
+    pub pos_x: f64,
+    pub pos_y: f64,
+    pub pos_z: f64,
+    pub dir_x: f64,
+    pub dir_y: f64,
+    pub dir_z: f64,
+    pub wavelength: f64,
+    pub power: f64,
+    pub weight: f64,
+    pub tof: f64,
+    // Read separately from the configured column, always written as hex
+    #[serde(serialize_with = "array_bytes::ser_hexify", skip_deserializing)]
+    pub uid: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum UidFormat {
+    // Detected from the values of the column
+    #[default]
+    Auto,
+    Hex,
+    Decimal,
+    Display,
+}
+
+impl FromStr for UidFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(UidFormat::Auto),
+            "hex" => Ok(UidFormat::Hex),
+            "dec" | "decimal" => Ok(UidFormat::Decimal),
+            "display" => Ok(UidFormat::Display),
+            _ => Err(format!("Unknown uid format `{}`, expected auto, hex, dec or display", s)),
+        }
+    }
+}
+
+// Column holding the uid, looked up among the usual names if not given
+#[derive(Clone, Debug, Default)]
+pub struct UidColumn {
+    pub name: Option<String>,
+    pub format: UidFormat,
+}
+
+const UID_COLUMN_NAMES: &[&str] = &["uid", "UID", "Uid", "photon_uid", "event_uid", "uid_hex"];
+
+pub fn parse_uid(value: &str, format: UidFormat) -> Result<u64, String> {
+    let value = value.trim();
+    let format = match format {
+        UidFormat::Auto => detect_uid_format([value])?,
+        format => format,
+    };
+    match format {
+        UidFormat::Hex => u64::from_str_radix(value.trim_start_matches("0x"), 16)
+            .map_err(|e| format!("Invalid hex uid `{}`: {}", value, e)),
+        UidFormat::Decimal => value.parse::<u64>().map_err(|e| format!("Invalid decimal uid `{}`: {}", value, e)),
+        UidFormat::Display => {
+            if let Some((seq_id, event)) = value.trim_start_matches("0x").split_once('_') {
+                let seq_id = u32::from_str_radix(seq_id, 16).map_err(|e| format!("Invalid uid `{}`: {}", value, e))?;
+                let event = u32::from_str_radix(event, 16).map_err(|e| format!("Invalid uid `{}`: {}", value, e))?;
+                Ok(Uid::new(seq_id, event).encode())
+            } else {
+                value.parse::<Uid>().map(|uid| uid.encode())
+            }
+        }
+        UidFormat::Auto => unreachable!(),
+    }
+}
+
+// Plain digits are ambiguous between decimal and the unprefixed hex encoding: a column is decimal
+// once a value doesn't fit 16 hex digits, and fails when all of its values are up to 16 digits long,
+// such that the format has to be given instead of reading decimal uids as hex.
+pub fn detect_uid_format<'a, I: IntoIterator<Item = &'a str>>(values: I) -> Result<UidFormat, String> {
+    let mut short_digits = None;
+    for value in values {
+        let value = value.trim();
+        if value.contains(',') || value.contains('_') {
+            return Ok(UidFormat::Display);
+        }
+        if value.starts_with("0x") || value.chars().any(|c| c.is_ascii_hexdigit() && !c.is_ascii_digit()) {
+            return Ok(UidFormat::Hex);
+        }
+        if value.len() > 16 {
+            return Ok(UidFormat::Decimal);
+        }
+        short_digits.get_or_insert(value);
+    }
+    match short_digits {
+        Some(value) => Err(format!(
+            "Uid `{}` is ambiguous between hex and decimal, set the uid format to hex or dec",
+            value
+        )),
+        None => Ok(UidFormat::Hex),
+    }
+}
+
+// ----------------------------------------------------
+// Column schema
+// ----------------------------------------------------
+// Photon tables written by other engines name and order their columns differently. The schema maps
+// column names to PhotonRecord fields, columns absent from the mapping are read by field name:
+// ```toml
+// ignore_extra = false
+// [columns]
+// x = "pos_x"
+// lambda_nm = "wavelength"
+// photon_id = "uid"
+// ```
+// A column mapped to `uid` is the uid column, unless one is given by the UidColumn.
+
+const FLOAT_FIELDS: [&str; 10] =
+    ["pos_x", "pos_y", "pos_z", "dir_x", "dir_y", "dir_z", "wavelength", "power", "weight", "tof"];
+
+fn float_field(record: &mut PhotonRecord, idx: usize) -> &mut f64 {
+    match idx {
+        0 => &mut record.pos_x,
+        1 => &mut record.pos_y,
+        2 => &mut record.pos_z,
+        3 => &mut record.dir_x,
+        4 => &mut record.dir_y,
+        5 => &mut record.dir_z,
+        6 => &mut record.wavelength,
+        7 => &mut record.power,
+        8 => &mut record.weight,
+        _ => &mut record.tof,
+    }
+}
+
+fn float_value(record: &PhotonRecord, idx: usize) -> f64 {
+    [
+        record.pos_x,
+        record.pos_y,
+        record.pos_z,
+        record.dir_x,
+        record.dir_y,
+        record.dir_z,
+        record.wavelength,
+        record.power,
+        record.weight,
+        record.tof,
+    ][idx]
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PhotonSchema {
+    // Column name -> PhotonRecord field
+    #[serde(default)]
+    pub columns: BTreeMap<String, String>,
+    // Columns which are neither mapped nor named after a field are skipped, otherwise rejected
+    #[serde(default = "ignore_extra_default")]
+    pub ignore_extra: bool,
+}
+
+fn ignore_extra_default() -> bool {
+    true
+}
+
+impl Default for PhotonSchema {
+    fn default() -> Self {
+        PhotonSchema { columns: BTreeMap::new(), ignore_extra: true }
+    }
+}
+
+impl FromStr for PhotonSchema {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let schema: PhotonSchema = toml::from_str(s).map_err(|e| format!("Invalid photon schema: {}", e))?;
+        if let Some(field) = schema.columns.values().find(|field| *field != "uid" && !FLOAT_FIELDS.contains(&field.as_str())) {
+            return Err(format!("Unknown photon field `{}`, expected one of {:?} or uid", field, FLOAT_FIELDS));
+        }
+        Ok(schema)
+    }
+}
+
+impl PhotonSchema {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read photon schema {}: {}", path.display(), e))?
+            .parse()
+    }
+
+    // Field read from the column `header`
+    fn field_of<'a>(&'a self, header: &'a str) -> &'a str {
+        self.columns.get(header).map(String::as_str).unwrap_or(header)
+    }
+
+    // Index of the column of each float field, in FLOAT_FIELDS order, and of the uid column
+    fn resolve(&self, headers: &csv::StringRecord, uid_column: &UidColumn) -> Result<([usize; 10], usize), String> {
+        let headers = headers.iter().collect::<Vec<&str>>();
+        let mut float_idx = [0; 10];
+        for (field_idx, field) in FLOAT_FIELDS.iter().enumerate() {
+            float_idx[field_idx] = headers
+                .iter()
+                .position(|header| self.field_of(header) == *field)
+                .ok_or_else(|| format!("No column for the photon field `{}`, found columns {:?}", field, headers))?;
+        }
+        let uid_idx = match &uid_column.name {
+            Some(name) => headers.iter().position(|header| header == name),
+            None => headers
+                .iter()
+                .position(|header| self.columns.get(*header).is_some_and(|field| field == "uid"))
+                .or_else(|| headers.iter().position(|header| UID_COLUMN_NAMES.contains(header))),
+        }
+        .ok_or_else(|| format!("No uid column, found columns {:?}", headers))?;
+
+        if !self.ignore_extra
+            && let Some(extra) = headers.iter().enumerate().find_map(|(idx, header)| {
+                (idx != uid_idx && !float_idx.contains(&idx)).then_some(header)
+            })
+        {
+            return Err(format!("Unexpected column `{}`, map it in the schema or ignore extra columns", extra));
+        }
+        Ok((float_idx, uid_idx))
+    }
+}
+
+pub fn read_photons_csv<P: AsRef<Path>>(path: P, uid_column: &UidColumn) -> Result<Vec<PhotonRecord>, String> {
+    read_photons_csv_with_schema(path, uid_column, &PhotonSchema::default())
+}
+
+pub fn read_photons_csv_with_schema<P: AsRef<Path>>(
+    path: P,
+    uid_column: &UidColumn,
+    schema: &PhotonSchema,
+) -> Result<Vec<PhotonRecord>, String> {
+    let path = path.as_ref();
+    let mut reader = csv::Reader::from_path(path).map_err(|e| format!("Unable to open {}: {}", path.display(), e))?;
+    let headers = reader.headers().map_err(|e| e.to_string())?.clone();
+    let (float_idx, uid_idx) =
+        schema.resolve(&headers, uid_column).map_err(|e| format!("{} in {}", e, path.display()))?;
+
+    let rows = reader.records().collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+    let format = match uid_column.format {
+        UidFormat::Auto => detect_uid_format(rows.iter().filter_map(|row| row.get(uid_idx)))
+            .map_err(|e| format!("{} in {}", e, path.display()))?,
+        format => format,
+    };
+    rows.iter()
+        .map(|row| {
+            let mut record = PhotonRecord::default();
+            for (field_idx, column_idx) in float_idx.iter().enumerate() {
+                let value = row.get(*column_idx).unwrap_or_default().trim();
+                *float_field(&mut record, field_idx) = value
+                    .parse()
+                    .map_err(|e| format!("Invalid {} `{}`: {}", FLOAT_FIELDS[field_idx], value, e))?;
+            }
+            record.uid = parse_uid(row.get(uid_idx).unwrap_or_default(), format)?;
+            Ok(record)
+        })
+        .collect()
+}
+
+// ----------------------------------------------------
+// Gated selection of photons
+// ----------------------------------------------------
+// Combines conditions on the photon fields with conditions on the chain of each photon, i.e. for
+// gated imaging: "tof < 200ps AND chain MATCHES 'MCRT|Material -> Detection'". Conditions are joined
+// with AND:
+//     <field> (= | != | < | <= | > | >=) <number>[unit]  -- field of PhotonRecord
+//     chain MATCHES '<filter expression>'                  -- pipe syntax of `filter::parse`
+// Times may be given with a unit (s, ms, us, ns, ps), converted with the unit of the tof column set
+// by `with_tof_unit` (seconds by default). Chains are matched as in the queries, from the start
+// event to the photon's last event, and each UID is only looked up once.
+
+// Seconds per unit of time
+pub fn time_unit(unit: &str) -> Option<f64> {
+    match unit {
+        "s" => Some(1.0),
+        "ms" => Some(1e-3),
+        "us" => Some(1e-6),
+        "ns" => Some(1e-9),
+        "ps" => Some(1e-12),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum GateCondition {
+    // Index in the float fields, with the value in seconds if given with a time unit
+    Field { field: usize, op: CmpOp, value: f64, in_seconds: bool },
+    ChainMatches(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhotonGate {
+    pub conditions: Vec<GateCondition>,
+    // Seconds per unit of the tof column
+    pub tof_unit: f64,
+}
+
+impl FromStr for PhotonGate {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Tokens { tokens: tokenize(s)?, pos: 0 };
+        let mut conditions = Vec::new();
+        loop {
+            conditions.push(Self::parse_condition(&mut tokens)?);
+            if !tokens.peek_keyword("AND") {
+                break;
+            }
+            tokens.pos += 1;
+        }
+        if let Some(token) = tokens.tokens.get(tokens.pos) {
+            return Err(format!("Unexpected token in gate: {}", token));
+        }
+        Ok(PhotonGate { conditions, tof_unit: 1.0 })
+    }
+}
+
+impl PhotonGate {
+    pub fn with_tof_unit(mut self, seconds: f64) -> Self {
+        self.tof_unit = seconds;
+        self
+    }
+
+    fn parse_condition(tokens: &mut Tokens) -> Result<GateCondition, String> {
+        let name = tokens.next()?.to_string();
+        if name.eq_ignore_ascii_case("chain") || name.eq_ignore_ascii_case("seq") {
+            tokens.expect("MATCHES")?;
+            let expr = tokens.next()?;
+            return match expr.strip_prefix(['\'', '"']) {
+                Some(expr) => Ok(GateCondition::ChainMatches(expr.to_string())),
+                None => Err(format!("Expected a quoted filter expression, found {}", expr)),
+            };
+        }
+        let field = FLOAT_FIELDS
+            .iter()
+            .position(|field| *field == name)
+            .ok_or_else(|| format!("Unknown photon field `{}`, expected one of {:?} or chain", name, FLOAT_FIELDS))?;
+        let op = CmpOp::parse(tokens.next()?)?;
+        let token = tokens.next()?;
+        let split = token.find(|c: char| c.is_ascii_alphabetic() && c != 'e' && c != 'E').unwrap_or(token.len());
+        let (number, unit) = token.split_at(split);
+        let value = number.parse::<f64>().map_err(|_| format!("Expected a number, found {}", token))?;
+        match unit {
+            "" => Ok(GateCondition::Field { field, op, value, in_seconds: false }),
+            unit if name == "tof" => {
+                let seconds = time_unit(unit).ok_or_else(|| format!("Unknown time unit `{}`", unit))?;
+                Ok(GateCondition::Field { field, op, value: value * seconds, in_seconds: true })
+            }
+            unit => Err(format!("Unexpected unit `{}` on the {} field", unit, name)),
+        }
+    }
+
+    // Resolve the chain conditions against `ledger`
+    pub fn matcher<'l>(&self, ledger: &'l Ledger) -> Result<GateMatcher<'l>, String> {
+        let sequences = self
+            .conditions
+            .iter()
+            .filter_map(|condition| match condition {
+                GateCondition::ChainMatches(expr) => Some(filter::parse_with_ledger(expr, ledger)),
+                _ => None,
+            })
+            .collect::<Result<Vec<FilterExpr>, String>>()?;
+        let fields = self
+            .conditions
+            .iter()
+            .filter_map(|condition| match condition {
+                GateCondition::Field { field, op, value, in_seconds } => {
+                    let scale = if *in_seconds { self.tof_unit } else { 1.0 };
+                    Some((*field, *op, *value, scale))
+                }
+                _ => None,
+            })
+            .collect();
+        Ok(GateMatcher { ledger, fields, sequences, chains: HashMap::new() })
+    }
+
+    // Photons passing every condition, in a single pass over `records`
+    pub fn filter<'a>(&self, ledger: &Ledger, records: &'a [PhotonRecord]) -> Result<Vec<&'a PhotonRecord>, String> {
+        let mut matcher = self.matcher(ledger)?;
+        Ok(records.iter().filter(|record| matcher.matches(record)).collect())
+    }
+}
+
+pub struct GateMatcher<'l> {
+    ledger: &'l Ledger,
+    // (field, op, value, seconds per unit of the field)
+    fields: Vec<(usize, CmpOp, f64, f64)>,
+    // Filter expression of each chain condition
+    sequences: Vec<FilterExpr>,
+    // Outcome of the chain conditions by photon uid
+    chains: HashMap<u64, bool>,
+}
+
+impl GateMatcher<'_> {
+    pub fn matches(&mut self, record: &PhotonRecord) -> bool {
+        let fields_match = self
+            .fields
+            .iter()
+            .all(|(field, op, value, scale)| op.apply(float_value(record, *field) * scale, *value));
+        if !fields_match || self.sequences.is_empty() {
+            return fields_match;
+        }
+        let (ledger, sequences) = (self.ledger, &self.sequences);
+        *self.chains.entry(record.uid).or_insert_with(|| {
+            let uid = Uid::decode(record.uid);
+            ledger.contains(&uid) && {
+                let chain = ledger.get_chain(uid);
+                sequences.iter().all(|expr| expr.matches(&chain))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uid_formats() {
+        let uid = Uid::new(1, 0x03000002).encode();
+        assert_eq!(parse_uid("103000002", UidFormat::Hex), Ok(uid));
+        assert_eq!(parse_uid("0x103000002", UidFormat::Auto), Ok(uid));
+        assert_eq!(parse_uid(&uid.to_string(), UidFormat::Decimal), Ok(uid));
+        assert_eq!(parse_uid("1, 0x03000002", UidFormat::Auto), Ok(uid));
+        assert_eq!(parse_uid("0x00000001_03000002", UidFormat::Auto), Ok(uid));
+        assert!(parse_uid("0xZZ", UidFormat::Hex).is_err());
+
+        assert_eq!(detect_uid_format(["100000003", "1000000ab"]), Ok(UidFormat::Hex));
+        assert_eq!(detect_uid_format(["4294967299", "18446744073709551615"]), Ok(UidFormat::Decimal));
+        // All-digit columns fitting 16 hex digits can be either
+        assert!(detect_uid_format(["4294967299", "100000003"]).is_err());
+        assert!(parse_uid("4294967299", UidFormat::Auto).is_err());
+        assert_eq!(UidFormat::from_str("dec"), Ok(UidFormat::Decimal));
+    }
+
+    #[test]
+    fn read_uid_column() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photons.csv");
+        let uid = Uid::new(7, 0x05000000).encode();
+        std::fs::write(
+            &path,
+            format!(
+                "pos_x,pos_y,pos_z,dir_x,dir_y,dir_z,wavelength,power,weight,tof,photon_uid\n\
+                 0,0,1,0,0,1,633,1,1,0.5,{}\n",
+                uid
+            ),
+        )
+        .unwrap();
+
+        // Short digit strings are ambiguous unless configured
+        let err = read_photons_csv(&path, &UidColumn::default()).unwrap_err();
+        assert!(err.contains("ambiguous"), "{}", err);
+        let uid_column = UidColumn { name: Some("photon_uid".to_string()), format: UidFormat::Decimal };
+        let records = read_photons_csv(&path, &uid_column).expect("Unable to read photons");
+        assert_eq!(records[0].uid, uid);
+        assert_eq!(records[0].wavelength, 633.0);
+
+        let uid_column = UidColumn { name: Some("uid".to_string()), format: UidFormat::Auto };
+        assert!(read_photons_csv(&path, &uid_column).is_err());
+
+        // Decimal uids past 16 digits are detected
+        let uids = [Uid::new(0x12345678, 0x05000000).encode(), Uid::new(3, 0x03a50001).encode()];
+        std::fs::write(
+            &path,
+            format!(
+                "pos_x,pos_y,pos_z,dir_x,dir_y,dir_z,wavelength,power,weight,tof,photon_uid\n\
+                 0,0,1,0,0,1,633,1,1,0.5,{}\n\
+                 0,0,1,0,0,1,633,1,1,0.5,{}\n",
+                uids[0], uids[1]
+            ),
+        )
+        .unwrap();
+        let records = read_photons_csv(&path, &UidColumn::default()).expect("Unable to read photons");
+        assert_eq!(records.iter().map(|record| record.uid).collect::<Vec<_>>(), uids);
+    }
+
+    #[test]
+    fn schema_mapping() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photons.csv");
+        std::fs::write(
+            &path,
+            "photon_id,lambda_nm,x,y,z,u,v,w,power,weight,time,bounces\n\
+             0x100000003,532,1,2,3,0,0,1,0.5,1,2.5,4\n",
+        )
+        .unwrap();
+        let schema: PhotonSchema = r#"
+            [columns]
+            photon_id = "uid"
+            lambda_nm = "wavelength"
+            x = "pos_x"
+            y = "pos_y"
+            z = "pos_z"
+            u = "dir_x"
+            v = "dir_y"
+            w = "dir_z"
+            time = "tof"
+        "#
+        .parse()
+        .unwrap();
+
+        let records = read_photons_csv_with_schema(&path, &UidColumn::default(), &schema).unwrap();
+        assert_eq!(records[0].uid, Uid::new(1, 3).encode());
+        assert_eq!((records[0].wavelength, records[0].pos_y, records[0].tof), (532.0, 2.0, 2.5));
+        assert!(read_photons_csv(&path, &UidColumn::default()).is_err());
+
+        let strict = PhotonSchema { ignore_extra: false, ..schema };
+        let err = read_photons_csv_with_schema(&path, &UidColumn::default(), &strict).unwrap_err();
+        assert!(err.contains("bounces"));
+        assert!("[columns]\nx = \"position\"".parse::<PhotonSchema>().is_err());
+    }
+
+    #[test]
+    fn gated_photons() {
+        use crate::detection::Detection;
+        use crate::emission::Emission;
+        use crate::{EventId, SrcId, mcrt_event};
+
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("tissue".to_string()).unwrap();
+        let start = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+        let ballistic = ledger.insert(start, EventId::new_detection(Detection::Direct, SrcId::Detector(0))).unwrap();
+        let scatter = ledger.insert(start, EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+        let diffuse = ledger.insert(scatter, EventId::new_detection(Detection::Direct, SrcId::Detector(0))).unwrap();
+        // Time of flight in ns
+        let photon = |uid: Uid, tof: f64| PhotonRecord { tof, uid: uid.encode(), ..PhotonRecord::default() };
+        let records = vec![photon(ballistic, 0.1), photon(ballistic, 0.5), photon(diffuse, 0.1), photon(diffuse, 0.9)];
+
+        let gate: PhotonGate = "tof < 200ps AND chain MATCHES 'MCRT -> Detection'".parse().unwrap();
+        let gate = gate.with_tof_unit(1e-9);
+        assert_eq!(gate.filter(&ledger, &records).unwrap(), vec![&records[2]]);
+
+        let gate: PhotonGate = "tof >= 0.5 and seq matches 'Detection'".parse().unwrap();
+        assert_eq!(gate.filter(&ledger, &records).unwrap(), vec![&records[1], &records[3]]);
+
+        assert!("tof < 200ly".parse::<PhotonGate>().is_err());
+        assert!("wavelength > 600ns".parse::<PhotonGate>().is_err());
+        assert!("bounces > 2".parse::<PhotonGate>().is_err());
+        assert!("tof < 2 AND".parse::<PhotonGate>().is_err());
+        let gate: PhotonGate = "chain MATCHES 'MCRT|Material|*|*|*|Mat(oil)'".parse().unwrap();
+        assert!(gate.filter(&ledger, &records).is_err());
+    }
+}
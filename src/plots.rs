@@ -0,0 +1,383 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use plotters::coord::Shift;
+use plotters::prelude::*;
+use plotters::style::{FontStyle, register_font};
+
+use crate::kind::{EventKind, Granularity, scatter_order_histogram};
+use crate::ledger::Uid;
+use crate::taxonomy::legend;
+
+// ----------------------------------------------------
+// Quick-look figures of the ledger analytics
+// ----------------------------------------------------
+// Rendered with plotters to PNG or SVG depending on the output extension, using the taxonomy colors
+// such that the figures match the ones produced by the Python tooling:
+//   Figure::KindHistogram(kind_histogram(&chains, Granularity::SubType)).save("kinds.png")?;
+// Text is rendered with a system TrueType font, found in the usual locations or given by the
+// AETHERUS_PLOT_FONT environment variable. The rendering test needs one as well, hence only runs
+// with `cargo test --features plots -- --ignored`.
+
+const FIGURE_SIZE: (u32, u32) = (1024, 640);
+const FONT_ENV: &str = "AETHERUS_PLOT_FONT";
+const FONT_CANDIDATES: &[&str] = &[
+    "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+    "/usr/share/fonts/TTF/DejaVuSans.ttf",
+    "/usr/share/fonts/dejavu/DejaVuSans.ttf",
+    "/usr/share/fonts/truetype/liberation/LiberationSans-Regular.ttf",
+    "/Library/Fonts/Arial.ttf",
+    "/System/Library/Fonts/Supplemental/Arial.ttf",
+    "C:\\Windows\\Fonts\\arial.ttf",
+];
+
+pub enum Figure {
+    // Number of events of each kind
+    KindHistogram(BTreeMap<EventKind, usize>),
+    // Number of chains by number of scattering events
+    ScatterOrders(BTreeMap<usize, usize>),
+    // Time-of-flight distribution of detected photons
+    TimeOfFlight { tof: Vec<f64>, bins: usize },
+    // Flows between event kinds at each step of the chains
+    Sankey(BTreeMap<(usize, EventKind, EventKind), usize>),
+}
+
+// ----------------------------------------------------
+// Analytics feeding the figures
+// ----------------------------------------------------
+
+pub fn kind_histogram(chains: &[Vec<Uid>], granularity: Granularity) -> BTreeMap<EventKind, usize> {
+    let mut histogram = BTreeMap::new();
+    for uid in chains.iter().flatten() {
+        *histogram.entry(EventKind::from_raw(uid.event, granularity)).or_default() += 1;
+    }
+    histogram
+}
+
+// Scattering order of each chain, i.e. number of elastic and inelastic events, histogrammed
+pub fn scatter_orders(chains: &[Vec<Uid>]) -> BTreeMap<usize, usize> {
+    scatter_order_histogram(chains.iter().map(|chain| (chain.as_slice(), 1)))
+}
+
+// Number of chains going from one kind to the next at each step, up to `max_steps` transitions
+pub fn step_transitions(
+    chains: &[Vec<Uid>],
+    granularity: Granularity,
+    max_steps: usize,
+) -> BTreeMap<(usize, EventKind, EventKind), usize> {
+    let mut transitions = BTreeMap::new();
+    for chain in chains {
+        for (step, pair) in chain.windows(2).take(max_steps).enumerate() {
+            let from = EventKind::from_raw(pair[0].event, granularity);
+            let to = EventKind::from_raw(pair[1].event, granularity);
+            *transitions.entry((step, from, to)).or_default() += 1;
+        }
+    }
+    transitions
+}
+
+// Equal-width histogram of `values` as (bin start, bin end, count)
+pub fn binned(values: &[f64], bins: usize) -> Vec<(f64, f64, usize)> {
+    let finite: Vec<f64> = values.iter().copied().filter(|value| value.is_finite()).collect();
+    if finite.is_empty() || bins == 0 {
+        return Vec::new();
+    }
+    let min = finite.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = finite.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let width = if max > min { (max - min) / bins as f64 } else { 1.0 };
+    let mut counts = vec![0; bins];
+    for value in finite {
+        let bin = (((value - min) / width) as usize).min(bins - 1);
+        counts[bin] += 1;
+    }
+    counts
+        .into_iter()
+        .enumerate()
+        .map(|(bin, count)| (min + bin as f64 * width, min + (bin + 1) as f64 * width, count))
+        .collect()
+}
+
+// ----------------------------------------------------
+// Rendering
+// ----------------------------------------------------
+
+fn ensure_font() -> Result<(), String> {
+    static FONT: OnceLock<Result<(), String>> = OnceLock::new();
+    FONT.get_or_init(|| {
+        let candidates = std::env::var(FONT_ENV).into_iter().chain(FONT_CANDIDATES.iter().map(|path| path.to_string()));
+        for path in candidates {
+            if let Ok(bytes) = std::fs::read(&path) {
+                // Registered fonts must live for the whole program
+                let bytes: &'static [u8] = Box::leak(bytes.into_boxed_slice());
+                return register_font("sans-serif", FontStyle::Normal, bytes)
+                    .map_err(|_| format!("Invalid font file {}", path));
+            }
+        }
+        Err(format!("No TrueType font found for the figure labels, set {} to a .ttf file", FONT_ENV))
+    })
+    .clone()
+}
+
+fn kind_color(kind: &EventKind) -> RGBColor {
+    let name = format!("{:?}", kind);
+    legend()
+        .into_iter()
+        .find(|entry| entry.kind == name)
+        .and_then(|entry| parse_hex(&entry.color))
+        .unwrap_or(RGBColor(128, 128, 128))
+}
+
+fn parse_hex(color: &str) -> Option<RGBColor> {
+    let hex = color.strip_prefix('#')?;
+    let channel = |idx: usize| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok();
+    Some(RGBColor(channel(0)?, channel(2)?, channel(4)?))
+}
+
+impl Figure {
+    // Render to `path`, as SVG for a .svg extension and PNG otherwise
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        ensure_font()?;
+        let path = path.as_ref();
+        let is_svg = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("svg"));
+        if is_svg {
+            let root = SVGBackend::new(path, FIGURE_SIZE).into_drawing_area();
+            self.draw(&root)?;
+            root.present().map_err(|err| err.to_string())
+        } else {
+            let root = BitMapBackend::new(path, FIGURE_SIZE).into_drawing_area();
+            self.draw(&root)?;
+            root.present().map_err(|err| err.to_string())
+        }
+    }
+
+    pub fn draw<DB: DrawingBackend>(&self, root: &DrawingArea<DB, Shift>) -> Result<(), String> {
+        root.fill(&WHITE).map_err(|err| err.to_string())?;
+        match self {
+            Figure::KindHistogram(histogram) => draw_kind_histogram(root, histogram),
+            Figure::ScatterOrders(orders) => draw_scatter_orders(root, orders),
+            Figure::TimeOfFlight { tof, bins } => draw_tof(root, tof, *bins),
+            Figure::Sankey(transitions) => draw_sankey(root, transitions),
+        }
+        .map_err(|err| err.to_string())
+    }
+}
+
+type DrawResult<DB> = Result<(), DrawingAreaErrorKind<<DB as DrawingBackend>::ErrorType>>;
+
+fn draw_kind_histogram<DB: DrawingBackend>(root: &DrawingArea<DB, Shift>, histogram: &BTreeMap<EventKind, usize>) -> DrawResult<DB> {
+    let bars: Vec<(&EventKind, &usize)> = histogram.iter().collect();
+    let max_count = bars.iter().map(|(_, count)| **count).max().unwrap_or(0).max(1);
+    let mut chart = ChartBuilder::on(root)
+        .caption("Events by kind", ("sans-serif", 24))
+        .margin(16)
+        .x_label_area_size(120)
+        .y_label_area_size(60)
+        .build_cartesian_2d((0..bars.len().max(1)).into_segmented(), 0..max_count + max_count / 10 + 1)?;
+    chart
+        .configure_mesh()
+        .disable_x_mesh()
+        .x_labels(bars.len().max(1))
+        .x_label_style(("sans-serif", 12).into_font().transform(FontTransform::Rotate90))
+        .x_label_formatter(&|segment| match segment {
+            SegmentValue::CenterOf(idx) => bars.get(*idx).map(|(kind, _)| kind.label()).unwrap_or_default(),
+            _ => String::new(),
+        })
+        .y_desc("Events")
+        .draw()?;
+    chart.draw_series(bars.iter().enumerate().map(|(idx, (kind, count))| {
+        let mut bar = Rectangle::new(
+            [(SegmentValue::Exact(idx), 0), (SegmentValue::Exact(idx + 1), **count)],
+            kind_color(kind).filled(),
+        );
+        bar.set_margin(0, 0, 4, 4);
+        bar
+    }))?;
+    Ok(())
+}
+
+fn draw_scatter_orders<DB: DrawingBackend>(root: &DrawingArea<DB, Shift>, orders: &BTreeMap<usize, usize>) -> DrawResult<DB> {
+    let max_order = orders.keys().max().copied().unwrap_or(0);
+    let max_count = orders.values().max().copied().unwrap_or(0).max(1);
+    let mut chart = ChartBuilder::on(root)
+        .caption("Scattering orders", ("sans-serif", 24))
+        .margin(16)
+        .x_label_area_size(40)
+        .y_label_area_size(60)
+        .build_cartesian_2d((0..max_order).into_segmented(), 0..max_count + max_count / 10 + 1)?;
+    chart
+        .configure_mesh()
+        .disable_x_mesh()
+        .x_desc("Scattering events per chain")
+        .y_desc("Chains")
+        .draw()?;
+    chart.draw_series(
+        Histogram::vertical(&chart)
+            .style(kind_color(&EventKind::Material).filled())
+            .margin(4)
+            .data(orders.iter().map(|(order, count)| (*order, *count))),
+    )?;
+    Ok(())
+}
+
+fn draw_tof<DB: DrawingBackend>(root: &DrawingArea<DB, Shift>, tof: &[f64], bins: usize) -> DrawResult<DB> {
+    let binned = binned(tof, bins);
+    let (start, end) = match (binned.first(), binned.last()) {
+        (Some(first), Some(last)) => (first.0, last.1),
+        _ => (0.0, 1.0),
+    };
+    let max_count = binned.iter().map(|(_, _, count)| *count).max().unwrap_or(0).max(1);
+    let mut chart = ChartBuilder::on(root)
+        .caption("Time of flight", ("sans-serif", 24))
+        .margin(16)
+        .x_label_area_size(40)
+        .y_label_area_size(60)
+        .build_cartesian_2d(start..end, 0..max_count + max_count / 10 + 1)?;
+    chart.configure_mesh().x_desc("Time of flight").y_desc("Photons").draw()?;
+    chart.draw_series(binned.iter().map(|(bin_start, bin_end, count)| {
+        Rectangle::new([(*bin_start, 0), (*bin_end, *count)], kind_color(&EventKind::Detection).filled())
+    }))?;
+    Ok(())
+}
+
+// Columns are the steps of the chains, with a node per kind sized by the number of chains through
+// it, and bands between the nodes of consecutive steps sized by the transitions.
+fn draw_sankey<DB: DrawingBackend>(
+    root: &DrawingArea<DB, Shift>,
+    transitions: &BTreeMap<(usize, EventKind, EventKind), usize>,
+) -> DrawResult<DB> {
+    let area = root.titled("Event flows", ("sans-serif", 24))?.margin(16, 16, 16, 16);
+    let mut nodes: BTreeMap<(usize, EventKind), (usize, usize)> = BTreeMap::new();
+    for ((step, from, to), count) in transitions {
+        nodes.entry((*step, *from)).or_default().1 += count;
+        nodes.entry((*step + 1, *to)).or_default().0 += count;
+    }
+    let Some(max_step) = nodes.keys().map(|(step, _)| *step).max() else {
+        return Ok(());
+    };
+    let node_size = |&(inflow, outflow): &(usize, usize)| inflow.max(outflow);
+
+    let (width, height) = area.dim_in_pixel();
+    let (width, height) = (width as f64, height as f64);
+    let node_width = 14.0;
+    let gap = 8.0;
+    let column_totals = (0..=max_step).map(|step| {
+        let column = nodes.range((step, EventKind::None)..=(step, EventKind::Custom));
+        (column.clone().map(|(_, flows)| node_size(flows)).sum::<usize>(), column.count())
+    });
+    let scale = column_totals
+        .map(|(total, count)| (height - gap * count.saturating_sub(1) as f64) / total.max(1) as f64)
+        .fold(f64::INFINITY, f64::min);
+    let column_x = |step: usize| (width - node_width - 120.0) * step as f64 / max_step.max(1) as f64;
+
+    // Top of each node, and the running offsets of its incoming and outgoing bands
+    let mut node_top: BTreeMap<(usize, EventKind), f64> = BTreeMap::new();
+    for step in 0..=max_step {
+        let mut y = 0.0;
+        for (key, flows) in nodes.range((step, EventKind::None)..=(step, EventKind::Custom)) {
+            node_top.insert(*key, y);
+            y += node_size(flows) as f64 * scale + gap;
+        }
+    }
+    let mut in_offset: BTreeMap<(usize, EventKind), f64> = BTreeMap::new();
+    let mut out_offset: BTreeMap<(usize, EventKind), f64> = BTreeMap::new();
+
+    for ((step, from, to), count) in transitions {
+        let band = *count as f64 * scale;
+        let out_y = node_top[&(*step, *from)] + *out_offset.entry((*step, *from)).or_default();
+        let in_y = node_top[&(*step + 1, *to)] + *in_offset.entry((*step + 1, *to)).or_default();
+        *out_offset.get_mut(&(*step, *from)).unwrap() += band;
+        *in_offset.get_mut(&(*step + 1, *to)).unwrap() += band;
+
+        let (x0, x1) = (column_x(*step) + node_width, column_x(*step + 1));
+        // Smoothstep curve between the two nodes, along the top edge then back along the bottom edge
+        let curve = |t: f64, y0: f64, y1: f64| {
+            let s = t * t * (3.0 - 2.0 * t);
+            ((x0 + (x1 - x0) * t) as i32, (y0 + (y1 - y0) * s) as i32)
+        };
+        let samples = 24;
+        let mut points: Vec<(i32, i32)> = (0..=samples)
+            .map(|idx| curve(idx as f64 / samples as f64, out_y, in_y))
+            .collect();
+        points.extend((0..=samples).rev().map(|idx| curve(idx as f64 / samples as f64, out_y + band, in_y + band)));
+        area.draw(&Polygon::new(points, kind_color(from).mix(0.35).filled()))?;
+    }
+
+    for ((step, kind), flows) in nodes.iter() {
+        let (x, y) = (column_x(*step), node_top[&(*step, *kind)]);
+        let size = node_size(flows) as f64 * scale;
+        area.draw(&Rectangle::new(
+            [(x as i32, y as i32), ((x + node_width) as i32, (y + size).max(1.0) as i32)],
+            kind_color(kind).filled(),
+        ))?;
+        area.draw(&Text::new(
+            format!("{} ({})", kind.label(), node_size(flows)),
+            ((x + node_width + 4.0) as i32, (y + size / 2.0) as i32 - 6),
+            ("sans-serif", 12),
+        ))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emission::Emission;
+    use crate::ledger::Ledger;
+    use crate::{EventId, SrcId, mcrt_event};
+
+    fn scattering_ledger() -> Ledger {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("tissue".to_string()).unwrap();
+        let uid1 = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+        let uid2 = ledger.insert(uid1, EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+        let uid3 = ledger.insert(uid2, EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Side), mat_id)).unwrap();
+        ledger.insert(uid3, EventId::new_detection(crate::detection::Detection::Direct, SrcId::None)).unwrap();
+        ledger.insert(uid1, EventId::new_mcrt(mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        ledger
+    }
+
+    #[test]
+    fn figure_analytics() {
+        let ledger = scattering_ledger();
+        let chains: Vec<Vec<Uid>> = ledger.chains().collect();
+
+        let histogram = kind_histogram(&chains, Granularity::SubType);
+        assert_eq!(histogram[&EventKind::PencilBeam], 2);
+        assert_eq!(histogram[&EventKind::Mie], 2);
+        assert_eq!(histogram[&EventKind::Absorption], 1);
+
+        let orders = scatter_orders(&chains);
+        assert_eq!(orders, BTreeMap::from([(0, 1), (2, 1)]));
+
+        let transitions = step_transitions(&chains, Granularity::Pipeline, 2);
+        assert_eq!(transitions[&(0, EventKind::Emission, EventKind::MCRT)], 2);
+        assert_eq!(transitions[&(1, EventKind::MCRT, EventKind::MCRT)], 1);
+        assert!(!transitions.keys().any(|(step, _, _)| *step >= 2));
+
+        let bins = binned(&[0.0, 0.5, 1.0, 2.0], 2);
+        assert_eq!(bins, vec![(0.0, 1.0, 2), (1.0, 2.0, 2)]);
+        assert_eq!(parse_hex("#ff8000"), Some(RGBColor(255, 128, 0)));
+    }
+
+    #[test]
+    #[ignore = "needs a system TrueType font, see FONT_CANDIDATES or set AETHERUS_PLOT_FONT"]
+    fn render_figures() {
+        ensure_font().expect("No font to render the figures with");
+        let ledger = scattering_ledger();
+        let chains: Vec<Vec<Uid>> = ledger.chains().collect();
+        let dir = tempfile::tempdir().unwrap();
+        let figures = [
+            ("kinds.svg", Figure::KindHistogram(kind_histogram(&chains, Granularity::SubType))),
+            ("orders.png", Figure::ScatterOrders(scatter_orders(&chains))),
+            ("tof.svg", Figure::TimeOfFlight { tof: vec![1.0, 1.5, 2.5, 4.0], bins: 4 }),
+            ("flows.png", Figure::Sankey(step_transitions(&chains, Granularity::SubType, 8))),
+        ];
+        for (name, figure) in figures {
+            let path = dir.path().join(name);
+            figure.save(&path).expect("Unable to render figure");
+            assert!(std::fs::metadata(&path).unwrap().len() > 0, "Empty figure {}", name);
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use serde::{Deserialize, Serialize};
+
+use crate::raw::RawField;
+use num_enum::{TryFromPrimitive, IntoPrimitive};
+
+// NOTE: Processing events describe what happens to a detected photon downstream of the detector,
+// following the emission layout without a source:
+// | Pipeline (4) | Processing (8) | Unused (16) |
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive, IntoPrimitive, Hash, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum Processing {
+    // Photon passed through a post-detection filtering stage
+    Filtering,
+    // Photon converted to a digital count
+    Digitization,
+    // Optical components between the detector surface and the sensor. The generic Filtering and
+    // Digitization codes are kept for ledgers recorded before these were introduced.
+    // Photon transmitted or blocked by a spectral (bandpass, longpass, dichroic, ...) filter
+    SpectralFilterPass,
+    SpectralFilterReject,
+    // Photon weight attenuated by a neutral-density filter
+    NeutralDensity,
+    // Branch taken at a beamsplitter
+    BeamsplitterTransmitted,
+    BeamsplitterReflected,
+    // Photon lost when coupling into a fiber (NA or core mismatch)
+    FiberCouplingLoss,
+    // Signal sampled by the analog-to-digital converter of the readout
+    AdcDigitization,
+}
+
+impl RawField for Processing {
+    const MASK: u32 = 0x00FF0000;
+    const SHIFT: usize = 16;
+    const BITSIZE: usize = 8;
+}
+
+crate::raw::const_encode!(Processing);
@@ -0,0 +1,199 @@
+use pyo3::IntoPyObjectExt;
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use serde_json::Value;
+
+use crate::filter::{find_forward_uid_expr, parse_with_ledger};
+use crate::ledger;
+use crate::{EventId, RawEvent, TryDecode};
+
+// ----------------------------------------------------
+// Python bindings, behind the `python` feature
+// ----------------------------------------------------
+// Built as the `aetherus_events` extension module with maturin (see pyproject.toml), such that
+// notebooks filter ledgers without re-implementing the bit format:
+//     ledger = aetherus_events.Ledger.from_json("ledger.json")
+//     for uid in ledger.find("MCRT|Material|Elastic|*|*|Mat(water) -> Detection"):
+//         print([aetherus_events.decode_event(step.event) for step in ledger.chain(uid)])
+// Errors of the crate are raised as ValueError, or IOError for unreadable files.
+
+#[pyclass(name = "Uid", module = "aetherus_events", frozen, eq, hash)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct PyUid(pub ledger::Uid);
+
+#[pymethods]
+impl PyUid {
+    #[new]
+    fn new(seq_id: u32, event: u32) -> Self {
+        PyUid(ledger::Uid { seq_id, event })
+    }
+
+    // Uid in its display format, i.e. "3, 0x03A50001"
+    #[staticmethod]
+    fn parse(uid: &str) -> PyResult<Self> {
+        uid.parse().map(PyUid).map_err(PyValueError::new_err)
+    }
+
+    #[getter]
+    fn seq_id(&self) -> u32 {
+        self.0.seq_id
+    }
+
+    #[getter]
+    fn event(&self) -> u32 {
+        self.0.event
+    }
+
+    fn __str__(&self) -> String {
+        self.0.to_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Uid({})", self.0)
+    }
+}
+
+#[pyclass(name = "Ledger", module = "aetherus_events")]
+pub struct PyLedger(pub ledger::Ledger);
+
+#[pymethods]
+impl PyLedger {
+    #[staticmethod]
+    fn from_json(path: &str) -> PyResult<Self> {
+        let json = std::fs::read_to_string(path).map_err(|err| PyIOError::new_err(format!("Unable to read {}: {}", path, err)))?;
+        Self::from_json_str(&json)
+    }
+
+    #[staticmethod]
+    fn from_json_str(json: &str) -> PyResult<Self> {
+        ledger::parse_ledger_json(json).map(PyLedger).map_err(|err| PyValueError::new_err(err.to_string()))
+    }
+
+    // Leaves of the chains matching a filter expression, with the source names of the ledger
+    fn find(&self, filter: &str) -> PyResult<Vec<PyUid>> {
+        find_forward_uid_seq_py(self, filter)
+    }
+
+    fn chain(&self, uid: PyUid) -> PyResult<Vec<PyUid>> {
+        if !self.0.contains(&uid.0) {
+            return Err(PyValueError::new_err(format!("UID {} is not in the ledger", uid.0)));
+        }
+        Ok(self.0.get_chain(uid.0).into_iter().map(PyUid).collect())
+    }
+
+    fn start_events(&self) -> Vec<PyUid> {
+        self.0.get_start_events().iter().copied().map(PyUid).collect()
+    }
+
+    fn leaves(&self) -> Vec<PyUid> {
+        self.0.leaves().into_iter().map(PyUid).collect()
+    }
+
+    // Names registered for the source of an event
+    fn event_names(&self, event: u32) -> PyResult<Vec<String>> {
+        let event_id = decode(event)?;
+        Ok(self.0.event_names(&event_id).iter().map(|name| name.to_string()).collect())
+    }
+}
+
+fn decode(event: u32) -> PyResult<EventId> {
+    EventId::try_decode(event).map_err(|err| PyValueError::new_err(err.to_string()))
+}
+
+fn to_py<'py>(py: Python<'py>, value: &Value) -> PyResult<Bound<'py, PyAny>> {
+    match value {
+        Value::Null => Ok(py.None().into_bound(py)),
+        Value::Bool(flag) => flag.into_bound_py_any(py),
+        Value::Number(number) => match number.as_u64() {
+            Some(number) => number.into_bound_py_any(py),
+            None => number.as_f64().into_bound_py_any(py),
+        },
+        Value::String(text) => text.into_bound_py_any(py),
+        Value::Array(values) => {
+            let list = PyList::empty(py);
+            for value in values {
+                list.append(to_py(py, value)?)?;
+            }
+            Ok(list.into_any())
+        }
+        Value::Object(fields) => {
+            let dict = PyDict::new(py);
+            for (key, value) in fields {
+                dict.set_item(key, to_py(py, value)?)?;
+            }
+            Ok(dict.into_any())
+        }
+    }
+}
+
+// EventId of a raw event as a dict of its serialized fields (event_type, src_id, channel, ...),
+// along with the raw word and its pipeline, "Custom" for the pipelines defined by the engine
+#[pyfunction]
+fn decode_event<'py>(py: Python<'py>, event: u32) -> PyResult<Bound<'py, PyAny>> {
+    let event_id = decode(event)?;
+    let value = serde_json::to_value(&event_id).map_err(|err| PyValueError::new_err(err.to_string()))?;
+    let dict = to_py(py, &value)?;
+    dict.set_item("raw", event)?;
+    let pipeline = event.try_pipeline().map(|pipeline| format!("{:?}", pipeline)).unwrap_or_else(|_| "Custom".to_string());
+    dict.set_item("pipeline", pipeline)?;
+    Ok(dict)
+}
+
+#[pyfunction]
+#[pyo3(name = "find_forward_uid_seq")]
+fn find_forward_uid_seq_py(ledger: &PyLedger, filter: &str) -> PyResult<Vec<PyUid>> {
+    let expr = parse_with_ledger(filter, &ledger.0).map_err(PyValueError::new_err)?;
+    Ok(find_forward_uid_expr(&ledger.0, &expr).into_iter().map(PyUid).collect())
+}
+
+#[pymodule]
+pub fn aetherus_events(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyUid>()?;
+    m.add_class::<PyLedger>()?;
+    m.add_function(wrap_pyfunction!(decode_event, m)?)?;
+    m.add_function(wrap_pyfunction!(find_forward_uid_seq_py, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SrcId;
+    use crate::ledger::tests::detected_chain;
+
+    #[test]
+    fn python_module() {
+        let (ledger, [_, scatter, detected]) = detected_chain();
+        let mat_id = SrcId::Mat(0);
+        let json = serde_json::to_string(&ledger).unwrap();
+
+        Python::initialize();
+        Python::attach(|py| {
+            let module = PyModule::new(py, "aetherus_events").unwrap();
+            aetherus_events(&module).unwrap();
+            let locals = PyDict::new(py);
+            locals.set_item("aev", &module).unwrap();
+            locals.set_item("json", json).unwrap();
+            let run = |code: &str| py.eval(&std::ffi::CString::new(code).unwrap(), None, Some(&locals)).unwrap();
+
+            let ledger = run("aev.Ledger.from_json_str(json)");
+            locals.set_item("ledger", ledger).unwrap();
+            let found: Vec<PyUid> = run("ledger.find('MCRT|Material|Elastic|*|*|Mat(water) -> Detection')").extract().unwrap();
+            assert_eq!(found, vec![PyUid(detected)]);
+            let found: Vec<PyUid> = run("aev.find_forward_uid_seq(ledger, 'Detection')").extract().unwrap();
+            assert_eq!(found, vec![PyUid(detected)]);
+            assert_eq!(run("[str(uid) for uid in ledger.chain(aev.Uid.parse(str(ledger.leaves()[0])))]").extract::<Vec<String>>().unwrap().len(), 3);
+
+            locals.set_item("raw", scatter.event).unwrap();
+            assert_eq!(run("aev.decode_event(raw)['event_type']['MCRT']['Material']['Elastic']['Mie']").extract::<String>().unwrap(), "Forward");
+            let src_id: std::collections::HashMap<String, u16> = run("aev.decode_event(raw)['src_id']").extract().unwrap();
+            assert_eq!(src_id.values().next(), mat_id.id().as_ref());
+            assert_eq!(run("aev.decode_event(raw)['pipeline']").extract::<String>().unwrap(), "MCRT");
+            assert_eq!(run("ledger.event_names(raw)").extract::<Vec<String>>().unwrap(), vec!["water"]);
+            assert_eq!(run("aev.decode_event(0x0F000000)['pipeline']").extract::<String>().unwrap(), "Custom");
+            assert!(py.eval(c"aev.decode_event(0x03400000)", None, Some(&locals)).is_err());
+            assert!(py.eval(c"aev.Uid.parse('3')", None, Some(&locals)).is_err());
+        });
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::BTreeMap;
+
+use crate::Decode;
+use crate::EventId;
+use crate::filter::{self, FilterExpr};
+use crate::kind::{EventKind, Granularity};
+use crate::ledger::{Ledger, Uid};
+
+// ----------------------------------------------------
+// Mini query language over ledgers
+// ----------------------------------------------------
+// SELECT (chains | count)
+//     [WHERE <condition> [AND <condition>]...]
+//     [GROUP BY (src | root | kind | length)]
+//     [LIMIT <n>]
+// with the conditions:
+//     seq MATCHES '<filter expression>'  -- pipe syntax of `filter::parse`, names resolved in the ledger
+//     length (= | != | < | <= | > | >=) <n>  -- number of events in the chain
+// i.e. "SELECT chains WHERE seq MATCHES 'MCRT|Material|Elastic -> Detection' AND length > 5 GROUP BY src"
+// Chains run from a start event to a leaf. Grouping uses the source (`src`) or the kind (`kind`) of
+// the last event, the source of the start event (`root`), or the chain length.
+// LIMIT applies to the rows of the result, after the grouping: the first n groups with all of their
+// chains, the first n chains without GROUP BY, and nothing to an ungrouped count.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Select {
+    Chains,
+    Count,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GroupBy {
+    Src,
+    Root,
+    Kind,
+    Length,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CmpOp {
+    pub(crate) fn parse(token: &str) -> Result<Self, String> {
+        match token {
+            "=" | "==" => Ok(CmpOp::Eq),
+            "!=" => Ok(CmpOp::Ne),
+            "<" => Ok(CmpOp::Lt),
+            "<=" => Ok(CmpOp::Le),
+            ">" => Ok(CmpOp::Gt),
+            ">=" => Ok(CmpOp::Ge),
+            other => Err(format!("Unknown comparison: {}", other)),
+        }
+    }
+
+    pub(crate) fn apply<T: PartialOrd>(&self, lhs: T, rhs: T) -> bool {
+        match self {
+            CmpOp::Eq => lhs == rhs,
+            CmpOp::Ne => lhs != rhs,
+            CmpOp::Lt => lhs < rhs,
+            CmpOp::Le => lhs <= rhs,
+            CmpOp::Gt => lhs > rhs,
+            CmpOp::Ge => lhs >= rhs,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    SeqMatches(String),
+    Length(CmpOp, usize),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    pub select: Select,
+    pub conditions: Vec<Condition>,
+    pub group_by: Option<GroupBy>,
+    pub limit: Option<usize>,
+}
+
+// Matched chains per group, with a single "" group if the query has no GROUP BY
+#[derive(Debug, Default, PartialEq)]
+pub struct QueryResult {
+    pub select: Option<Select>,
+    pub groups: BTreeMap<String, Vec<Vec<Uid>>>,
+}
+
+impl QueryResult {
+    pub fn total(&self) -> usize {
+        self.groups.values().map(|chains| chains.len()).sum()
+    }
+}
+
+pub fn query(ledger: &Ledger, text: &str) -> Result<QueryResult, String> {
+    Query::parse(text)?.execute(ledger)
+}
+
+// Split on whitespace, keeping quoted strings and comparison operators as single tokens
+pub(crate) fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '\'' | '"' => {
+                let mut quoted = String::from(c);
+                loop {
+                    match chars.next() {
+                        Some(next) if next == c => break,
+                        Some(next) => quoted.push(next),
+                        None => return Err(format!("Unterminated string in query: {}", quoted)),
+                    }
+                }
+                tokens.push(quoted);
+            }
+            '<' | '>' | '!' | '=' => {
+                let mut op = String::from(c);
+                if chars.peek() == Some(&'=') {
+                    op.push(chars.next().unwrap());
+                }
+                tokens.push(op);
+            }
+            _ => {
+                let mut word = String::from(c);
+                while let Some(next) = chars.peek() {
+                    if next.is_whitespace() || "<>!='\"".contains(*next) {
+                        break;
+                    }
+                    word.push(chars.next().unwrap());
+                }
+                tokens.push(word);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+pub(crate) struct Tokens {
+    pub(crate) tokens: Vec<String>,
+    pub(crate) pos: usize,
+}
+
+impl Tokens {
+    pub(crate) fn peek_keyword(&self, keyword: &str) -> bool {
+        self.tokens.get(self.pos).is_some_and(|token| token.eq_ignore_ascii_case(keyword))
+    }
+
+    pub(crate) fn next(&mut self) -> Result<&str, String> {
+        let token = self.tokens.get(self.pos).ok_or("Unexpected end of query")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    pub(crate) fn expect(&mut self, keyword: &str) -> Result<(), String> {
+        let token = self.next()?;
+        if token.eq_ignore_ascii_case(keyword) {
+            Ok(())
+        } else {
+            Err(format!("Expected {}, found {}", keyword, token))
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, String> {
+        let token = self.next()?;
+        token.parse().map_err(|_| format!("Expected a number, found {}", token))
+    }
+}
+
+impl Query {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut tokens = Tokens { tokens: tokenize(text)?, pos: 0 };
+        tokens.expect("SELECT")?;
+        let select = match tokens.next()?.to_ascii_lowercase().as_str() {
+            "chains" => Select::Chains,
+            "count" => Select::Count,
+            other => return Err(format!("Unknown selection: {}", other)),
+        };
+
+        let mut conditions = Vec::new();
+        if tokens.peek_keyword("WHERE") {
+            tokens.pos += 1;
+            loop {
+                conditions.push(Self::parse_condition(&mut tokens)?);
+                if !tokens.peek_keyword("AND") {
+                    break;
+                }
+                tokens.pos += 1;
+            }
+        }
+
+        let mut group_by = None;
+        if tokens.peek_keyword("GROUP") {
+            tokens.pos += 1;
+            tokens.expect("BY")?;
+            group_by = Some(match tokens.next()?.to_ascii_lowercase().as_str() {
+                "src" => GroupBy::Src,
+                "root" => GroupBy::Root,
+                "kind" => GroupBy::Kind,
+                "length" => GroupBy::Length,
+                other => return Err(format!("Unknown grouping: {}", other)),
+            });
+        }
+
+        let mut limit = None;
+        if tokens.peek_keyword("LIMIT") {
+            tokens.pos += 1;
+            limit = Some(tokens.number()?);
+        }
+
+        if let Some(token) = tokens.tokens.get(tokens.pos) {
+            return Err(format!("Unexpected token in query: {}", token));
+        }
+        Ok(Query { select, conditions, group_by, limit })
+    }
+
+    fn parse_condition(tokens: &mut Tokens) -> Result<Condition, String> {
+        match tokens.next()?.to_ascii_lowercase().as_str() {
+            "seq" => {
+                tokens.expect("MATCHES")?;
+                let expr = tokens.next()?;
+                match expr.strip_prefix(['\'', '"']) {
+                    Some(expr) => Ok(Condition::SeqMatches(expr.to_string())),
+                    None => Err(format!("Expected a quoted filter expression, found {}", expr)),
+                }
+            }
+            "length" => {
+                let op = CmpOp::parse(tokens.next()?)?;
+                Ok(Condition::Length(op, tokens.number()?))
+            }
+            other => Err(format!("Unknown condition on {}", other)),
+        }
+    }
+
+    pub fn execute(&self, ledger: &Ledger) -> Result<QueryResult, String> {
+        let sequences = self
+            .conditions
+            .iter()
+            .filter_map(|condition| match condition {
+                Condition::SeqMatches(expr) => Some(filter::parse_with_ledger(expr, ledger)),
+                _ => None,
+            })
+            .collect::<Result<Vec<FilterExpr>, String>>()?;
+
+        let mut result = QueryResult {
+            select: Some(self.select),
+            groups: BTreeMap::new(),
+        };
+        let mut chains: Vec<Vec<Uid>> = ledger
+            .chains()
+            .filter(|chain| {
+                sequences.iter().all(|expr| expr.matches(chain))
+            })
+            .filter(|chain| {
+                self.conditions.iter().all(|condition| match condition {
+                    Condition::Length(op, n) => op.apply(chain.len(), *n),
+                    _ => true,
+                })
+            })
+            .collect();
+        // Stable output, independent of the ledger maps
+        chains.sort();
+        for chain in chains {
+            let key = match self.group_by {
+                None => String::new(),
+                Some(GroupBy::Src) => src_key(ledger, chain.last().unwrap()),
+                Some(GroupBy::Root) => src_key(ledger, chain.first().unwrap()),
+                Some(GroupBy::Kind) => EventKind::from_raw(chain.last().unwrap().event, Granularity::SubType).to_string(),
+                Some(GroupBy::Length) => format!("{:>4}", chain.len()),
+            };
+            result.groups.entry(key).or_default().push(chain);
+        }
+        match (self.limit, self.group_by, self.select) {
+            (Some(limit), Some(_), _) => result.groups = std::mem::take(&mut result.groups).into_iter().take(limit).collect(),
+            (Some(limit), None, Select::Chains) => result.groups.values_mut().for_each(|chains| chains.truncate(limit)),
+            _ => {}
+        }
+        Ok(result)
+    }
+}
+
+// Registered source of the event, such that merged sources share a group
+fn src_key(ledger: &Ledger, uid: &Uid) -> String {
+    let event_id = EventId::decode(uid.event);
+    let src_id = ledger.event_src(&event_id);
+    let names: Vec<String> = ledger.names(&src_id).iter().map(|name| name.to_string()).collect();
+    if names.is_empty() {
+        src_id.to_string()
+    } else {
+        format!("{} [{}]", src_id, names.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emission::Emission;
+    use crate::detection::Detection;
+    use crate::{SrcId, mcrt_event};
+    use crate::error::LedgerError;
+    use crate::ledger::tests::detected_chain;
+
+    #[test]
+    fn parse_query() {
+        let query = Query::parse("select chains where seq matches 'MCRT|Material -> Detection' and length >= 3 group by src limit 10")
+            .expect("Unable to parse query");
+        assert_eq!(query.select, Select::Chains);
+        assert_eq!(
+            query.conditions,
+            vec![
+                Condition::SeqMatches("MCRT|Material -> Detection".to_string()),
+                Condition::Length(CmpOp::Ge, 3),
+            ]
+        );
+        assert_eq!(query.group_by, Some(GroupBy::Src));
+        assert_eq!(query.limit, Some(10));
+
+        assert!(Query::parse("SELECT rows").is_err());
+        assert!(Query::parse("SELECT chains WHERE seq MATCHES MCRT").is_err());
+        assert!(Query::parse("SELECT chains WHERE length ~ 3").is_err());
+        assert!(Query::parse("SELECT chains LIMIT 3 extra").is_err());
+    }
+
+    #[test]
+    fn execute_query() {
+        let (mut ledger, [uid1, uid2, uid3]) = detected_chain();
+        let uid4 = ledger.insert(uid2, EventId::new_mcrt(mcrt_event!(Material, Absorption), SrcId::Mat(0))).unwrap();
+        let uid5 = ledger.insert(uid1, EventId::new_detection(Detection::Direct, SrcId::Detector(1))).unwrap();
+
+        let result = query(&ledger, "SELECT chains WHERE seq MATCHES 'MCRT|Material|*|*|*|Mat(water) -> Detection'")
+            .expect("Unable to run query");
+        assert_eq!(result.groups[""], vec![vec![uid1, uid2, uid3]]);
+
+        let result = query(&ledger, "SELECT count WHERE length > 2 GROUP BY kind").expect("Unable to run query");
+        assert_eq!(result.total(), 2);
+        assert_eq!(result.groups["Absorption"], vec![vec![uid1, uid2, uid4]]);
+        assert_eq!(result.groups["Direct"], vec![vec![uid1, uid2, uid3]]);
+
+        let result = query(&ledger, "SELECT chains WHERE seq MATCHES 'Detection' GROUP BY src").expect("Unable to run query");
+        assert_eq!(result.groups["Detector(1)"], vec![vec![uid1, uid5]]);
+        assert_eq!(result.groups.len(), 2);
+        assert!(query(&ledger, "SELECT chains WHERE seq MATCHES 'MCRT|Material|*|*|*|Mat(oil)'").is_err());
+
+        // LIMIT applies to the groups, counting all of their chains
+        let result = query(&ledger, "SELECT count GROUP BY root LIMIT 1").expect("Unable to run query");
+        assert_eq!(result.groups.len(), 1);
+        assert_eq!(result.total(), 3);
+        let result = query(&ledger, "SELECT count GROUP BY kind LIMIT 1").expect("Unable to run query");
+        assert_eq!(result.groups.keys().collect::<Vec<_>>(), vec!["Absorption"]);
+        assert_eq!(query(&ledger, "SELECT chains LIMIT 2").unwrap().total(), 2);
+        assert_eq!(query(&ledger, "SELECT count LIMIT 2").unwrap().total(), 3);
+    }
+
+    #[test]
+    fn merged_sources() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let water_id = ledger.with_mat("water".to_string()).unwrap();
+        let saline_id = ledger.with_mat("saline".to_string()).unwrap();
+        // Chains ending in the scattering event, such that they're grouped by material
+        let mut leaves = Vec::new();
+        for mat_id in [water_id, saline_id] {
+            let uid = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+            leaves.push(ledger.insert(uid, EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap());
+        }
+        let by_src = "SELECT count WHERE seq MATCHES 'MCRT|Material|*|*|*|Mat(water)' GROUP BY src";
+        assert_eq!(query(&ledger, by_src).unwrap().total(), 1);
+
+        ledger.alias_src_by_name("water", "saline").expect("Unable to merge the sources");
+        assert_eq!(ledger.canonical_src(&saline_id), water_id);
+        assert_eq!(ledger.src_id_by_name("saline"), Some(water_id));
+        assert_eq!(ledger.names(&saline_id).len(), 2);
+        assert!(matches!(ledger.alias_src(saline_id, water_id), Err(LedgerError::SrcAlreadyMerged { .. })));
+
+        let result = query(&ledger, by_src).unwrap();
+        assert_eq!(result.total(), 2);
+        assert_eq!(result.groups.keys().collect::<Vec<_>>(), vec!["Mat(0) [water, saline]"]);
+        let expr = filter::parse_with_ledger("MCRT|Material|*|*|*|Mat(1)", &ledger).unwrap();
+        let mut found = filter::find_forward_uid_expr(&ledger, &expr);
+        found.sort();
+        assert_eq!(found, leaves);
+
+        let json = serde_json::to_string(&ledger).unwrap();
+        let stored_ledger: Ledger = serde_json::from_str(&json).unwrap();
+        assert_eq!(stored_ledger.merged_srcs(&saline_id), vec![water_id, saline_id]);
+    }
+}
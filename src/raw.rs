@@ -1,20 +1,29 @@
 use num_enum::{TryFromPrimitive, IntoPrimitive};
-use std::convert::TryFrom;
-use std::usize;
 
+use crate::TryDecode;
+use crate::error::DecodeError;
+
+// NOTE: The layout of the fields are associated consts, such that the field enums can encode in
+// const contexts, see `const_encode`. This module and `mcrt` don't allocate and build under no_std.
 pub trait RawField: Clone {
-    fn mask() -> u32;
-    fn shift() -> usize;
-    fn bitsize() -> usize;
+    const MASK: u32;
+    const SHIFT: usize;
+    const BITSIZE: usize;
+    fn mask() -> u32 {
+        Self::MASK
+    }
+    fn shift() -> usize {
+        Self::SHIFT
+    }
+    fn bitsize() -> usize {
+        Self::BITSIZE
+    }
+    // Panics on codes without a variant, see `TryDecode`
     fn decode(raw: u32) -> Self
     where
         Self: TryFrom<u8>,
-        <Self as TryFrom<u8>>::Error: std::fmt::Debug,
     {
-        let value = ((raw & Self::mask()) >> Self::shift()) as u8;
-        Self::try_from(value).unwrap_or_else( |err| {
-            panic!("Failed to convert value: {:?}, error: {:?}", value, err);
-        })
+        decode_field(raw).unwrap_or_else(|err| panic!("{}", err))
     }
     fn encode(&self) -> u32
     where
@@ -26,6 +35,54 @@ pub trait RawField: Clone {
     }
 }
 
+// Field of a raw event, the error naming the field when its code has no variant
+pub fn decode_field<T: RawField + TryFrom<u8>>(raw: u32) -> Result<T, DecodeError> {
+    let code = ((raw & T::mask()) >> T::shift()) as u8;
+    T::try_from(code).map_err(|_| DecodeError::UnknownCode {
+        field: core::any::type_name::<T>().rsplit("::").next().unwrap_or_default(),
+        code,
+        raw,
+    })
+}
+
+macro_rules! try_decode_fields {
+    ($($field:ty),* $(,)?) => {
+        $(
+            impl TryDecode<u32> for $field {
+                fn try_decode(raw: u32) -> Result<Self, DecodeError> {
+                    decode_field(raw)
+                }
+            }
+        )*
+    };
+}
+
+try_decode_fields!(Pipeline, MCRT, Interface, Reflector, Material, Inelastic, Elastic, ScatterDir, Roulette);
+#[cfg(feature = "std")]
+try_decode_fields!(
+    crate::emission::Emission, crate::emission::TemporalMode, crate::detection::Detection,
+    crate::processing::Processing, crate::transport::Transport,
+);
+
+// Const version of `RawField::encode` for the field enums, i.e.
+//     const MIE: u32 = raw::Elastic::Mie.encode();
+// The inherent method takes precedence over the trait one in method calls.
+macro_rules! const_encode {
+    ($($field:ty),* $(,)?) => {
+        $(
+            impl $field {
+                pub const fn encode(self) -> u32 {
+                    (self as u32) << <$field as $crate::raw::RawField>::SHIFT
+                }
+            }
+        )*
+    };
+}
+#[cfg(feature = "std")]
+pub(crate) use const_encode;
+
+const_encode!(Pipeline, MCRT, Interface, Reflector, Material, Inelastic, Elastic, ScatterDir, Roulette);
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
 #[repr(u8)]
 pub enum Pipeline {
@@ -33,13 +90,15 @@ pub enum Pipeline {
     MCRT       = 3,
     Detection  = 5,
     Processing = 7,
+    Transport  = 11,
+    Voxel      = 13,
     // Other codes are free to be used for custom pipeline stages
 }
 
 impl RawField for Pipeline {
-    fn mask() -> u32 { 0x0F000000 }
-    fn shift() -> usize { 24 }
-    fn bitsize() -> usize { 4 }
+    const MASK: u32 = 0x0F000000;
+    const SHIFT: usize = 24;
+    const BITSIZE: usize = 4;
 }
 
 // SuperType represents the 2-bit super type category
@@ -49,13 +108,14 @@ pub enum MCRT {
     Interface = 0,
     Reflector = 1,
     Material  = 2,
-    //Custom    = 3,
+    // House-specific interactions of an engine, identified by the code in the subtype bits
+    Custom    = 3,
 }
 
 impl RawField for MCRT {
-    fn mask() -> u32 { 0x00C00000 }
-    fn shift() -> usize { 22 }
-    fn bitsize() -> usize { 2 }
+    const MASK: u32 = 0x00C00000;
+    const SHIFT: usize = 22;
+    const BITSIZE: usize = 2;
 }
 
 // SubType for Interface events (6 bits, but simplified enum)
@@ -64,14 +124,16 @@ impl RawField for MCRT {
 pub enum Interface {
     Reflection = 0,
     Refraction = 1,
+    // Photon split into a reflected and a transmitted branch, see `Ledger::insert_fresnel_split`
+    FresnelSplit = 2,
     ReEmittance = 4,
     // Custom 32-63
 }
 
 impl RawField for Interface {
-    fn mask() -> u32 { 0x003F0000 }
-    fn shift() -> usize { 16 }
-    fn bitsize() -> usize { 6 }
+    const MASK: u32 = 0x003F0000;
+    const SHIFT: usize = 16;
+    const BITSIZE: usize = 6;
 }
 
 // SubType for Reflector events
@@ -90,9 +152,9 @@ pub enum Reflector {
 }
 
 impl RawField for Reflector {
-    fn mask() -> u32 { 0x003F0000 }
-    fn shift() -> usize { 16 }
-    fn bitsize() -> usize { 6 }
+    const MASK: u32 = 0x003F0000;
+    const SHIFT: usize = 16;
+    const BITSIZE: usize = 6;
 }
 
 // MaterialInteraction encodes the interaction type (2 bits)
@@ -102,12 +164,15 @@ pub enum Material {
     Absorption = 0b00,
     Inelastic  = 0b01,
     Elastic    = 0b10,
+    // Russian roulette played on the photon weight, not a physical interaction but recorded
+    // alongside the material event that triggered it
+    Roulette   = 0b11,
 }
 
 impl RawField for Material {
-    fn mask() -> u32 { 0x00300000 }
-    fn shift() -> usize { 20 }
-    fn bitsize() -> usize { 2 }
+    const MASK: u32 = 0x00300000;
+    const SHIFT: usize = 20;
+    const BITSIZE: usize = 2;
 }
 
 // ScatterType for scattering events (2 bits)
@@ -119,9 +184,9 @@ pub enum Inelastic {
 }
 
 impl RawField for Inelastic {
-    fn mask() -> u32 { 0x000C0000 }
-    fn shift() -> usize { 18 }
-    fn bitsize() -> usize { 2 }
+    const MASK: u32 = 0x000C0000;
+    const SHIFT: usize = 18;
+    const BITSIZE: usize = 2;
 }
 
 // ScatterType for scattering events (2 bits)
@@ -135,9 +200,9 @@ pub enum Elastic {
 }
 
 impl RawField for Elastic {
-    fn mask() -> u32 { 0x000C0000 }
-    fn shift() -> usize { 18 }
-    fn bitsize() -> usize { 2 }
+    const MASK: u32 = 0x000C0000;
+    const SHIFT: usize = 18;
+    const BITSIZE: usize = 2;
 }
 
 // Direction for scattering (2 bits)
@@ -151,11 +216,35 @@ pub enum ScatterDir {
 }
 
 impl RawField for ScatterDir {
-    fn mask() -> u32 { 0x00030000 }
-    fn shift() -> usize { 16 }
-    fn bitsize() -> usize { 2 }
+    const MASK: u32 = 0x00030000;
+    const SHIFT: usize = 16;
+    const BITSIZE: usize = 2;
+}
+
+// Outcome of a roulette (1 bit), survivors record the class of their weight boost in the
+// remaining 3 bits
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+pub enum Roulette {
+    Killed   = 0b0,
+    Survived = 0b1,
+}
+
+impl RawField for Roulette {
+    const MASK: u32 = 0x00080000;
+    const SHIFT: usize = 19;
+    const BITSIZE: usize = 1;
 }
 
+pub const BOOST_CLASS_MASK: u32 = 0x00070000;
+pub const BOOST_CLASS_SHIFT: usize = 16;
+pub const MAX_BOOST_CLASS: u8 = 7;
+
+// Code of a custom MCRT interaction (6 bits)
+pub const MCRT_CUSTOM_MASK: u32 = 0x003F0000;
+pub const MCRT_CUSTOM_SHIFT: usize = 16;
+pub const MAX_MCRT_CUSTOM: u16 = 0x3F;
+
 
 
 #[cfg(test)]
@@ -181,7 +270,7 @@ mod tests {
     #[test]
     fn elastic_encoding() {
         let dec_list = vec![Elastic::HenyeyGreenstein, Elastic::Mie, Elastic::Rayleigh, Elastic::SphericalCdf];
-        let enc_list = vec![0x00000000, 0x00040000, 0x00080000, 0x000C0000];
+        let enc_list = [0x00000000, 0x00040000, 0x00080000, 0x000C0000];
         for (enc, dec) in enc_list.iter().zip(dec_list) {
             assert_eq!(*enc, dec.encode());
             assert_eq!(Elastic::decode(*enc), dec);
@@ -191,7 +280,7 @@ mod tests {
     #[test]
     fn inelastic_encoding() {
         let dec_list = vec![Inelastic::Raman, Inelastic::Fluorescence];
-        let enc_list = vec![0x00000000, 0x00040000];
+        let enc_list = [0x00000000, 0x00040000];
         for (enc, dec) in enc_list.iter().zip(dec_list) {
             assert_eq!(*enc, dec.encode());
             assert_eq!(Inelastic::decode(*enc), dec);
@@ -201,7 +290,7 @@ mod tests {
     #[test]
     fn scatter_dir_encoding() {
         let dec_list = vec![ScatterDir::Any, ScatterDir::Forward, ScatterDir::Side, ScatterDir::Backward];
-        let enc_list = vec![0x00000000, 0x00010000, 0x00020000, 0x00030000];
+        let enc_list = [0x00000000, 0x00010000, 0x00020000, 0x00030000];
         for (enc, dec) in enc_list.iter().zip(dec_list) {
             assert_eq!(*enc, dec.encode());
             assert_eq!(ScatterDir::decode(*enc), dec);
@@ -211,7 +300,7 @@ mod tests {
     #[test]
     fn material_encoding() {
         let dec_list = vec![Material::Absorption, Material::Inelastic, Material::Elastic];
-        let enc_list = vec![0x00000000, 0x00100000, 0x00200000];
+        let enc_list = [0x00000000, 0x00100000, 0x00200000];
         for (enc, dec) in enc_list.iter().zip(dec_list) {
             assert_eq!(*enc, dec.encode());
             assert_eq!(Material::decode(*enc), dec);
@@ -220,8 +309,8 @@ mod tests {
 
     #[test]
     fn mcrt_encoding() {
-        let dec_list = vec![MCRT::Interface, MCRT::Reflector, MCRT::Material];
-        let enc_list = vec![0x00000000, 0x00400000, 0x00800000];
+        let dec_list = vec![MCRT::Interface, MCRT::Reflector, MCRT::Material, MCRT::Custom];
+        let enc_list = [0x00000000, 0x00400000, 0x00800000, 0x00C00000];
         for (enc, dec) in enc_list.iter().zip(dec_list) {
             assert_eq!(*enc, dec.encode());
             assert_eq!(MCRT::decode(*enc), dec);
@@ -231,7 +320,7 @@ mod tests {
     #[test]
     fn interface_encoding() {
         let dec_list = vec![Interface::Reflection, Interface::Refraction, Interface::ReEmittance];
-        let enc_list = vec![0x00000000, 0x00010000, 0x00040000];
+        let enc_list = [0x00000000, 0x00010000, 0x00040000];
         for (enc, dec) in enc_list.iter().zip(dec_list) {
             assert_eq!(*enc, dec.encode());
             assert_eq!(Interface::decode(*enc), dec);
@@ -241,7 +330,7 @@ mod tests {
     #[test]
     fn reflector_encoding() {
         let dec_list = vec![Reflector::Diffuse, Reflector::Specular, Reflector::Composite, Reflector::RetroReflective, Reflector::CompRetroRef];
-        let enc_list = vec![0x00020000, 0x00040000, 0x00060000, 0x00080000, 0x00090000];
+        let enc_list = [0x00020000, 0x00040000, 0x00060000, 0x00080000, 0x00090000];
         for (enc, dec) in enc_list.iter().zip(dec_list) {
             assert_eq!(*enc, dec.encode());
             assert_eq!(Reflector::decode(*enc), dec);
@@ -250,8 +339,8 @@ mod tests {
 
     #[test]
     fn pipeline_encoding() {
-        let dec_list = vec![Pipeline::Emission, Pipeline::MCRT, Pipeline::Detection, Pipeline::Processing];
-        let enc_list = vec![0x01000000, 0x03000000, 0x05000000, 0x07000000];
+        let dec_list = vec![Pipeline::Emission, Pipeline::MCRT, Pipeline::Detection, Pipeline::Processing, Pipeline::Transport, Pipeline::Voxel];
+        let enc_list = [0x01000000, 0x03000000, 0x05000000, 0x07000000, 0x0B000000, 0x0D000000];
         for (enc, dec) in enc_list.iter().zip(dec_list) {
             assert_eq!(*enc, dec.encode());
             assert_eq!(Pipeline::decode(*enc), dec);
@@ -0,0 +1,210 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::DecodeError;
+use crate::filter::BitsMatch;
+use crate::raw::{Pipeline, RawField};
+use crate::{Encode, EventId, RawEvent, SrcId, TryDecode};
+
+// ----------------------------------------------------
+// Extended 64-bit event format
+// ----------------------------------------------------
+// Opt-in layout for runs outgrowing the u32 one, i.e. with more than 65536 sources or with scatter
+// metadata that doesn't fit the subtype bits, and the only wide event of the crate. The low word
+// holds the u32 layout, such that filters and event kinds apply unchanged, and the high word
+// extends it:
+// | Version (4) | Reserved (4) | SrcIdHi (8) | Extended subtype (16) | u32 layout (32) |
+// Version 0 is the legacy layout with an unused high word. Events fitting the u32 layout keep it,
+// such that they compare equal to the events of old ledgers read as `Ledger<RawEvent64>`, while
+// events using the extension fields are tagged with version 1.
+// The sources past the 16 bits of SrcId are registered with `Ledger::with_wide_src`. An EventId
+// can't hold them, such that `try_decode` fails on them and `decode_wide` returns the full source
+// id next to the event. Filters pinning the SrcId also match SrcIdHi, 0 unless set by
+// `wide_src_bits_match`, such that sources 0x012345 and 0x002345 don't alias.
+
+pub const VERSION_MASK: u64 = 0xF000_0000_0000_0000;
+pub const VERSION_SHIFT: usize = 60;
+pub const SRC_ID_HI_MASK: u64 = 0x00FF_0000_0000_0000;
+pub const SRC_ID_HI_SHIFT: usize = 48;
+pub const SUBTYPE_EXT_MASK: u64 = 0x0000_FFFF_0000_0000;
+pub const SUBTYPE_EXT_SHIFT: usize = 32;
+
+pub const LEGACY_VERSION: u8 = 0;
+pub const EXTENDED_VERSION: u8 = 1;
+pub const MAX_SRC_ID: u32 = 0x00FF_FFFF;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RawEvent64(pub u64);
+
+impl RawEvent64 {
+    pub fn from_legacy(raw: u32) -> Self {
+        RawEvent64(raw as u64)
+    }
+
+    // Event of the u32 layout `word` with a 24-bit source id and extended subtype bits, replacing
+    // the SrcId bits of the word
+    pub fn new(word: u32, src_id: u32, subtype_ext: u16) -> Self {
+        assert!(src_id <= MAX_SRC_ID, "SrcId {} exceeds {}", src_id, MAX_SRC_ID);
+        let word = (word & 0xFFFF_0000) | (src_id & 0xFFFF);
+        let src_id_hi = (src_id >> 16) as u64;
+        if src_id_hi == 0 && subtype_ext == 0 {
+            return Self::from_legacy(word);
+        }
+        RawEvent64(
+            ((EXTENDED_VERSION as u64) << VERSION_SHIFT)
+                | (src_id_hi << SRC_ID_HI_SHIFT)
+                | ((subtype_ext as u64) << SUBTYPE_EXT_SHIFT)
+                | word as u64,
+        )
+    }
+
+    pub fn extended(event_id: &EventId, src_id: u32, subtype_ext: u16) -> Self {
+        Self::new(event_id.encode(), src_id, subtype_ext)
+    }
+
+    pub fn version(&self) -> u8 {
+        ((self.0 & VERSION_MASK) >> VERSION_SHIFT) as u8
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version() == LEGACY_VERSION
+    }
+
+    // Full 24-bit source id, `RawEvent::id` and the decoded `EventId` only hold its low 16 bits
+    pub fn src_id(&self) -> u32 {
+        (((self.0 & SRC_ID_HI_MASK) >> SRC_ID_HI_SHIFT) << 16) as u32 | (self.0 & 0xFFFF) as u32
+    }
+
+    pub fn subtype_ext(&self) -> u16 {
+        ((self.0 & SUBTYPE_EXT_MASK) >> SUBTYPE_EXT_SHIFT) as u16
+    }
+
+    // Decoded event with its full source id, the SrcId of the EventId only holding the low 16 bits
+    pub fn decode_wide(&self) -> Result<(EventId, u32), DecodeError> {
+        Ok((EventId::try_decode(self.word())?, self.src_id()))
+    }
+
+    // Event in the u32 layout, failing if it uses the extension fields
+    pub fn to_legacy(&self) -> Result<u32, String> {
+        if !self.is_legacy() {
+            return Err(format!(
+                "Event 0x{:016X} doesn't fit the u32 layout (SrcId {}, extended subtype 0x{:04X})",
+                self.0, self.src_id(), self.subtype_ext()
+            ));
+        }
+        Ok(self.word())
+    }
+}
+
+impl From<u32> for RawEvent64 {
+    fn from(raw: u32) -> Self {
+        Self::from_legacy(raw)
+    }
+}
+
+impl TryFrom<RawEvent64> for u32 {
+    type Error = String;
+    fn try_from(event: RawEvent64) -> Result<Self, Self::Error> {
+        event.to_legacy()
+    }
+}
+
+// Filter on the source id `src_id` of wide events, in addition to `bits_match`
+pub fn wide_src_bits_match(bits_match: BitsMatch, src_id: u32) -> BitsMatch {
+    assert!(src_id <= MAX_SRC_ID, "SrcId {} exceeds {}", src_id, MAX_SRC_ID);
+    BitsMatch {
+        mask: bits_match.mask | SrcId::mask(),
+        value: (bits_match.value & !SrcId::mask()) | (src_id & SrcId::mask()),
+        ext_mask: bits_match.ext_mask | (SRC_ID_HI_MASK >> 32) as u32,
+        ext_value: (bits_match.ext_value & !(SRC_ID_HI_MASK >> 32) as u32)
+            | ((src_id >> 16) << (SRC_ID_HI_SHIFT - 32)),
+    }
+}
+
+impl RawEvent for RawEvent64 {
+    type Raw = u64;
+    const MAX_SRC_ID: u32 = MAX_SRC_ID;
+
+    fn pipeline(&self) -> Pipeline {
+        self.word().pipeline()
+    }
+    // Panics on the sources past 16 bits, see `decode_wide`
+    fn decode(&self) -> EventId {
+        self.try_decode().unwrap_or_else(|err| panic!("{}", err))
+    }
+    fn try_decode(&self) -> Result<EventId, DecodeError> {
+        if self.src_id() > SrcId::mask() {
+            return Err(DecodeError::WideSrcId { src_id: self.src_id(), raw: self.0 });
+        }
+        EventId::try_decode(self.word())
+    }
+    fn id(&self) -> u16 {
+        (self.0 & 0xFFFF) as u16
+    }
+    fn raw(&self) -> u64 {
+        self.0
+    }
+    fn from_raw(raw: u64) -> Self {
+        RawEvent64(raw)
+    }
+    fn from_event(event_id: &EventId) -> Self {
+        Self::from_legacy(event_id.encode())
+    }
+    fn word(&self) -> u32 {
+        self.0 as u32
+    }
+    fn ext_word(&self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+    // Filters pinning the 16 bits of SrcId pin SrcIdHi as well
+    fn matches(&self, bits_match: &BitsMatch) -> bool {
+        let src_id_hi_mask = (SRC_ID_HI_MASK >> 32) as u32;
+        let ext_mask = match bits_match.mask & SrcId::mask() == SrcId::mask() {
+            true => bits_match.ext_mask | src_id_hi_mask,
+            false => bits_match.ext_mask,
+        };
+        (self.word() & bits_match.mask) == bits_match.value && (self.ext_word() & ext_mask) == bits_match.ext_value
+    }
+    fn ser_hex<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        array_bytes::ser_hexify_prefixed(self.0, serializer)
+    }
+    fn de_hex<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        array_bytes::de_dehexify(deserializer).map(RawEvent64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emission::Emission;
+    use crate::ledger::Ledger;
+    use crate::{Decode, SrcId, mcrt_event};
+
+    #[test]
+    fn extended_events() {
+        let scatter = EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), SrcId::Mat(1));
+        let legacy = RawEvent64::from(scatter.encode());
+        assert!(legacy.is_legacy());
+        assert_eq!(u32::try_from(legacy), Ok(0x03a50001));
+        assert_eq!(RawEvent64::extended(&scatter, 1, 0), legacy);
+
+        let wide = RawEvent64::extended(&scatter, 0x012345, 0xBEEF);
+        assert_eq!(wide.0, 0x1001_BEEF_03A5_2345);
+        assert_eq!((wide.version(), wide.src_id(), wide.subtype_ext()), (EXTENDED_VERSION, 0x012345, 0xBEEF));
+        assert_eq!(wide.decode_wide().unwrap(), (EventId::decode(0x03a52345), 0x012345));
+        assert!(wide.try_decode().is_err());
+        assert!(wide.to_legacy().is_err());
+
+        // Ledgers of u32 events read as extended ones, and keep merging the legacy events
+        let mut ledger = Ledger::new();
+        let start = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, SrcId::Light(0))).unwrap();
+        let uid = ledger.insert(start, scatter.clone()).unwrap();
+        let json = serde_json::to_string(&ledger).unwrap();
+        let mut extended: Ledger<RawEvent64> = serde_json::from_str(&json).unwrap();
+        let start = extended.get_start_events()[0];
+        assert_eq!(extended.insert(start, scatter).unwrap().event, RawEvent64::from(uid.event));
+        let wide_uid = extended.insert_raw(start, wide).unwrap();
+        assert_eq!(extended.get_chain(wide_uid), vec![start, wide_uid]);
+        assert_eq!(extended.leaves().len(), 2);
+    }
+}
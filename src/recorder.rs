@@ -0,0 +1,556 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::{Encode, EventId};
+use crate::kind::is_scatter;
+use crate::transport::Transport;
+use crate::mcrt::{ScatterBinning, ScatterDir};
+use crate::aev::AevWriter;
+use crate::error::LedgerError;
+use crate::journal::JournalWriter;
+use crate::ledger::{ChoiceProbability, Ledger, SrcTable, Uid};
+use crate::raw::{Pipeline, RawField};
+
+// ----------------------------------------------------
+// Recorder: Ledger front-end used by the simulation
+// ----------------------------------------------------
+// Inserts the events in the owned Ledger and forwards every new entry to the registered sinks,
+// i.e. an `AevWriter` streaming to disk while the simulation runs. Sinks are handed the sources
+// again whenever they changed, before the next entry is forwarded.
+
+pub trait EventSink {
+    fn record(&mut self, uid: &Uid) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+    // Sinks writing the sources once in a header ignore later registrations
+    fn record_sources(&mut self, _src_table: &SrcTable) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write> EventSink for AevWriter<W> {
+    fn record(&mut self, uid: &Uid) -> io::Result<()> {
+        self.write(uid)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        AevWriter::flush(self)
+    }
+}
+
+impl<W: Write> EventSink for JournalWriter<W> {
+    fn record(&mut self, uid: &Uid) -> io::Result<()> {
+        self.write(uid)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        JournalWriter::flush(self)
+    }
+    fn record_sources(&mut self, src_table: &SrcTable) -> io::Result<()> {
+        self.write_sources(src_table)
+    }
+}
+
+// ----------------------------------------------------
+// Sampling policy, stored in the ledger header
+// ----------------------------------------------------
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SamplingPolicy {
+    // Record the chain of every Nth photon, starting with the first one
+    EveryNth(u32),
+    // Record each event with the probability given for its pipeline code, pipelines not listed
+    // are always recorded. A skipped event is contracted out of the chain, subsequent events are
+    // linked to the last recorded event.
+    PipelineProbability { probabilities: BTreeMap<u8, f64>, seed: u64 },
+}
+
+impl SamplingPolicy {
+    pub fn pipeline_probability(probabilities: &[(Pipeline, f64)], seed: u64) -> Self {
+        for (pipeline, probability) in probabilities {
+            assert!((0.0..=1.0).contains(probability), "Invalid sampling probability {} for {:?}", probability, pipeline);
+        }
+        SamplingPolicy::PipelineProbability {
+            probabilities: probabilities.iter().map(|(pipeline, p)| (u8::from(*pipeline), *p)).collect(),
+            seed,
+        }
+    }
+
+    // Probability with which an event is recorded, given the chain was sampled
+    pub fn probability(&self, raw_event: u32) -> f64 {
+        match self {
+            SamplingPolicy::EveryNth(_) => 1.0,
+            SamplingPolicy::PipelineProbability { probabilities, .. } => {
+                let code = ((raw_event & Pipeline::mask()) >> Pipeline::shift()) as u8;
+                probabilities.get(&code).cloned().unwrap_or(1.0)
+            }
+        }
+    }
+}
+
+pub struct Recorder {
+    ledger: Ledger,
+    sinks: Vec<Box<dyn EventSink + Send>>,
+    // Revision of the sources last handed to the sinks
+    src_revision: u64,
+    photon_count: u64,
+    rng_state: u64,
+}
+
+impl Recorder {
+    pub fn new(ledger: Ledger) -> Self {
+        let rng_state = match ledger.sampling_policy() {
+            Some(SamplingPolicy::PipelineProbability { seed, .. }) => *seed,
+            _ => 0,
+        };
+        Self {
+            ledger,
+            sinks: Vec::new(),
+            src_revision: 0,
+            photon_count: 0,
+            rng_state,
+        }
+    }
+
+    pub fn with_sampling(mut self, policy: SamplingPolicy) -> Self {
+        if let SamplingPolicy::EveryNth(n) = policy {
+            assert!(n > 0, "Sampling interval must be positive");
+        }
+        if let SamplingPolicy::PipelineProbability { seed, .. } = policy {
+            self.rng_state = seed;
+        }
+        self.ledger.set_sampling_policy(Some(policy));
+        self
+    }
+
+    pub fn with_sink<S: EventSink + Send + 'static>(mut self, sink: S) -> Self {
+        let mut sink = Box::new(sink);
+        if let Err(err) = sink.record_sources(&self.ledger.src_table()) {
+            error!("Failed to record sources to event sink: {}", err);
+        }
+        self.src_revision = self.ledger.src_revision();
+        self.sinks.push(sink);
+        self
+    }
+
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    // Access to register sources, prefer to register all sources before attaching sinks, as aev
+    // stream headers only contain the sources known at the time they are written. Journal sinks
+    // record later registrations.
+    pub fn ledger_mut(&mut self) -> &mut Ledger {
+        &mut self.ledger
+    }
+
+    // Returns None if the photon is not sampled, in which case its chain is not recorded. Fails once
+    // the seq_ids are exhausted, see `Ledger::insert_start`
+    pub fn insert_start(&mut self, start_event: EventId) -> Result<Option<Uid>, LedgerError> {
+        if self.photon_count == 0 {
+            self.ledger.start_recording();
+        }
+        self.photon_count += 1;
+        if !self.sample_photon() || !self.sample_event(start_event.encode()) {
+            return Ok(None);
+        }
+        let is_new = !self.ledger.contains(&Uid::new(0, start_event.encode()));
+        let uid = self.ledger.insert_start(start_event)?;
+        if is_new {
+            self.forward(&uid);
+        }
+        Ok(Some(uid))
+    }
+
+    // Scatter depth, i.e. number of scattering events in a chain, past which the chain is truncated.
+    // The scattering event exceeding it is replaced by a `Transport::MaxDepth` event and any further event of
+    // the chain is dropped. Stored in the ledger header, such that analyses know truncation occurred.
+    pub fn with_max_depth(mut self, max_depth: u32) -> Self {
+        self.ledger.set_max_depth(Some(max_depth));
+        self
+    }
+
+    // Bins of `scatter_dir`, stored in the ledger header such that analyses know which angles the
+    // recorded Forward/Side/Backward directions stand for
+    pub fn with_scatter_binning(mut self, binning: ScatterBinning) -> Self {
+        self.ledger.set_scatter_binning(Some(binning));
+        self
+    }
+
+    pub fn scatter_dir(&self, theta: f64) -> ScatterDir {
+        self.ledger.scatter_binning().classify(theta)
+    }
+
+    // Returns `prev_event` if the event is skipped by the sampling policy, or if the chain was
+    // truncated at the max depth
+    pub fn insert(&mut self, prev_event: Uid, event: EventId) -> Result<Uid, LedgerError> {
+        let event = match self.ledger.max_depth() {
+            Some(max_depth) => {
+                if is_max_depth(prev_event.event) {
+                    return Ok(prev_event);
+                }
+                if is_scatter(event.encode()) && self.ledger.chain_depth(prev_event) >= max_depth {
+                    EventId::new_transport(Transport::MaxDepth)
+                } else {
+                    event
+                }
+            }
+            None => event,
+        };
+        if !self.sample_event(event.encode()) {
+            return Ok(prev_event);
+        }
+        let is_new = self
+            .ledger
+            .get_next_seq_id(&prev_event)
+            .is_none_or(|seq_id| !self.ledger.contains(&Uid::new(seq_id, event.encode())));
+        let uid = self.ledger.insert(prev_event, event)?;
+        if is_new {
+            self.forward(&uid);
+        }
+        Ok(uid)
+    }
+
+    // Same as `insert_start`, annotating the entry with the probability of the sampled emission
+    pub fn insert_start_with_probability(&mut self, start_event: EventId, choice: ChoiceProbability) -> Result<Option<Uid>, LedgerError> {
+        let uid = self.insert_start(start_event)?;
+        if let Some(uid) = &uid {
+            self.annotate(uid, choice);
+        }
+        Ok(uid)
+    }
+
+    // Same as `insert`, annotating the entry with the probability of the stochastic choice of the
+    // event. Events skipped by the sampling policy or replaced at the max depth are not annotated.
+    pub fn insert_with_probability(
+        &mut self,
+        prev_event: Uid,
+        event: EventId,
+        choice: ChoiceProbability,
+    ) -> Result<Uid, LedgerError> {
+        let raw_event = event.encode();
+        let uid = self.insert(prev_event, event)?;
+        if uid != prev_event && uid.event == raw_event {
+            self.annotate(&uid, choice);
+        }
+        Ok(uid)
+    }
+
+    fn annotate(&mut self, uid: &Uid, choice: ChoiceProbability) {
+        if let Err(err) = self.ledger.annotate_probability(uid, choice) {
+            error!("Failed to annotate {}: {}", uid, err);
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.sync_sources();
+        for sink in self.sinks.iter_mut() {
+            sink.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn into_ledger(mut self) -> Ledger {
+        self.ledger.stop_recording();
+        if let Err(err) = self.flush() {
+            error!("Failed to flush event sinks: {}", err);
+        }
+        self.ledger
+    }
+
+    fn sample_photon(&self) -> bool {
+        match self.ledger.sampling_policy() {
+            Some(SamplingPolicy::EveryNth(n)) => (self.photon_count - 1).is_multiple_of(*n as u64),
+            _ => true,
+        }
+    }
+
+    fn sample_event(&mut self, raw_event: u32) -> bool {
+        let probability = match self.ledger.sampling_policy() {
+            Some(policy) => policy.probability(raw_event),
+            None => return true,
+        };
+        probability >= 1.0 || self.next_uniform() < probability
+    }
+
+    fn next_uniform(&mut self) -> f64 {
+        (splitmix64(&mut self.rng_state) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn sync_sources(&mut self) {
+        if self.ledger.src_revision() == self.src_revision {
+            return;
+        }
+        self.src_revision = self.ledger.src_revision();
+        let src_table = self.ledger.src_table();
+        for sink in self.sinks.iter_mut() {
+            if let Err(err) = sink.record_sources(&src_table) {
+                error!("Failed to record sources to event sink: {}", err);
+            }
+        }
+    }
+
+    // NOTE: A failing sink must not abort the simulation, the Ledger still holds every event
+    fn forward(&mut self, uid: &Uid) {
+        self.sync_sources();
+        for sink in self.sinks.iter_mut() {
+            if let Err(err) = sink.record(uid) {
+                error!("Failed to record {} to event sink: {}", uid, err);
+            }
+        }
+    }
+}
+
+fn is_max_depth(raw_event: u32) -> bool {
+    (raw_event & (Pipeline::mask() | Transport::mask())) == Pipeline::Transport.encode() | Transport::MaxDepth.encode()
+}
+
+// SplitMix64, enough for decimation and sampling without pulling in an RNG dependency
+pub(crate) fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+// ----------------------------------------------------
+// Non-blocking sink writing on a tokio task
+// ----------------------------------------------------
+#[cfg(feature = "async")]
+pub use self::async_sink::AsyncSink;
+
+#[cfg(feature = "async")]
+mod async_sink {
+    use std::io;
+
+    use log::error;
+    use tokio::io::{AsyncWrite, AsyncWriteExt};
+    use tokio::sync::mpsc;
+    use tokio::task::JoinHandle;
+
+    use super::EventSink;
+    use crate::aev::AevWriter;
+    use crate::ledger::{Ledger, Uid};
+
+    // Frames are batched in blocks of `capacity` bytes and handed over an unbounded channel, such
+    // that `record` never waits on the writer task. Dropping the sink sends the remaining frames
+    // and closes the stream, after which the writer task completes.
+    pub struct AsyncSink {
+        buffer: Vec<u8>,
+        capacity: usize,
+        sender: mpsc::UnboundedSender<Vec<u8>>,
+    }
+
+    impl AsyncSink {
+        pub const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+        // Spawn the writer task on the current tokio runtime, writing an .aev stream with the
+        // sources of `ledger` in the header to `writer` (i.e. a tokio File or TcpStream).
+        // Await the returned handle after dropping the sink to make sure all events are written.
+        pub fn spawn<W>(writer: W, ledger: &Ledger) -> io::Result<(Self, JoinHandle<io::Result<()>>)>
+        where
+            W: AsyncWrite + Unpin + Send + 'static,
+        {
+            Self::spawn_with_capacity(writer, ledger, Self::DEFAULT_CAPACITY)
+        }
+
+        pub fn spawn_with_capacity<W>(
+            mut writer: W,
+            ledger: &Ledger,
+            capacity: usize,
+        ) -> io::Result<(Self, JoinHandle<io::Result<()>>)>
+        where
+            W: AsyncWrite + Unpin + Send + 'static,
+        {
+            let header = AevWriter::new(Vec::new(), ledger)?.into_inner()?;
+            let (sender, mut receiver) = mpsc::unbounded_channel::<Vec<u8>>();
+            let task = tokio::spawn(async move {
+                writer.write_all(&header).await?;
+                while let Some(block) = receiver.recv().await {
+                    writer.write_all(&block).await?;
+                }
+                writer.flush().await?;
+                writer.shutdown().await
+            });
+            let sink = Self {
+                buffer: Vec::with_capacity(capacity),
+                capacity,
+                sender,
+            };
+            Ok((sink, task))
+        }
+
+        fn send(&mut self, block: Vec<u8>) -> io::Result<()> {
+            self.sender
+                .send(block)
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Event writer task stopped"))
+        }
+    }
+
+    impl EventSink for AsyncSink {
+        fn record(&mut self, uid: &Uid) -> io::Result<()> {
+            self.buffer.extend_from_slice(&uid.seq_id.to_le_bytes());
+            self.buffer.extend_from_slice(&uid.event.to_le_bytes());
+            if self.buffer.len() >= self.capacity {
+                let block = std::mem::replace(&mut self.buffer, Vec::with_capacity(self.capacity));
+                self.send(block)?;
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            if self.buffer.is_empty() {
+                return Ok(());
+            }
+            let block = std::mem::take(&mut self.buffer);
+            self.send(block)
+        }
+    }
+
+    impl Drop for AsyncSink {
+        fn drop(&mut self) {
+            if let Err(err) = EventSink::flush(self) {
+                error!("Failed to send the remaining events to the writer task: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aev::AevReader;
+    use crate::emission::Emission;
+    use crate::mcrt::{Elastic, MCRT, Material};
+    use crate::{SrcId, mcrt_event};
+
+    #[test]
+    fn recorder_streams_new_entries() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let writer = AevWriter::create(file.path(), &ledger).unwrap();
+        let mut recorder = Recorder::new(ledger).with_sink(writer);
+
+        let uid1 = recorder.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap().unwrap();
+        let uid2 = recorder.insert(uid1, EventId::new_mcrt(mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        // Duplicated events are not streamed again
+        recorder.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+        recorder.insert(uid1, EventId::new_mcrt(mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        let ledger = recorder.into_ledger();
+
+        let mut reader = AevReader::open(file.path()).unwrap();
+        assert_eq!(reader.read_frame().unwrap(), Some((uid1.seq_id, uid1.event)));
+        assert_eq!(reader.read_frame().unwrap(), Some((uid2.seq_id, uid2.event)));
+        assert_eq!(reader.read_frame().unwrap(), None);
+        assert_eq!(ledger.get_chain(uid2), vec![uid1, uid2]);
+        assert_eq!(ledger.names(&SrcId::Light(0)).len(), 1);
+        assert_eq!(ledger.audit().recording_segments().len(), 1);
+        assert!(!ledger.audit().is_recording());
+    }
+
+    #[test]
+    fn sampling_every_nth_photon() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mut recorder = Recorder::new(ledger).with_sampling(SamplingPolicy::EveryNth(3));
+        let sampled: Vec<bool> = (0..7)
+            .map(|_| recorder.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap().is_some())
+            .collect();
+        assert_eq!(sampled, vec![true, false, false, true, false, false, true]);
+
+        let ledger = recorder.into_ledger();
+        let json = serde_json::to_string(&ledger).unwrap();
+        let stored_ledger: Ledger = serde_json::from_str(&json).unwrap();
+        assert_eq!(stored_ledger.sampling_policy(), Some(&SamplingPolicy::EveryNth(3)));
+    }
+
+    #[test]
+    fn sampling_pipeline_probability() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let policy = SamplingPolicy::pipeline_probability(&[(Pipeline::MCRT, 0.0)], 7);
+        let mut recorder = Recorder::new(ledger).with_sampling(policy);
+        let uid1 = recorder.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap().unwrap();
+        // Scattering events are contracted out, detection is linked to the emission
+        let uid2 = recorder.insert(uid1, EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Any), mat_id)).unwrap();
+        assert_eq!(uid2, uid1);
+        let uid3 = recorder.insert(uid2, EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(0))).unwrap();
+        assert_eq!(recorder.ledger().get_chain(uid3), vec![uid1, uid3]);
+
+        let policy = SamplingPolicy::pipeline_probability(&[(Pipeline::MCRT, 0.5)], 7);
+        let mut recorder = Recorder::new(Ledger::new()).with_sampling(policy);
+        let recorded = (0..1000).filter(|_| recorder.sample_event(0x03800000)).count();
+        assert!((400..600).contains(&recorded), "Recorded {} out of 1000 events", recorded);
+    }
+
+    #[test]
+    fn max_depth_truncation() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let mut recorder = Recorder::new(ledger).with_max_depth(2);
+        let scatter = || EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Any), mat_id);
+        let uid1 = recorder.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap().unwrap();
+        let uid2 = recorder.insert(uid1, scatter()).unwrap();
+        let uid3 = recorder.insert(uid2, scatter()).unwrap();
+        let truncated = recorder.insert(uid3, scatter()).unwrap();
+        assert!(is_max_depth(truncated.event));
+        // Only scattering events count towards the depth
+        let absorbed = recorder.insert(uid3, EventId::new_mcrt(mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        assert!(!is_max_depth(absorbed.event));
+        // The rest of the chain is dropped
+        assert_eq!(recorder.insert(truncated, scatter()).unwrap(), truncated);
+        assert_eq!(recorder.insert(truncated, EventId::new_detection(crate::detection::Detection::Direct, SrcId::Detector(0))).unwrap(), truncated);
+        assert_eq!(recorder.ledger().get_chain(truncated), vec![uid1, uid2, uid3, truncated]);
+
+        let json = serde_json::to_string(&recorder.into_ledger()).unwrap();
+        let stored_ledger: Ledger = serde_json::from_str(&json).unwrap();
+        assert_eq!(stored_ledger.max_depth(), Some(2));
+    }
+
+    #[test]
+    fn scatter_binning_header() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("water".to_string()).unwrap();
+        let binning = ScatterBinning::new([0.0, 0.1, 3.0, std::f64::consts::PI]).unwrap();
+        let mut recorder = Recorder::new(ledger).with_scatter_binning(binning);
+        let uid = recorder.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap().unwrap();
+        let dir = recorder.scatter_dir(0.5);
+        assert_eq!(dir, ScatterDir::Side);
+        let scatter = recorder.insert(uid, EventId::new_mcrt(MCRT::Material(Material::Elastic(Elastic::Mie(dir))), mat_id)).unwrap();
+
+        let json = serde_json::to_string(&recorder.into_ledger()).unwrap();
+        let stored_ledger: Ledger = serde_json::from_str(&json).unwrap();
+        assert_eq!(stored_ledger.scatter_binning(), binning);
+        assert_eq!(stored_ledger.scatter_range(scatter.event), Some((0.1, 3.0)));
+        assert_eq!(stored_ledger.scatter_range(uid.event), None);
+        assert_eq!(Ledger::new().scatter_binning(), ScatterBinning::default());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_sink_writes_stream() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_path_buf();
+        runtime.block_on(async {
+            let mut ledger = Ledger::new();
+            let light_id = ledger.with_light("laser".to_string()).unwrap();
+            let writer = tokio::fs::File::create(&path).await.unwrap();
+            // Small capacity to exercise the block hand-over
+            let (sink, task) = AsyncSink::spawn_with_capacity(writer, &ledger, 16).unwrap();
+            let mut recorder = Recorder::new(ledger).with_sink(sink);
+            for emission in [Emission::PencilBeam, Emission::PointSource, Emission::PlaneWave] {
+                recorder.insert_start(EventId::new_emission(emission, light_id)).unwrap();
+            }
+            drop(recorder.into_ledger());
+            task.await.unwrap().unwrap();
+        });
+        let ledger = AevReader::open(&path).unwrap().into_ledger().unwrap();
+        assert_eq!(ledger.get_start_events().len(), 3);
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::RawEvent;
+use crate::aev::invalid_data;
+use crate::journal::{self, Record};
+use crate::ledger::Uid;
+
+// ----------------------------------------------------
+// Spill-to-disk storage of cold ledger entries
+// ----------------------------------------------------
+// Under a memory budget, the Ledger moves the oldest groups of its next map (the entries sharing a
+// seq_id, lowest seq_ids first) to a temp file, one block per spill, and reads them back on demand.
+// Each entry is stored as a little-endian triple sorted by (seq_id, event):
+// | seq_id (u32) | event (width of the raw event) | next_seq_id (u32) |
+// The index of each block keeps the range of seq_ids and allocated next_seq_ids it covers, such
+// that a lookup only reads the blocks that can hold the entry. A group spilled earlier can get new
+// entries in memory and be spilled again, hence block ranges may overlap.
+// A store created with `create_stream` writes to an append-only ledger file instead, writing each
+// block as an entries record of the journal framing and keeping the file once dropped, see `stream`.
+
+// Approximate memory taken by an in-memory entry, across the next and prev maps
+pub const ENTRY_BYTES: usize = 96;
+
+type Triple<E> = (u32, E, u32);
+type Entries<E> = Arc<Vec<Triple<E>>>;
+
+static NEXT_SPILL_FILE: AtomicUsize = AtomicUsize::new(0);
+
+struct SpillBlock {
+    seq_ids: RangeInclusive<u32>,
+    next_seq_ids: RangeInclusive<u32>,
+    offset: u64,
+    bytes: usize,
+}
+
+pub struct SpillStore<E: RawEvent = u32> {
+    path: PathBuf,
+    file: Mutex<File>,
+    blocks: Vec<SpillBlock>,
+    len: usize,
+    // Last block read, walking up a chain mostly hits the same block
+    cache: Mutex<Option<(usize, Entries<E>)>>,
+    is_stream: bool,
+}
+
+impl<E: RawEvent> SpillStore<E> {
+    const EVENT_BYTES: usize = std::mem::size_of::<E::Raw>();
+    pub(crate) const TRIPLE_BYTES: usize = 8 + Self::EVENT_BYTES;
+
+    pub fn create_in<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let path = dir.as_ref().join(format!(
+            "aetherus-spill-{}-{}.bin",
+            std::process::id(),
+            NEXT_SPILL_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+            blocks: Vec::new(),
+            len: 0,
+            cache: Mutex::new(None),
+            is_stream: false,
+        })
+    }
+
+    // Store writing to the ledger file at `path`, replacing any existing file
+    pub fn create_stream<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+            blocks: Vec::new(),
+            len: 0,
+            cache: Mutex::new(None),
+            is_stream: true,
+        })
+    }
+
+    pub fn is_stream(&self) -> bool {
+        self.is_stream
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Number of spilled entries
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Append the (seq_id, event, next_seq_id) entries as a new block
+    pub fn spill(&mut self, mut entries: Vec<Triple<E>>) -> io::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        entries.sort_unstable();
+        let (offset, bytes) = if self.is_stream {
+            self.append_record(&entries_record(&entries))?
+        } else {
+            self.append(&encode_entries(&entries))?
+        };
+        let next_min = entries.iter().map(|entry| entry.2).min().unwrap();
+        let next_max = entries.iter().map(|entry| entry.2).max().unwrap();
+        self.blocks.push(SpillBlock {
+            seq_ids: entries[0].0..=entries[entries.len() - 1].0,
+            next_seq_ids: next_min..=next_max,
+            offset,
+            bytes,
+        });
+        self.len += entries.len();
+        Ok(())
+    }
+
+    // Append a record to the ledger file of a stream, returns its (offset, length in bytes)
+    pub(crate) fn append_record(&mut self, record: &Record<E>) -> io::Result<(u64, usize)> {
+        assert!(self.is_stream, "Records are only written to ledger streams");
+        self.append(&journal::encode_record(record)?)
+    }
+
+    fn append(&mut self, bytes: &[u8]) -> io::Result<(u64, usize)> {
+        let mut file = self.file.lock().unwrap();
+        let offset = file.seek(SeekFrom::End(0))?;
+        file.write_all(bytes)?;
+        file.flush()?;
+        Ok((offset, bytes.len()))
+    }
+
+    pub fn get_next_seq_id(&self, uid: &Uid<E>) -> Option<u32> {
+        self.blocks_with_seq_id(uid.seq_id).find_map(|block| {
+            let entries = self.read_block(block);
+            entries
+                .binary_search_by(|entry| (entry.0, entry.1).cmp(&(uid.seq_id, uid.event)))
+                .ok()
+                .map(|idx| entries[idx].2)
+        })
+    }
+
+    // (event, next_seq_id) of the spilled entries of the group `seq_id`
+    pub fn group(&self, seq_id: u32) -> Vec<(E, u32)> {
+        self.blocks_with_seq_id(seq_id)
+            .flat_map(|block| {
+                let entries = self.read_block(block);
+                let start = entries.partition_point(|entry| entry.0 < seq_id);
+                entries[start..]
+                    .iter()
+                    .take_while(|entry| entry.0 == seq_id)
+                    .map(|entry| (entry.1, entry.2))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    pub fn contains_group(&self, seq_id: u32) -> bool {
+        !self.group(seq_id).is_empty()
+    }
+
+    // Entry which allocated `next_seq_id`
+    pub fn get_prev(&self, next_seq_id: u32) -> Option<Uid<E>> {
+        (0..self.blocks.len())
+            .filter(|idx| self.blocks[*idx].next_seq_ids.contains(&next_seq_id))
+            .find_map(|idx| {
+                self.read_block(idx)
+                    .iter()
+                    .find(|entry| entry.2 == next_seq_id)
+                    .map(|entry| Uid { seq_id: entry.0, event: entry.1 })
+            })
+    }
+
+    // Seq_ids of the spilled groups, reading one block at a time
+    pub fn group_ids(&self) -> BTreeSet<u32> {
+        (0..self.blocks.len()).flat_map(|idx| self.read_block(idx).iter().map(|entry| entry.0).collect::<Vec<_>>()).collect()
+    }
+
+    // Every spilled entry, block by block
+    pub fn entries(&self) -> Vec<Triple<E>> {
+        (0..self.blocks.len()).flat_map(|idx| self.read_block(idx).iter().cloned().collect::<Vec<_>>()).collect()
+    }
+
+    fn blocks_with_seq_id(&self, seq_id: u32) -> impl Iterator<Item = usize> + '_ {
+        (0..self.blocks.len()).filter(move |idx| self.blocks[*idx].seq_ids.contains(&seq_id))
+    }
+
+    // NOTE: The spill file is private to the process, failing to read it back is unrecoverable
+    fn read_block(&self, idx: usize) -> Entries<E> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some((cached, entries)) = cache.as_ref()
+            && *cached == idx
+        {
+            return entries.clone();
+        }
+        let block = &self.blocks[idx];
+        let mut bytes = vec![0u8; block.bytes];
+        let entries = {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(block.offset))
+                .and_then(|_| file.read_exact(&mut bytes))
+                .and_then(|_| {
+                    if self.is_stream {
+                        record_entries(journal::decode_record(&bytes)?)
+                    } else {
+                        Ok(decode_entries(&bytes))
+                    }
+                })
+                .unwrap_or_else(|err| panic!("Unable to read spilled entries from {}: {}", self.path.display(), err))
+        };
+        let entries = Arc::new(entries);
+        *cache = Some((idx, entries.clone()));
+        entries
+    }
+}
+
+impl<E: RawEvent> Drop for SpillStore<E> {
+    fn drop(&mut self) {
+        if !self.is_stream {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+pub(crate) fn encode_entries<E: RawEvent>(entries: &[Triple<E>]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(entries.len() * SpillStore::<E>::TRIPLE_BYTES);
+    for (seq_id, event, next_seq_id) in entries {
+        bytes.extend_from_slice(&seq_id.to_le_bytes());
+        bytes.extend_from_slice(&event.raw().into().to_le_bytes()[..SpillStore::<E>::EVENT_BYTES]);
+        bytes.extend_from_slice(&next_seq_id.to_le_bytes());
+    }
+    bytes
+}
+
+pub(crate) fn decode_entry<E: RawEvent>(bytes: &[u8]) -> Triple<E> {
+    let word = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+    let (seq_id, rest) = bytes.split_at(4);
+    let (raw, next_seq_id) = rest.split_at(SpillStore::<E>::EVENT_BYTES);
+    let mut event = [0u8; 8];
+    event[..raw.len()].copy_from_slice(raw);
+    let event = E::Raw::try_from(u64::from_le_bytes(event)).map(E::from_raw).ok().unwrap();
+    (word(seq_id), event, word(next_seq_id))
+}
+
+// (seq_id, event, next_seq_id) entries of a block, the trailing bytes of a partial entry are ignored
+pub(crate) fn decode_entries<E: RawEvent>(bytes: &[u8]) -> Vec<Triple<E>> {
+    bytes.chunks_exact(SpillStore::<E>::TRIPLE_BYTES).map(decode_entry).collect()
+}
+
+pub(crate) fn entries_record<E: RawEvent>(entries: &[Triple<E>]) -> Record<E> {
+    Record::Entries {
+        event_bytes: SpillStore::<E>::EVENT_BYTES as u8,
+        triples: entries.iter().map(|(seq_id, event, next_seq_id)| (*seq_id, event.raw().into(), *next_seq_id)).collect(),
+    }
+}
+
+// Entries of an entries record, which must hold raw events of the width of `E`
+pub(crate) fn record_entries<E: RawEvent>(record: Record<E>) -> io::Result<Vec<Triple<E>>> {
+    let Record::Entries { event_bytes, triples } = record else {
+        return Err(invalid_data("Expected a record of spilled entries".to_string()));
+    };
+    if event_bytes as usize != SpillStore::<E>::EVENT_BYTES {
+        return Err(invalid_data(format!(
+            "Ledger file holds {} byte events, expected {} byte ones",
+            event_bytes,
+            SpillStore::<E>::EVENT_BYTES
+        )));
+    }
+    triples
+        .into_iter()
+        .map(|(seq_id, raw, next_seq_id)| {
+            let event = E::Raw::try_from(raw)
+                .map(E::from_raw)
+                .map_err(|_| invalid_data(format!("Raw event 0x{:X} exceeds {} bytes", raw, event_bytes)))?;
+            Ok((seq_id, event, next_seq_id))
+        })
+        .collect()
+}
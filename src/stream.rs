@@ -0,0 +1,126 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use crate::RawEvent;
+use crate::aev::invalid_data;
+use crate::journal::{self, Record};
+use crate::ledger::{Ledger, SrcTable};
+use crate::spill::record_entries;
+
+// ----------------------------------------------------
+// Append-only ledger file (.jsonl)
+// ----------------------------------------------------
+// Written by `Ledger::stream_to` while the simulation runs, such that the next/prev maps are kept in
+// memory under a budget rather than until the end of the run. The file uses the records of the
+// journal, one JSON record per line, see `journal`:
+// {"sources": SrcTable} first, then {"src_delta": SrcTableDelta} before a block whenever sources
+// were registered, and the spilled blocks of (seq_id, raw event, next_seq_id) triples:
+// {"entries": {"event_bytes": 4, "triples": [[seq_id, raw, next_seq_id], ...]}}
+// `Ledger::finish_stream` writes the entries left in memory and ends the file with the ledger
+// without its entries (start events, timestamps, child roots, ...):
+// {"ledger": Ledger}
+// A file without the ledger record, left by an interrupted run, still reads as the entries spilled
+// so far with the last sources written.
+
+// ----------------------------------------------------
+// Reader
+// ----------------------------------------------------
+pub fn read_stream<E: RawEvent, P: AsRef<Path>>(path: P) -> io::Result<Ledger<E>> {
+    read_stream_from(BufReader::new(File::open(path)?))
+}
+
+// Rebuild the Ledger of a ledger file. A truncated last record, left by an interrupted run, is skipped.
+pub fn read_stream_from<E: RawEvent, R: BufRead>(reader: R) -> io::Result<Ledger<E>> {
+    let mut sources: Option<SrcTable> = None;
+    let mut trailer: Option<Ledger<E>> = None;
+    let mut entries = Vec::new();
+    journal::read_records(reader, |record| {
+        match record {
+            Record::Sources(src_table) => sources = Some(*src_table),
+            Record::SrcDelta(delta) => {
+                journal::apply_delta(&mut sources, *delta)?;
+            }
+            Record::Entries { .. } => entries.extend(record_entries::<E>(record)?),
+            Record::Ledger(ledger) => trailer = Some(*ledger),
+            Record::Links(_) => {
+                return Err(invalid_data("Journal of event links, read it with `journal::read_journal`".to_string()));
+            }
+        }
+        Ok(())
+    })?;
+
+    let mut ledger = trailer.unwrap_or_else(|| {
+        let mut ledger = Ledger::default();
+        if let Some(src_table) = sources {
+            ledger.set_src_table(src_table);
+        }
+        ledger
+    });
+    ledger.restore_entries(entries);
+    ledger.validate_events().map_err(|err| invalid_data(err.to_string()))?;
+    Ok(ledger)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detection::Detection;
+    use crate::emission::Emission;
+    use crate::raw64::RawEvent64;
+    use crate::spill::ENTRY_BYTES;
+    use crate::{EventId, SrcId, mcrt_event};
+
+    #[test]
+    fn stream_round_trip() {
+        let build = |ledger: &mut Ledger| {
+            let light_id = ledger.with_light("laser".to_string()).unwrap();
+            let mut leaves = Vec::new();
+            for depth in 0..60 {
+                // Registered while the entries are streamed, written again before the next block
+                let mat_id = ledger.with_mat(format!("layer{}", depth / 20)).unwrap();
+                let mut leaf = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+                for _ in 0..=depth % 7 {
+                    leaf = ledger.insert(leaf, EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+                }
+                leaves.push(ledger.insert(leaf, EventId::new_detection(Detection::Direct, SrcId::Detector(depth % 3))).unwrap());
+            }
+            leaves
+        };
+        let mut reference = Ledger::new();
+        let leaves = build(&mut reference);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.jsonl");
+        let mut ledger = Ledger::new();
+        ledger.stream_to(&path, 20 * ENTRY_BYTES).unwrap();
+        assert_eq!(build(&mut ledger), leaves);
+        assert!(ledger.spilled_len() > 0);
+
+        // Interrupted run, the entries spilled so far with the sources of the last block
+        let partial: Ledger = read_stream(&path).unwrap();
+        assert_eq!(partial.entries().count(), ledger.spilled_len());
+        assert_eq!(partial.names(&SrcId::Mat(2)), reference.names(&SrcId::Mat(2)));
+        let bytes = std::fs::read(&path).unwrap();
+        let truncated: Ledger = read_stream_from(&bytes[..bytes.len() - 3]).unwrap();
+        assert!(truncated.entries().count() < partial.entries().count());
+
+        let src_table = ledger.src_table();
+        ledger.finish_stream().unwrap();
+        let mut streamed: Ledger = read_stream(&path).unwrap();
+        assert_eq!(streamed.src_table(), src_table);
+        assert_eq!(streamed.get_start_events(), reference.get_start_events());
+        assert_eq!(streamed.entries().count(), reference.entries().count());
+        for leaf in &leaves {
+            assert_eq!(streamed.get_chain(*leaf), reference.get_chain(*leaf));
+        }
+        let next = streamed.insert(leaves[0], EventId::new_detection(Detection::Direct, SrcId::Detector(0))).unwrap();
+        assert_eq!(next, reference.insert(leaves[0], EventId::new_detection(Detection::Direct, SrcId::Detector(0))).unwrap());
+
+        assert!(read_stream::<RawEvent64, _>(&path).is_err());
+        assert!(journal::read_journal(&path).is_err());
+        assert!(Ledger::new().finish_stream().is_err());
+        let unwritable = dir.path().join("missing").join("run.jsonl");
+        assert!(matches!(Ledger::new().stream_to(unwritable, ENTRY_BYTES), Err(crate::error::LedgerError::Io(_))));
+    }
+}
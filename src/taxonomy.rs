@@ -0,0 +1,202 @@
+use std::io::{self, Write};
+
+use serde::Serialize;
+
+use crate::{SrcId, SrcKind};
+use crate::kind::EventKind;
+use crate::ledger::Ledger;
+
+// ----------------------------------------------------
+// Legend and colormap for plotting tools
+// ----------------------------------------------------
+// Stable mapping of event kind -> short label -> suggested color, such that every figure uses the
+// same labels and colors. Each family of kinds (a pipeline, MCRT supertype or scattering model)
+// gets its own hue, its members are shaded by lightness, i.e. all Mie directions are purples.
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LegendEntry {
+    pub kind: String,
+    pub label: String,
+    pub color: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SourceLegendEntry {
+    pub src: String,
+    pub name: String,
+    pub color: String,
+}
+
+// (first kind of the family, hue, saturation), the family extends up to the next listed kind
+const FAMILIES: &[(EventKind, f64, f64)] = &[
+    (EventKind::None,             0.0,   0.0),
+    (EventKind::Emission,         45.0,  0.9),
+    (EventKind::MCRT,             215.0, 0.3),
+    (EventKind::Interface,        200.0, 0.8),
+    (EventKind::Reflector,        130.0, 0.6),
+    (EventKind::Material,         0.0,   0.75),
+    (EventKind::Raman,            330.0, 0.7),
+    (EventKind::Fluorescence,     300.0, 0.7),
+    (EventKind::HenyeyGreenstein, 250.0, 0.6),
+    (EventKind::Mie,              275.0, 0.6),
+    (EventKind::Rayleigh,         225.0, 0.6),
+    (EventKind::SphericalCdf,     185.0, 0.6),
+    (EventKind::Detection,        0.0,   0.0),
+    (EventKind::Processing,       25.0,  0.55),
+    (EventKind::Transport,        170.0, 0.7),
+    (EventKind::Voxel,            90.0,  0.6),
+    (EventKind::Custom,           0.0,   0.0),
+];
+
+// Qualitative palette for sources (matplotlib's tab10)
+const SOURCE_PALETTE: &[&str] = &[
+    "#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd",
+    "#8c564b", "#e377c2", "#7f7f7f", "#bcbd22", "#17becf",
+];
+
+fn hsl_to_hex(hue: f64, saturation: f64, lightness: f64) -> String {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let h = hue / 60.0;
+    let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let to_u8 = |c: f64| ((c + m) * 255.0).round().clamp(0.0, 255.0) as u8;
+    format!("#{:02x}{:02x}{:02x}", to_u8(r), to_u8(g), to_u8(b))
+}
+
+// Legend of every event kind, in the `EventKind` order
+pub fn legend() -> Vec<LegendEntry> {
+    let mut entries = Vec::with_capacity(EventKind::ALL.len());
+    let mut family = FAMILIES[0];
+    let mut shade = 0;
+    for kind in EventKind::ALL {
+        if let Some(next_family) = FAMILIES.iter().find(|(head, _, _)| head == kind) {
+            family = *next_family;
+            shade = 0;
+        }
+        let (_, hue, saturation) = family;
+        // The family head is the darkest, its members get lighter without washing out
+        let lightness = 0.35 + 0.08 * (shade % 6) as f64;
+        entries.push(LegendEntry {
+            kind: format!("{:?}", kind),
+            label: kind.label(),
+            color: hsl_to_hex(hue, saturation, lightness),
+        });
+        shade += 1;
+    }
+    entries
+}
+
+// Color of a source, derived from its kind and id only, such that a source keeps its color when
+// other sources are registered. Each kind starts at its own palette offset, consecutive ids of a
+// kind getting consecutive colors (MatSurf ids count down from u16::MAX).
+pub fn source_color(src_id: SrcId) -> &'static str {
+    let offset = match src_id.kind() {
+        SrcKind::Light    => 0,
+        SrcKind::Mat      => 2,
+        SrcKind::Surf     => 4,
+        SrcKind::MatSurf  => 6,
+        SrcKind::Detector => 8,
+        SrcKind::None     => 7,
+    };
+    let id = match src_id {
+        SrcId::MatSurf(id) => u16::MAX - id,
+        _ => src_id.id().unwrap_or(0),
+    };
+    SOURCE_PALETTE[(offset + id as usize) % SOURCE_PALETTE.len()]
+}
+
+// Legend of the sources registered in `ledger`, ordered by kind and id
+pub fn source_legend(ledger: &Ledger) -> Vec<SourceLegendEntry> {
+    let mut sources: Vec<(SrcId, String)> = ledger
+        .sources()
+        .map(|(src_id, names)| {
+            let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+            (*src_id, names.join(", "))
+        })
+        .collect();
+    sources.sort_by_key(|(src_id, _)| (src_id.kind().to_string(), src_id.id()));
+    sources
+        .into_iter()
+        .map(|(src_id, name)| SourceLegendEntry {
+            src: src_id.to_string(),
+            name,
+            color: source_color(src_id).to_string(),
+        })
+        .collect()
+}
+
+pub fn write_json<W: Write, T: Serialize>(entries: &[T], writer: W) -> io::Result<()> {
+    serde_json::to_writer_pretty(writer, entries).map_err(io::Error::other)
+}
+
+pub fn write_csv<W: Write, T: Serialize>(entries: &[T], writer: W) -> io::Result<()> {
+    let mut csv_writer = csv::Writer::from_writer(writer);
+    for entry in entries {
+        csv_writer.serialize(entry).map_err(io::Error::other)?;
+    }
+    csv_writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legend_is_stable() {
+        let entries = legend();
+        assert_eq!(entries.len(), EventKind::ALL.len());
+        assert_eq!(entries, legend());
+
+        let mie_forward = entries.iter().find(|entry| entry.kind == "MieForward").unwrap();
+        assert_eq!(mie_forward.label, "Mie/Forward");
+        let mie = entries.iter().find(|entry| entry.kind == "Mie").unwrap();
+        assert_ne!(mie.color, mie_forward.color);
+        assert_eq!(hsl_to_hex(0.0, 1.0, 0.5), "#ff0000");
+        assert_eq!(hsl_to_hex(120.0, 1.0, 0.5), "#00ff00");
+
+        let mut buffer = Vec::new();
+        write_csv(&entries, &mut buffer).expect("Unable to write legend");
+        let text = String::from_utf8(buffer).unwrap();
+        assert_eq!(text.lines().next(), Some("kind,label,color"));
+        assert_eq!(text.lines().count(), entries.len() + 1);
+    }
+
+    #[test]
+    fn source_legend_order() {
+        let mut ledger = Ledger::new();
+        ledger.with_mat("water".to_string()).unwrap();
+        ledger.with_light("laser".to_string()).unwrap();
+        ledger.with_mat("glass".to_string()).unwrap();
+
+        let entries = source_legend(&ledger);
+        let srcs: Vec<&str> = entries.iter().map(|entry| entry.src.as_str()).collect();
+        assert_eq!(srcs, vec!["Light(0)", "Mat(0)", "Mat(1)"]);
+        assert_eq!(entries[1].name, "water");
+        assert_eq!(entries[0].color, SOURCE_PALETTE[0]);
+        assert_ne!(entries[1].color, entries[2].color);
+
+        // Registering other sources keeps the colors
+        ledger.with_light("lamp".to_string()).unwrap();
+        ledger.with_surf("lens".to_string(), None).unwrap();
+        let more_entries = source_legend(&ledger);
+        let color_of = |entries: &[SourceLegendEntry], src: &str| {
+            entries.iter().find(|entry| entry.src == src).map(|entry| entry.color.clone())
+        };
+        for src in ["Light(0)", "Mat(0)", "Mat(1)"] {
+            assert_eq!(color_of(&more_entries, src), color_of(&entries, src));
+        }
+
+        let mut buffer = Vec::new();
+        write_json(&entries, &mut buffer).expect("Unable to write legend");
+        let parsed: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(parsed[2]["name"], "glass");
+    }
+}
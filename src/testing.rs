@@ -0,0 +1,212 @@
+use arbitrary::{Arbitrary, Result, Unstructured};
+use proptest::prelude::*;
+
+use crate::detection::{self, Detection};
+use crate::emission::{Emission, Modulation, TemporalMode};
+use crate::ledger::{Ledger, Uid};
+use crate::mcrt::{Elastic, Inelastic, Interface, MCRT, Material, Reflector, Roulette, ScatterDir};
+use crate::processing::Processing;
+use crate::raw;
+use crate::transport::{self, Transport};
+use crate::voxel::Voxel;
+use crate::wavelength::{self, Channel};
+use crate::{Encode, EventId, SrcId};
+
+// ----------------------------------------------------
+// Generators for property tests, behind the `testing` feature
+// ----------------------------------------------------
+// The Arbitrary implementations only produce valid events, i.e. whose raw word decodes and encodes
+// back to the same word, and small Ledgers described by a `LedgerRecipe`. The proptest strategies
+// feed bytes drawn by proptest to the same implementations, such that failing cases shrink towards
+// the events generated from zeroed bytes.
+
+// Every variant of a field enum, in encoding order
+fn variants<T: TryFrom<u8>>() -> Vec<T> {
+    (0..=u8::MAX).filter_map(|code| T::try_from(code).ok()).collect()
+}
+
+fn scatter_dir(u: &mut Unstructured) -> Result<ScatterDir> {
+    Ok(*u.choose(&[ScatterDir::Any, ScatterDir::Forward, ScatterDir::Side, ScatterDir::Backward])?)
+}
+
+fn mcrt(u: &mut Unstructured) -> Result<MCRT> {
+    let mcrt = match u.int_in_range(0..=2)? {
+        0 => MCRT::Interface(*u.choose(&[
+            Interface::Reflection,
+            Interface::Refraction,
+            Interface::FresnelSplit,
+            Interface::ReEmittance,
+        ])?),
+        1 => MCRT::Reflector(*u.choose(&[
+            Reflector::Diffuse,
+            Reflector::Specular,
+            Reflector::Composite,
+            Reflector::RetroReflective,
+            Reflector::CompositeRetroReflective,
+        ])?),
+        _ => MCRT::Material(match u.int_in_range(0..=3)? {
+            0 => Material::Absorption,
+            1 if u.arbitrary()? => Material::Inelastic(Inelastic::Raman(scatter_dir(u)?)),
+            1 => Material::Inelastic(Inelastic::Fluorescence(scatter_dir(u)?)),
+            2 => Material::Elastic(match u.int_in_range(0..=3)? {
+                0 => Elastic::HenyeyGreenstein(scatter_dir(u)?),
+                1 => Elastic::Mie(scatter_dir(u)?),
+                2 => Elastic::Rayleigh(scatter_dir(u)?),
+                _ => Elastic::SphericalCdf(scatter_dir(u)?),
+            }),
+            _ if u.arbitrary()? => Material::Roulette(Roulette::Killed),
+            _ => Material::Roulette(Roulette::Survived { boost_class: u.int_in_range(0..=raw::MAX_BOOST_CLASS)? }),
+        }),
+    };
+    Ok(mcrt)
+}
+
+impl<'a> Arbitrary<'a> for EventId {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut event_id = match u.int_in_range(0..=5)? {
+            0 => {
+                let event_id = EventId::new_emission(*u.choose(&variants::<Emission>())?, SrcId::Light(u.arbitrary()?));
+                let mode = *u.choose(&variants::<TemporalMode>())?;
+                let pulse = match mode {
+                    TemporalMode::Pulsed if u.arbitrary()? => Some(u.int_in_range(0..=15)?),
+                    _ => None,
+                };
+                if mode == TemporalMode::Continuous {
+                    event_id
+                } else {
+                    event_id.with_modulation(Modulation { mode, pulse })
+                }
+            }
+            // Decoding gives the MatSurf superset, equal to any kind through the canonical encoding
+            1 => {
+                let id = u.arbitrary()?;
+                let src_id = *u.choose(&[SrcId::Mat(id), SrcId::Surf(id), SrcId::MatSurf(id)])?;
+                EventId::new_mcrt(mcrt(u)?, src_id)
+            }
+            2 => {
+                let detection = *u.choose(&variants::<Detection>())?;
+                if u.arbitrary()? {
+                    let detector_id = u.int_in_range(0..=detection::MAX_PIXELATED_DETECTOR)?;
+                    EventId::new_detection(detection, SrcId::Detector(detector_id))
+                        .with_pixel(u.int_in_range(0..=detection::MAX_PIXEL)?)
+                } else {
+                    EventId::new_detection(detection, SrcId::Detector(u.arbitrary()?))
+                }
+            }
+            3 => EventId::new_processing(*u.choose(&variants::<Processing>())?),
+            4 => {
+                let transport = *u.choose(&variants::<Transport>())?;
+                let event_id = EventId::new_transport(transport);
+                if transport.is_boundary() && u.arbitrary()? {
+                    event_id.with_face(u.int_in_range(0..=transport::NO_FACE - 1)?)
+                } else {
+                    event_id
+                }
+            }
+            _ => EventId::new_voxel(Voxel::new(u.int_in_range(0..=Voxel::MAX_INDEX)?)),
+        };
+        let pulse_indexed = event_id.modulation.is_some_and(|modulation| modulation.pulse.is_some());
+        if wavelength::supports_channel(&event_id.event_type) && !pulse_indexed && u.arbitrary()? {
+            event_id = event_id.with_channel(Channel::new(u.int_in_range(1..=Channel::MAX)?));
+        }
+        Ok(event_id)
+    }
+}
+
+// Inserts building a small Ledger: each event either starts a new chain (None) or follows the
+// `idx`th entry inserted before it, such that chains share prefixes and branch out
+#[derive(Clone, Debug)]
+pub struct LedgerRecipe {
+    pub inserts: Vec<(Option<usize>, EventId)>,
+}
+
+impl LedgerRecipe {
+    pub const MAX_INSERTS: usize = 64;
+
+    pub fn build(&self) -> Ledger {
+        let mut ledger = Ledger::new();
+        ledger.with_light("laser".to_string()).unwrap();
+        ledger.with_mat("water".to_string()).unwrap();
+        ledger.with_surf("lens".to_string(), None).expect("Ungrouped surfaces don't conflict");
+        let mut uids: Vec<Uid> = Vec::with_capacity(self.inserts.len());
+        for (prev, event_id) in &self.inserts {
+            let uid = match prev {
+                Some(idx) => ledger.insert(uids[*idx], event_id.clone()).expect("Recipes follow earlier entries"),
+                None => ledger.insert_start(event_id.clone()).expect("Recipes stay within the seq_ids"),
+            };
+            uids.push(uid);
+        }
+        ledger
+    }
+}
+
+impl<'a> Arbitrary<'a> for LedgerRecipe {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let len = u.int_in_range(1..=Self::MAX_INSERTS)?;
+        let mut inserts = Vec::with_capacity(len);
+        for idx in 0..len {
+            let prev = if idx == 0 || u.ratio(1, 8)? { None } else { Some(u.int_in_range(0..=idx - 1)?) };
+            inserts.push((prev, u.arbitrary()?));
+        }
+        Ok(LedgerRecipe { inserts })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Ledger {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(LedgerRecipe::arbitrary(u)?.build())
+    }
+}
+
+// ----------------------------------------------------
+// Proptest strategies
+// ----------------------------------------------------
+fn from_bytes<T: for<'a> Arbitrary<'a> + std::fmt::Debug>(max_bytes: usize) -> impl Strategy<Value = T> {
+    proptest::collection::vec(any::<u8>(), 0..=max_bytes)
+        .prop_map(|bytes| T::arbitrary_take_rest(Unstructured::new(&bytes)).expect("Generators never reject their input"))
+}
+
+pub fn event_id() -> impl Strategy<Value = EventId> {
+    from_bytes(32)
+}
+
+// Raw words of valid events
+pub fn raw_event() -> impl Strategy<Value = u32> {
+    event_id().prop_map(|event_id| event_id.encode())
+}
+
+// Build the Ledger with `LedgerRecipe::build`, the Ledger itself can't be printed on failure
+pub fn ledger_recipe() -> impl Strategy<Value = LedgerRecipe> {
+    from_bytes(LedgerRecipe::MAX_INSERTS * 24)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Decode;
+    use crate::filter::{BitsMatch, find_forward_uid_seq};
+
+    proptest! {
+        #[test]
+        fn raw_event_round_trip(raw_event in raw_event()) {
+            prop_assert_eq!(EventId::decode(raw_event).encode(), raw_event);
+        }
+
+        #[test]
+        fn event_id_round_trip(event_id in event_id()) {
+            prop_assert_eq!(EventId::decode(event_id.encode()), event_id);
+        }
+
+        #[test]
+        fn chains_match_their_events(recipe in ledger_recipe()) {
+            let ledger = recipe.build();
+            for chain in ledger.chains() {
+                let leaf = *chain.last().unwrap();
+                prop_assert!(ledger.find_events(BitsMatch::new(u32::MAX, leaf.event)).contains(&leaf));
+                // Sequence filters skip the start event
+                let filter = chain[1..].iter().map(|uid| BitsMatch::new(u32::MAX, uid.event)).collect();
+                prop_assert!(find_forward_uid_seq(&ledger, filter).contains(&leaf));
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+
+use crate::filter::BitsMatch;
+use crate::ledger::Uid;
+use crate::raw::{Pipeline, RawField};
+use num_enum::{TryFromPrimitive, IntoPrimitive};
+
+// NOTE: Transport events record decisions of the transport engine itself rather than physical
+// interactions, such as variance reduction, following the emission layout without a source:
+// | Pipeline (4) | Transport (8) | Unused (16) |
+// Boundary crossings use the unused bits for the index of the domain face crossed:
+// | Pipeline (4) | Transport (8) | Face (16) |
+// with the all-ones face reserved for crossings recorded without a face, see `NO_FACE`.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive, IntoPrimitive, Hash, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum Transport {
+    // Photon split into several copies sharing the chain up to this event, each copy continues
+    // as a separate child of the Split entry, see `Ledger::branches`
+    Split,
+    // Photon crossed a periodic boundary and re-enters the domain through the opposite face
+    PeriodicBoundary,
+    // Photon crossed a mirrored boundary and is reflected back into the domain
+    MirrorBoundary,
+    // Photon left the domain through an open boundary, i.e. leakage
+    OpenBoundary,
+    // Chain truncated by the Recorder once the photon exceeded the max scatter depth stored in the
+    // ledger, see `Recorder::with_max_depth`
+    MaxDepth,
+}
+
+impl RawField for Transport {
+    const MASK: u32 = 0x00FF0000;
+    const SHIFT: usize = 16;
+    const BITSIZE: usize = 8;
+}
+
+crate::raw::const_encode!(Transport);
+
+impl Transport {
+    pub fn is_boundary(&self) -> bool {
+        matches!(self, Transport::PeriodicBoundary | Transport::MirrorBoundary | Transport::OpenBoundary)
+    }
+}
+
+pub const FACE_MASK: u32 = 0x0000FFFF;
+// Face bits of boundary crossings recorded without a face, such that they don't decode as face 0
+pub const NO_FACE: u16 = 0xFFFF;
+
+// Faces of box domains are indexed as 2 * axis + (0 at the min side, 1 at the max side), i.e.
+// -X = 0, +X = 1, -Y = 2, +Y = 3, -Z = 4, +Z = 5. Other domains are free to use their own indices.
+pub fn box_face(axis: usize, max_side: bool) -> u16 {
+    assert!(axis < 3, "Box domains have 3 axes, not {}", axis + 1);
+    (2 * axis + max_side as usize) as u16
+}
+
+pub fn face_of(raw_event: u32) -> Option<u16> {
+    if (raw_event & Pipeline::mask()) != Pipeline::Transport.encode() {
+        return None;
+    }
+    let transport = Transport::try_from(((raw_event & Transport::mask()) >> Transport::shift()) as u8).ok()?;
+    let face = (raw_event & FACE_MASK) as u16;
+    (transport.is_boundary() && face != NO_FACE).then_some(face)
+}
+
+// Crossings of the boundary `transport` through `face`
+pub fn face_bits_match(transport: Transport, face: u16) -> BitsMatch {
+    assert!(transport.is_boundary(), "{:?} is not a boundary crossing", transport);
+    assert!(face != NO_FACE, "Face {} is reserved for crossings without a face", NO_FACE);
+    BitsMatch::new(
+        Pipeline::mask() | Transport::mask() | FACE_MASK,
+        Pipeline::Transport.encode() | transport.encode() | face as u32,
+    )
+}
+
+// Net number of periodic wraps along each axis of a box domain, such that the unwrapped position
+// is the wrapped one plus `offsets * domain size`
+pub fn periodic_offsets(chain: &[Uid]) -> [i32; 3] {
+    let periodic = Pipeline::Transport.encode() | Transport::PeriodicBoundary.encode();
+    let mut offsets = [0; 3];
+    for uid in chain {
+        if uid.event & (Pipeline::mask() | Transport::mask()) != periodic {
+            continue;
+        }
+        let face = (uid.event & FACE_MASK) as usize;
+        if face < 6 {
+            offsets[face / 2] += if face % 2 == 1 { 1 } else { -1 };
+        }
+    }
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Decode, Encode, EventId, EventType};
+
+    #[test]
+    fn boundary_crossings() {
+        let event_id = EventId::new_transport(Transport::PeriodicBoundary).with_face(box_face(2, true));
+        let raw_event = event_id.encode();
+        assert_eq!(raw_event, 0x0B010005);
+        let decoded = EventId::decode(raw_event);
+        assert_eq!(decoded.event_type, EventType::Transport(Transport::PeriodicBoundary));
+        assert_eq!(decoded.face, Some(5));
+        assert_eq!(face_of(raw_event), Some(5));
+        assert_eq!(face_of(EventId::new_transport(Transport::Split).encode()), None);
+
+        let bits_match = face_bits_match(Transport::PeriodicBoundary, 5);
+        assert_eq!(raw_event & bits_match.mask, bits_match.value);
+        assert_ne!(raw_event & face_bits_match(Transport::OpenBoundary, 5).mask, face_bits_match(Transport::OpenBoundary, 5).value);
+
+        let wrap = |face| Uid::new(0, EventId::new_transport(Transport::PeriodicBoundary).with_face(face).encode());
+        let mirror = Uid::new(0, EventId::new_transport(Transport::MirrorBoundary).with_face(0).encode());
+        let chain = [wrap(box_face(0, true)), wrap(box_face(0, true)), mirror, wrap(box_face(1, false)), wrap(box_face(2, true))];
+        assert_eq!(periodic_offsets(&chain), [2, -1, 1]);
+
+        // Crossings without a face don't decode as face 0
+        let faceless = EventId::new_transport(Transport::OpenBoundary);
+        assert_eq!(faceless.encode(), 0x0B03FFFF);
+        assert_eq!(face_of(faceless.encode()), None);
+        assert_eq!(EventId::decode(faceless.encode()).face, None);
+        assert_eq!(EventId::decode(EventId::new_transport(Transport::OpenBoundary).with_face(0).encode()).face, Some(0));
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+
+use serde::{Deserialize, Serialize};
+
+use crate::filter::BitsMatch;
+use crate::kind::{EventKind, Granularity};
+use crate::ledger::{Ledger, Uid};
+use crate::raw::{Pipeline, RawField};
+use crate::{Decode, Encode};
+
+// NOTE: Voxel events tag *where* the subsequent MCRT event took place in a voxelized geometry. They
+// are optional and interleaved before the tagged event, i.e. Emission -> Voxel -> MCRT -> Voxel ->
+// MCRT. The index takes all bits below the pipeline, without a source:
+// | Pipeline (4) | VoxelIndex (24) |
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Voxel(u32);
+
+impl Voxel {
+    // NOTE: Not a RawField, the index doesn't fit the u8 conversions of its default decode/encode
+    pub const MASK: u32 = 0x00FFFFFF;
+    pub const BITSIZE: usize = 24;
+    pub const MAX_INDEX: u32 = Self::MASK;
+
+    pub fn new(index: u32) -> Self {
+        assert!(index <= Self::MAX_INDEX, "Voxel index {} exceeds 24 bits", index);
+        Voxel(index)
+    }
+
+    pub fn index(&self) -> u32 {
+        self.0
+    }
+}
+
+impl std::fmt::Display for Voxel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Voxel({})", self.0)
+    }
+}
+
+impl Encode<u32> for Voxel {
+    fn encode(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Decode<u32> for Voxel {
+    fn decode(raw: u32) -> Self {
+        Voxel(raw & Self::MASK)
+    }
+}
+
+fn is_voxel_event(raw_event: u32) -> bool {
+    (raw_event & Pipeline::mask()) == Pipeline::Voxel.encode()
+}
+
+// ----------------------------------------------------
+// Filters by voxel range
+// ----------------------------------------------------
+// A BitsMatch only covers aligned power-of-two blocks of indices, so an arbitrary range is split
+// into the minimal set of such blocks. An event is in the range if it matches any of them.
+pub fn range_bits_matches(range: RangeInclusive<u32>) -> Vec<BitsMatch> {
+    let (mut start, end) = (*range.start() as u64, (*range.end()).min(Voxel::MAX_INDEX) as u64);
+    let mut bits_matches = Vec::new();
+    while start <= end {
+        // Largest aligned block starting at `start` that doesn't exceed `end`
+        let mut size = if start == 0 { 1u64 << Voxel::BITSIZE } else { 1u64 << start.trailing_zeros() };
+        while start + size - 1 > end {
+            size >>= 1;
+        }
+        bits_matches.push(BitsMatch::new(
+            Pipeline::mask() | (Voxel::MASK & !((size - 1) as u32)),
+            Pipeline::Voxel.encode() | start as u32,
+        ));
+        start += size;
+    }
+    bits_matches
+}
+
+pub fn in_range(raw_event: u32, range: &RangeInclusive<u32>) -> bool {
+    is_voxel_event(raw_event) && range.contains(&Voxel::decode(raw_event).index())
+}
+
+// Chains visiting any voxel of `range`
+pub fn chains_in_range(ledger: &Ledger, range: RangeInclusive<u32>) -> Vec<Vec<Uid>> {
+    ledger
+        .chains()
+        .filter(|chain| chain.iter().any(|uid| in_range(uid.event, &range)))
+        .collect()
+}
+
+// ----------------------------------------------------
+// Per-voxel analytics
+// ----------------------------------------------------
+
+// Voxel in which the event `uid` took place, given it was tagged by a preceding Voxel event
+pub fn voxel_of(ledger: &Ledger, uid: &Uid) -> Option<Voxel> {
+    ledger
+        .get_prev(uid.seq_id)
+        .filter(|prev| is_voxel_event(prev.event))
+        .map(|prev| Voxel::decode(prev.event))
+}
+
+// Number of ledger entries of each event kind tagged with each voxel. Identical histories share
+// their entries, so this counts distinct histories rather than photons.
+pub fn voxel_histogram(ledger: &Ledger, granularity: Granularity) -> BTreeMap<Voxel, BTreeMap<EventKind, usize>> {
+    let mut histogram: BTreeMap<Voxel, BTreeMap<EventKind, usize>> = BTreeMap::new();
+    for uid in ledger.entries().filter(|uid| is_voxel_event(uid.event)) {
+        let voxel = Voxel::decode(uid.event);
+        for next_uid in ledger.get_next(&uid) {
+            let kind = EventKind::from_raw(next_uid.event, granularity);
+            *histogram.entry(voxel).or_default().entry(kind).or_default() += 1;
+        }
+    }
+    histogram
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emission::Emission;
+    use crate::{EventId, EventType, SrcId, filter_seq, mcrt_event};
+
+    #[test]
+    fn voxel_encoding() {
+        let raw_event = EventId::new_voxel(Voxel::new(0x012345)).encode();
+        assert_eq!(raw_event, 0x0D012345);
+        let decoded = EventId::decode(raw_event);
+        assert_eq!(decoded.event_type, EventType::Voxel(Voxel::new(0x012345)));
+        assert_eq!(decoded.src_id, SrcId::None);
+
+        let bits_match = filter_seq!(Voxel, 0x012345);
+        assert_eq!((raw_event & bits_match.mask), bits_match.value);
+        let bits_match = filter_seq!(Voxel);
+        assert_eq!((raw_event & bits_match.mask), bits_match.value);
+    }
+
+    #[test]
+    fn voxel_range_filter() {
+        let range = 3..=17;
+        let bits_matches = range_bits_matches(range.clone());
+        assert_eq!(bits_matches.len(), 4); // 3, 4-7, 8-15, 16-17
+        for index in 0..64 {
+            let raw_event = EventId::new_voxel(Voxel::new(index)).encode();
+            let matched = bits_matches.iter().any(|bm| (raw_event & bm.mask) == bm.value);
+            assert_eq!(matched, range.contains(&index), "Voxel index {}", index);
+            assert_eq!(in_range(raw_event, &range), range.contains(&index));
+        }
+        assert_eq!(range_bits_matches(0..=Voxel::MAX_INDEX).len(), 1);
+    }
+
+    #[test]
+    fn voxel_analytics() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let mat_id = ledger.with_mat("tissue".to_string()).unwrap();
+        let uid1 = ledger.insert_start(EventId::new_emission(Emission::PencilBeam, light_id)).unwrap();
+        let vox1 = ledger.insert(uid1, EventId::new_voxel(Voxel::new(10))).unwrap();
+        let uid2 = ledger.insert(vox1, EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Forward), mat_id)).unwrap();
+        let vox2 = ledger.insert(uid2, EventId::new_voxel(Voxel::new(42))).unwrap();
+        let uid3 = ledger.insert(vox2, EventId::new_mcrt(mcrt_event!(Material, Absorption), mat_id)).unwrap();
+        ledger.insert(vox1, EventId::new_mcrt(mcrt_event!(Material, Absorption), mat_id)).unwrap();
+
+        assert_eq!(voxel_of(&ledger, &uid2), Some(Voxel::new(10)));
+        assert_eq!(voxel_of(&ledger, &uid3), Some(Voxel::new(42)));
+        assert_eq!(voxel_of(&ledger, &vox1), None);
+
+        let histogram = voxel_histogram(&ledger, Granularity::SubType);
+        assert_eq!(histogram[&Voxel::new(10)][&EventKind::Mie], 1);
+        assert_eq!(histogram[&Voxel::new(10)][&EventKind::Absorption], 1);
+        assert_eq!(histogram[&Voxel::new(42)][&EventKind::Absorption], 1);
+
+        assert_eq!(chains_in_range(&ledger, 40..=50), vec![vec![uid1, vox1, uid2, vox2, uid3]]);
+        assert_eq!(chains_in_range(&ledger, 0..=10).len(), 2);
+    }
+}
@@ -0,0 +1,105 @@
+use wasm_bindgen::prelude::*;
+
+use crate::filter::{find_forward_uid_expr, parse_with_ledger};
+use crate::ledger::{self, Uid};
+use crate::{EventId, TryDecode};
+
+// ----------------------------------------------------
+// WASM bindings, behind the `wasm` feature
+// ----------------------------------------------------
+// Exports for browser based chain viewers, built for wasm32-unknown-unknown (see the README):
+//     const ledger = Ledger.fromJson(await (await fetch("ledger.json")).text());
+//     for (const uid of ledger.find("MCRT|Material|Elastic|*|*|Mat(water) -> Detection"))
+//         console.log(ledger.path(uid));
+// UIDs cross the boundary in their display format, i.e. "3, 0x03A50001", such that they key maps
+// and DOM nodes of the viewer as is. Errors of the crate are thrown as strings.
+
+#[wasm_bindgen(js_name = Ledger)]
+pub struct WasmLedger(ledger::Ledger);
+
+#[wasm_bindgen(js_class = Ledger)]
+impl WasmLedger {
+    #[wasm_bindgen(js_name = fromJson)]
+    pub fn from_json(json: &str) -> Result<WasmLedger, String> {
+        ledger::parse_ledger_json(json).map(WasmLedger).map_err(|err| err.to_string())
+    }
+
+    // Leaves of the chains matching a filter expression, with the source names of the ledger
+    pub fn find(&self, filter: &str) -> Result<Vec<String>, String> {
+        let expr = parse_with_ledger(filter, &self.0)?;
+        Ok(display(find_forward_uid_expr(&self.0, &expr)))
+    }
+
+    pub fn chain(&self, uid: &str) -> Result<Vec<String>, String> {
+        Ok(display(self.0.get_chain(self.uid(uid)?)))
+    }
+
+    // Readable events of the chain leading to `uid`, one per step, i.e. "2: MCRT(...) Mat(1) [water]",
+    // see `Ledger::describe_chain`. Steps which don't decode hold the decoding error.
+    pub fn path(&self, uid: &str) -> Result<Vec<String>, String> {
+        let steps = self.0.describe_chain(self.uid(uid)?);
+        Ok(steps.into_iter().map(|step| step.map_or_else(|err| err.to_string(), |entry| entry.to_string())).collect())
+    }
+
+    pub fn children(&self, uid: &str) -> Result<Vec<String>, String> {
+        Ok(display(self.0.get_next(&self.uid(uid)?)))
+    }
+
+    #[wasm_bindgen(js_name = startEvents)]
+    pub fn start_events(&self) -> Vec<String> {
+        display(self.0.get_start_events().iter().copied())
+    }
+
+    pub fn leaves(&self) -> Vec<String> {
+        display(self.0.leaves())
+    }
+}
+
+impl WasmLedger {
+    fn uid(&self, uid: &str) -> Result<Uid, String> {
+        let uid = uid.parse()?;
+        if !self.0.contains(&uid) {
+            return Err(format!("UID {} is not in the ledger", uid));
+        }
+        Ok(uid)
+    }
+}
+
+fn display(uids: impl IntoIterator<Item = Uid>) -> Vec<String> {
+    uids.into_iter().map(|uid| uid.to_string()).collect()
+}
+
+// EventId of a raw event as an object of its serialized fields (event_type, src_id, channel, ...)
+#[wasm_bindgen(js_name = decodeEvent)]
+pub fn decode_event(event: u32) -> Result<JsValue, String> {
+    let event_id = EventId::try_decode(event).map_err(|err| err.to_string())?;
+    serde_wasm_bindgen::to_value(&event_id).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::tests::detected_chain;
+
+    #[test]
+    fn wasm_ledger() {
+        let (ledger, [start, scatter, detected]) = detected_chain();
+
+        let ledger = WasmLedger::from_json(&serde_json::to_string(&ledger).unwrap()).unwrap();
+        let found = ledger.find("MCRT|Material|Elastic|*|*|Mat(water) -> Detection").unwrap();
+        assert_eq!(found, vec![detected.to_string()]);
+        assert_eq!(ledger.leaves(), found);
+        assert_eq!(ledger.chain(&found[0]).unwrap(), display([start, scatter, detected]));
+        assert_eq!(ledger.children(&ledger.start_events()[0]).unwrap(), vec![scatter.to_string()]);
+
+        let path = ledger.path(&found[0]).unwrap();
+        assert_eq!(path.len(), 3);
+        assert!(path[0].ends_with("[laser]"), "{}", path[0]);
+        assert_eq!(path[1], format!("{}: MCRT(Material(Elastic(Mie(Forward)))) Mat(0) [water]", scatter.seq_id));
+
+        assert!(ledger.chain("7, 0x03A50001").is_err());
+        assert!(ledger.chain("7").is_err());
+        assert!(ledger.find("MCRT|Material|Elastic|*|*|Mat(steel)").is_err());
+        assert!(WasmLedger::from_json("{").is_err());
+    }
+}
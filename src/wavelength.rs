@@ -0,0 +1,169 @@
+use serde::{Deserialize, Serialize};
+
+use crate::filter::BitsMatch;
+use crate::raw::{Pipeline, RawField};
+use crate::{EventType, emission, mcrt};
+
+// NOTE: Multispectral runs tag emission and inelastic (Raman, Fluorescence) events with the
+// wavelength channel of the photon leaving the event, in the otherwise unused top nibble:
+// | Channel (4) | Pipeline (4) | EventType (8) | SrcId (16) |
+// Channel 0 is left for events without a channel, the ledger registers up to 15 channels.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Channel(u8);
+
+impl Channel {
+    pub const MAX: u8 = 15;
+
+    pub fn new(id: u8) -> Self {
+        assert!((1..=Self::MAX).contains(&id), "Wavelength channel {} out of range 1..={}", id, Self::MAX);
+        Channel(id)
+    }
+
+    pub fn id(&self) -> u8 {
+        self.0
+    }
+}
+
+impl std::fmt::Display for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Channel({})", self.0)
+    }
+}
+
+impl From<u8> for Channel {
+    fn from(id: u8) -> Self {
+        Channel(id)
+    }
+}
+
+impl From<Channel> for u8 {
+    fn from(channel: Channel) -> u8 {
+        channel.0
+    }
+}
+
+impl RawField for Channel {
+    const MASK: u32 = 0xF0000000;
+    const SHIFT: usize = 28;
+    const BITSIZE: usize = 4;
+}
+
+// Pulse-indexed emission events hold their pulse index in the channel bits
+fn is_pulse_indexed(raw_event: u32) -> bool {
+    (raw_event & Pipeline::mask()) == Pipeline::Emission.encode() && (raw_event & emission::PULSE_INDEXED) != 0
+}
+
+// Channel of a raw event, None if the event doesn't carry one
+pub fn channel_of(raw_event: u32) -> Option<Channel> {
+    match Channel::decode(raw_event) {
+        Channel(0) => None,
+        _ if is_pulse_indexed(raw_event) => None,
+        channel => Some(channel),
+    }
+}
+
+// Restrict `bits_match` to the events of `channel`. Filters on emission events also exclude the
+// pulse-indexed ones, whose pulse index would otherwise match as a channel.
+pub fn channel_bits_match(channel: Channel, bits_match: BitsMatch) -> BitsMatch {
+    let emission_only = (bits_match.mask & Pipeline::mask()) == Pipeline::mask()
+        && (bits_match.value & Pipeline::mask()) == Pipeline::Emission.encode();
+    let pulse_mask = if emission_only { emission::PULSE_INDEXED } else { 0 };
+    BitsMatch::new(
+        bits_match.mask | Channel::mask() | pulse_mask,
+        (bits_match.value & !pulse_mask) | channel.encode(),
+    )
+}
+
+// Only events creating a photon of a (possibly new) wavelength carry a channel
+pub fn supports_channel(event_type: &EventType) -> bool {
+    matches!(
+        event_type,
+        EventType::Emission(_) | EventType::MCRT(mcrt::MCRT::Material(mcrt::Material::Inelastic(_)))
+    )
+}
+
+// Wavelength band of a registered channel, in nanometers
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WavelengthChannel {
+    pub name: String,
+    pub min_nm: f64,
+    pub max_nm: f64,
+}
+
+impl WavelengthChannel {
+    pub fn contains(&self, wavelength_nm: f64) -> bool {
+        wavelength_nm >= self.min_nm && wavelength_nm < self.max_nm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emission::Emission;
+    use crate::ledger::Ledger;
+    use crate::{Decode, Encode, EventId, SrcId, filter_seq, mcrt_event};
+
+    #[test]
+    fn channel_encoding() {
+        let mut ledger = Ledger::new();
+        let light_id = ledger.with_light("laser".to_string()).unwrap();
+        let blue = ledger.with_channel("blue".to_string(), 450.0, 495.0);
+        let red = ledger.with_channel("red".to_string(), 620.0, 750.0);
+        assert_eq!(blue, Channel::new(1));
+        assert_eq!(ledger.channel_by_name("red"), Some(red));
+        assert!(ledger.channel(&red).unwrap().contains(633.0));
+
+        let raw_event = EventId::new_emission(Emission::PencilBeam, light_id).with_channel(red).encode();
+        assert_eq!(raw_event, 0x21000000);
+        assert_eq!(channel_of(raw_event), Some(red));
+        let decoded = EventId::decode(raw_event);
+        assert_eq!(decoded.event_type, EventType::Emission(Emission::PencilBeam));
+        assert_eq!(decoded.channel, Some(red));
+        assert_eq!(decoded.encode(), raw_event);
+
+        let raw_event = EventId::new_mcrt(mcrt_event!(Material, Inelastic, Fluorescence, Any), SrcId::Mat(1))
+            .with_channel(blue)
+            .encode();
+        assert_eq!(channel_of(raw_event), Some(blue));
+        assert_eq!(channel_of(EventId::new_emission(Emission::PencilBeam, light_id).encode()), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn channel_on_elastic_event() {
+        EventId::new_mcrt(mcrt_event!(Material, Elastic, Mie, Any), SrcId::Mat(1)).with_channel(Channel::new(1));
+    }
+
+    #[test]
+    fn channel_filter() {
+        let red = Channel::new(2);
+        let bits_match = filter_seq!(Channel(red), Emission, SrcId::Light(0));
+        let raw_event = EventId::new_emission(Emission::PencilBeam, SrcId::Light(0)).with_channel(red).encode();
+        assert_eq!(raw_event & bits_match.mask, bits_match.value);
+        let raw_event = EventId::new_emission(Emission::PencilBeam, SrcId::Light(0)).with_channel(Channel::new(1)).encode();
+        assert_ne!(raw_event & bits_match.mask, bits_match.value);
+
+        let bits_match = filter_seq!(Channel(red), MCRT, Material, Inelastic, Raman, Any, SrcId::Mat(1));
+        let raw_event = EventId::new_mcrt(mcrt_event!(Material, Inelastic, Raman, Any), SrcId::Mat(1)).with_channel(red).encode();
+        assert_eq!(raw_event & bits_match.mask, bits_match.value);
+    }
+
+    #[test]
+    fn channel_filter_skips_pulses() {
+        let pulse = EventId::new_emission(Emission::GaussianBeam, SrcId::Light(0))
+            .with_modulation(emission::Modulation::pulsed(Some(2)))
+            .encode();
+        assert_eq!(channel_of(pulse), None);
+        let bits_match = filter_seq!(Channel(Channel::new(2)), Emission, SrcId::Light(0));
+        assert_ne!(pulse & bits_match.mask, bits_match.value);
+    }
+
+    #[test]
+    #[should_panic(expected = "Pulse-indexed")]
+    fn channel_and_pulse_on_encode() {
+        let mut event_id = EventId::new_emission(Emission::GaussianBeam, SrcId::Light(0)).with_channel(Channel::new(1));
+        event_id.modulation = Some(emission::Modulation::pulsed(Some(3)));
+        event_id.encode();
+    }
+}